@@ -146,6 +146,12 @@ int USPiSendFrame (const void *pBuffer, unsigned nLength);
 #define USPI_FRAME_BUFFER_SIZE	1600
 int USPiReceiveFrame (void *pBuffer, unsigned *pResultLength);
 
+// pHandler is called from the USB interrupt with each received frame,
+// USPiReceiveFrame must not be used after registering it
+// returns 0 on failure
+typedef void TUSPiFrameReceivedHandler (const void *pBuffer, unsigned nLength);
+int USPiEthernetRegisterReceiveHandler (TUSPiFrameReceivedHandler *pHandler);
+
 //
 // GamePad device
 //
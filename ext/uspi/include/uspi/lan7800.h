@@ -28,6 +28,9 @@
 
 #define FRAME_BUFFER_SIZE	1600
 
+// called from the USB interrupt with each received frame, without the RX command words and FCS
+typedef void TLAN7800FrameHandler (const void *pBuffer, unsigned nLength);
+
 typedef struct TLAN7800Device
 {
 	TUSBFunction m_USBFunction;
@@ -38,6 +41,10 @@ typedef struct TLAN7800Device
 	TMACAddress m_MACAddress;
 
 	u8 *m_pTxBuffer;
+
+	TUSBRequest m_RxURB;
+	u8 *m_pRxBuffer;
+	TLAN7800FrameHandler *m_pFrameHandler;
 }
 TLAN7800Device;
 
@@ -53,6 +60,9 @@ boolean LAN7800DeviceSendFrame (TLAN7800Device *pThis, const void *pBuffer, unsi
 // pBuffer must have size FRAME_BUFFER_SIZE
 boolean LAN7800DeviceReceiveFrame (TLAN7800Device *pThis, void *pBuffer, unsigned *pResultLength);
 
+// receive frames asynchronously from now on, LAN7800DeviceReceiveFrame must not be used anymore
+boolean LAN7800DeviceRegisterFrameHandler (TLAN7800Device *pThis, TLAN7800FrameHandler *pHandler);
+
 // returns TRUE if PHY link is up
 boolean LAN7800DeviceIsLinkUp (TLAN7800Device *pThis);
 
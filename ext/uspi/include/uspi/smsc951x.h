@@ -28,6 +28,9 @@
 
 #define FRAME_BUFFER_SIZE	1600
 
+// called from the USB interrupt with each received frame, without the RX status and CRC
+typedef void TSMSC951xFrameHandler (const void *pBuffer, unsigned nLength);
+
 typedef struct TSMSC951xDevice
 {
 	TUSBFunction m_USBFunction;
@@ -38,6 +41,10 @@ typedef struct TSMSC951xDevice
 	TMACAddress m_MACAddress;
 
 	u8 *m_pTxBuffer;
+
+	TUSBRequest m_RxURB;
+	u8 *m_pRxBuffer;
+	TSMSC951xFrameHandler *m_pFrameHandler;
 }
 TSMSC951xDevice;
 
@@ -53,6 +60,9 @@ boolean SMSC951xDeviceSendFrame (TSMSC951xDevice *pThis, const void *pBuffer, un
 // pBuffer must have size FRAME_BUFFER_SIZE
 boolean SMSC951xDeviceReceiveFrame (TSMSC951xDevice *pThis, void *pBuffer, unsigned *pResultLength);
 
+// receive frames asynchronously from now on, SMSC951xDeviceReceiveFrame must not be used anymore
+boolean SMSC951xDeviceRegisterFrameHandler (TSMSC951xDevice *pThis, TSMSC951xFrameHandler *pHandler);
+
 // returns TRUE if PHY link is up
 boolean SMSC951xDeviceIsLinkUp (TSMSC951xDevice *pThis);
 
@@ -61,6 +71,8 @@ boolean SMSC951xDevicePHYWrite (TSMSC951xDevice *pThis, u8 uchIndex, u16 usValue
 boolean SMSC951xDevicePHYRead (TSMSC951xDevice *pThis, u8 uchIndex, u16 *pValue);
 boolean SMSC951xDevicePHYWaitNotBusy (TSMSC951xDevice *pThis);
 
+boolean SMSC951xDeviceStartRxRequest (TSMSC951xDevice *pThis);
+
 boolean SMSC951xDeviceWriteReg (TSMSC951xDevice *pThis, u32 nIndex, u32 nValue);
 boolean SMSC951xDeviceReadReg (TSMSC951xDevice *pThis, u32 nIndex, u32 *pValue);
 
@@ -238,6 +238,10 @@ boolean LAN7800DeviceReadWriteReg (TLAN7800Device *pThis, u32 nIndex, u32 nOrMas
 boolean LAN7800DeviceWriteReg (TLAN7800Device *pThis, u32 nIndex, u32 nValue);
 boolean LAN7800DeviceReadReg (TLAN7800Device *pThis, u32 nIndex, u32 *pValue);
 
+boolean LAN7800DeviceStartRxRequest (TLAN7800Device *pThis);
+static void LAN7800DeviceRxCompletionRoutine (TUSBRequest *pURB, void *pParam, void *pContext);
+static void LAN7800DeviceRxTimerHandler (TKernelTimerHandle hTimer, void *pParam, void *pContext);
+
 static const char FromLAN7800[] = "lan7800";
 
 // starting at 10, to be sure to not collide with smsc951x driver
@@ -253,6 +257,8 @@ void LAN7800Device (TLAN7800Device *pThis, TUSBFunction *pFunction)
 	pThis->m_pEndpointBulkIn = 0;
 	pThis->m_pEndpointBulkOut = 0;
 	pThis->m_pTxBuffer = 0;
+	pThis->m_pRxBuffer = 0;
+	pThis->m_pFrameHandler = 0;
 
 	pThis->m_pTxBuffer = malloc (FRAME_BUFFER_SIZE);
 	assert (pThis->m_pTxBuffer != 0);
@@ -268,6 +274,12 @@ void _LAN7800Device (TLAN7800Device *pThis)
 		pThis->m_pTxBuffer = 0;
 	}
 
+	if (pThis->m_pRxBuffer != 0)
+	{
+		free (pThis->m_pRxBuffer);
+		pThis->m_pRxBuffer = 0;
+	}
+
 	if (pThis->m_pEndpointBulkOut != 0)
 	{
 		_USBEndpoint (pThis->m_pEndpointBulkOut);
@@ -419,7 +431,7 @@ boolean LAN7800DeviceConfigure (TUSBFunction *pUSBFunction)
 	}
 
 	// init receive filtering engine
-	if (!LAN7800DeviceReadWriteReg (pThis, RFE_CTL, RFE_CTL_BCAST_EN | RFE_CTL_DA_PERFECT, ~0U))
+	if (!LAN7800DeviceReadWriteReg (pThis, RFE_CTL, RFE_CTL_BCAST_EN | RFE_CTL_MCAST_EN | RFE_CTL_DA_PERFECT, ~0U))
 	{
 		return FALSE;
 	}
@@ -558,6 +570,77 @@ boolean LAN7800DeviceReceiveFrame (TLAN7800Device *pThis, void *pBuffer, unsigne
 	return TRUE;
 }
 
+boolean LAN7800DeviceRegisterFrameHandler (TLAN7800Device *pThis, TLAN7800FrameHandler *pHandler)
+{
+	assert (pThis != 0);
+	assert (pHandler != 0);
+	assert (pThis->m_pFrameHandler == 0);
+
+	// NAK on RX FIFO empty, so that the request only completes with a frame
+	if (!LAN7800DeviceReadWriteReg (pThis, USB_CFG0, USB_CFG_BIR, ~0U))
+	{
+		return FALSE;
+	}
+
+	pThis->m_pRxBuffer = malloc (FRAME_BUFFER_SIZE);
+	assert (pThis->m_pRxBuffer != 0);
+	pThis->m_pFrameHandler = pHandler;
+
+	return LAN7800DeviceStartRxRequest (pThis);
+}
+
+boolean LAN7800DeviceStartRxRequest (TLAN7800Device *pThis)
+{
+	assert (pThis != 0);
+	assert (pThis->m_pRxBuffer != 0);
+
+	USBRequest (&pThis->m_RxURB, pThis->m_pEndpointBulkIn, pThis->m_pRxBuffer, FRAME_BUFFER_SIZE, 0);
+	USBRequestSetCompletionRoutine (&pThis->m_RxURB, LAN7800DeviceRxCompletionRoutine, 0, pThis);
+
+	return DWHCIDeviceSubmitAsyncRequest (USBFunctionGetHost (&pThis->m_USBFunction), &pThis->m_RxURB);
+}
+
+void LAN7800DeviceRxCompletionRoutine (TUSBRequest *pURB, void *pParam, void *pContext)
+{
+	TLAN7800Device *pThis = (TLAN7800Device *) pContext;
+	assert (pThis != 0);
+	assert (&pThis->m_RxURB == pURB);
+
+	boolean bOK = USBRequestGetStatus (pURB) != 0;
+	u32 nResultLength = USBRequestGetResultLength (pURB);
+	if (bOK && nResultLength >= RX_HEADER_SIZE)
+	{
+		u32 nRxStatus = *(u32 *) pThis->m_pRxBuffer;	// RX command A
+		u32 nFrameLength = nRxStatus & RX_CMD_A_LEN_MASK;
+		if (   !(nRxStatus & RX_CMD_A_RED)
+		    && nFrameLength > 4
+		    && nFrameLength <= nResultLength-RX_HEADER_SIZE)
+		{
+			(*pThis->m_pFrameHandler) (pThis->m_pRxBuffer + RX_HEADER_SIZE, nFrameLength-4);
+		}
+	}
+
+	_USBRequest (&pThis->m_RxURB);
+
+	// don't retry a failing transfer from the interrupt right away
+	if (!bOK)
+	{
+		StartKernelTimer (1, LAN7800DeviceRxTimerHandler, 0, pThis);
+
+		return;
+	}
+
+	LAN7800DeviceStartRxRequest (pThis);
+}
+
+void LAN7800DeviceRxTimerHandler (TKernelTimerHandle hTimer, void *pParam, void *pContext)
+{
+	TLAN7800Device *pThis = (TLAN7800Device *) pContext;
+	assert (pThis != 0);
+
+	LAN7800DeviceStartRxRequest (pThis);
+}
+
 boolean LAN7800DeviceIsLinkUp (TLAN7800Device *pThis)
 {
 	assert (pThis != 0);
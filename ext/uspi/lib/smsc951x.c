@@ -124,6 +124,9 @@ void SMSC951xDeviceDumpReg(TSMSC951xDevice *pThis, const char *pName, u32 nIndex
 void SMSC951xDeviceDumpRegs(TSMSC951xDevice *pThis);
 #endif
 
+static void SMSC951xDeviceRxCompletionRoutine(TUSBRequest *pURB, void *pParam, void *pContext);
+static void SMSC951xDeviceRxTimerHandler(TKernelTimerHandle hTimer, void *pParam, void *pContext);
+
 void SMSC951xDevice(TSMSC951xDevice *pThis, TUSBFunction *pDevice)
 {
 	assert(pThis != 0);
@@ -134,6 +137,8 @@ void SMSC951xDevice(TSMSC951xDevice *pThis, TUSBFunction *pDevice)
 	pThis->m_pEndpointBulkIn = 0;
 	pThis->m_pEndpointBulkOut = 0;
 	pThis->m_pTxBuffer = 0;
+	pThis->m_pRxBuffer = 0;
+	pThis->m_pFrameHandler = 0;
 
 	pThis->m_pTxBuffer = malloc(FRAME_BUFFER_SIZE);
 	assert(pThis->m_pTxBuffer != 0);
@@ -149,6 +154,12 @@ void _SMSC951xDevice(TSMSC951xDevice *pThis)
 		pThis->m_pTxBuffer = 0;
 	}
 
+	if (pThis->m_pRxBuffer != 0)
+	{
+		free(pThis->m_pRxBuffer);
+		pThis->m_pRxBuffer = 0;
+	}
+
 	if (pThis->m_pEndpointBulkOut != 0)
 	{
 		_USBEndpoint(pThis->m_pEndpointBulkOut);
@@ -267,6 +278,7 @@ boolean SMSC951xDeviceConfigure(TUSBFunction *pUSBFunction)
 
 	if (!SMSC951xDeviceWriteReg(pThis, LED_GPIO_CFG, LED_GPIO_CFG_SPD_LED | LED_GPIO_CFG_LNK_LED | LED_GPIO_CFG_FDX_LED) || !SMSC951xDeviceWriteReg(pThis, MAC_CR, MAC_CR_RCVOWN
 																																									   //| MAC_CR_PRMS		// promiscous mode
+																																									   | MAC_CR_MCPAS		// all multicast, for IPv6 neighbor discovery
 																																									   | MAC_CR_TXEN | MAC_CR_RXEN) ||
 		!SMSC951xDeviceWriteReg(pThis, TX_CFG, TX_CFG_ON))
 	{
@@ -374,6 +386,76 @@ boolean SMSC951xDeviceReceiveFrame(TSMSC951xDevice *pThis, void *pBuffer, unsign
 	return TRUE;
 }
 
+boolean SMSC951xDeviceRegisterFrameHandler(TSMSC951xDevice *pThis, TSMSC951xFrameHandler *pHandler)
+{
+	assert(pThis != 0);
+	assert(pHandler != 0);
+	assert(pThis->m_pFrameHandler == 0);
+
+	// NAK on an empty RX FIFO, so that the request only completes with a frame
+	u32 nHWConfig;
+	if (!SMSC951xDeviceReadReg(pThis, HW_CFG, &nHWConfig) || !SMSC951xDeviceWriteReg(pThis, HW_CFG, nHWConfig | HW_CFG_BIR))
+	{
+		return FALSE;
+	}
+
+	pThis->m_pRxBuffer = malloc(FRAME_BUFFER_SIZE);
+	assert(pThis->m_pRxBuffer != 0);
+	pThis->m_pFrameHandler = pHandler;
+
+	return SMSC951xDeviceStartRxRequest(pThis);
+}
+
+boolean SMSC951xDeviceStartRxRequest(TSMSC951xDevice *pThis)
+{
+	assert(pThis != 0);
+	assert(pThis->m_pRxBuffer != 0);
+
+	USBRequest(&pThis->m_RxURB, pThis->m_pEndpointBulkIn, pThis->m_pRxBuffer, FRAME_BUFFER_SIZE, 0);
+	USBRequestSetCompletionRoutine(&pThis->m_RxURB, SMSC951xDeviceRxCompletionRoutine, 0, pThis);
+
+	return DWHCIDeviceSubmitAsyncRequest(USBFunctionGetHost(&pThis->m_USBFunction), &pThis->m_RxURB);
+}
+
+void SMSC951xDeviceRxCompletionRoutine(TUSBRequest *pURB, void *pParam, void *pContext)
+{
+	TSMSC951xDevice *pThis = (TSMSC951xDevice *)pContext;
+	assert(pThis != 0);
+	assert(&pThis->m_RxURB == pURB);
+
+	boolean bOK = USBRequestGetStatus(pURB) != 0;
+	u32 nResultLength = USBRequestGetResultLength(pURB);
+	if (bOK && nResultLength >= 4)
+	{
+		u32 nRxStatus = *(u32 *)pThis->m_pRxBuffer;
+		u32 nFrameLength = RX_STS_FRAMELEN(nRxStatus);
+		if (!(nRxStatus & RX_STS_ERROR) && nFrameLength > 4 && nFrameLength <= nResultLength - 4)
+		{
+			(*pThis->m_pFrameHandler)(pThis->m_pRxBuffer + 4, nFrameLength - 4); // without CRC
+		}
+	}
+
+	_USBRequest(&pThis->m_RxURB);
+
+	// don't retry a failing transfer from the interrupt right away
+	if (!bOK)
+	{
+		StartKernelTimer(1, SMSC951xDeviceRxTimerHandler, 0, pThis);
+
+		return;
+	}
+
+	SMSC951xDeviceStartRxRequest(pThis);
+}
+
+void SMSC951xDeviceRxTimerHandler(TKernelTimerHandle hTimer, void *pParam, void *pContext)
+{
+	TSMSC951xDevice *pThis = (TSMSC951xDevice *)pContext;
+	assert(pThis != 0);
+
+	SMSC951xDeviceStartRxRequest(pThis);
+}
+
 boolean SMSC951xDeviceIsLinkUp(TSMSC951xDevice *pThis)
 {
 	assert(pThis != 0);
@@ -283,6 +283,19 @@ int USPiReceiveFrame(void *pBuffer, unsigned *pResultLength)
 	return SMSC951xDeviceReceiveFrame(s_pLibrary->pEth0, pBuffer, pResultLength) ? 1 : 0;
 }
 
+int USPiEthernetRegisterReceiveHandler(TUSPiFrameReceivedHandler *pHandler)
+{
+	assert(s_pLibrary != 0);
+
+	if (s_pLibrary->pEth10 != 0)
+	{
+		return LAN7800DeviceRegisterFrameHandler(s_pLibrary->pEth10, pHandler) ? 1 : 0;
+	}
+
+	assert(s_pLibrary->pEth0 != 0);
+	return SMSC951xDeviceRegisterFrameHandler(s_pLibrary->pEth0, pHandler) ? 1 : 0;
+}
+
 int USPiGamePadAvailable(void)
 {
 	assert(s_pLibrary != 0);
@@ -4,6 +4,7 @@ mod mailbox;
 mod mini_uart;
 mod pl011_uart;
 mod timers;
+mod watchdog;
 
 pub use gpio::*;
 pub use interrupt_controller::*;
@@ -11,3 +12,4 @@ pub use mailbox::*;
 pub use mini_uart::*;
 pub use pl011_uart::*;
 pub use timers::*;
+pub use watchdog::*;
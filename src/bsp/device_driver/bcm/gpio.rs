@@ -12,6 +12,22 @@ register_bitfields! {
 
     /// GPIO Function Select 1
     GPFSEL1 [
+        /// Pin 17
+        FSEL17 OFFSET(21) NUMBITS(3) [
+            Input = 0b000,
+            Output = 0b001,
+            AltFunc3 = 0b111, // PL011 UART RTS
+            AltFunc5 = 0b010  // Mini UART RTS
+        ],
+
+        /// Pin 16
+        FSEL16 OFFSET(18) NUMBITS(3) [
+            Input = 0b000,
+            Output = 0b001,
+            AltFunc3 = 0b111, // PL011 UART CTS
+            AltFunc5 = 0b010  // Mini UART CTS
+        ],
+
         /// Pin 15
         FSEL15 OFFSET(15) NUMBITS(3) [
             Input = 0b000,
@@ -32,6 +48,18 @@ register_bitfields! {
 
     /// GPIO Pull-up/down Clock Register 0
     GPPUDCLK0 [
+        /// Pin 17
+        PUDCLK17 OFFSET(17) NUMBITS(1) [
+            NoEffect = 0,
+            AssertClock = 1
+        ],
+
+        /// Pin 16
+        PUDCLK16 OFFSET(16) NUMBITS(1) [
+            NoEffect = 0,
+            AssertClock = 1
+        ],
+
         /// Pin 15
         PUDCLK15 OFFSET(15) NUMBITS(1) [
             NoEffect = 0,
@@ -121,6 +149,41 @@ impl GPIO {
 
         data.GPPUDCLK0.set(0);
     }
+
+    /// Map PL011 UART hardware flow control.
+    ///
+    /// CTS to pin 16
+    /// RTS to pin 17
+    pub fn map_pl011_flow_control(&self) {
+        let data = &self.inner.lock();
+
+        data.GPFSEL1
+            .modify(GPFSEL1::FSEL16::AltFunc3 + GPFSEL1::FSEL17::AltFunc3);
+        Self::disable_pull_16_17(data);
+    }
+
+    /// Map mini UART hardware flow control.
+    ///
+    /// CTS to pin 16
+    /// RTS to pin 17
+    pub fn map_mini_uart_flow_control(&self) {
+        let data = &self.inner.lock();
+
+        data.GPFSEL1
+            .modify(GPFSEL1::FSEL16::AltFunc5 + GPFSEL1::FSEL17::AltFunc5);
+        Self::disable_pull_16_17(data);
+    }
+
+    fn disable_pull_16_17(data: &Regs) {
+        data.GPPUD.set(0);
+        cpu::spin_for_cycles(150);
+
+        data.GPPUDCLK0
+            .write(GPPUDCLK0::PUDCLK16::AssertClock + GPPUDCLK0::PUDCLK17::AssertClock);
+        cpu::spin_for_cycles(150);
+
+        data.GPPUDCLK0.set(0);
+    }
 }
 
 //------------------------------------------------------------------------------
@@ -2,6 +2,7 @@ pub mod local_ic;
 mod peripheral_ic;
 
 use crate::{cpu, driver, exception};
+use alloc::vec::Vec;
 
 /// Wrapper struct for a bitmask indicating pending IRQ numbers.
 struct PendingIRQs {
@@ -66,6 +67,31 @@ impl InterruptController {
             local: local_ic::LocalIC::new(local_base_addr),
         }
     }
+
+    /// Enable IPIs to the executing core.
+    pub fn enable_ipi(&self) {
+        self.local.enable_ipi()
+    }
+
+    /// Interrupt `core` with an IPI.
+    pub fn send_ipi(&self, core: usize) {
+        self.local.send_ipi(core)
+    }
+
+    /// Silence all peripherals, before leaving the kernel.
+    pub fn disable_peripheral_irqs(&self) {
+        self.periph.disable_all()
+    }
+
+    /// Returns whether the GPU FIQ is routed to the executing core.
+    pub fn fiq_routed_here(&self) -> bool {
+        self.local.gpu_fiq_core() == cpu::core_id::<usize>()
+    }
+
+    /// Returns the core the GPU FIQ is routed to.
+    pub fn fiq_core(&self) -> usize {
+        self.local.gpu_fiq_core()
+    }
 }
 
 //------------------------------------------------------------------------------
@@ -76,6 +102,14 @@ impl driver::interface::DeviceDriver for InterruptController {
     fn compatible(&self) -> &str {
         "BCM Interrupt Controller"
     }
+
+    fn init(&self) -> Result<(), ()> {
+        // Start out with all peripheral interrupts on the boot core.
+        self.local.route_gpu_irq(cpu::BOOT_CORE_ID);
+        self.local.route_gpu_fiq(cpu::BOOT_CORE_ID);
+
+        Ok(())
+    }
 }
 
 impl exception::asynchronous::interface::IRQManager for InterruptController {
@@ -121,19 +155,61 @@ impl exception::asynchronous::interface::IRQManager for InterruptController {
         }
     }
 
+    /// Route a peripheral interrupt to `core`.
+    ///
+    /// The BCM2836/7 steers peripheral interrupts as a group: the FIQ source has its own route,
+    /// every other peripheral IRQ shares the GPU IRQ route. Changing the affinity of one IRQ
+    /// therefore moves all IRQs sharing its route. Local IRQs are bound to their core.
+    fn set_affinity(&self, irq: Self::IRQNumberType, core: usize) -> Result<(), &'static str> {
+        if core >= cpu::NUM_CORES {
+            return Err("IRQ affinity core out of range");
+        }
+
+        match irq {
+            IRQNumber::Peripheral(pirq) if self.periph.is_fiq(pirq) => {
+                self.local.route_gpu_fiq(core)
+            }
+            IRQNumber::Peripheral(_) => self.local.route_gpu_irq(core),
+            IRQNumber::Local(_) => return Err("Local IRQs can not be rerouted"),
+        }
+
+        Ok(())
+    }
+
+    fn affinity(&self, irq: Self::IRQNumberType) -> Option<usize> {
+        match irq {
+            IRQNumber::Peripheral(pirq) if self.periph.is_fiq(pirq) => {
+                Some(self.local.gpu_fiq_core())
+            }
+            IRQNumber::Peripheral(_) => Some(self.local.gpu_irq_core()),
+            IRQNumber::Local(_) => None,
+        }
+    }
+
     fn handle_pending_irqs<'irq_context>(
         &'irq_context self,
         ic: &exception::asynchronous::IRQContext<'irq_context>,
         e: &mut exception::ExceptionContext,
     ) {
-        if cpu::core_id::<usize>() == 0 {
+        if cpu::core_id::<usize>() == self.local.gpu_irq_core() {
             self.periph.handle_pending_irqs(ic, e);
         }
         self.local.handle_pending_irqs(ic, e);
     }
 
     fn print_handler(&self) {
+        crate::info!(
+            "      GPU IRQ routed to core {}, GPU FIQ routed to core {}",
+            self.local.gpu_irq_core(),
+            self.local.gpu_fiq_core()
+        );
         self.periph.print_handler();
         self.local.print_handler();
     }
+
+    fn statistics(&self) -> Vec<exception::asynchronous::IRQStatistic> {
+        let mut statistics = self.periph.statistics();
+        statistics.extend(self.local.statistics());
+        statistics
+    }
 }
@@ -1,6 +1,20 @@
 use super::{InterruptController, LocalIRQ, PendingIRQs};
 use crate::{bsp::device_driver::common::MMIODerefWrapper, cpu, exception};
-use register::{mmio::*, register_structs};
+use alloc::vec::Vec;
+use register::{mmio::*, register_bitfields, register_structs};
+
+register_bitfields! {
+    u32,
+
+    /// GPU Interrupts Routing (QA7: 4.4)
+    GPU_INT_ROUTING [
+        /// Core that receives the GPU FIQ.
+        GPU_FIQ_ROUTING OFFSET(2) NUMBITS(2) [],
+
+        /// Core that receives the GPU IRQ.
+        GPU_IRQ_ROUTING OFFSET(0) NUMBITS(2) []
+    ]
+}
 
 // BCM2837 Local Peripheral Registers (QA7: Chapter 4)
 register_structs! {
@@ -9,7 +23,7 @@ register_structs! {
         (0x00 => control: ReadWrite<u32>),
         (0x04 => _reserved),
         (0x08 => core_timer_prescaler: ReadWrite<u32>),
-        (0x0C => gpu_interrupts_routing: ReadWrite<u32>),
+        (0x0C => gpu_interrupts_routing: ReadWrite<u32, GPU_INT_ROUTING::Register>),
         (0x10 => pm_interrupts_routing_set: ReadWrite<u32>),
         (0x14 => pm_interrupts_routing_clear: ReadWrite<u32>),
         (0x18 => _reserved1),
@@ -26,7 +40,9 @@ register_structs! {
         (0x50 => core_mailboxes_interrupt_control: [ReadWrite<u32>; 4]),
         (0x60 => core_irq_source: [ReadOnly<u32>; 4]),
         (0x70 => core_fiq_source: [ReadWrite<u32>; 4]),
-        (0x80 => @END),
+        (0x80 => core_mailbox_write_set: [WriteOnly<u32>; 16]),
+        (0xC0 => core_mailbox_read_clear: [ReadWrite<u32>; 16]),
+        (0x100 => @END),
     }
 }
 
@@ -35,12 +51,27 @@ type Regs = MMIODerefWrapper<Registers>;
 type HandlerTable =
     [Option<exception::asynchronous::IRQDescriptor>; InterruptController::NUM_LOCAL_IRQS];
 
+/// Local source bit signalling a pending GPU (peripheral) interrupt.
+pub const GPU_IRQ: usize = 8;
+
+/// Local source bit signalling a write to the core's mailbox 0, used for IPIs.
+pub const MAILBOX0_IRQ: usize = 4;
+
+/// Mailboxes per core.
+const NUM_MAILBOXES: usize = 4;
+
 /// Representation of the peripheral interrupt regsler.
 pub struct LocalIC {
     registers: Regs,
 
     // Stores registered IRQ handlers. Writable only during kernel init. RO afterwards.
     handler_tables: spin::RwLock<[HandlerTable; 4]>,
+
+    // Serializes read-modify-write of the GPU routing register.
+    routing: spin::Mutex<()>,
+
+    // Handler calls per core and IRQ number. Only taken with IRQs masked.
+    counts: spin::Mutex<[[u64; InterruptController::NUM_LOCAL_IRQS]; 4]>,
 }
 
 impl LocalIC {
@@ -49,9 +80,57 @@ impl LocalIC {
         Self {
             registers: Regs::new(base_addr),
             handler_tables: spin::RwLock::new([[None; InterruptController::NUM_LOCAL_IRQS]; 4]),
+            routing: spin::Mutex::new(()),
+            counts: spin::Mutex::new([[0; InterruptController::NUM_LOCAL_IRQS]; 4]),
         }
     }
 
+    /// Route the GPU IRQ, and with it every peripheral IRQ, to `core`.
+    pub fn route_gpu_irq(&self, core: usize) {
+        let _guard = self.routing.lock();
+        self.registers
+            .gpu_interrupts_routing
+            .modify(GPU_INT_ROUTING::GPU_IRQ_ROUTING.val(core as u32));
+    }
+
+    /// Route the GPU FIQ to `core`.
+    pub fn route_gpu_fiq(&self, core: usize) {
+        let _guard = self.routing.lock();
+        self.registers
+            .gpu_interrupts_routing
+            .modify(GPU_INT_ROUTING::GPU_FIQ_ROUTING.val(core as u32));
+    }
+
+    /// Return the core currently receiving the GPU IRQ.
+    pub fn gpu_irq_core(&self) -> usize {
+        self.registers
+            .gpu_interrupts_routing
+            .read(GPU_INT_ROUTING::GPU_IRQ_ROUTING) as usize
+    }
+
+    /// Return the core currently receiving the GPU FIQ.
+    pub fn gpu_fiq_core(&self) -> usize {
+        self.registers
+            .gpu_interrupts_routing
+            .read(GPU_INT_ROUTING::GPU_FIQ_ROUTING) as usize
+    }
+
+    /// Raise the IRQ of the executing core's mailbox 0 when it is written.
+    pub fn enable_ipi(&self) {
+        self.registers.core_mailboxes_interrupt_control[cpu::core_id::<usize>()].set(1);
+    }
+
+    /// Raise an IRQ on `core` by writing its mailbox 0.
+    pub fn send_ipi(&self, core: usize) {
+        self.registers.core_mailbox_write_set[core * NUM_MAILBOXES].set(1);
+    }
+
+    /// Acknowledge the IPIs sent to the executing core.
+    fn clear_ipi(&self) {
+        self.registers.core_mailbox_read_clear[cpu::core_id::<usize>() * NUM_MAILBOXES]
+            .set(u32::MAX);
+    }
+
     /// Query the list of pending IRQs.
     fn get_pending(&self) -> PendingIRQs {
         let pending_mask: u64 =
@@ -70,11 +149,12 @@ impl exception::asynchronous::interface::IRQManager for LocalIC {
     ) -> Result<(), &'static str> {
         let irq_number = irq.get();
         let mut handler_tables = self.handler_tables.write();
+        let table = &mut handler_tables[cpu::core_id::<usize>()];
 
-        if handler_tables[0][irq_number].is_some() {
+        if table[irq_number].is_some() {
             return Err("IRQ handler already registered");
         }
-        handler_tables[cpu::core_id::<usize>()][irq_number] = Some(descriptor);
+        table[irq_number] = Some(descriptor);
 
         Ok(())
     }
@@ -100,20 +180,19 @@ impl exception::asynchronous::interface::IRQManager for LocalIC {
         for irq_number in self.get_pending() {
             let core_handler_table = handler_tables[cpu::core_id::<usize>()];
             match core_handler_table[irq_number] {
-                None => {
-                    // check if from gpu first
-                    if irq_number == 8 {
-                        crate::info!("Local Interrupt Controller: IRQ 8 fired");
-                    } else {
-                        panic!(
-                            "Local Interrupt Controller: No handler registered for IRQ {}",
-                            irq_number
-                        )
-                    }
-                }
+                // GPU interrupts are dispatched by the peripheral controller on the routed core.
+                None if irq_number == GPU_IRQ => {}
+                // IPIs halt cores on panic, which happens at exception entry, or ask for a task
+                // switch, which happens once the IRQs are handled.
+                None if irq_number == MAILBOX0_IRQ => self.clear_ipi(),
+                None => panic!(
+                    "Local Interrupt Controller: No handler registered for IRQ {}",
+                    irq_number
+                ),
                 Some(descriptor) => {
                     // Call the IRQ handler. Panics on failure.
                     descriptor.handler.handle(e).expect("Error handling IRQ");
+                    self.counts.lock()[cpu::core_id::<usize>()][irq_number] += 1;
                 }
             }
         }
@@ -130,4 +209,25 @@ impl exception::asynchronous::interface::IRQManager for LocalIC {
             }
         }
     }
+
+    fn statistics(&self) -> Vec<exception::asynchronous::IRQStatistic> {
+        let counts = exception::asynchronous::exec_with_irq_masked(|| *self.counts.lock());
+        let handler_tables = self.handler_tables.read();
+
+        let mut statistics = Vec::new();
+        for (core, table) in handler_tables.iter().enumerate() {
+            for (i, opt) in table.iter().enumerate() {
+                if let Some(handler) = opt {
+                    statistics.push(exception::asynchronous::IRQStatistic {
+                        name: handler.name,
+                        number: i,
+                        core: Some(core),
+                        fiq: false,
+                        count: counts[core][i],
+                    });
+                }
+            }
+        }
+        statistics
+    }
 }
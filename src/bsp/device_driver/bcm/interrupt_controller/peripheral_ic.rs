@@ -1,5 +1,7 @@
 use super::{InterruptController, PendingIRQs, PeripheralIRQ};
 use crate::{bsp::device_driver::common::MMIODerefWrapper, exception};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
 use register::{mmio::*, register_structs};
 
 // https://tc.gts3.org/cs3210/2020/spring/r/BCM2837-ARM-Peripherals.pdf
@@ -54,6 +56,15 @@ pub struct PeripheralIC {
 
     // only handling one FIQ anyway
     fiq_handler: spin::Mutex<Option<exception::asynchronous::IRQDescriptor>>,
+
+    /// The peripheral IRQ currently raised as FIQ, if any.
+    fiq_number: spin::Mutex<Option<PeripheralIRQ>>,
+
+    /// Handler calls per IRQ number. Only taken with IRQs masked.
+    counts: spin::Mutex<[u64; InterruptController::NUM_PERIPHERAL_IRQS]>,
+
+    /// Handled FIQs, counted apart so that the FIQ takes no lock shared with the IRQ path.
+    fiq_count: AtomicU64,
 }
 
 //--------------------------------------------------------------------------------------------------
@@ -72,9 +83,29 @@ impl PeripheralIC {
             ro_regs: ReadOnlyRegs::new(base_addr),
             handler_table: spin::RwLock::new([None; InterruptController::NUM_PERIPHERAL_IRQS]),
             fiq_handler: spin::Mutex::new(None),
+            fiq_number: spin::Mutex::new(None),
+            counts: spin::Mutex::new([0; InterruptController::NUM_PERIPHERAL_IRQS]),
+            fiq_count: AtomicU64::new(0),
         }
     }
 
+    /// Returns whether `irq` is the peripheral IRQ currently raised as FIQ.
+    pub fn is_fiq(&self, irq: PeripheralIRQ) -> bool {
+        match *self.fiq_number.lock() {
+            Some(fiq) => fiq.get() == irq.get(),
+            None => false,
+        }
+    }
+
+    /// Disable every peripheral IRQ and the FIQ.
+    pub fn disable_all(&self) {
+        let regs = &self.wo_regs.lock();
+        regs.DISABLE_1.set(u32::MAX);
+        regs.DISABLE_2.set(u32::MAX);
+        regs.FIQ_CONTROL.set(0);
+        *self.fiq_number.lock() = None;
+    }
+
     /// Query the list of pending IRQs.
     fn get_pending(&self) -> PendingIRQs {
         let pending_mask: u64 = (u64::from(self.ro_regs.PENDING_2.get()) << 32)
@@ -137,6 +168,7 @@ impl exception::asynchronous::interface::IRQManager for PeripheralIC {
         self.disable(int);
         let regs = &self.wo_regs.lock();
         regs.FIQ_CONTROL.set((1 << 7) | (int.get() as u32));
+        *self.fiq_number.lock() = Some(int);
     }
 
     fn register_fiq(&self, descriptor: exception::asynchronous::IRQDescriptor) {
@@ -147,6 +179,7 @@ impl exception::asynchronous::interface::IRQManager for PeripheralIC {
     fn handle_fiq(&self, e: &mut exception::ExceptionContext) {
         let descriptor = self.fiq_handler.lock().unwrap();
         descriptor.handler.handle(e).expect("Error handling FIQ");
+        self.fiq_count.fetch_add(1, Ordering::Relaxed);
     }
 
     fn handle_pending_irqs<'irq_context>(
@@ -166,6 +199,7 @@ impl exception::asynchronous::interface::IRQManager for PeripheralIC {
                     unsafe { exception::asynchronous::local_fiq_unmask() };
                     descriptor.handler.handle(e).expect("Error handling IRQ");
                     unsafe { exception::asynchronous::local_fiq_mask() };
+                    self.counts.lock()[irq_number] += 1;
                 }
             }
         }
@@ -183,4 +217,38 @@ impl exception::asynchronous::interface::IRQManager for PeripheralIC {
             }
         }
     }
+
+    fn statistics(&self) -> Vec<exception::asynchronous::IRQStatistic> {
+        let counts = exception::asynchronous::exec_with_irq_masked(|| *self.counts.lock());
+        let table = &self.handler_table.read();
+
+        let mut statistics: Vec<_> = table
+            .iter()
+            .enumerate()
+            .filter_map(|(i, opt)| {
+                opt.map(|handler| exception::asynchronous::IRQStatistic {
+                    name: handler.name,
+                    number: i,
+                    core: None,
+                    fiq: false,
+                    count: counts[i],
+                })
+            })
+            .collect();
+
+        // The FIQ takes its handler's lock as well.
+        let fiq = exception::asynchronous::exec_with_interrupts_masked(|| {
+            (*self.fiq_handler.lock(), *self.fiq_number.lock())
+        });
+        if let (Some(handler), Some(number)) = fiq {
+            statistics.push(exception::asynchronous::IRQStatistic {
+                name: handler.name,
+                number: number.get(),
+                core: None,
+                fiq: true,
+                count: self.fiq_count.load(Ordering::Relaxed),
+            });
+        }
+        statistics
+    }
 }
@@ -1,8 +1,8 @@
-use crate::bsp::generic_timer;
-use crate::info;
 use crate::memory::map::mmio::BASE;
-use crate::memory::ALLOCATOR;
-use core::alloc::Layout;
+use crate::time;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::fmt;
 use core::time::Duration;
 
 /// MBox
@@ -33,174 +33,455 @@ pub const MBOX_CH_TOUCH: u8 = 6;
 pub const MBOX_CH_COUNT: u8 = 7;
 pub const MBOX_CH_PROP: u32 = 8;
 
-/// MBox tags
-pub const MBOX_TAG_GETREVISION: u32 = 0x10002;
-pub const MBOX_TAG_GETMAC: u32 = 0x10003;
-pub const MBOX_TAG_GETSERIAL: u32 = 0x10004;
-pub const MBOX_TAG_TEMPERATURE: u32 = 0x30006;
-pub const MBOX_TAG_SET_POWER: u32 = 0x28001;
 pub const MBOX_TAG_LAST: u32 = 0;
 
-/// Power Management
-pub const PM_RSTC: *mut u32 = (BASE + 0x0010001c) as *mut u32;
-pub const PM_RSTS: *mut u32 = (BASE + 0x00100020) as *mut u32;
-pub const PM_WDOG: *mut u32 = (BASE + 0x00100024) as *mut u32;
-pub const PM_WDOG_MAGIC: u32 = 0x5a000000;
-pub const PM_RSTC_FULLRST: u32 = 0x00000020;
+/// Set in a tag's request/response code once the firmware has processed it.
+const MBOX_TAG_RESPONSE: u32 = 1 << 31;
 
-use core::ptr::NonNull;
+/// Size of the message buffer in words, large enough for a dozen tags.
+const MBOX_WORDS: usize = 64;
 
-// Public interface to the mailbox
+/// Words in the message header (size, request code) and of each tag header (id, size, code).
+const MESSAGE_HEADER_WORDS: usize = 2;
+const TAG_HEADER_WORDS: usize = 3;
+
+const MBOX_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Clocks managed by the firmware.
+#[allow(missing_docs)]
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[repr(u32)]
+pub enum Clock {
+    Emmc = 1,
+    Uart = 2,
+    Arm = 3,
+    Core = 4,
+    V3d = 5,
+    H264 = 6,
+    Isp = 7,
+    Sdram = 8,
+    Pixel = 9,
+    Pwm = 10,
+    Emmc2 = 12,
+}
+
+/// Voltage domains managed by the firmware.
+#[allow(missing_docs)]
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[repr(u32)]
+pub enum Voltage {
+    Core = 1,
+    SdramC = 2,
+    SdramP = 3,
+    SdramI = 4,
+}
+
+/// A request tag of the property channel.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Tag {
+    GetFirmwareRevision,
+    GetBoardModel,
+    GetBoardRevision,
+    GetMacAddress,
+    GetSerial,
+    GetArmMemory,
+    GetVcMemory,
+    SetPowerState { device: u32, on: bool },
+    GetClockRate(Clock),
+    GetMaxClockRate(Clock),
+    GetMinClockRate(Clock),
+    SetClockRate { clock: Clock, hz: u32 },
+    GetVoltage(Voltage),
+    GetTemperature,
+    GetMaxTemperature,
+}
+
+/// The firmware's answer to a `Tag`.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum TagResponse {
+    FirmwareRevision(u32),
+    BoardModel(u32),
+    BoardRevision(u32),
+    MacAddress([u8; 6]),
+    Serial(u64),
+    ArmMemory { base: u32, size: u32 },
+    VcMemory { base: u32, size: u32 },
+    PowerState { device: u32, on: bool, exists: bool },
+    ClockRate { clock: u32, hz: u32 },
+    Voltage { id: u32, microvolts: u32 },
+    /// Temperatures are in thousandths of a degree Celsius.
+    Temperature(u32),
+    MaxTemperature(u32),
+}
+
+/// Mailbox errors.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum MBoxError {
+    /// The tags do not fit into the message buffer.
+    MessageTooLong,
+
+    /// The firmware did not answer in time.
+    Timeout,
+
+    /// The firmware could not parse the message.
+    RequestFailed(u32),
+
+    /// The firmware did not process the tag.
+    TagFailed(Tag),
+
+    /// The firmware returned less data than the tag's response needs.
+    Truncated(Tag),
+}
+
+/// 16 byte aligned property message, as demanded by the mailbox.
+#[repr(C, align(16))]
+struct MessageBuffer([u32; MBOX_WORDS]);
+
+/// The property channel of the VideoCore mailbox.
+///
+/// The buffer is allocated from the heap, which is mapped non-cacheable, so the firmware and the
+/// ARM cores agree on its contents without cache maintenance.
 pub struct MBox {
-    buffer: NonNull<[u32]>,
+    buffer: Box<MessageBuffer>,
 }
 
-impl MBox {
-    pub unsafe fn new() -> Result<MBox, ()> {
-        let lay;
-        match Layout::from_size_align(32 as usize * core::mem::size_of::<u32>(), 16) {
-            Ok(layout) => lay = layout,
-
-            Err(_) => {
-                info!("[e] Layout Error!");
-                return Err(());
+//--------------------------------------------------------------------------------------------------
+// Private Code
+//--------------------------------------------------------------------------------------------------
+
+impl Tag {
+    fn id(&self) -> u32 {
+        match self {
+            Tag::GetFirmwareRevision => 0x0000_0001,
+            Tag::GetBoardModel => 0x0001_0001,
+            Tag::GetBoardRevision => 0x0001_0002,
+            Tag::GetMacAddress => 0x0001_0003,
+            Tag::GetSerial => 0x0001_0004,
+            Tag::GetArmMemory => 0x0001_0005,
+            Tag::GetVcMemory => 0x0001_0006,
+            Tag::SetPowerState { .. } => 0x0002_8001,
+            Tag::GetClockRate(_) => 0x0003_0002,
+            Tag::GetMaxClockRate(_) => 0x0003_0004,
+            Tag::GetMinClockRate(_) => 0x0003_0007,
+            Tag::SetClockRate { .. } => 0x0003_8002,
+            Tag::GetVoltage(_) => 0x0003_0003,
+            Tag::GetTemperature => 0x0003_0006,
+            Tag::GetMaxTemperature => 0x0003_000a,
+        }
+    }
+
+    /// The request values and their number.
+    fn request(&self) -> ([u32; 3], usize) {
+        match *self {
+            Tag::SetPowerState { device, on } => {
+                // Bit 1: wait for the power change to settle.
+                ([device, (1 << 1) | on as u32, 0], 2)
             }
+            Tag::GetClockRate(clock)
+            | Tag::GetMaxClockRate(clock)
+            | Tag::GetMinClockRate(clock) => ([clock as u32, 0, 0], 1),
+            // The third value is "skip setting turbo", left clear.
+            Tag::SetClockRate { clock, hz } => ([clock as u32, hz, 0], 3),
+            Tag::GetVoltage(id) => ([id as u32, 0, 0], 1),
+            Tag::GetTemperature | Tag::GetMaxTemperature => ([0, 0, 0], 1),
+            _ => ([0, 0, 0], 0),
         }
+    }
 
-        let ptr = (&ALLOCATOR)
-            .lock()
-            .allocate_first_fit(lay)
-            .expect("Out of Memory I guess");
+    /// Length of the response in bytes.
+    fn response_len(&self) -> usize {
+        match self {
+            Tag::GetFirmwareRevision | Tag::GetBoardModel | Tag::GetBoardRevision => 4,
+            Tag::GetMacAddress => 6,
+            _ => 8,
+        }
+    }
 
-        let buffer = ptr.cast::<[u32; 32]>();
+    /// Words reserved for the tag's value buffer, which holds both the request and the response.
+    fn value_words(&self) -> usize {
+        let (_, request_words) = self.request();
 
-        return Ok(MBox { buffer });
+        request_words.max((self.response_len() + 3) / 4)
     }
 
-    pub unsafe fn call(&mut self, ch: u32) -> bool {
-        while (MBOX_STATUS.read_volatile() & MBOX_FULL) != 0 {}
+    fn parse(&self, value: &[u32]) -> TagResponse {
+        match *self {
+            Tag::GetFirmwareRevision => TagResponse::FirmwareRevision(value[0]),
+            Tag::GetBoardModel => TagResponse::BoardModel(value[0]),
+            Tag::GetBoardRevision => TagResponse::BoardRevision(value[0]),
+            Tag::GetMacAddress => {
+                let (low, high) = (value[0].to_le_bytes(), value[1].to_le_bytes());
+                TagResponse::MacAddress([low[0], low[1], low[2], low[3], high[0], high[1]])
+            }
+            Tag::GetSerial => TagResponse::Serial(value[0] as u64 | (value[1] as u64) << 32),
+            Tag::GetArmMemory => TagResponse::ArmMemory {
+                base: value[0],
+                size: value[1],
+            },
+            Tag::GetVcMemory => TagResponse::VcMemory {
+                base: value[0],
+                size: value[1],
+            },
+            Tag::SetPowerState { .. } => TagResponse::PowerState {
+                device: value[0],
+                on: value[1] & 0b01 != 0,
+                exists: value[1] & 0b10 == 0,
+            },
+            Tag::GetClockRate(_)
+            | Tag::GetMaxClockRate(_)
+            | Tag::GetMinClockRate(_)
+            | Tag::SetClockRate { .. } => TagResponse::ClockRate {
+                clock: value[0],
+                hz: value[1],
+            },
+            Tag::GetVoltage(_) => TagResponse::Voltage {
+                id: value[0],
+                microvolts: value[1],
+            },
+            Tag::GetTemperature => TagResponse::Temperature(value[1]),
+            Tag::GetMaxTemperature => TagResponse::MaxTemperature(value[1]),
+        }
+    }
+}
 
-        /* write the address of our message to the mailbox with channel identifier */
-        let buf = self.buffer.as_ptr() as *const u32;
-        MBOX_WRITE.write_volatile((buf as u32 & !0xF) | (ch & 0xF));
+/// Write a property message carrying `tags` into `buf`.
+fn encode(tags: &[Tag], buf: &mut [u32]) -> Result<(), MBoxError> {
+    let words = MESSAGE_HEADER_WORDS
+        + tags
+            .iter()
+            .map(|tag| TAG_HEADER_WORDS + tag.value_words())
+            .sum::<usize>()
+        + 1;
+    if words > buf.len() {
+        return Err(MBoxError::MessageTooLong);
+    }
 
-        generic_timer().spin_sleep(Duration::from_millis(100));
+    buf[0] = (words * 4) as u32;
+    buf[1] = MBOX_REQUEST;
 
-        /* now wait for the response */
-        loop {
-            /* is there a response? */
-            while (MBOX_STATUS.read_volatile() & MBOX_EMPTY) != 0 {}
-            let resp: u32 = MBOX_READ.read_volatile();
+    let mut i = MESSAGE_HEADER_WORDS;
+    for tag in tags {
+        let value_words = tag.value_words();
+        let (request, request_words) = tag.request();
 
-            /* is it a response to our message? */
-            if ((resp & 0xF) == ch) && ((resp & !0xF) == buf as u32) {
-                llvm_asm!("dsb SY" ::: "memory" : "volatile");
-                /* is it a valid successful response? */
-                return self.buffer.as_ref()[1] == MBOX_RESPONSE;
-            }
+        buf[i] = tag.id();
+        buf[i + 1] = (value_words * 4) as u32;
+        buf[i + 2] = MBOX_REQUEST;
+        i += TAG_HEADER_WORDS;
+
+        for (j, word) in buf[i..i + value_words].iter_mut().enumerate() {
+            *word = if j < request_words { request[j] } else { 0 };
         }
+        i += value_words;
     }
+    buf[i] = MBOX_TAG_LAST;
 
-    pub fn serial_number(&mut self) -> Option<u64> {
-        let buf = unsafe { self.buffer.as_mut() };
-        buf[0] = 8 * 4;
-        buf[1] = MBOX_REQUEST;
-        buf[2] = MBOX_TAG_GETSERIAL;
-        buf[3] = 8;
-        buf[4] = 8;
-        buf[5] = 0;
-        buf[6] = 0;
-        buf[7] = MBOX_TAG_LAST;
+    Ok(())
+}
 
-        unsafe {
-            if self.call(MBOX_CH_PROP) {
-                let ser: u64 =
-                    (self.buffer.as_ref()[5] as u64) | ((self.buffer.as_ref()[6] as u64) << 32);
-                Some(ser)
-            } else {
-                None
-            }
+/// Parse the firmware's answers to `tags` from `buf`.
+fn decode(tags: &[Tag], buf: &[u32]) -> Result<Vec<TagResponse>, MBoxError> {
+    if buf[1] != MBOX_RESPONSE {
+        return Err(MBoxError::RequestFailed(buf[1]));
+    }
+
+    let mut responses = Vec::with_capacity(tags.len());
+    let mut i = MESSAGE_HEADER_WORDS;
+    for tag in tags {
+        let value_words = tag.value_words();
+        let code = buf[i + 2];
+        i += TAG_HEADER_WORDS;
+
+        if code & MBOX_TAG_RESPONSE == 0 {
+            return Err(MBoxError::TagFailed(*tag));
+        }
+        if ((code & !MBOX_TAG_RESPONSE) as usize) < tag.response_len() {
+            return Err(MBoxError::Truncated(*tag));
         }
+
+        responses.push(tag.parse(&buf[i..i + value_words]));
+        i += value_words;
     }
 
-    pub fn mac_address(&mut self) -> Option<u64> {
-        let buf = unsafe { self.buffer.as_mut() };
-        buf[0] = 8 * 4;
-        buf[1] = MBOX_REQUEST;
-        buf[2] = MBOX_TAG_GETMAC;
-        buf[3] = 8;
-        buf[4] = 8;
-        buf[5] = 0;
-        buf[6] = 0;
-        buf[7] = MBOX_TAG_LAST;
+    Ok(responses)
+}
+
+impl MBox {
+    /// Send the buffer to the firmware on channel `ch` and wait for its answer.
+    fn call(&mut self, ch: u32) -> Result<(), MBoxError> {
+        let deadline = time::time_manager().uptime() + MBOX_TIMEOUT;
+        let timed_out = || time::time_manager().uptime() > deadline;
+        let buf = self.buffer.0.as_ptr() as u32;
 
         unsafe {
-            if self.call(MBOX_CH_PROP) {
-                let ser: u64 =
-                    (self.buffer.as_ref()[5] as u64) | ((self.buffer.as_ref()[6] as u64) << 32);
-                Some(ser)
-            } else {
-                None
+            while (MBOX_STATUS.read_volatile() & MBOX_FULL) != 0 {
+                if timed_out() {
+                    return Err(MBoxError::Timeout);
+                }
+            }
+
+            // Make the message visible before handing it to the firmware.
+            llvm_asm!("dsb SY" ::: "memory" : "volatile");
+            MBOX_WRITE.write_volatile((buf & !0xF) | (ch & 0xF));
+
+            loop {
+                while (MBOX_STATUS.read_volatile() & MBOX_EMPTY) != 0 {
+                    if timed_out() {
+                        return Err(MBoxError::Timeout);
+                    }
+                }
+
+                // Replies to other messages are dropped.
+                let resp: u32 = MBOX_READ.read_volatile();
+                if ((resp & 0xF) == ch) && ((resp & !0xF) == buf) {
+                    llvm_asm!("dsb SY" ::: "memory" : "volatile");
+                    return Ok(());
+                }
             }
         }
     }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+impl MBox {
+    /// Create an instance with its own message buffer.
+    pub fn new() -> MBox {
+        MBox {
+            buffer: Box::new(MessageBuffer([0; MBOX_WORDS])),
+        }
+    }
+
+    /// Send all `tags` in one property message and return the responses in the same order.
+    pub fn query(&mut self, tags: &[Tag]) -> Result<Vec<TagResponse>, MBoxError> {
+        encode(tags, &mut self.buffer.0)?;
+        self.call(MBOX_CH_PROP)?;
+
+        decode(tags, &self.buffer.0)
+    }
+
+    /// Send a single tag.
+    pub fn query_one(&mut self, tag: Tag) -> Result<TagResponse, MBoxError> {
+        Ok(self.query(&[tag])?[0])
+    }
+
+    pub fn serial_number(&mut self) -> Result<u64, MBoxError> {
+        match self.query_one(Tag::GetSerial)? {
+            TagResponse::Serial(serial) => Ok(serial),
+            _ => unreachable!(),
+        }
+    }
 
-    pub fn board_revision(&mut self) -> Option<u32> {
-        let buf = unsafe { self.buffer.as_mut() };
-        buf[0] = 7 * 4;
-        buf[1] = MBOX_REQUEST;
-        buf[2] = MBOX_TAG_GETREVISION;
-        buf[3] = 4;
-        buf[4] = 8;
-        buf[5] = 0;
-        buf[6] = MBOX_TAG_LAST;
+    pub fn mac_address(&mut self) -> Result<[u8; 6], MBoxError> {
+        match self.query_one(Tag::GetMacAddress)? {
+            TagResponse::MacAddress(mac) => Ok(mac),
+            _ => unreachable!(),
+        }
+    }
 
-        if unsafe { self.call(MBOX_CH_PROP) } {
-            Some(unsafe { self.buffer.as_ref()[5] })
-        } else {
-            None
+    pub fn board_revision(&mut self) -> Result<u32, MBoxError> {
+        match self.query_one(Tag::GetBoardRevision)? {
+            TagResponse::BoardRevision(revision) => Ok(revision),
+            _ => unreachable!(),
         }
     }
 
-    pub fn core_temperature(&mut self) -> Option<u32> {
-        let buf = unsafe { self.buffer.as_mut() };
-        buf[0] = 8 * 4;
-        buf[1] = MBOX_REQUEST;
-        buf[2] = MBOX_TAG_TEMPERATURE;
-        buf[3] = 8;
-        buf[4] = 8;
-        buf[5] = 0;
-        buf[6] = 0;
-        buf[7] = MBOX_TAG_LAST;
+    /// The SoC temperature in thousandths of a degree Celsius.
+    pub fn core_temperature(&mut self) -> Result<u32, MBoxError> {
+        match self.query_one(Tag::GetTemperature)? {
+            TagResponse::Temperature(temperature) => Ok(temperature),
+            _ => unreachable!(),
+        }
+    }
 
-        if unsafe { self.call(MBOX_CH_PROP) } {
-            Some(unsafe { self.buffer.as_ref()[6] })
-        } else {
-            None
+    /// The current rate of `clock` in Hz.
+    pub fn clock_rate(&mut self, clock: Clock) -> Result<u32, MBoxError> {
+        match self.query_one(Tag::GetClockRate(clock))? {
+            TagResponse::ClockRate { hz, .. } => Ok(hz),
+            _ => unreachable!(),
         }
     }
 
-    pub fn set_power_state(&mut self, device_id: u32, enable: bool) -> Option<bool> {
-        let mut state = 0u32;
-        state |= 1 << 1; // wait for power change
-        if enable {
-            state |= 1;
+    /// Set `clock` to `hz` and return the rate the firmware actually applied.
+    pub fn set_clock_rate(&mut self, clock: Clock, hz: u32) -> Result<u32, MBoxError> {
+        match self.query_one(Tag::SetClockRate { clock, hz })? {
+            TagResponse::ClockRate { hz, .. } => Ok(hz),
+            _ => unreachable!(),
         }
+    }
 
-        let buf = unsafe { self.buffer.as_mut() };
-        buf[0] = 8 * 4;
-        buf[1] = MBOX_REQUEST;
-        buf[2] = MBOX_TAG_SET_POWER;
-        buf[3] = 8;
-        buf[4] = 8;
-        buf[5] = device_id;
-        buf[6] = state;
-        buf[7] = MBOX_TAG_LAST;
+    /// Power `device` on or off. Returns whether it is on afterwards.
+    pub fn set_power_state(&mut self, device: u32, on: bool) -> Result<bool, MBoxError> {
+        match self.query_one(Tag::SetPowerState { device, on })? {
+            TagResponse::PowerState { on, exists, .. } => Ok(exists && on),
+            _ => unreachable!(),
+        }
+    }
+}
 
-        if unsafe { self.call(MBOX_CH_PROP) } {
-            Some((unsafe { self.buffer.as_ref()[6] & 0b10 }) == 0)
-        } else {
-            None
+impl fmt::Display for MBoxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MBoxError::MessageTooLong => write!(f, "message too long"),
+            MBoxError::Timeout => write!(f, "timeout"),
+            MBoxError::RequestFailed(code) => write!(f, "request failed ({:#010x})", code),
+            MBoxError::TagFailed(tag) => write!(f, "{:?} not processed", tag),
+            MBoxError::Truncated(tag) => write!(f, "{:?} response truncated", tag),
         }
     }
 }
+
+//--------------------------------------------------------------------------------------------------
+// Testing
+//--------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_macros::kernel_test;
+
+    /// Batched responses are parsed in request order.
+    #[kernel_test]
+    fn decode_batched_tags() {
+        let tags = [Tag::GetBoardRevision, Tag::GetClockRate(Clock::Uart)];
+        let mut buf = [0; MBOX_WORDS];
+        encode(&tags, &mut buf).unwrap();
+        assert_eq!(buf[0], 12 * 4);
+        assert_eq!(buf[9], Clock::Uart as u32);
+
+        // What the firmware would write back.
+        buf[1] = MBOX_RESPONSE;
+        buf[4] = MBOX_TAG_RESPONSE | 4;
+        buf[5] = 0xa02082;
+        buf[8] = MBOX_TAG_RESPONSE | 8;
+        buf[10] = 48_000_000;
+
+        let responses = decode(&tags, &buf).unwrap();
+        assert_eq!(responses[0], TagResponse::BoardRevision(0xa02082));
+        assert_eq!(
+            responses[1],
+            TagResponse::ClockRate {
+                clock: Clock::Uart as u32,
+                hz: 48_000_000
+            }
+        );
+    }
+
+    /// Unprocessed and short tags are reported.
+    #[kernel_test]
+    fn decode_reports_tag_errors() {
+        let tags = [Tag::GetSerial];
+        let mut buf = [0; MBOX_WORDS];
+        encode(&tags, &mut buf).unwrap();
+        buf[1] = MBOX_RESPONSE;
+        assert_eq!(decode(&tags, &buf), Err(MBoxError::TagFailed(Tag::GetSerial)));
+
+        buf[4] = MBOX_TAG_RESPONSE | 4;
+        assert_eq!(decode(&tags, &buf), Err(MBoxError::Truncated(Tag::GetSerial)));
+
+        let too_many = [Tag::GetSerial; 20];
+        assert_eq!(encode(&too_many, &mut buf), Err(MBoxError::MessageTooLong));
+    }
+}
@@ -1,4 +1,5 @@
-use crate::bsp::device_driver::GPIO;
+use crate::bsp::device_driver::{Clock, MBox, GPIO};
+use crate::console::{FlowControl, Parity, SerialConfig, StopBits};
 use crate::{bsp, console, driver};
 pub use asm::nop;
 use core::{fmt, ops};
@@ -60,6 +61,19 @@ register_bitfields! {
 
     /// Mini Uart Extra Control
     AUX_MU_CNTL [
+        /// If this bit is set the transmitter will stop if the CTS line is de-asserted.
+        TX_AUTO_FLOW OFFSET(3) NUMBITS(1) [
+            Disabled = 0,
+            Enabled = 1
+        ],
+
+        /// If this bit is set the RTS line will de-assert if the receive FIFO reaches its 'auto
+        /// flow' level.
+        RX_AUTO_FLOW OFFSET(2) NUMBITS(1) [
+            Disabled = 0,
+            Enabled = 1
+        ],
+
         /// If this bit is set the mini UART transmitter is enabled.
         /// If this bit is clear the mini UART transmitter is disabled.
         TX_EN OFFSET(1) NUMBITS(1) [
@@ -118,10 +132,32 @@ impl MiniUart {
         let data = self.inner.lock();
         data.init(gpio);
     }
+
+    /// Read a received byte, if there is one, without converting carriage returns. For binary
+    /// protocols.
+    pub fn try_read_byte(&self) -> Option<u8> {
+        let data = self.inner.lock();
+
+        if data.AUX_MU_LSR.is_set(AUX_MU_LSR::DATA_READY) {
+            Some(data.AUX_MU_IO.get() as u8)
+        } else {
+            None
+        }
+    }
+
+    /// Write a byte, waiting for room in the FIFO.
+    pub fn write_byte(&self, byte: u8) {
+        self.inner.lock().write_char(byte as char);
+    }
 }
 
+/// The mini UART is clocked by the core clock, nominally 250 MHz.
+const DEFAULT_CLOCK_HZ: u32 = 250_000_000;
+
 pub struct MiniUartInner {
     base_addr: usize,
+    config: SerialConfig,
+    clock_hz: u32,
 }
 
 /// Deref to RegisterBlock
@@ -144,7 +180,11 @@ impl ops::Deref for MiniUartInner {
 
 impl MiniUartInner {
     pub const fn new(base_addr: usize) -> MiniUartInner {
-        MiniUartInner { base_addr }
+        MiniUartInner {
+            base_addr,
+            config: SerialConfig::new(115200),
+            clock_hz: DEFAULT_CLOCK_HZ,
+        }
     }
 
     /// Returns a pointer to the register block
@@ -152,26 +192,76 @@ impl MiniUartInner {
         self.base_addr as *const _
     }
 
-    ///Set baud rate and characteristics (115200 8N1) and map to GPIO
+    ///Set baud rate and characteristics (115200 8N1 by default) and map to GPIO
     pub fn init(&self, gpio: &GPIO) {
         // initialize UART
         self.AUX_ENABLES.modify(AUX_ENABLES::MINI_UART_ENABLE::SET);
         self.AUX_MU_IER.set(0);
         self.AUX_MU_CNTL.set(0);
-        self.AUX_MU_LCR.write(AUX_MU_LCR::DATA_SIZE::EightBit);
         self.AUX_MU_MCR.set(0);
         self.AUX_MU_IIR.write(AUX_MU_IIR::FIFO_CLEAR::All);
-        self.AUX_MU_BAUD.write(AUX_MU_BAUD::RATE.val(270)); // 115200 baud
+        self.program_line();
 
         gpio.map_mini_uart();
+        if self.config.flow_control == FlowControl::RtsCts {
+            gpio.map_mini_uart_flow_control();
+        }
 
-        self.AUX_MU_CNTL
-            .write(AUX_MU_CNTL::RX_EN::Enabled + AUX_MU_CNTL::TX_EN::Enabled);
+        self.enable();
 
         // Clear FIFOs before using the device
         self.AUX_MU_IIR.write(AUX_MU_IIR::FIFO_CLEAR::All);
     }
 
+    /// Write the baud rate and frame format of the current config.
+    fn program_line(&self) {
+        let data_size = match self.config.data_bits {
+            7 => AUX_MU_LCR::DATA_SIZE::SevenBit,
+            _ => AUX_MU_LCR::DATA_SIZE::EightBit,
+        };
+
+        let divisor = baud_divisor(self.clock_hz, self.config.baud).unwrap_or(270); // 115200 baud
+
+        self.AUX_MU_LCR.write(data_size);
+        self.AUX_MU_BAUD.write(AUX_MU_BAUD::RATE.val(divisor));
+    }
+
+    fn enable(&self) {
+        let flow_control = match self.config.flow_control {
+            FlowControl::None => {
+                AUX_MU_CNTL::TX_AUTO_FLOW::Disabled + AUX_MU_CNTL::RX_AUTO_FLOW::Disabled
+            }
+            FlowControl::RtsCts => {
+                AUX_MU_CNTL::TX_AUTO_FLOW::Enabled + AUX_MU_CNTL::RX_AUTO_FLOW::Enabled
+            }
+        };
+
+        self.AUX_MU_CNTL.write(
+            AUX_MU_CNTL::RX_EN::Enabled + AUX_MU_CNTL::TX_EN::Enabled + flow_control,
+        );
+    }
+
+    /// Switch to `config` after sending all pending output with the old settings.
+    ///
+    /// The mini UART supports 7 or 8 data bits, no parity and one stop bit only.
+    fn configure(&mut self, config: SerialConfig) -> Result<(), &'static str> {
+        baud_divisor(self.clock_hz, config.baud)?;
+        if config.data_bits != 7 && config.data_bits != 8 {
+            return Err("unsupported number of data bits");
+        }
+        if config.parity != Parity::None || config.stop_bits != StopBits::One {
+            return Err("parity and two stop bits are not supported");
+        }
+
+        self.wait_tx_fifo_empty();
+        self.config = config;
+        self.AUX_MU_CNTL.set(0);
+        self.program_line();
+        self.enable();
+
+        Ok(())
+    }
+
     pub fn wait_tx_fifo_empty(&self) {
         loop {
             if self.AUX_MU_LSR.is_set(AUX_MU_LSR::TX_IDLE) {
@@ -228,6 +318,19 @@ impl MiniUartInner {
     }
 }
 
+/// Compute the baud rate register value, `clock / (8 * baud) - 1`.
+fn baud_divisor(clock_hz: u32, baud: u32) -> Result<u32, &'static str> {
+    if baud == 0 {
+        return Err("invalid baud rate");
+    }
+
+    match clock_hz / 8 / baud {
+        0 => Err("baud rate out of range for the UART clock"),
+        divisor if divisor > 0x1_0000 => Err("baud rate out of range for the UART clock"),
+        divisor => Ok(divisor - 1),
+    }
+}
+
 impl Drop for MiniUartInner {
     fn drop(&mut self) {
         self.AUX_ENABLES
@@ -277,6 +380,20 @@ impl console::interface::Read for MiniUart {
     }
 }
 
+impl console::interface::Configure for MiniUart {
+    fn configure(&self, config: &SerialConfig) -> Result<(), &'static str> {
+        if config.flow_control == FlowControl::RtsCts {
+            bsp::GPIO.map_mini_uart_flow_control();
+        }
+
+        self.inner.lock().configure(*config)
+    }
+
+    fn config(&self) -> SerialConfig {
+        self.inner.lock().config
+    }
+}
+
 impl console::interface::Statistics for MiniUart {
     fn chars_written(&self) -> usize {
         0
@@ -293,7 +410,12 @@ impl driver::interface::DeviceDriver for MiniUart {
     }
 
     fn init(&self) -> Result<(), ()> {
-        let data = self.inner.lock();
+        let clock_hz = MBox::new()
+            .clock_rate(Clock::Core)
+            .unwrap_or(DEFAULT_CLOCK_HZ);
+
+        let mut data = self.inner.lock();
+        data.clock_hz = clock_hz;
         data.init(&bsp::GPIO);
 
         Ok(())
@@ -1,4 +1,11 @@
-use crate::{bsp, console, cpu, driver, exception};
+use crate::{
+    bsp,
+    bsp::device_driver::{Clock, MBox},
+    console,
+    console::{FlowControl, Parity, SerialConfig, StopBits},
+    cpu, driver, exception,
+    ring_buffer::RingBuffer,
+};
 use core::{fmt, ops};
 use register::{mmio::*, register_bitfields, register_structs};
 use spin;
@@ -32,7 +39,34 @@ register_bitfields! {
         ///
         /// If the FIFO is disabled, this bit is set when the receive holding register is empty. If
         /// the FIFO is enabled, the RXFE bit is set when the receive FIFO is empty.
-        RXFE OFFSET(4) NUMBITS(1) []
+        RXFE OFFSET(4) NUMBITS(1) [],
+
+        /// UART busy. If this bit is set to 1, the UART is busy transmitting data. This bit remains
+        /// set until the complete byte, including all the stop bits, has been sent from the shift
+        /// register.
+        BUSY OFFSET(3) NUMBITS(1) []
+    ],
+
+    /// Data Register
+    DR [
+        /// Overrun error. This bit is set to 1 if data is received and the receive FIFO is already
+        /// full.
+        OE   OFFSET(11) NUMBITS(1) [],
+
+        /// Break error. This bit is set to 1 if a break condition was detected.
+        BE   OFFSET(10) NUMBITS(1) [],
+
+        /// Parity error. When set to 1, it indicates that the parity of the received data character
+        /// does not match the parity that the EPS and SPS bits in the Line Control Register,
+        /// UART_LCRH select.
+        PE   OFFSET(9) NUMBITS(1) [],
+
+        /// Framing error. When set to 1, it indicates that the received character did not have a
+        /// valid stop bit (a valid stop bit is 1).
+        FE   OFFSET(8) NUMBITS(1) [],
+
+        /// Receive (read) data character. Transmit (write) data character.
+        DATA OFFSET(0) NUMBITS(8) []
     ],
 
     /// Integer Baud rate divisor
@@ -67,11 +101,33 @@ register_bitfields! {
         FEN  OFFSET(4) NUMBITS(1) [
             FifosDisabled = 0,
             FifosEnabled = 1
-        ]
+        ],
+
+        /// Two stop bits select. If this bit is set to 1, two stop bits are transmitted at the end
+        /// of the frame.
+        STP2 OFFSET(3) NUMBITS(1) [],
+
+        /// Even parity select. 0 = odd parity, 1 = even parity. Has no effect when parity is
+        /// disabled by clearing PEN.
+        EPS  OFFSET(2) NUMBITS(1) [
+            Odd = 0,
+            Even = 1
+        ],
+
+        /// Parity enable. If this bit is set to 1, parity checking and generation is enabled.
+        PEN  OFFSET(1) NUMBITS(1) []
     ],
 
     /// Control Register
     CR [
+        /// CTS hardware flow control enable. If this bit is set to 1, data is only transmitted
+        /// when the nUARTCTS signal is asserted.
+        CTSEN  OFFSET(15) NUMBITS(1) [],
+
+        /// RTS hardware flow control enable. If this bit is set to 1, data is only requested when
+        /// there is space in the receive FIFO for it to be received.
+        RTSEN  OFFSET(14) NUMBITS(1) [],
+
         /// Receive enable. If this bit is set to 1, the receive section of the UART is enabled.
         /// Data reception occurs for UART signals. When the UART is disabled in the middle of
         /// reception, it completes the current character before stopping.
@@ -107,18 +163,59 @@ register_bitfields! {
             OneHalf = 0b010,
             ThreeQuarters = 0b011,
             SevenEights = 0b100
+        ],
+
+        /// Transmit interrupt FIFO level select. The trigger points for the transmit interrupt are
+        /// as follows.
+        TXIFLSEL OFFSET(0) NUMBITS(3) [
+            OneEigth = 0b000,
+            OneQuarter = 0b001,
+            OneHalf = 0b010,
+            ThreeQuarters = 0b011,
+            SevenEights = 0b100
         ]
     ],
 
     /// Interrupt Mask Set Clear Register
     IMSC [
-                // Receive timeout interrupt mask. A read returns the current mask for the UARTRTINTR
-        // interrupt. On a write of 1, the mask of the interrupt is set. A write of 0 clears the
-        // mask.
+        /// Overrun error interrupt mask.
+        OEIM OFFSET(10) NUMBITS(1) [
+            Disabled = 0,
+            Enabled = 1
+        ],
+
+        /// Break error interrupt mask.
+        BEIM OFFSET(9) NUMBITS(1) [
+            Disabled = 0,
+            Enabled = 1
+        ],
+
+        /// Parity error interrupt mask.
+        PEIM OFFSET(8) NUMBITS(1) [
+            Disabled = 0,
+            Enabled = 1
+        ],
+
+        /// Framing error interrupt mask.
+        FEIM OFFSET(7) NUMBITS(1) [
+            Disabled = 0,
+            Enabled = 1
+        ],
+
+        /// Receive timeout interrupt mask. A read returns the current mask for the UARTRTINTR
+        /// interrupt. On a write of 1, the mask of the interrupt is set. A write of 0 clears the
+        /// mask.
         RTIM OFFSET(6) NUMBITS(1) [
             Disabled = 0,
             Enabled = 1
         ],
+
+        /// Transmit interrupt mask. A read returns the current mask for the UARTTXINTR interrupt.
+        /// On a write of 1, the mask of the interrupt is set. A write of 0 clears the mask.
+        TXIM OFFSET(5) NUMBITS(1) [
+            Disabled = 0,
+            Enabled = 1
+        ],
         /// Receive interrupt mask. A read returns the current mask for the UARTRXINTR interrupt. On
         /// a write of 1, the mask of the interrupt is set. A write of 0 clears the mask.
         RXIM OFFSET(4) NUMBITS(1) [
@@ -132,6 +229,9 @@ register_bitfields! {
         // interrupt.
         RTRIS OFFSET(6) NUMBITS(1) [],
 
+        /// Transmit interrupt status. Returns the raw interrupt state of the UARTTXINTR interrupt.
+        TXRIS OFFSET(5) NUMBITS(1) [],
+
         /// Receive interrupt status. Returns the raw interrupt state of the UARTRXINTR interrupt.
         RXRIS OFFSET(4) NUMBITS(1) []
     ],
@@ -152,7 +252,7 @@ enum BlockingMode {
 register_structs! {
     #[allow(non_snake_case)]
     pub RegisterBlock {
-        (0x00 => DR: ReadWrite<u32>),
+        (0x00 => DR: ReadWrite<u32, DR::Register>),
         (0x04 => _reserved1),
         (0x18 => FR: ReadOnly<u32, FR::Register>),
         (0x1c => _reserved2),
@@ -168,10 +268,33 @@ register_structs! {
     }
 }
 
+/// The line settings the firmware's config.txt is expected to leave behind.
+const DEFAULT_CONFIG: SerialConfig = SerialConfig::new(230400);
+
+/// The UART reference clock if the mailbox can't be asked.
+const DEFAULT_CLOCK_HZ: u32 = 48_000_000;
+
+/// Characters buffered for transmission beyond the 16 entry TX FIFO.
+const TX_BUFFER_SIZE: usize = 4096;
+
+/// Characters buffered between the RX interrupt and readers.
+const RX_BUFFER_SIZE: usize = 256;
+
 pub struct PL011UartInner {
     base_addr: usize,
     chars_written: usize,
     chars_read: usize,
+    overrun_errors: usize,
+    framing_errors: usize,
+    parity_errors: usize,
+    tx_buffer: RingBuffer<TX_BUFFER_SIZE>,
+    rx_buffer: RingBuffer<RX_BUFFER_SIZE>,
+    config: SerialConfig,
+    clock_hz: u32,
+
+    /// Set once the IRQ handler is registered. Until then, and in the panic handler, writes spin
+    /// on the TX FIFO.
+    irq_driven: bool,
 }
 
 // Export the inner struct so that BSPs can use it for the panic handler.
@@ -216,26 +339,118 @@ impl PL011UartInner {
             base_addr,
             chars_written: 0,
             chars_read: 0,
+            overrun_errors: 0,
+            framing_errors: 0,
+            parity_errors: 0,
+            tx_buffer: RingBuffer::new(),
+            rx_buffer: RingBuffer::new(),
+            config: DEFAULT_CONFIG,
+            clock_hz: DEFAULT_CLOCK_HZ,
+            irq_driven: false,
+        }
+    }
+
+    /// Create an instance for the panic handler, using the settings of the console.
+    ///
+    /// # Safety
+    ///
+    /// - The user must ensure to provide the correct `base_addr`.
+    pub unsafe fn with_settings(base_addr: usize, settings: Option<(SerialConfig, u32)>) -> Self {
+        let mut uart = Self::new(base_addr);
+        if let Some((config, clock_hz)) = settings {
+            uart.config = config;
+            uart.clock_hz = clock_hz;
         }
+
+        uart
     }
 
     /// Set up baud rate and characteristics.
     ///
-    /// Results in 8N1 and 230400 baud (if the clk has been previously set to 48 MHz by the
-    /// firmware).
+    /// Defaults to 8N1 and 230400 baud, with the divisors derived from the UART clock.
     pub fn init(&mut self) {
         // Turn it off temporarily.
         self.CR.set(0);
 
         self.ICR.write(ICR::ALL::CLEAR);
-        self.IBRD.write(IBRD::IBRD.val(13));
-        self.FBRD.write(FBRD::FBRD.val(1));
+        self.program_line();
+        self.IFLS
+            .write(IFLS::RXIFLSEL::OneEigth + IFLS::TXIFLSEL::OneEigth); // FIFO levels at 1/8
+
+        // RX IRQ + RX timeout IRQ + error IRQs. The TX IRQ is only enabled while the TX buffer
+        // holds data.
+        self.IMSC.write(
+            IMSC::RXIM::Enabled
+                + IMSC::RTIM::Enabled
+                + IMSC::OEIM::Enabled
+                + IMSC::BEIM::Enabled
+                + IMSC::PEIM::Enabled
+                + IMSC::FEIM::Enabled,
+        );
+        self.enable();
+    }
+
+    /// Write divisors and frame format of the current config.
+    ///
+    /// The UART must be disabled. LCRH is written last, as it latches the divisors.
+    fn program_line(&mut self) {
+        let (ibrd, fbrd) = divisors(self.clock_hz, self.config.baud).unwrap_or_else(|_| {
+            self.config = DEFAULT_CONFIG;
+            divisors(DEFAULT_CLOCK_HZ, DEFAULT_CONFIG.baud).unwrap()
+        });
+        let wlen = match self.config.data_bits {
+            5 => LCRH::WLEN::FiveBit,
+            6 => LCRH::WLEN::SixBit,
+            7 => LCRH::WLEN::SevenBit,
+            _ => LCRH::WLEN::EightBit,
+        };
+        let parity = match self.config.parity {
+            Parity::None => LCRH::PEN::CLEAR,
+            Parity::Even => LCRH::PEN::SET + LCRH::EPS::Even,
+            Parity::Odd => LCRH::PEN::SET + LCRH::EPS::Odd,
+        };
+        let stop_bits = match self.config.stop_bits {
+            StopBits::One => LCRH::STP2::CLEAR,
+            StopBits::Two => LCRH::STP2::SET,
+        };
+
+        self.IBRD.write(IBRD::IBRD.val(ibrd));
+        self.FBRD.write(FBRD::FBRD.val(fbrd));
         self.LCRH
-            .write(LCRH::WLEN::EightBit + LCRH::FEN::FifosEnabled); // 8N1 + Fifo on
-        self.IFLS.write(IFLS::RXIFLSEL::OneEigth); // RX FIFO fill level at 1/8
-        self.IMSC.write(IMSC::RXIM::Enabled + IMSC::RTIM::Enabled); // RX IRQ + RX timeout IRQ
-        self.CR
-            .write(CR::UARTEN::Enabled + CR::TXE::Enabled + CR::RXE::Enabled);
+            .write(wlen + parity + stop_bits + LCRH::FEN::FifosEnabled);
+    }
+
+    fn enable(&self) {
+        let flow_control = match self.config.flow_control {
+            FlowControl::None => CR::CTSEN::CLEAR + CR::RTSEN::CLEAR,
+            FlowControl::RtsCts => CR::CTSEN::SET + CR::RTSEN::SET,
+        };
+
+        self.CR.write(
+            CR::UARTEN::Enabled + CR::TXE::Enabled + CR::RXE::Enabled + flow_control,
+        );
+    }
+
+    /// Switch to `config` after sending all pending output with the old settings.
+    fn configure(&mut self, config: SerialConfig) -> Result<(), &'static str> {
+        divisors(self.clock_hz, config.baud)?;
+        if !(5..=8).contains(&config.data_bits) {
+            return Err("unsupported number of data bits");
+        }
+
+        while !self.tx_buffer.is_empty() {
+            self.fill_tx_fifo();
+        }
+        while !self.FR.matches_all(FR::TXFE::SET) || self.FR.matches_all(FR::BUSY::SET) {
+            cpu::nop();
+        }
+
+        self.config = config;
+        self.CR.set(0);
+        self.program_line();
+        self.enable();
+
+        Ok(())
     }
 
     /// Return a pointer to the register block.
@@ -243,36 +458,84 @@ impl PL011UartInner {
         self.base_addr as *const _
     }
 
+    /// Move buffered characters into the TX FIFO until it is full.
+    fn fill_tx_fifo(&mut self) {
+        while !self.FR.matches_all(FR::TXFF::SET) {
+            match self.tx_buffer.pop() {
+                Some(byte) => self.DR.set(byte as u32),
+                None => break,
+            }
+        }
+    }
+
     /// Send a character.
     fn write_char(&mut self, c: char) {
-        // Spin while TX FIFO full is set, waiting for an empty slot.
-        while self.FR.matches_all(FR::TXFF::SET) {
-            cpu::nop();
+        if !self.irq_driven {
+            // Spin while TX FIFO full is set, waiting for an empty slot.
+            while self.FR.matches_all(FR::TXFF::SET) {
+                cpu::nop();
+            }
+            self.DR.set(c as u32);
+        } else {
+            self.fill_tx_fifo();
+            if self.tx_buffer.is_empty() && !self.FR.matches_all(FR::TXFF::SET) {
+                self.DR.set(c as u32);
+            } else {
+                // The buffer is full if the IRQ can't keep up. Spin rather than drop output.
+                while !self.tx_buffer.push(c as u8) {
+                    self.fill_tx_fifo();
+                }
+                self.IMSC.modify(IMSC::TXIM::Enabled);
+            }
         }
 
-        // Write the character to the buffer.
-        self.DR.set(c as u32);
-
         self.chars_written += 1;
     }
 
+    /// Drain the RX FIFO into the RX buffer, counting errors.
+    fn receive(&mut self) {
+        while !self.FR.matches_all(FR::RXFE::SET) {
+            let data = self.DR.extract();
+
+            if data.is_set(DR::OE) {
+                self.overrun_errors += 1;
+            }
+            if data.is_set(DR::FE) || data.is_set(DR::BE) {
+                self.framing_errors += 1;
+                continue;
+            }
+            if data.is_set(DR::PE) {
+                self.parity_errors += 1;
+                continue;
+            }
+
+            if !self.rx_buffer.push(data.read(DR::DATA) as u8) {
+                self.overrun_errors += 1;
+            }
+        }
+    }
+
     /// Retrieve a character.
     fn read_char_converting(&mut self, blocking_mode: BlockingMode) -> Option<char> {
-        // If RX FIFO is empty,
-        if self.FR.matches_all(FR::RXFE::SET) {
+        // Pick up characters that arrived while the RX IRQ was not serviced.
+        self.receive();
+
+        // If RX buffer is empty,
+        if self.rx_buffer.is_empty() {
             // immediately return in non-blocking mode.
             if blocking_mode == BlockingMode::NonBlocking {
                 return None;
             }
 
             // Otherwise, wait until a char was received.
-            while self.FR.matches_all(FR::RXFE::SET) {
+            while self.rx_buffer.is_empty() {
                 cpu::nop();
+                self.receive();
             }
         }
 
         // Read one character.
-        let mut ret = self.DR.get() as u8 as char;
+        let mut ret = self.rx_buffer.pop().unwrap() as char;
 
         // Convert carrige return to newline.
         if ret == '\r' {
@@ -305,6 +568,23 @@ impl fmt::Write for PL011UartInner {
     }
 }
 
+/// Compute the integer and fractional baud rate divisors.
+///
+/// The divisor is `clock / (16 * baud)`, with the fraction in 1/64ths, rounded to nearest.
+fn divisors(clock_hz: u32, baud: u32) -> Result<(u32, u32), &'static str> {
+    if baud == 0 {
+        return Err("invalid baud rate");
+    }
+    let divisor_x64 = (clock_hz as u64 * 4 + baud as u64 / 2) / baud as u64;
+    let (ibrd, fbrd) = ((divisor_x64 >> 6) as u32, (divisor_x64 & 0x3f) as u32);
+
+    if ibrd == 0 || ibrd > 0xffff {
+        return Err("baud rate out of range for the UART clock");
+    }
+
+    Ok((ibrd, fbrd))
+}
+
 impl PL011Uart {
     /// # Safety
     ///
@@ -315,19 +595,35 @@ impl PL011Uart {
             irq_number,
         }
     }
+
+    /// The line settings and UART clock, unless the UART is locked.
+    ///
+    /// Used by the panic handler, which must not wait on a lock the panicking core may hold.
+    pub fn line_settings(&self) -> Option<(SerialConfig, u32)> {
+        self.inner
+            .try_lock()
+            .map(|data| (data.config, data.clock_hz))
+    }
 }
 
 //------------------------------------------------------------------------------
 // OS Interface Code
 //------------------------------------------------------------------------------
 
+use exception::asynchronous::exec_with_irq_masked;
+
 impl driver::interface::DeviceDriver for PL011Uart {
     fn compatible(&self) -> &str {
         "BCM PL011 UART"
     }
 
     fn init(&self) -> Result<(), ()> {
+        let clock_hz = MBox::new()
+            .clock_rate(Clock::Uart)
+            .unwrap_or(DEFAULT_CLOCK_HZ);
+
         let mut data = self.inner.lock();
+        data.clock_hz = clock_hz;
         data.init();
 
         Ok(())
@@ -344,30 +640,37 @@ impl driver::interface::DeviceDriver for PL011Uart {
 
         irq_manager().register_handler(self.irq_number, descriptor)?;
         irq_manager().enable(self.irq_number);
+        exec_with_irq_masked(|| self.inner.lock().irq_driven = true);
 
         Ok(())
     }
 }
 
+// The IRQ handler takes the same lock, so all users mask IRQs while holding it.
 impl console::interface::Write for PL011Uart {
     /// Passthrough of `args` to the `core::fmt::Write` implementation, but guarded by a Mutex to
     /// serialize access.
     fn write_char(&self, c: char) {
-        let mut data = self.inner.lock();
-        data.write_char(c);
+        exec_with_irq_masked(|| self.inner.lock().write_char(c))
     }
 
     fn write_fmt(&self, args: core::fmt::Arguments) -> fmt::Result {
         // Fully qualified syntax for the call to `core::fmt::Write::write:fmt()` to increase
         // readability.
-        let mut data = self.inner.lock();
-        fmt::Write::write_fmt(&mut *data, args)
+        exec_with_irq_masked(|| fmt::Write::write_fmt(&mut *self.inner.lock(), args))
     }
 
     fn flush(&self) {
-        // Spin until TX FIFO empty is set.
-        let data = self.inner.lock();
-        while !data.FR.matches_all(FR::TXFE::SET) {
+        // Spin until the TX buffer is drained and the last character left the shift register.
+        loop {
+            let done = exec_with_irq_masked(|| {
+                let mut data = self.inner.lock();
+                data.fill_tx_fifo();
+                data.tx_buffer.is_empty() && !data.FR.matches_any(FR::BUSY::SET)
+            });
+            if done {
+                break;
+            }
             cpu::nop();
         }
     }
@@ -375,48 +678,111 @@ impl console::interface::Write for PL011Uart {
 
 impl console::interface::Read for PL011Uart {
     fn read_char(&self) -> char {
-        let mut data = self.inner.lock();
-        data.read_char_converting(BlockingMode::Blocking).unwrap()
+        // Poll without holding the lock, so the RX IRQ can fill the buffer meanwhile.
+        loop {
+            if let Some(c) = self.try_read_char() {
+                return c;
+            }
+            cpu::nop();
+        }
+    }
+
+    fn try_read_char(&self) -> Option<char> {
+        exec_with_irq_masked(|| {
+            self.inner
+                .lock()
+                .read_char_converting(BlockingMode::NonBlocking)
+        })
     }
 
     fn clear(&self) {
-        let data = self.inner.lock();
+        exec_with_irq_masked(|| {
+            let mut data = self.inner.lock();
+
+            // Read from the RX FIFO until it is indicating empty.
+            while !data.FR.matches_all(FR::RXFE::SET) {
+                data.DR.get();
+            }
+            data.rx_buffer.clear();
+        })
+    }
+}
 
-        // Read from the RX FIFO until it is indicating empty.
-        while !data.FR.matches_all(FR::RXFE::SET) {
-            data.DR.get();
+impl console::interface::Configure for PL011Uart {
+    fn configure(&self, config: &SerialConfig) -> Result<(), &'static str> {
+        if config.flow_control == FlowControl::RtsCts {
+            bsp::GPIO.map_pl011_flow_control();
         }
+
+        exec_with_irq_masked(|| self.inner.lock().configure(*config))
+    }
+
+    fn config(&self) -> SerialConfig {
+        exec_with_irq_masked(|| self.inner.lock().config)
     }
 }
 
 impl console::interface::Statistics for PL011Uart {
     fn chars_written(&self) -> usize {
-        let data = self.inner.lock();
-        data.chars_written
+        exec_with_irq_masked(|| self.inner.lock().chars_written)
     }
 
     fn chars_read(&self) -> usize {
-        let data = self.inner.lock();
-        data.chars_read
+        exec_with_irq_masked(|| self.inner.lock().chars_read)
+    }
+
+    fn overrun_errors(&self) -> usize {
+        exec_with_irq_masked(|| self.inner.lock().overrun_errors)
+    }
+
+    fn framing_errors(&self) -> usize {
+        exec_with_irq_masked(|| self.inner.lock().framing_errors)
+    }
+
+    fn parity_errors(&self) -> usize {
+        exec_with_irq_masked(|| self.inner.lock().parity_errors)
     }
 }
 
 impl exception::asynchronous::interface::IRQHandler for PL011Uart {
     fn handle(&self, _e: &mut exception::ExceptionContext) -> Result<(), &'static str> {
         let mut data = self.inner.lock();
-        // Echo any received characters.
         let pending = data.RIS.extract();
 
         // Clear all pending IRQs.
         data.ICR.write(ICR::ALL::CLEAR);
 
-        // Check for any kind of RX interrupt.
-        if pending.matches_any(RIS::RXRIS::SET + RIS::RTRIS::SET) {
-            // Echo any received characters.
-            while let Some(c) = data.read_char_converting(BlockingMode::NonBlocking) {
-                data.write_char(c)
+        // Drain RX on any interrupt. Errors are flagged per character in DR, so the error IRQs
+        // need no handling of their own.
+        data.receive();
+
+        if pending.matches_all(RIS::TXRIS::SET) {
+            data.fill_tx_fifo();
+            if data.tx_buffer.is_empty() {
+                data.IMSC.modify(IMSC::TXIM::Disabled);
             }
         }
+
         Ok(())
     }
 }
+
+//--------------------------------------------------------------------------------------------------
+// Testing
+//--------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_macros::kernel_test;
+
+    /// Divisors match the PL011 manual's formula for common clocks.
+    #[kernel_test]
+    fn baud_rate_divisors() {
+        assert_eq!(divisors(48_000_000, 230400), Ok((13, 1)));
+        assert_eq!(divisors(48_000_000, 115200), Ok((26, 3)));
+        assert_eq!(divisors(3_000_000, 115200), Ok((1, 40)));
+        assert!(divisors(48_000_000, 0).is_err());
+        assert!(divisors(48_000_000, 4_000_000).is_err());
+    }
+}
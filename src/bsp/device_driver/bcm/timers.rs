@@ -1,5 +1,4 @@
-use crate::{bsp, driver, exception};
-use core::ops;
+use crate::{bsp, bsp::device_driver::common::MMIODerefWrapper, cpu, exception, time};
 use core::time::Duration;
 use cortex_a::regs::*;
 use register::{mmio::*, register_bitfields, register_structs};
@@ -39,182 +38,197 @@ register_structs! {
         (0x10 => C1: ReadWrite<u32>),
         (0x14 => C2: ReadWrite<u32>),
         (0x18 => C3: ReadWrite<u32>),
-        (0x22 => @END),
+        (0x1c => @END),
     }
 }
 
-pub struct SystemTimer {
-    inner: spin::RwLock<SystemTimerInner>,
-    irq_number: bsp::device_driver::IRQNumber,
-}
+/// Abstraction for the associated MMIO registers.
+type Regs = MMIODerefWrapper<RegisterBlock>;
 
-pub struct SystemTimerInner {
-    base_addr: usize,
-    interval: u32,
-    cur_val: u32,
-}
+const NS_PER_S: u64 = 1_000_000_000;
 
-impl ops::Deref for SystemTimerInner {
-    type Target = RegisterBlock;
+/// Maximum number of pending timer events per core.
+const MAX_EVENTS: usize = 8;
 
-    fn deref(&self) -> &Self::Target {
-        unsafe { &*self.ptr() }
-    }
+//--------------------------------------------------------------------------------------------------
+// BCM System Timer
+//--------------------------------------------------------------------------------------------------
+
+/// The BCM free running 1 MHz system timer.
+///
+/// Kept as an alternative clocksource. Compare channels 0 and 2 belong to the VideoCore, channel 3
+/// is used by USPi.
+pub struct SystemTimer {
+    registers: Regs,
 }
 
-impl SystemTimerInner {
+impl SystemTimer {
+    /// Create an instance.
+    ///
+    /// # Safety
+    ///
+    /// - The user must ensure to provide the correct `base_addr`.
     pub const unsafe fn new(base_addr: usize) -> Self {
         Self {
-            base_addr,
-            interval: 1000,
-            cur_val: 0,
+            registers: Regs::new(base_addr),
         }
     }
 
-    pub fn init(&mut self) {
-        self.cur_val = self.CLO.get();
-        self.cur_val += self.interval;
-        self.C1.set(self.cur_val);
-    }
-
-    fn ptr(&self) -> *const RegisterBlock {
-        self.base_addr as *const _
+    /// Read the 64 bit counter, retrying if the high word changed while reading the low word.
+    fn counter(&self) -> u64 {
+        loop {
+            let high = self.registers.CHI.get();
+            let low = self.registers.CLO.get();
+            if high == self.registers.CHI.get() {
+                return ((high as u64) << 32) | low as u64;
+            }
+        }
     }
+}
 
-    fn handle(&mut self) {
-        self.cur_val += self.interval;
-        self.C1.set(self.cur_val);
-        self.CS.write(CS::M1::Match);
+impl time::interface::TimeManager for SystemTimer {
+    fn resolution(&self) -> Duration {
+        Duration::from_micros(1)
     }
 
-    fn tick_in(&mut self, t: Duration) {
-        let current = self.CLO.get();
-        let target = current.wrapping_add(t.as_micros() as u32);
-
-        self.C1.set(target);
-        self.CS.write(CS::M1::Match);
+    fn uptime(&self) -> Duration {
+        Duration::from_micros(self.counter())
     }
 
-    fn current_time(&self) -> Duration {
-        let low = self.CLO.get();
-        let high = self.CHI.get();
-        Duration::from_micros(((high as u64) << 32) | low as u64)
+    fn spin_for(&self, duration: Duration) {
+        let end_time = self.uptime() + duration;
+        while end_time > self.uptime() {
+            cpu::nop();
+        }
     }
 }
 
-impl SystemTimer {
-    pub const unsafe fn new(base_addr: usize, irq_number: bsp::device_driver::IRQNumber) -> Self {
-        Self {
-            inner: spin::RwLock::new(SystemTimerInner::new(base_addr)),
-            irq_number: irq_number,
-        }
-    }
+//--------------------------------------------------------------------------------------------------
+// ARM Generic Timer
+//--------------------------------------------------------------------------------------------------
 
-    fn current_time(&self) -> Duration {
-        self.inner.read().current_time()
-    }
+/// The ARM generic timer's physical counter (CNTPCT_EL0).
+///
+/// The counter is shared by all cores, so it serves as the kernel's monotonic clock.
+pub struct GenericTimer;
 
-    fn tick_in(&self, t: Duration) {
-        self.inner.write().tick_in(t)
+impl GenericTimer {
+    /// Create an instance.
+    pub const fn new() -> Self {
+        Self
     }
 }
 
-impl driver::interface::DeviceDriver for SystemTimer {
-    fn compatible(&self) -> &str {
-        "System Timer"
-    }
+/// Convert a duration into ticks of the generic timer.
+fn duration_to_ticks(duration: Duration) -> u64 {
+    let frequency = CNTFRQ_EL0.get() as u64;
 
-    fn init(&self) -> Result<(), ()> {
-        let mut data = self.inner.write();
-        data.init();
+    duration.as_secs() * frequency + (duration.subsec_nanos() as u64 * frequency) / NS_PER_S
+}
 
-        Ok(())
+impl time::interface::TimeManager for GenericTimer {
+    fn resolution(&self) -> Duration {
+        Duration::from_nanos(NS_PER_S / (CNTFRQ_EL0.get() as u64))
     }
 
-    fn register_and_enable_irq_handler(&'static self) -> Result<(), &'static str> {
-        use bsp::exception::asynchronous::irq_manager;
-        use exception::asynchronous::{interface::IRQManager, IRQDescriptor};
+    fn uptime(&self) -> Duration {
+        let frequency = CNTFRQ_EL0.get() as u64;
+        let ticks = CNTPCT_EL0.get();
 
-        let descriptor = IRQDescriptor {
-            name: "System Timer",
-            handler: self,
-        };
+        let secs = ticks / frequency;
+        let nanos = ((ticks % frequency) * NS_PER_S) / frequency;
 
-        irq_manager().register_handler(self.irq_number, descriptor)?;
-        irq_manager().enable(self.irq_number);
-
-        Ok(())
+        Duration::new(secs, nanos as u32)
     }
-}
 
-impl exception::asynchronous::interface::IRQHandler for SystemTimer {
-    fn handle(&self, _e: &mut exception::ExceptionContext) -> Result<(), &'static str> {
-        Ok(())
+    fn spin_for(&self, duration: Duration) {
+        let end = CNTPCT_EL0.get() + duration_to_ticks(duration);
+        while CNTPCT_EL0.get() < end {
+            cpu::nop();
+        }
     }
 }
 
-pub struct GenericSystemTimer {
-    base_addr: usize,
-}
+//--------------------------------------------------------------------------------------------------
+// Per-core event timer
+//--------------------------------------------------------------------------------------------------
 
-impl GenericSystemTimer {
-    pub const unsafe fn new(base_addr: usize) -> Self {
-        Self { base_addr }
-    }
+#[derive(Copy, Clone)]
+struct TimerEvent {
+    /// Counter value at which the event fires.
+    deadline: u64,
 
-    fn ptr(&self) -> *const RegisterBlock {
-        self.base_addr as *const _
-    }
+    /// Reload value in counter ticks. Zero for one-shot events.
+    period: u64,
 
-    pub fn current_time(&self) -> Duration {
-        let low = self.CLO.get();
-        let high = self.CHI.get();
-        Duration::from_micros(((high as u64) << 32) | low as u64)
-    }
+    handler: time::EventHandler,
+}
+
+/// The pending events of a single core.
+struct CoreEvents {
+    slots: [Option<TimerEvent>; MAX_EVENTS],
+}
 
-    /// Spins until `t` duration have passed.
-    pub fn spin_sleep(&self, t: Duration) {
-        let end_time = self.current_time() + t;
-        while end_time > self.current_time() {
-            continue;
+impl CoreEvents {
+    const fn new() -> Self {
+        Self {
+            slots: [None; MAX_EVENTS],
         }
     }
-}
 
-impl ops::Deref for GenericSystemTimer {
-    type Target = RegisterBlock;
+    fn next_deadline(&self) -> Option<u64> {
+        self.slots.iter().flatten().map(|event| event.deadline).min()
+    }
 
-    fn deref(&self) -> &Self::Target {
-        unsafe { &*self.ptr() }
+    /// Program the core's CNTP comparator for the earliest pending event.
+    fn program(&self) {
+        match self.next_deadline() {
+            Some(deadline) => {
+                let delta = deadline
+                    .saturating_sub(CNTPCT_EL0.get())
+                    .max(1)
+                    .min(i32::MAX as u64);
+                CNTP_TVAL_EL0.set(delta as u32);
+                CNTP_CTL_EL0.modify(CNTP_CTL_EL0::ENABLE::SET + CNTP_CTL_EL0::IMASK::CLEAR);
+            }
+            None => CNTP_CTL_EL0.modify(CNTP_CTL_EL0::IMASK::SET),
+        }
     }
 }
 
+/// Per-core one-shot and periodic events, backed by each core's CNTP timer.
+///
+/// A single instance serves all cores. Every function acts on the executing core.
 pub struct LocalTimer {
-    interval: u64,
     irq_number: bsp::device_driver::IRQNumber,
+    events: [spin::Mutex<CoreEvents>; cpu::NUM_CORES],
 }
 
 impl LocalTimer {
+    /// Create an instance.
+    ///
+    /// # Safety
+    ///
+    /// - The user must ensure to provide the correct `irq_number`.
     pub const unsafe fn new(irq_number: bsp::device_driver::IRQNumber) -> Self {
         Self {
-            interval: 200, // in milliseconds
-            irq_number: irq_number,
+            irq_number,
+            events: [
+                spin::Mutex::new(CoreEvents::new()),
+                spin::Mutex::new(CoreEvents::new()),
+                spin::Mutex::new(CoreEvents::new()),
+                spin::Mutex::new(CoreEvents::new()),
+            ],
         }
     }
 
-    pub fn resolution(&self) -> Duration {
-        Duration::from_nanos(1_000_000_000 / (CNTFRQ_EL0.get() as u64))
-    }
-
-    pub fn init(&self) {
-        self.tick();
-    }
-
+    /// Register and enable the timer IRQ on the executing core.
+    ///
+    /// Local IRQs are per core, so every core calls this once during its bring-up.
     pub fn register_and_enable_irq_handler(&'static self) -> Result<(), &'static str> {
         use bsp::exception::asynchronous::irq_manager;
         use exception::asynchronous::{interface::IRQManager, IRQDescriptor};
 
-        // setup irq handler for local timer
         let descriptor = IRQDescriptor {
             name: "Local Timer",
             handler: self,
@@ -222,25 +236,103 @@ impl LocalTimer {
 
         irq_manager().register_handler(self.irq_number, descriptor)?;
         irq_manager().enable(self.irq_number);
-        self.tick();
+
         Ok(())
     }
 
-    fn tick(&self) {
-        use core::convert::TryInto;
-        let timer_frequency = CNTFRQ_EL0.get() as u64;
-        let interval = Duration::from_millis(self.interval);
-        let ticks = (timer_frequency * interval.as_nanos() as u64) / 1_000_000_000;
-        CNTP_TVAL_EL0.set(ticks.try_into().unwrap());
-        CNTP_CTL_EL0.modify(CNTP_CTL_EL0::ENABLE::SET + CNTP_CTL_EL0::IMASK::CLEAR);
+    fn add_event(
+        &self,
+        after: Duration,
+        period: Duration,
+        handler: time::EventHandler,
+    ) -> Result<time::EventId, &'static str> {
+        exception::asynchronous::exec_with_irq_masked(|| {
+            let mut events = self.events[cpu::core_id::<usize>()].lock();
+            let slot = events
+                .slots
+                .iter()
+                .position(|slot| slot.is_none())
+                .ok_or("No free timer event slot")?;
+
+            events.slots[slot] = Some(TimerEvent {
+                deadline: CNTPCT_EL0.get() + duration_to_ticks(after),
+                period: duration_to_ticks(period),
+                handler,
+            });
+            events.program();
+
+            Ok(time::EventId(slot))
+        })
+    }
+}
+
+//------------------------------------------------------------------------------
+// OS Interface Code
+//------------------------------------------------------------------------------
+
+impl time::interface::EventTimer for LocalTimer {
+    fn set_oneshot(
+        &self,
+        after: Duration,
+        handler: time::EventHandler,
+    ) -> Result<time::EventId, &'static str> {
+        self.add_event(after, Duration::from_secs(0), handler)
+    }
+
+    fn set_periodic(
+        &self,
+        period: Duration,
+        handler: time::EventHandler,
+    ) -> Result<time::EventId, &'static str> {
+        if period == Duration::from_secs(0) {
+            return Err("Periodic timer event needs a non-zero period");
+        }
+        self.add_event(period, period, handler)
+    }
+
+    fn cancel(&self, id: time::EventId) {
+        exception::asynchronous::exec_with_irq_masked(|| {
+            let mut events = self.events[cpu::core_id::<usize>()].lock();
+            if let Some(slot) = events.slots.get_mut(id.0) {
+                *slot = None;
+            }
+            events.program();
+        })
     }
 }
 
 impl exception::asynchronous::interface::IRQHandler for LocalTimer {
     fn handle(&self, e: &mut exception::ExceptionContext) -> Result<(), &'static str> {
-        use crate::sched::SCHEDULER;
-        SCHEDULER.timer_tick(e);
-        self.tick();
+        let mut due: [Option<time::EventHandler>; MAX_EVENTS] = [None; MAX_EVENTS];
+
+        {
+            let mut events = self.events[cpu::core_id::<usize>()].lock();
+            let now = CNTPCT_EL0.get();
+
+            for (i, slot) in events.slots.iter_mut().enumerate() {
+                let event = match slot {
+                    Some(event) if event.deadline <= now => event,
+                    _ => continue,
+                };
+
+                due[i] = Some(event.handler);
+                if event.period == 0 {
+                    *slot = None;
+                } else {
+                    // Skip missed periods instead of firing them back to back.
+                    event.deadline += event.period;
+                    if event.deadline <= now {
+                        event.deadline = now + event.period;
+                    }
+                }
+            }
+            events.program();
+        }
+
+        // Handlers may context switch, so run them without holding the lock.
+        for handler in due.iter().flatten() {
+            handler(e);
+        }
 
         Ok(())
     }
@@ -0,0 +1,160 @@
+use crate::{bsp::device_driver::common::MMIODerefWrapper, cpu, driver, watchdog};
+use core::fmt;
+use core::time::Duration;
+use register::{mmio::*, register_structs};
+
+// Power management watchdog registers.
+//
+// Not covered by the BCM2837 peripherals manual, descriptions taken from Linux's bcm2835_wdt.c.
+register_structs! {
+    #[allow(non_snake_case)]
+    pub RegisterBlock {
+        (0x00 => _reserved1),
+        (0x1c => RSTC: ReadWrite<u32>),
+        (0x20 => RSTS: ReadWrite<u32>),
+        (0x24 => WDOG: ReadWrite<u32>),
+        (0x28 => @END),
+    }
+}
+
+/// Abstraction for the associated MMIO registers.
+type Regs = MMIODerefWrapper<RegisterBlock>;
+
+/// Every write to the PM registers must carry the password in the top byte.
+const PM_PASSWORD: u32 = 0x5a00_0000;
+
+const PM_RSTC_WRCFG_CLR: u32 = 0xffff_ffcf;
+const PM_RSTC_WRCFG_FULL_RESET: u32 = 0x0000_0020;
+const PM_RSTC_RESET: u32 = 0x0000_0102;
+
+/// The watchdog counter runs at 64 kHz and is 20 bits wide, which caps the timeout at ~16s.
+const PM_WDOG_TICKS_PER_SEC: u64 = 1 << 16;
+const PM_WDOG_TIME_SET: u32 = 0x000f_ffff;
+
+const PM_RSTS_HADPOR: u32 = 1 << 12;
+const PM_RSTS_HADWRH: u32 = 1 << 6;
+
+/// Ticks from the watchdog firing a reboot to the reset.
+const REBOOT_TICKS: u32 = 10;
+
+/// Why the board last came out of reset, as recorded in PM_RSTS.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ResetReason {
+    PowerOn,
+    Watchdog,
+    Unknown(u32),
+}
+
+/// The BCM power management watchdog.
+pub struct Watchdog {
+    registers: Regs,
+
+    /// The loaded timeout in counter ticks, zero while stopped.
+    ticks: spin::Mutex<u32>,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+impl Watchdog {
+    /// Create an instance.
+    ///
+    /// # Safety
+    ///
+    /// - The user must ensure to provide the correct `base_addr`.
+    pub const unsafe fn new(base_addr: usize) -> Self {
+        Self {
+            registers: Regs::new(base_addr),
+            ticks: spin::Mutex::new(0),
+        }
+    }
+
+    /// Load the counter and arm a full reset on expiry.
+    fn arm(&self, ticks: u32) {
+        self.registers
+            .WDOG
+            .set(PM_PASSWORD | (ticks & PM_WDOG_TIME_SET));
+
+        let rstc = self.registers.RSTC.get() & PM_RSTC_WRCFG_CLR;
+        self.registers
+            .RSTC
+            .set(PM_PASSWORD | rstc | PM_RSTC_WRCFG_FULL_RESET);
+    }
+
+    /// Read why the board was last reset.
+    pub fn reset_reason(&self) -> ResetReason {
+        let rsts = self.registers.RSTS.get();
+
+        if rsts & PM_RSTS_HADWRH != 0 {
+            ResetReason::Watchdog
+        } else if rsts & PM_RSTS_HADPOR != 0 {
+            ResetReason::PowerOn
+        } else {
+            ResetReason::Unknown(rsts)
+        }
+    }
+
+    /// Reset the board right away.
+    pub fn reboot(&self) -> ! {
+        let _ticks = self.ticks.lock();
+        self.arm(REBOOT_TICKS);
+
+        cpu::wait_forever()
+    }
+}
+
+impl fmt::Display for ResetReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResetReason::PowerOn => write!(f, "power on"),
+            ResetReason::Watchdog => write!(f, "watchdog"),
+            ResetReason::Unknown(rsts) => write!(f, "unknown (PM_RSTS {:#010x})", rsts),
+        }
+    }
+}
+
+//------------------------------------------------------------------------------
+// OS Interface Code
+//------------------------------------------------------------------------------
+
+impl driver::interface::DeviceDriver for Watchdog {
+    fn compatible(&self) -> &str {
+        "BCM Watchdog"
+    }
+}
+
+impl watchdog::interface::Watchdog for Watchdog {
+    fn start(&self, timeout: Duration) {
+        let mut ticks = self.ticks.lock();
+
+        *ticks = (timeout.as_millis() as u64 * PM_WDOG_TICKS_PER_SEC / 1_000)
+            .max(1)
+            .min(PM_WDOG_TIME_SET as u64) as u32;
+        self.arm(*ticks);
+    }
+
+    fn feed(&self) {
+        let ticks = self.ticks.lock();
+
+        if *ticks != 0 {
+            self.registers.WDOG.set(PM_PASSWORD | *ticks);
+        }
+    }
+
+    fn stop(&self) {
+        let mut ticks = self.ticks.lock();
+
+        self.registers.RSTC.set(PM_PASSWORD | PM_RSTC_RESET);
+        *ticks = 0;
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        match *self.ticks.lock() {
+            0 => None,
+            ticks => Some(Duration::from_millis(
+                ticks as u64 * 1_000 / PM_WDOG_TICKS_PER_SEC,
+            )),
+        }
+    }
+}
@@ -3,7 +3,7 @@ pub mod driver;
 pub mod exception;
 
 use crate::memory;
-use crate::{bsp::device_driver, console};
+use crate::{bsp::device_driver, console, driver, net, warn, watchdog};
 use core::fmt;
 
 pub static GPIO: device_driver::GPIO =
@@ -19,18 +19,27 @@ static PL011_UART: device_driver::PL011Uart = unsafe {
 pub static MINI_UART: device_driver::MiniUart =
     unsafe { device_driver::MiniUart::new(memory::map::mmio::MINI_UART_BASE) };
 
-// pub static SYSTEM_TIMER3: device_driver::SystemTimer = unsafe {
-//     device_driver::SystemTimer::new(
-//         memory::map::mmio::SYS_TIMER_BASE,
-//         exception::asynchronous::irq_map::SYSTEM_TIMER3,
-//     )
-// };
-
-// get an instance to the generic system timer
-// for reading time
-pub fn generic_timer() -> device_driver::GenericSystemTimer {
-    unsafe { device_driver::GenericSystemTimer::new(memory::map::mmio::SYS_TIMER_BASE) }
-}
+/// The PL011 UART and the RAM log until the command line says otherwise.
+static CONSOLE: console::Registry = console::Registry::new(
+    ("ttyAMA0", &PL011_UART),
+    [
+        Some(("ttyAMA0", &PL011_UART)),
+        Some(("ram", &console::ram_log::RAM_LOG)),
+        None,
+        None,
+    ],
+);
+
+pub static SYSTEM_TIMER: device_driver::SystemTimer =
+    unsafe { device_driver::SystemTimer::new(memory::map::mmio::SYS_TIMER_BASE) };
+
+pub static GENERIC_TIMER: device_driver::GenericTimer = device_driver::GenericTimer::new();
+
+pub static LOCAL_TIMER: device_driver::LocalTimer =
+    unsafe { device_driver::LocalTimer::new(exception::asynchronous::irq_map::LOCAL_TIMER) };
+
+pub static WATCHDOG: device_driver::Watchdog =
+    unsafe { device_driver::Watchdog::new(memory::map::mmio::PM_BASE) };
 
 pub static INTERRUPT_CONTROLLER: device_driver::InterruptController = unsafe {
     device_driver::InterruptController::new(
@@ -48,14 +57,89 @@ pub fn board_name() -> &'static str {
 /// something before the system is halted.
 /// - Use only for printing during a panic.
 pub unsafe fn panic_console_out() -> impl fmt::Write {
-    let mut uart = device_driver::PanicUart::new(memory::map::mmio::PL011_UART_BASE);
+    let mut uart = device_driver::PanicUart::with_settings(
+        memory::map::mmio::PL011_UART_BASE,
+        PL011_UART.line_settings(),
+    );
     uart.init();
     uart
 }
 
 /// Return a reference to the console.
-pub fn console() -> &'static impl console::interface::All {
-    &PL011_UART
+pub fn console() -> &'static console::Registry {
+    &CONSOLE
+}
+
+/// The kernel command line passed by the firmware.
+pub fn cmdline() -> Option<&'static str> {
+    atags::Atags::get().find_map(|atag| atag.cmd())
+}
+
+/// Select the console devices and apply the line settings from the command line.
+///
+/// Every `console=<device>[,<options>]` option adds a device: `ttyAMA0` is the PL011 UART and
+/// `ttyS0` the mini UART, as on Linux, `netcon0` sends UDP datagrams to the host and `ram` is the
+/// RAM log, which is always on. The UARTs share GPIO 14 and 15, so only the last one named is used,
+/// and it also takes input. Without a `console=` option the PL011 UART is used.
+pub fn init_consoles() -> Result<(), &'static str> {
+    use console::interface::Configure;
+    use driver::interface::DeviceDriver;
+
+    let cmdline = cmdline().unwrap_or("");
+    let mut uart = "ttyAMA0";
+    for device in console::cmdline_devices(cmdline) {
+        match device {
+            "ttyAMA0" | "ttyS0" => uart = device,
+            "netcon0" => CONSOLE.add("netcon0", &net::netconsole::NETCONSOLE)?,
+            "ram" => (),
+            _ => warn!("Ignoring unknown console device {}", device),
+        }
+    }
+
+    if uart == "ttyS0" {
+        // Not `MINI_UART.init()`, which is the inherent method that skips the clock query.
+        DeviceDriver::init(&MINI_UART).map_err(|_| "failed to initialize the mini UART")?;
+        CONSOLE.remove("ttyAMA0");
+        CONSOLE.add("ttyS0", &MINI_UART)?;
+        CONSOLE.set_input("ttyS0", &MINI_UART);
+    }
+
+    match console::parse_cmdline(cmdline) {
+        Some(("ttyAMA0", config)) => PL011_UART.configure(&config?),
+        Some(("ttyS0", config)) => MINI_UART.configure(&config?),
+        Some(_) => Err("line settings for an unknown console device"),
+        None => Ok(()),
+    }
+}
+
+/// Bring up the mini UART for the GDB stub, unless the console uses it.
+///
+/// It takes GPIO 14 and 15 from the PL011 UART, so on real hardware the console has to go through
+/// the network or the RAM log. QEMU does not model the pin multiplexing and keeps both.
+pub fn init_gdb_uart() -> Result<&'static device_driver::MiniUart, &'static str> {
+    use driver::interface::DeviceDriver;
+
+    if CONSOLE.input_name() == "ttyS0" {
+        return Err("the mini UART is the console");
+    }
+    DeviceDriver::init(&MINI_UART).map_err(|_| "failed to initialize the mini UART")?;
+
+    Ok(&MINI_UART)
+}
+
+/// Return a reference to the hardware watchdog.
+pub fn watchdog() -> &'static impl watchdog::interface::Watchdog {
+    &WATCHDOG
+}
+
+/// Reset the board.
+pub fn reboot() -> ! {
+    WATCHDOG.reboot()
+}
+
+/// Why the board was last reset.
+pub fn reset_reason() -> device_driver::ResetReason {
+    WATCHDOG.reset_reason()
 }
 
 //--------------------------------------------------------------------------------------------------
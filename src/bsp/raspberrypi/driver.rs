@@ -2,16 +2,16 @@ use crate::driver;
 
 /// Device Driver Manager type.
 pub struct BSPDriverManager {
-    device_drivers: [&'static (dyn DeviceDriver + Sync); 3],
+    device_drivers: [&'static (dyn DeviceDriver + Sync); 4],
 }
 
 static BSP_DRIVER_MANAGER: BSPDriverManager = BSPDriverManager {
     device_drivers: [
         &super::GPIO,
         &super::PL011_UART,
-        //&super::MINI_UART,
+        // MINI_UART shares its pins with PL011_UART, `init_consoles()` brings it up if selected.
         &super::INTERRUPT_CONTROLLER,
-        //&super::SYSTEM_TIMER,
+        &super::WATCHDOG,
     ],
 };
 
@@ -1,4 +1,4 @@
-use crate::{bsp, exception};
+use crate::{bsp, exception, warn};
 
 pub mod irq_map {
     use super::bsp::device_driver::{IRQNumber, LocalIRQ, PeripheralIRQ};
@@ -6,8 +6,17 @@ pub mod irq_map {
     pub const PL011_UART: IRQNumber = IRQNumber::Peripheral(PeripheralIRQ::new(57));
     pub const SYSTEM_TIMER1: IRQNumber = IRQNumber::Peripheral(PeripheralIRQ::new(1));
     pub const SYSTEM_TIMER3: IRQNumber = IRQNumber::Peripheral(PeripheralIRQ::new(3));
-    pub const USB: IRQNumber = IRQNumber::Peripheral(PeripheralIRQ::new(2));
+    pub const USB: IRQNumber = IRQNumber::Peripheral(PeripheralIRQ::new(9));
     pub const LOCAL_TIMER: IRQNumber = IRQNumber::Local(LocalIRQ::new(1));
+
+    /// The core dedicated to servicing the network stack.
+    pub const NET_CORE: usize = 1;
+
+    /// Preferred cores for IRQs that should not be serviced by the boot core.
+    ///
+    /// The USB controller is raised as FIQ, which has its own route, so moving it does not drag
+    /// the UART and system timer IRQs along.
+    pub const PREFERRED_CORES: [(IRQNumber, usize); 1] = [(USB, NET_CORE)];
 }
 
 /// Return a reference to the IRQ manager.
@@ -16,3 +25,42 @@ pub fn irq_manager() -> &'static impl exception::asynchronous::interface::IRQMan
 > {
     &super::super::INTERRUPT_CONTROLLER
 }
+
+/// Route IRQs to the preferred cores declared in `irq_map`.
+///
+/// Must be called after the IRQs have been enabled, since the controller needs to know which
+/// peripheral IRQ is raised as FIQ.
+pub fn route_preferred_cores() {
+    use exception::asynchronous::interface::IRQManager;
+
+    for (irq, core) in irq_map::PREFERRED_CORES.iter() {
+        if let Err(msg) = irq_manager().set_affinity(*irq, *core) {
+            warn!("Error routing IRQ to core {}: {}", core, msg);
+        }
+    }
+}
+
+/// Returns whether the executing core is the target of the peripheral FIQ.
+pub fn fiq_routed_here() -> bool {
+    super::super::INTERRUPT_CONTROLLER.fiq_routed_here()
+}
+
+/// Returns the core the peripheral FIQ is routed to.
+pub fn fiq_core() -> usize {
+    super::super::INTERRUPT_CONTROLLER.fiq_core()
+}
+
+/// Enable IPIs to the executing core. Called on every core during boot.
+pub fn enable_ipi() {
+    super::super::INTERRUPT_CONTROLLER.enable_ipi()
+}
+
+/// Interrupt `core` with an IPI.
+pub fn send_ipi(core: usize) {
+    super::super::INTERRUPT_CONTROLLER.send_ipi(core)
+}
+
+/// Disable all peripheral IRQs and the FIQ. Local IRQs, the timers and IPIs, stay enabled.
+pub fn disable_peripheral_irqs() {
+    super::super::INTERRUPT_CONTROLLER.disable_peripheral_irqs()
+}
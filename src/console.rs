@@ -22,6 +22,11 @@ pub mod interface {
             ' '
         }
 
+        /// Read a single character if one is available, without blocking.
+        fn try_read_char(&self) -> Option<char> {
+            None
+        }
+
         /// Clear RX buffers, if any.
         fn clear(&self);
     }
@@ -37,8 +42,385 @@ pub mod interface {
         fn chars_read(&self) -> usize {
             0
         }
+
+        /// Return the number of characters lost because the RX FIFO or buffer was full.
+        fn overrun_errors(&self) -> usize {
+            0
+        }
+
+        /// Return the number of characters received without a valid stop bit.
+        fn framing_errors(&self) -> usize {
+            0
+        }
+
+        /// Return the number of characters received with a parity mismatch.
+        fn parity_errors(&self) -> usize {
+            0
+        }
+    }
+
+    /// Console line settings.
+    pub trait Configure {
+        /// Apply `config`. Pending output is sent with the old settings first.
+        fn configure(&self, config: &super::SerialConfig) -> Result<(), &'static str>;
+
+        /// Return the active settings.
+        fn config(&self) -> super::SerialConfig;
+    }
+
+    /// A full-fledged console.
+    ///
+    /// A trait with a blanket implementation rather than a trait alias, so that it can be used as
+    /// a trait object.
+    pub trait All: Write + Read + Statistics + Configure {}
+
+    impl<T: Write + Read + Statistics + Configure> All for T {}
+}
+
+pub mod ram_log;
+
+use core::fmt;
+
+/// Maximum number of console sinks that can be active at once.
+pub const MAX_SINKS: usize = 4;
+
+/// A console device that takes output.
+pub type Sink = &'static (dyn interface::Write + Sync);
+
+/// A console device that also provides input.
+pub type Device = &'static (dyn interface::All + Sync);
+
+/// A source of input without line settings, like a remote session.
+pub type Input = &'static (dyn interface::Read + Sync);
+
+/// The set of active console devices.
+///
+/// Output is fanned out to all sinks, input is taken from one selected device. A remote session
+/// can attach a second input, which is read next to it.
+pub struct Registry {
+    inner: spin::RwLock<RegistryInner>,
+}
+
+struct RegistryInner {
+    sinks: [Option<(&'static str, Sink)>; MAX_SINKS],
+    input: (&'static str, Device),
+    remote: Option<(&'static str, Input)>,
+}
+
+impl Registry {
+    /// Create an instance taking input from `input` and writing to `sinks`.
+    pub const fn new(
+        input: (&'static str, Device),
+        sinks: [Option<(&'static str, Sink)>; MAX_SINKS],
+    ) -> Self {
+        Self {
+            inner: spin::RwLock::new(RegistryInner {
+                sinks,
+                input,
+                remote: None,
+            }),
+        }
+    }
+
+    /// Add `sink` under `name`. Adding a name twice replaces the earlier sink.
+    pub fn add(&self, name: &'static str, sink: Sink) -> Result<(), &'static str> {
+        let mut inner = self.inner.write();
+
+        let slot = match inner
+            .sinks
+            .iter()
+            .position(|entry| matches!(entry, Some((n, _)) if *n == name))
+        {
+            Some(slot) => slot,
+            None => inner
+                .sinks
+                .iter()
+                .position(Option::is_none)
+                .ok_or("too many console sinks")?,
+        };
+        inner.sinks[slot] = Some((name, sink));
+
+        Ok(())
+    }
+
+    /// Remove the sink registered under `name`, if any.
+    pub fn remove(&self, name: &str) {
+        for entry in self.inner.write().sinks.iter_mut() {
+            if matches!(entry, Some((n, _)) if *n == name) {
+                *entry = None;
+            }
+        }
+    }
+
+    /// Take input from `device` from now on.
+    pub fn set_input(&self, name: &'static str, device: Device) {
+        self.inner.write().input = (name, device);
+    }
+
+    /// The name of the input device.
+    pub fn input_name(&self) -> &'static str {
+        self.inner.read().input.0
+    }
+
+    /// Also take input from `input`, until detached. Only one can be attached at a time.
+    pub fn attach_input(&self, name: &'static str, input: Input) -> Result<(), &'static str> {
+        let mut inner = self.inner.write();
+        if inner.remote.is_some() {
+            return Err("an input is already attached");
+        }
+        inner.remote = Some((name, input));
+
+        Ok(())
+    }
+
+    /// Detach the input attached under `name`, if any.
+    pub fn detach_input(&self, name: &str) {
+        let mut inner = self.inner.write();
+        if matches!(inner.remote, Some((n, _)) if n == name) {
+            inner.remote = None;
+        }
+    }
+
+    /// The name of the attached input, if any.
+    pub fn attached_input_name(&self) -> Option<&'static str> {
+        self.inner.read().remote.map(|(name, _)| name)
+    }
+
+    /// Call `f` with the name of every sink.
+    pub fn for_each_sink(&self, mut f: impl FnMut(&'static str)) {
+        for (name, _) in self.inner.read().sinks.iter().flatten() {
+            f(name);
+        }
+    }
+
+    fn input(&self) -> Device {
+        self.inner.read().input.1
+    }
+
+    fn remote(&self) -> Option<Input> {
+        self.inner.read().remote.map(|(_, input)| input)
+    }
+}
+
+impl interface::Write for Registry {
+    fn write_char(&self, c: char) {
+        for (_, sink) in self.inner.read().sinks.iter().flatten() {
+            sink.write_char(c);
+        }
+    }
+
+    /// Write to every sink. Fails if any of them failed.
+    fn write_fmt(&self, args: fmt::Arguments) -> fmt::Result {
+        let mut result = Ok(());
+        for (_, sink) in self.inner.read().sinks.iter().flatten() {
+            result = result.and(sink.write_fmt(args));
+        }
+
+        result
+    }
+
+    fn flush(&self) {
+        for (_, sink) in self.inner.read().sinks.iter().flatten() {
+            sink.flush();
+        }
+    }
+}
+
+impl interface::Read for Registry {
+    /// Block on the input device only. Tasks read both inputs through the `getc` syscall.
+    fn read_char(&self) -> char {
+        self.input().read_char()
+    }
+
+    /// Read from the attached input first, then from the input device.
+    fn try_read_char(&self) -> Option<char> {
+        self.remote()
+            .and_then(|remote| remote.try_read_char())
+            .or_else(|| self.input().try_read_char())
+    }
+
+    fn clear(&self) {
+        if let Some(remote) = self.remote() {
+            remote.clear();
+        }
+        self.input().clear()
+    }
+}
+
+impl interface::Statistics for Registry {
+    fn chars_written(&self) -> usize {
+        self.input().chars_written()
     }
 
-    /// Trait alias for a full-fledged console.
-    pub trait All = Write + Read + Statistics;
+    fn chars_read(&self) -> usize {
+        self.input().chars_read()
+    }
+
+    fn overrun_errors(&self) -> usize {
+        self.input().overrun_errors()
+    }
+
+    fn framing_errors(&self) -> usize {
+        self.input().framing_errors()
+    }
+
+    fn parity_errors(&self) -> usize {
+        self.input().parity_errors()
+    }
+}
+
+impl interface::Configure for Registry {
+    fn configure(&self, config: &SerialConfig) -> Result<(), &'static str> {
+        self.input().configure(config)
+    }
+
+    fn config(&self) -> SerialConfig {
+        self.input().config()
+    }
+}
+
+/// Parity modes.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+/// Number of stop bits.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum StopBits {
+    One,
+    Two,
+}
+
+/// Flow control modes.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum FlowControl {
+    None,
+    RtsCts,
+}
+
+/// Serial line settings.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct SerialConfig {
+    pub baud: u32,
+    pub data_bits: u8,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub flow_control: FlowControl,
+}
+
+impl SerialConfig {
+    /// 8N1 without flow control at `baud`.
+    pub const fn new(baud: u32) -> Self {
+        Self {
+            baud,
+            data_bits: 8,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            flow_control: FlowControl::None,
+        }
+    }
+
+    /// Parse Linux style options, `<baud>[<parity>[<bits>[r]]]`, e.g. `115200n8r`.
+    ///
+    /// Parity is one of `n`, `e`, `o`, bits is 5 to 8 and `r` enables RTS/CTS flow control.
+    pub fn parse(options: &str) -> Result<Self, &'static str> {
+        let digits = options
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or_else(|| options.len());
+        let baud = options[..digits].parse().map_err(|_| "invalid baud rate")?;
+        let mut config = Self::new(baud);
+
+        let mut rest = options[digits..].chars();
+        if let Some(parity) = rest.next() {
+            config.parity = match parity {
+                'n' => Parity::None,
+                'e' => Parity::Even,
+                'o' => Parity::Odd,
+                _ => return Err("invalid parity"),
+            };
+        }
+        if let Some(bits) = rest.next() {
+            config.data_bits = match bits.to_digit(10) {
+                Some(bits @ 5..=8) => bits as u8,
+                _ => return Err("invalid data bits"),
+            };
+        }
+        match rest.next() {
+            Some('r') => config.flow_control = FlowControl::RtsCts,
+            Some(_) => return Err("invalid flow control"),
+            None => (),
+        }
+        if rest.next().is_some() {
+            return Err("trailing characters");
+        }
+
+        Ok(config)
+    }
+}
+
+/// The devices of all `console=<device>[,<options>]` options in a kernel command line, in order.
+pub fn cmdline_devices(cmdline: &str) -> impl Iterator<Item = &str> {
+    cmdline
+        .split_whitespace()
+        .filter_map(|arg| arg.strip_prefix("console="))
+        .filter_map(|value| value.split(',').next())
+}
+
+/// Find the last `console=<device>,<options>` option in a kernel command line.
+///
+/// Returns the device name and its parsed settings. Options without settings are skipped.
+pub fn parse_cmdline(cmdline: &str) -> Option<(&str, Result<SerialConfig, &'static str>)> {
+    let value = cmdline
+        .split_whitespace()
+        .filter_map(|arg| arg.strip_prefix("console="))
+        .filter(|value| value.contains(','))
+        .last()?;
+    let mut parts = value.splitn(2, ',');
+    let device = parts.next()?;
+    let options = parts.next()?;
+
+    Some((device, SerialConfig::parse(options)))
+}
+
+//--------------------------------------------------------------------------------------------------
+// Testing
+//--------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_macros::kernel_test;
+
+    /// Options parse like Linux' `console=` parameter.
+    #[kernel_test]
+    fn serial_config_parse() {
+        let config = SerialConfig::parse("115200o7r").unwrap();
+        assert_eq!(config.baud, 115200);
+        assert_eq!(config.parity, Parity::Odd);
+        assert_eq!(config.data_bits, 7);
+        assert_eq!(config.flow_control, FlowControl::RtsCts);
+
+        assert_eq!(SerialConfig::parse("9600"), Ok(SerialConfig::new(9600)));
+        assert!(SerialConfig::parse("9600x8").is_err());
+        assert!(SerialConfig::parse("n8").is_err());
+    }
+
+    /// The last `console=` option with settings wins.
+    #[kernel_test]
+    fn cmdline_console_option() {
+        let cmdline = "8250.nr_uarts=1 console=tty1 console=ttyAMA0,115200n8r rootwait";
+        let (device, config) = parse_cmdline(cmdline).unwrap();
+
+        assert_eq!(device, "ttyAMA0");
+        assert_eq!(config.unwrap().flow_control, FlowControl::RtsCts);
+        assert!(parse_cmdline("rootwait").is_none());
+
+        let mut devices = cmdline_devices(cmdline);
+        assert_eq!(devices.next(), Some("tty1"));
+        assert_eq!(devices.next(), Some("ttyAMA0"));
+        assert_eq!(devices.next(), None);
+    }
 }
@@ -0,0 +1,162 @@
+//! Console sink that keeps the most recent output in RAM.
+//!
+//! The log lives in the `.noinit` section, which the boot code neither loads nor zeroes, and which
+//! is mapped non-cacheable so that a watchdog reset loses nothing still sitting in the caches. There
+//! are two banks, and every boot writes to the one the previous boot did not, so that the previous
+//! boot's output can be read after a crash.
+
+use super::interface;
+use crate::exception::asynchronous::exec_with_irq_masked;
+use core::{fmt, mem::MaybeUninit};
+use spin::Mutex;
+
+/// Size of the ring buffer in each bank.
+pub const BANK_SIZE: usize = 16 * 1024;
+
+/// Marks a bank as written by this kernel, "RAMLOG01".
+const MAGIC: u64 = 0x3130_474f_4c4d_4152;
+
+#[repr(C)]
+struct Bank {
+    magic: u64,
+    /// Counts boots, the most recent bank has the highest number.
+    boot: u64,
+    /// Total number of bytes written. The oldest byte is at `written % BANK_SIZE` once the ring
+    /// has wrapped.
+    written: u64,
+    data: [u8; BANK_SIZE],
+}
+
+#[link_section = ".noinit"]
+static mut BANKS: MaybeUninit<[Bank; 2]> = MaybeUninit::uninit();
+
+fn bank(index: usize) -> *mut Bank {
+    unsafe { (BANKS.as_mut_ptr() as *mut Bank).add(index) }
+}
+
+/// The boot number stored in bank `index`, if it holds a log.
+fn boot_of(index: usize) -> Option<u64> {
+    let bank = unsafe { &*bank(index) };
+
+    if bank.magic == MAGIC {
+        Some(bank.boot)
+    } else {
+        None
+    }
+}
+
+/// The contents of `bank`, oldest part first.
+fn contents(bank: &'static Bank) -> [&'static [u8]; 2] {
+    let written = bank.written as usize;
+
+    if written <= BANK_SIZE {
+        [&bank.data[..written], &[]]
+    } else {
+        let head = written % BANK_SIZE;
+        [&bank.data[head..], &bank.data[..head]]
+    }
+}
+
+struct BankWriter(&'static mut Bank);
+
+impl fmt::Write for BankWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            let head = self.0.written as usize % BANK_SIZE;
+            self.0.data[head] = byte;
+            self.0.written += 1;
+        }
+
+        Ok(())
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public Definitions
+//--------------------------------------------------------------------------------------------------
+
+pub struct RamLog {
+    /// The bank this boot writes to, once initialized.
+    active: Mutex<Option<usize>>,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Global instances
+//--------------------------------------------------------------------------------------------------
+
+pub static RAM_LOG: RamLog = RamLog {
+    active: Mutex::new(None),
+};
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+impl RamLog {
+    /// Start logging into the bank not holding the previous boot's log. Output before this is
+    /// dropped.
+    ///
+    /// Must be called with the MMU on, as unaligned accesses fault on device memory.
+    pub fn init(&self) {
+        let mut active = self.active.lock();
+        if active.is_some() {
+            return;
+        }
+
+        let (index, boot) = match (boot_of(0), boot_of(1)) {
+            (Some(first), Some(second)) if first >= second => (1, first + 1),
+            (_, Some(second)) => (0, second + 1),
+            (Some(first), None) => (1, first + 1),
+            (None, None) => (0, 0),
+        };
+
+        let bank = unsafe { &mut *bank(index) };
+        bank.magic = MAGIC;
+        bank.boot = boot;
+        bank.written = 0;
+        *active = Some(index);
+    }
+
+    /// The previous boot's log, oldest part first, if there is one.
+    pub fn previous_boot(&self) -> Option<[&'static [u8]; 2]> {
+        let index = 1 - (*self.active.lock())?;
+
+        boot_of(index)?;
+        Some(contents(unsafe { &*bank(index) }))
+    }
+
+    /// Write `args` unless the log is in use. For the panic handler, which must not wait on a
+    /// lock the panicking code may hold.
+    pub fn try_write_fmt(&self, args: fmt::Arguments) -> fmt::Result {
+        match self.active.try_lock() {
+            Some(active) => Self::write_locked(*active, args),
+            None => Err(fmt::Error),
+        }
+    }
+
+    fn write_locked(active: Option<usize>, args: fmt::Arguments) -> fmt::Result {
+        match active {
+            Some(index) => {
+                fmt::Write::write_fmt(&mut BankWriter(unsafe { &mut *bank(index) }), args)
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+//------------------------------------------------------------------------------
+// OS Interface Code
+//------------------------------------------------------------------------------
+
+impl interface::Write for RamLog {
+    fn write_char(&self, c: char) {
+        let mut buf = [0; 4];
+        let _ = self.write_fmt(format_args!("{}", c.encode_utf8(&mut buf)));
+    }
+
+    fn write_fmt(&self, args: fmt::Arguments) -> fmt::Result {
+        exec_with_irq_masked(|| Self::write_locked(*self.active.lock(), args))
+    }
+
+    fn flush(&self) {}
+}
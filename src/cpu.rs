@@ -3,6 +3,10 @@ use core::ptr::{read_volatile, write_volatile};
 use core::sync::atomic::{AtomicUsize, Ordering};
 use cortex_a::{asm, regs::*};
 
+pub mod backtrace;
+pub mod chainload;
+pub mod debug;
+
 /// Used by `arch` code to find the early boot core.
 pub const BOOT_CORE_ID: usize = 0;
 
@@ -17,18 +21,6 @@ pub const SPINNING_BASE: *mut usize = 0xd8 as *mut usize;
 
 global_asm!(include_str!("exception.S"));
 
-static CORE1_TIMER: bsp::device_driver::LocalTimer = unsafe {
-    bsp::device_driver::LocalTimer::new(bsp::exception::asynchronous::irq_map::LOCAL_TIMER)
-};
-
-static CORE2_TIMER: bsp::device_driver::LocalTimer = unsafe {
-    bsp::device_driver::LocalTimer::new(bsp::exception::asynchronous::irq_map::LOCAL_TIMER)
-};
-
-static CORE3_TIMER: bsp::device_driver::LocalTimer = unsafe {
-    bsp::device_driver::LocalTimer::new(bsp::exception::asynchronous::irq_map::LOCAL_TIMER)
-};
-
 pub struct Coordinator(AtomicUsize);
 pub static CORE_COORD: Coordinator = Coordinator(AtomicUsize::new(0));
 
@@ -118,6 +110,9 @@ unsafe fn el2_to_el1() {
         // Set SCTLR to known state
         runtime_init::SCTLR_EL1.set(runtime_init::SCTLR_EL1::RES1);
 
+        // No task is running yet, see `sched::current_pid()`.
+        runtime_init::TPIDR_EL0.set(0);
+
         VBAR_EL1.set(&__exception_vector_start as *const _ as u64);
 
         // Set up a simulated exception return.
@@ -135,6 +130,13 @@ unsafe fn el2_to_el1() {
         // eret to itself, expecting current_el() == 1 this time.
         ELR_EL2.set(el2_to_el1 as *const () as u64);
         asm::eret();
+    } else if CurrentEL.get() == CurrentEL::EL::EL1.value {
+        // Chain-loaded by a previous kernel, see `chainload`, which left EL2 configured. This
+        // branch also runs after the eret above, and sets the same state again.
+        runtime_init::CPACR_EL1.set(runtime_init::CPACR_EL1.get() | (0b11 << 20));
+        runtime_init::SCTLR_EL1.set(runtime_init::SCTLR_EL1::RES1);
+        runtime_init::TPIDR_EL0.set(0);
+        VBAR_EL1.set(&__exception_vector_start as *const _ as u64);
     }
 }
 
@@ -220,29 +222,24 @@ unsafe fn kmain2() -> ! {
     // wait for shceduler to be initialized by core 0 before starting timers
     CORE_COORD.set_ready_and_wait();
     init_core_timer();
+    bsp::exception::asynchronous::enable_ipi();
+    if bsp::exception::asynchronous::fiq_routed_here() {
+        exception::asynchronous::local_fiq_unmask();
+    }
     exception::asynchronous::local_irq_unmask();
     loop {}
 }
 
-fn init_core_timer() {
-    use crate::warn;
-    match core_id::<usize>() {
-        1 => {
-            if let Err(mssg) = CORE1_TIMER.register_and_enable_irq_handler() {
-                warn!("Error registering IRQ handler: {}", mssg);
-            }
-        }
-        2 => {
-            if let Err(mssg) = CORE2_TIMER.register_and_enable_irq_handler() {
-                warn!("Error registering IRQ handler: {}", mssg);
-            }
-        }
-        3 => {
-            if let Err(mssg) = CORE3_TIMER.register_and_enable_irq_handler() {
-                warn!("Error registering IRQ handler: {}", mssg);
-            }
-        }
-        _ => warn!("Received wrong core in timer init: {}", core_id::<usize>()),
+/// Register the executing core's timer IRQ and start the scheduler tick.
+pub fn init_core_timer() {
+    use crate::{sched, time, time::interface::EventTimer, warn};
+
+    if let Err(mssg) = bsp::LOCAL_TIMER.register_and_enable_irq_handler() {
+        warn!("Error registering IRQ handler: {}", mssg);
+        return;
+    }
+    if let Err(mssg) = time::event_timer().set_periodic(sched::TICK, sched::tick) {
+        warn!("Error starting scheduler tick on core {}: {}", core_id::<usize>(), mssg);
     }
 }
 
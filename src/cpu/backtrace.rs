@@ -0,0 +1,93 @@
+//! Frame pointer based stack unwinding.
+//!
+//! The kernel is built with frame pointers, so x29 points to a record of the caller's frame pointer
+//! and the return address, and the records of all frames form a chain up the stack.
+
+use crate::memory;
+
+/// Frames walked at most, in case the chain is corrupt.
+const MAX_FRAMES: usize = 32;
+
+/// Return addresses up the call chain, innermost first.
+pub struct Frames {
+    fp: u64,
+    depth: usize,
+}
+
+/// Whether `fp` may point to a frame record. Records are 16 byte aligned and live in DRAM.
+fn is_valid(fp: u64) -> bool {
+    fp != 0 && fp % 16 == 0 && fp < memory::map::mmio::BASE as u64
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+impl Frames {
+    /// Walk the chain starting at the record `fp` points to.
+    pub fn from_fp(fp: u64) -> Self {
+        Self { fp, depth: 0 }
+    }
+
+    /// Walk the chain of the caller.
+    #[inline(always)]
+    pub fn here() -> Self {
+        let fp: u64;
+        unsafe { llvm_asm!("mov $0, x29" : "=r"(fp) ::: "volatile") };
+
+        Self::from_fp(fp)
+    }
+}
+
+impl Iterator for Frames {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        if self.depth == MAX_FRAMES || !is_valid(self.fp) {
+            return None;
+        }
+
+        let [caller_fp, lr] = unsafe { core::ptr::read_volatile(self.fp as *const [u64; 2]) };
+        // Stacks grow down, so callers' records lie above. Anything else ends the walk.
+        self.fp = if caller_fp > self.fp { caller_fp } else { 0 };
+        self.depth += 1;
+
+        if lr == 0 {
+            return None;
+        }
+        Some(lr)
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Testing
+//--------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_macros::kernel_test;
+
+    /// The walk follows records up the stack and stops at a broken link.
+    #[kernel_test]
+    fn walk_stops_at_broken_chain() {
+        #[repr(align(16))]
+        struct Stack([u64; 6]);
+
+        let mut stack = Stack([0; 6]);
+        let base = stack.0.as_ptr() as u64;
+        // Innermost record at the bottom, pointing to the next one up, which points down again.
+        stack.0[0] = base + 16;
+        stack.0[1] = 0x1000;
+        stack.0[2] = base + 32;
+        stack.0[3] = 0x2000;
+        stack.0[4] = base;
+        stack.0[5] = 0x3000;
+
+        let frames: [Option<u64>; 4] = {
+            let mut frames = Frames::from_fp(base);
+            [frames.next(), frames.next(), frames.next(), frames.next()]
+        };
+        assert_eq!(frames, [Some(0x1000), Some(0x2000), Some(0x3000), None]);
+    }
+}
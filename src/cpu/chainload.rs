@@ -0,0 +1,218 @@
+//! Chain-loading another kernel image.
+//!
+//! The image is copied over the running kernel at `0x80000` and entered on the boot core at EL1,
+//! with the MMU and the caches off. The other cores leave through their next exception: they clean
+//! their caches and wait on the firmware's spin table, so the new kernel wakes them the way the
+//! firmware's cores are woken. The boot core waits a second for them, and resets the board
+//! through the watchdog if one hasn't parked, rather than overwrite the kernel it still runs.
+//!
+//! The park loop and the copy routine can't run from the kernel being overwritten. They are copied
+//! to a page below the boot stacks, next to a flag per core that tells the boot core which cores
+//! have parked.
+
+use super::{core_id, BOOT_CORE_ID, NUM_CORES, SPINNING_BASE};
+use crate::bsp::{self, device_driver::MBox};
+use crate::{exception, gdb, memory, warn, watchdog::interface::Watchdog};
+use alloc::vec::Vec;
+use core::convert::Infallible;
+use core::mem;
+use core::ptr::{self, write_volatile};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+global_asm!(include_str!("chainload.S"));
+
+extern "C" {
+    fn __chainload_park(flag: *mut u64, slot: *mut usize, park_loop: usize) -> !;
+    fn __chainload_boot(
+        flags: *mut u64,
+        copy: usize,
+        dst: usize,
+        src: usize,
+        len: usize,
+        watchdog: usize,
+    ) -> !;
+
+    static __chainload_park_loop_start: u8;
+    static __chainload_park_loop_end: u8;
+    static __chainload_copy_start: u8;
+    static __chainload_copy_end: u8;
+}
+
+/// Where the image is entered.
+const KERNEL_START: usize = 0x80_000;
+
+/// The page the park loop and the copy routine run from, between the ATAGs and the boot stacks.
+const PARK_PAGE: usize = 0x4_0000;
+
+/// Offsets into `PARK_PAGE`.
+const PARK_LOOP_OFFSET: usize = 0;
+const COPY_OFFSET: usize = 0x100;
+const FLAGS_OFFSET: usize = 0x200;
+
+/// The USB HCD, in the firmware's power domains.
+const POWER_DEVICE_USB: u32 = 3;
+
+const CACHE_LINE: usize = 64;
+
+/// Set once the cores are to leave the kernel.
+static REQUEST: AtomicBool = AtomicBool::new(false);
+
+static IMAGE: AtomicUsize = AtomicUsize::new(0);
+static IMAGE_LEN: AtomicUsize = AtomicUsize::new(0);
+
+/// The bytes from `start` to `end`, linker symbols of this module's assembly.
+unsafe fn code(start: &u8, end: &u8) -> &'static [u8] {
+    let start = start as *const u8;
+    core::slice::from_raw_parts(start, end as *const u8 as usize - start as usize)
+}
+
+/// Clean and invalidate the data cache to the point of coherency, so that cores running with their
+/// caches off read what was written.
+unsafe fn clean_to_poc(addr: usize, len: usize) {
+    for line in (addr & !(CACHE_LINE - 1)..addr + len).step_by(CACHE_LINE) {
+        llvm_asm!("dc civac, $0" :: "r"(line) :: "volatile");
+    }
+    llvm_asm!("dsb sy" :::: "volatile");
+}
+
+/// Copy the park loop and the copy routine to `PARK_PAGE` and clear the flags and spin table slots.
+unsafe fn prepare_park_page() {
+    let park_loop = code(&__chainload_park_loop_start, &__chainload_park_loop_end);
+    let copy = code(&__chainload_copy_start, &__chainload_copy_end);
+    ptr::copy_nonoverlapping(
+        park_loop.as_ptr(),
+        (PARK_PAGE + PARK_LOOP_OFFSET) as *mut u8,
+        park_loop.len(),
+    );
+    ptr::copy_nonoverlapping(
+        copy.as_ptr(),
+        (PARK_PAGE + COPY_OFFSET) as *mut u8,
+        copy.len(),
+    );
+    for core in 0..NUM_CORES {
+        write_volatile(flags().add(core), 0);
+        write_volatile(SPINNING_BASE.add(core), 0);
+    }
+
+    clean_to_poc(PARK_PAGE, FLAGS_OFFSET + NUM_CORES * 8);
+    clean_to_poc(SPINNING_BASE as usize, NUM_CORES * 8);
+    llvm_asm!("ic ialluis; dsb sy; isb" :::: "volatile");
+}
+
+fn flags() -> *mut u64 {
+    (PARK_PAGE + FLAGS_OFFSET) as *mut u64
+}
+
+/// Leave the kernel: the boot core copies and enters the image, the others park.
+unsafe fn leave() -> ! {
+    let core = core_id::<usize>();
+
+    if core == BOOT_CORE_ID {
+        __chainload_boot(
+            flags(),
+            PARK_PAGE + COPY_OFFSET,
+            KERNEL_START,
+            IMAGE.load(Ordering::Acquire),
+            IMAGE_LEN.load(Ordering::Acquire),
+            memory::map::mmio::PM_BASE,
+        )
+    } else {
+        __chainload_park(
+            flags().add(core),
+            SPINNING_BASE.add(core),
+            PARK_PAGE + PARK_LOOP_OFFSET,
+        )
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// The CRC-32 of `data`, as computed by zlib.
+pub fn checksum(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+/// Replace the running kernel with `image`, if its CRC-32 is `crc`.
+///
+/// Peripheral interrupts stay off and the USB controller is powered down, so that no DMA writes
+/// into the new kernel. The watchdog stays armed: a kernel that doesn't start resets the board.
+///
+/// Fails while the debugger has a core stopped, as the parked cores can't leave. If a core doesn't
+/// take an exception within a second, such as one spinning with interrupts masked or waiting after
+/// a panic, the image is not copied and the board resets instead.
+pub fn boot(image: Vec<u8>, crc: u32) -> Result<Infallible, &'static str> {
+    if gdb::is_stopped() {
+        return Err("a core is stopped in the debugger");
+    }
+    if image.is_empty() {
+        return Err("empty image");
+    }
+    if checksum(&image) != crc {
+        return Err("checksum mismatch");
+    }
+
+    // The copy routine moves 8 bytes at a time. `tftp` leaves room for that, so the image isn't
+    // reallocated, which could take more heap than is left.
+    let mut image = image;
+    image.resize((image.len() + 7) & !7, 0);
+    let (addr, len) = (image.as_ptr() as usize, image.len());
+    mem::forget(image);
+
+    bsp::watchdog().feed();
+    bsp::exception::asynchronous::disable_peripheral_irqs();
+    if MBox::new()
+        .set_power_state(POWER_DEVICE_USB, false)
+        .is_err()
+    {
+        warn!("chainload: failed to power off USB");
+    }
+
+    unsafe {
+        exception::asynchronous::local_irq_mask();
+        exception::asynchronous::local_fiq_mask();
+        prepare_park_page();
+        clean_to_poc(addr, len);
+    }
+    IMAGE.store(addr, Ordering::Release);
+    IMAGE_LEN.store(len, Ordering::Release);
+    REQUEST.store(true, Ordering::Release);
+
+    for core in (0..NUM_CORES).filter(|&core| core != core_id::<usize>()) {
+        bsp::exception::asynchronous::send_ipi(core);
+    }
+    unsafe { leave() }
+}
+
+/// Leave the kernel if `boot` asked the cores to. Called on exception entry.
+pub fn park_if_requested() {
+    if REQUEST.load(Ordering::Acquire) {
+        unsafe { leave() }
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Testing
+//--------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_macros::kernel_test;
+
+    /// The check value of CRC-32.
+    #[kernel_test]
+    fn checksum_check_value() {
+        assert_eq!(checksum(b"123456789"), 0xcbf4_3926);
+        assert_eq!(checksum(b""), 0);
+    }
+}
@@ -0,0 +1,513 @@
+//! Hardware breakpoints and watchpoints.
+//!
+//! Every core has its own debug registers. Slots set here apply to all cores: the executing core
+//! programs its registers right away, the others on their next exception, which an IPI forces. Hits
+//! are only caught with debug exceptions unmasked, so not in exception handlers.
+//!
+//! A hit calls the slot's handler, or reports the hit with the exception context. To continue, the
+//! core single-steps the instruction with its breakpoints and watchpoints disabled.
+
+use crate::exception::ExceptionContext;
+use crate::{bsp, cpu, exception, runtime_init, symbols, warn};
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+/// Slots the architecture allows at most. The cores have fewer, see `ID_AA64DFR0_EL1`.
+const MAX_SLOTS: usize = 16;
+
+/// Fields of DBGBCR<n>_EL1 and DBGWCR<n>_EL1.
+mod control {
+    pub const ENABLE: u64 = 1;
+    /// Privileged access control: match at EL1, which tasks run at too.
+    pub const EL1: u64 = 0b01 << 1;
+    /// Byte address select, all of the instruction for breakpoints.
+    pub const BAS_SHIFT: u64 = 5;
+    pub const BREAKPOINT_BAS: u64 = 0b1111 << BAS_SHIFT;
+    /// Load/store control of watchpoints.
+    pub const LSC_SHIFT: u64 = 3;
+    /// Watched region size, as a power of two.
+    pub const MASK_SHIFT: u64 = 24;
+}
+
+/// Saved program status bits, also used by the GDB stub.
+pub mod spsr {
+    pub const SS: u64 = 1 << 21;
+    pub const D: u64 = 1 << 9;
+    pub const I: u64 = 1 << 7;
+    pub const F: u64 = 1 << 6;
+    /// Exception level and stack pointer selection.
+    pub const M: u64 = 0b1111;
+    /// EL1 using SP_EL0, the mode of user tasks.
+    pub const EL1T: u64 = 0b0100;
+
+    /// The masks a software step changes.
+    const MASKS: u64 = D | I | F;
+
+    /// Arm a software step of the code returned to with `spsr`. Returns the masks to restore with
+    /// `end_step()`.
+    ///
+    /// Interrupts stay masked, so that the step does not end in a handler.
+    pub fn arm_step(spsr: &mut u64) -> u64 {
+        let saved = *spsr & MASKS;
+        *spsr = (*spsr & !D) | SS | I | F;
+        saved
+    }
+
+    /// Restore the masks `arm_step()` returned, once the step is done.
+    pub fn end_step(spsr: &mut u64, saved: u64) {
+        *spsr = (*spsr & !(SS | MASKS)) | saved;
+    }
+}
+
+/// Write `$value` to the debug register `$name<$n>_EL1`. The index is part of the instruction.
+macro_rules! write_debug_register {
+    ($name:literal, $n:expr, $value:expr) => {
+        write_debug_register!($name, $n, $value, [0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15])
+    };
+    ($name:literal, $n:expr, $value:expr, [$($i:literal)*]) => {
+        match $n {
+            $($i => llvm_asm!(concat!("msr ", $name, $i, "_EL1, $0") :: "r"($value) :: "volatile"),)*
+            _ => (),
+        }
+    };
+}
+
+#[derive(Copy, Clone)]
+struct Slot {
+    /// The value register.
+    value: u64,
+    /// The control register, enabled.
+    control: u64,
+    /// The addresses caught, for telling hits apart.
+    start: u64,
+    end: u64,
+    handler: Option<Handler>,
+}
+
+struct Slots {
+    breakpoints: [Option<Slot>; MAX_SLOTS],
+    watchpoints: [Option<Slot>; MAX_SLOTS],
+}
+
+static SLOTS: Mutex<Slots> = Mutex::new(Slots {
+    breakpoints: [None; MAX_SLOTS],
+    watchpoints: [None; MAX_SLOTS],
+});
+
+/// Bumped on every change to `SLOTS`.
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+/// The generation each core's registers hold.
+static APPLIED: [AtomicUsize; cpu::NUM_CORES] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+
+/// The D, I and F bits of cores stepping over a hit, restored when the step completes.
+static STEPPING: [Mutex<Option<u64>>; cpu::NUM_CORES] = [
+    Mutex::new(None),
+    Mutex::new(None),
+    Mutex::new(None),
+    Mutex::new(None),
+];
+
+fn num_breakpoints() -> usize {
+    let dfr0 = unsafe { runtime_init::ID_AA64DFR0_EL1.get() };
+
+    (((dfr0 & runtime_init::ID_AA64DFR0_EL1::BRPS) >> 12) as usize + 1).min(MAX_SLOTS)
+}
+
+fn num_watchpoints() -> usize {
+    let dfr0 = unsafe { runtime_init::ID_AA64DFR0_EL1.get() };
+
+    (((dfr0 & runtime_init::ID_AA64DFR0_EL1::WRPS) >> 20) as usize + 1).min(MAX_SLOTS)
+}
+
+/// The value register, byte address select and mask catching exactly `[addr, addr + len)`.
+///
+/// The hardware watches bytes within a doubleword, or naturally aligned power of two regions.
+fn watch_range(addr: u64, len: u64) -> Result<(u64, u64, u64), &'static str> {
+    let end = addr.checked_add(len).ok_or("watched range out of bounds")?;
+    let doubleword = addr & !7;
+
+    if len == 0 {
+        Err("watched range is empty")
+    } else if end - doubleword <= 8 {
+        Ok((doubleword, ((1 << len) - 1) << (addr - doubleword), 0))
+    } else if len.is_power_of_two() && addr % len == 0 && len <= 1 << 31 {
+        Ok((addr, 0xff, u64::from(len.trailing_zeros())))
+    } else {
+        Err("watched ranges must lie within a doubleword or be aligned powers of two")
+    }
+}
+
+impl Slots {
+    fn is_armed(&self) -> bool {
+        self.breakpoints
+            .iter()
+            .chain(self.watchpoints.iter())
+            .any(Option::is_some)
+    }
+
+    /// Program the executing core's registers.
+    unsafe fn program(&self) {
+        for n in 0..num_breakpoints() {
+            let (value, control) = self.breakpoints[n].map_or((0, 0), |s| (s.value, s.control));
+            write_debug_register!("DBGBVR", n, value);
+            write_debug_register!("DBGBCR", n, control);
+        }
+        for n in 0..num_watchpoints() {
+            let (value, control) = self.watchpoints[n].map_or((0, 0), |s| (s.value, s.control));
+            write_debug_register!("DBGWVR", n, value);
+            write_debug_register!("DBGWCR", n, control);
+        }
+
+        if self.is_armed() {
+            runtime_init::OSLAR_EL1.set(0);
+            let mdscr = runtime_init::MDSCR_EL1.get();
+            runtime_init::MDSCR_EL1
+                .set(mdscr | runtime_init::MDSCR_EL1::MDE | runtime_init::MDSCR_EL1::KDE);
+        }
+        llvm_asm!("isb" :::: "volatile");
+    }
+
+    /// The breakpoint or watchpoint slot catching `addr`, and its handler.
+    fn find(&self, watchpoint: bool, addr: u64) -> Option<(usize, Option<Handler>)> {
+        let slots = if watchpoint {
+            &self.watchpoints
+        } else {
+            &self.breakpoints
+        };
+
+        slots.iter().enumerate().find_map(|(n, slot)| match slot {
+            Some(slot) if (slot.start..slot.end).contains(&addr) => Some((n, slot.handler)),
+            _ => None,
+        })
+    }
+}
+
+/// Disable the executing core's breakpoints and watchpoints, until the next `sync()`.
+unsafe fn disable_all() {
+    for n in 0..num_breakpoints() {
+        write_debug_register!("DBGBCR", n, 0u64);
+    }
+    for n in 0..num_watchpoints() {
+        write_debug_register!("DBGWCR", n, 0u64);
+    }
+    APPLIED[cpu::core_id::<usize>()].store(
+        GENERATION.load(Ordering::Acquire).wrapping_sub(1),
+        Ordering::Relaxed,
+    );
+}
+
+/// Change the slots, and apply the change to all cores.
+fn update<T>(f: impl FnOnce(&mut Slots) -> Result<T, &'static str>) -> Result<T, &'static str> {
+    let core = cpu::core_id::<usize>();
+    let mut slots = SLOTS.lock();
+    let result = f(&mut slots)?;
+
+    let generation = GENERATION.fetch_add(1, Ordering::AcqRel) + 1;
+    if STEPPING[core].lock().is_none() {
+        unsafe { slots.program() };
+        APPLIED[core].store(generation, Ordering::Relaxed);
+    }
+    drop(slots);
+
+    for other in (0..cpu::NUM_CORES).filter(|&other| other != core) {
+        bsp::exception::asynchronous::send_ipi(other);
+    }
+    Ok(result)
+}
+
+/// Step over the instruction that hit, with the executing core's slots disabled.
+fn step_over(e: &mut ExceptionContext) {
+    *STEPPING[cpu::core_id::<usize>()].lock() = Some(spsr::arm_step(&mut e.spsr));
+
+    unsafe {
+        disable_all();
+        let mdscr = runtime_init::MDSCR_EL1.get();
+        runtime_init::MDSCR_EL1.set(mdscr | runtime_init::MDSCR_EL1::SS);
+        llvm_asm!("isb" :::: "volatile");
+    }
+}
+
+fn handle_hit(e: &mut ExceptionContext, hit: Hit, handler: Option<Handler>) {
+    let action = match handler {
+        Some(handler) => handler(&hit, e),
+        None => {
+            warn!("{}\n{}", hit, e);
+            Action::Continue
+        }
+    };
+
+    match (action, hit) {
+        (Action::Continue, _) => (),
+        (Action::Remove, Hit::Breakpoint { slot: Some(n), .. }) => {
+            if clear_breakpoint(n).is_err() {
+                warn!("debug: failed to remove breakpoint {}", n);
+            }
+        }
+        (Action::Remove, Hit::Watchpoint { slot: Some(n), .. }) => {
+            if clear_watchpoint(n).is_err() {
+                warn!("debug: failed to remove watchpoint {}", n);
+            }
+        }
+        (Action::Remove, _) => warn!("debug: the hit slot is unknown, not removing it"),
+        (Action::Panic, _) => panic!("{}", hit),
+    }
+    step_over(e);
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// Accesses caught by a watchpoint.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+/// What to do after a hit.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Action {
+    /// Step over the instruction and carry on.
+    Continue,
+    /// Clear the slot and carry on.
+    Remove,
+    /// Panic, printing the hit and the exception context.
+    Panic,
+}
+
+/// A breakpoint or watchpoint hit. The slot is unknown if the slots were being changed.
+#[derive(Copy, Clone, Debug)]
+pub enum Hit {
+    Breakpoint {
+        slot: Option<usize>,
+        pc: u64,
+    },
+    Watchpoint {
+        slot: Option<usize>,
+        /// The address accessed.
+        addr: u64,
+        write: bool,
+        pc: u64,
+    },
+}
+
+impl fmt::Display for Hit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (kind, slot) = match self {
+            Hit::Breakpoint { slot, .. } => ("Breakpoint", slot),
+            Hit::Watchpoint { slot, .. } => ("Watchpoint", slot),
+        };
+        write!(f, "{} ", kind)?;
+        match slot {
+            Some(n) => write!(f, "{}", n)?,
+            None => write!(f, "?")?,
+        }
+
+        match self {
+            Hit::Breakpoint { pc, .. } => write!(f, " hit at {}", symbols::Address(*pc)),
+            Hit::Watchpoint {
+                addr, write, pc, ..
+            } => write!(
+                f,
+                " hit: {} {:#x} at {}",
+                if *write { "write to" } else { "read from" },
+                addr,
+                symbols::Address(*pc)
+            ),
+        }
+    }
+}
+
+/// Called on hits, with the context of the instruction that hit.
+pub type Handler = fn(&Hit, &ExceptionContext) -> Action;
+
+/// Break before executing the instruction at `addr`. Returns the slot.
+///
+/// Hits are passed to `handler`, or reported if there is none.
+pub fn set_breakpoint(addr: u64, handler: Option<Handler>) -> Result<usize, &'static str> {
+    if addr % 4 != 0 {
+        return Err("instructions are 4 byte aligned");
+    }
+
+    unsafe { exception::asynchronous::local_debug_unmask() };
+    update(|slots| {
+        let n = slots.breakpoints[..num_breakpoints()]
+            .iter()
+            .position(Option::is_none)
+            .ok_or("no free breakpoint")?;
+        slots.breakpoints[n] = Some(Slot {
+            value: addr,
+            control: control::ENABLE | control::EL1 | control::BREAKPOINT_BAS,
+            start: addr,
+            end: addr + 4,
+            handler,
+        });
+
+        Ok(n)
+    })
+}
+
+/// Catch `access`es to `[addr, addr + len)`, before they happen. Returns the slot.
+///
+/// The range must lie within an 8 byte aligned doubleword, or be a naturally aligned power of two.
+/// Hits are passed to `handler`, or reported if there is none.
+pub fn set_watchpoint(
+    addr: u64,
+    len: u64,
+    access: Access,
+    handler: Option<Handler>,
+) -> Result<usize, &'static str> {
+    let (value, bas, mask) = watch_range(addr, len)?;
+    let lsc = match access {
+        Access::Read => 0b01,
+        Access::Write => 0b10,
+        Access::ReadWrite => 0b11,
+    };
+
+    unsafe { exception::asynchronous::local_debug_unmask() };
+    update(|slots| {
+        let n = slots.watchpoints[..num_watchpoints()]
+            .iter()
+            .position(Option::is_none)
+            .ok_or("no free watchpoint")?;
+        slots.watchpoints[n] = Some(Slot {
+            value,
+            control: control::ENABLE
+                | control::EL1
+                | lsc << control::LSC_SHIFT
+                | bas << control::BAS_SHIFT
+                | mask << control::MASK_SHIFT,
+            start: addr,
+            end: addr + len,
+            handler,
+        });
+
+        Ok(n)
+    })
+}
+
+/// Free a breakpoint slot.
+pub fn clear_breakpoint(n: usize) -> Result<(), &'static str> {
+    update(|slots| {
+        slots
+            .breakpoints
+            .get_mut(n)
+            .and_then(Option::take)
+            .map(|_| ())
+            .ok_or("no such breakpoint")
+    })
+}
+
+/// Free a watchpoint slot.
+pub fn clear_watchpoint(n: usize) -> Result<(), &'static str> {
+    update(|slots| {
+        slots
+            .watchpoints
+            .get_mut(n)
+            .and_then(Option::take)
+            .map(|_| ())
+            .ok_or("no such watchpoint")
+    })
+}
+
+/// Called on exception entry: bring the executing core's registers up to date.
+///
+/// Unmasks debug exceptions in the interrupted context while slots are set.
+pub fn sync(e: &mut ExceptionContext) {
+    let core = cpu::core_id::<usize>();
+    let generation = GENERATION.load(Ordering::Acquire);
+
+    if APPLIED[core].load(Ordering::Relaxed) == generation || STEPPING[core].lock().is_some() {
+        return;
+    }
+    // Being changed, maybe by the interrupted code. The next exception picks the change up.
+    let slots = match SLOTS.try_lock() {
+        Some(slots) => slots,
+        None => return,
+    };
+
+    unsafe { slots.program() };
+    APPLIED[core].store(generation, Ordering::Relaxed);
+    if slots.is_armed() {
+        e.spsr &= !spsr::D;
+    }
+}
+
+/// Handle a breakpoint exception, taken before executing `e.elr`.
+pub fn breakpoint_hit(e: &mut ExceptionContext) {
+    let pc = e.elr;
+    let (slot, handler) = match SLOTS.try_lock().and_then(|slots| slots.find(false, pc)) {
+        Some((n, handler)) => (Some(n), handler),
+        None => (None, None),
+    };
+
+    handle_hit(e, Hit::Breakpoint { slot, pc }, handler);
+}
+
+/// Handle a watchpoint exception, taken before the access of `e.elr` to `addr`.
+pub fn watchpoint_hit(e: &mut ExceptionContext, addr: u64, write: bool) {
+    let pc = e.elr;
+    let (slot, handler) = match SLOTS.try_lock().and_then(|slots| slots.find(true, addr)) {
+        Some((n, handler)) => (Some(n), handler),
+        None => (None, None),
+    };
+
+    handle_hit(
+        e,
+        Hit::Watchpoint {
+            slot,
+            addr,
+            write,
+            pc,
+        },
+        handler,
+    );
+}
+
+/// Handle a software step exception. Returns `false` if the step was not over a hit.
+pub fn step_done(e: &mut ExceptionContext) -> bool {
+    let core = cpu::core_id::<usize>();
+    let bits = match STEPPING[core].lock().take() {
+        Some(bits) => bits,
+        None => return false,
+    };
+
+    spsr::end_step(&mut e.spsr, bits);
+    unsafe {
+        let mdscr = runtime_init::MDSCR_EL1.get();
+        runtime_init::MDSCR_EL1.set(mdscr & !runtime_init::MDSCR_EL1::SS);
+    }
+    sync(e);
+
+    true
+}
+
+//--------------------------------------------------------------------------------------------------
+// Testing
+//--------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_macros::kernel_test;
+
+    /// Ranges are watched exactly, or refused.
+    #[kernel_test]
+    fn watch_ranges_are_exact() {
+        assert_eq!(watch_range(0x1000, 8), Ok((0x1000, 0xff, 0)));
+        assert_eq!(watch_range(0x1003, 4), Ok((0x1000, 0b0111_1000, 0)));
+        assert_eq!(watch_range(0x1000, 0x100), Ok((0x1000, 0xff, 8)));
+        assert!(watch_range(0x1004, 8).is_err());
+        assert!(watch_range(0x1008, 0x100).is_err());
+        assert!(watch_range(0x1000, 0).is_err());
+    }
+}
@@ -10,8 +10,10 @@ pub enum PrivilegeLevel {
     Unknown,
 }
 
-use crate::{bsp, exception, syscall};
+use crate::{bsp, cpu, exception, gdb, panic_wait, sched, symbols, syscall};
 use core::fmt;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
 use cortex_a::regs::*;
 
 // Assembly counterpart to this file.
@@ -39,16 +41,83 @@ pub struct ExceptionContext {
 /// Wrapper struct for pretty printing ESR_EL1.
 struct EsrEL1;
 
+/// The context of the exception each core is handling, for the panic handler.
+static CONTEXTS: [AtomicPtr<ExceptionContext>; cpu::NUM_CORES] = [
+    AtomicPtr::new(ptr::null_mut()),
+    AtomicPtr::new(ptr::null_mut()),
+    AtomicPtr::new(ptr::null_mut()),
+    AtomicPtr::new(ptr::null_mut()),
+];
+
+/// Publishes the context of the exception being handled until dropped.
+struct ContextGuard {
+    previous: *mut ExceptionContext,
+}
+
+impl ContextGuard {
+    /// Called first thing by the handlers. Parks the core instead if another one panicked or a
+    /// new kernel is being chain-loaded, or until the debugger resumes the kernel if another one
+    /// is stopped in it.
+    fn enter(e: &mut ExceptionContext) -> Self {
+        if panic_wait::is_panicking() {
+            cpu::wait_forever();
+        }
+        cpu::chainload::park_if_requested();
+        gdb::park_if_stopped(e);
+        cpu::debug::sync(e);
+        let previous = CONTEXTS[cpu::core_id::<usize>()].swap(e, Ordering::Relaxed);
+
+        Self { previous }
+    }
+}
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        CONTEXTS[cpu::core_id::<usize>()].store(self.previous, Ordering::Relaxed);
+    }
+}
+
+/// Exception classes of debug exceptions, from ESR_EL1.
+const EC_BREAKPOINT: u64 = 0x31;
+const EC_SOFTWARE_STEP: u64 = 0x33;
+const EC_WATCHPOINT: u64 = 0x35;
+const EC_BRK64: u64 = 0x3c;
+
+/// Write not Read, in the syndrome of watchpoint exceptions.
+const ISS_WNR: u64 = 1 << 6;
+
+/// Hand debug exceptions to the hardware breakpoint code or the GDB stub. Returns `false` for other
+/// exceptions, or if nothing handled them.
+fn handle_debug_exception(e: &mut ExceptionContext) -> bool {
+    let esr = ESR_EL1.extract();
+    match esr.read(ESR_EL1::EC) {
+        EC_BREAKPOINT => cpu::debug::breakpoint_hit(e),
+        // The faulting address is in FAR_EL1.
+        EC_WATCHPOINT => {
+            cpu::debug::watchpoint_hit(e, FAR_EL1.get(), esr.read(ESR_EL1::ISS) & ISS_WNR != 0)
+        }
+        EC_SOFTWARE_STEP => {
+            return cpu::debug::step_done(e) || gdb::handle_exception(e, gdb::Stop::Step)
+        }
+        EC_BRK64 => {
+            return gdb::handle_exception(e, gdb::Stop::Breakpoint(esr.read(ESR_EL1::ISS) as u16))
+        }
+        _ => return false,
+    }
+
+    true
+}
+
 /// Print verbose information about the exception and the panic.
-fn default_exception_handler(e: &ExceptionContext) {
+///
+/// The panic handler prints the exception context.
+fn default_exception_handler(_e: &ExceptionContext) {
     panic!(
         "\n\nCPU Exception!\n\
          FAR_EL1: {:#018x}\n\
-         {}\n\
          {}",
         FAR_EL1.get(),
         EsrEL1 {},
-        e
     );
 }
 
@@ -58,11 +127,15 @@ fn default_exception_handler(e: &ExceptionContext) {
 
 #[no_mangle]
 unsafe extern "C" fn current_el0_synchronous(e: &mut ExceptionContext) {
+    let _context = ContextGuard::enter(e);
     // crate::info!(
     //     "Exception current_el0_synchronous for proc {:?}, core {}",
     //     e.tpidr,
     //     crate::cpu::core_id::<usize>()
     // );
+    if handle_debug_exception(e) {
+        return;
+    }
     match syscall::handle(e) {
         Ok(()) => {}
         Err(_) => default_exception_handler(e),
@@ -71,14 +144,17 @@ unsafe extern "C" fn current_el0_synchronous(e: &mut ExceptionContext) {
 
 #[no_mangle]
 unsafe extern "C" fn current_el0_irq(e: &mut ExceptionContext) {
+    let _context = ContextGuard::enter(e);
     //info!("Exception current_el0_irq for proc {:?}", e.tpidr);
     use exception::asynchronous::interface::IRQManager;
     let token = &exception::asynchronous::IRQContext::new();
     bsp::exception::asynchronous::irq_manager().handle_pending_irqs(token, e);
+    sched::switch_to_woken(e);
 }
 
 #[no_mangle]
 unsafe extern "C" fn current_el0_fiq(e: &mut ExceptionContext) {
+    let _context = ContextGuard::enter(e);
     //crate::info!("Exception current_el0_fiq for proc {:?}", e.tpidr);
     use exception::asynchronous::interface::IRQManager;
     let token = &exception::asynchronous::IRQContext::new();
@@ -87,7 +163,8 @@ unsafe extern "C" fn current_el0_fiq(e: &mut ExceptionContext) {
 
 #[no_mangle]
 unsafe extern "C" fn current_el0_serror(e: &mut ExceptionContext) {
-    crate::info!(
+    let _context = ContextGuard::enter(e);
+    crate::error!(
         "Exception current_el0_serror for proc {:?}, core {}",
         e.tpidr,
         crate::cpu::core_id::<usize>()
@@ -101,23 +178,30 @@ unsafe extern "C" fn current_el0_serror(e: &mut ExceptionContext) {
 
 #[no_mangle]
 unsafe extern "C" fn current_elx_synchronous(e: &mut ExceptionContext) {
+    let _context = ContextGuard::enter(e);
     // crate::info!(
     //     "Exception current_elx_synchronous for proc {:?}, core {}",
     //     e.tpidr,
     //     crate::cpu::core_id::<usize>()
     // );
+    if handle_debug_exception(e) {
+        return;
+    }
     default_exception_handler(e);
 }
 
 #[no_mangle]
 unsafe extern "C" fn current_elx_irq(e: &mut ExceptionContext) {
+    let _context = ContextGuard::enter(e);
     use exception::asynchronous::interface::IRQManager;
     let token = &exception::asynchronous::IRQContext::new();
     bsp::exception::asynchronous::irq_manager().handle_pending_irqs(token, e);
+    sched::switch_to_woken(e);
 }
 
 #[no_mangle]
 unsafe extern "C" fn current_elx_fiq(e: &mut ExceptionContext) {
+    let _context = ContextGuard::enter(e);
     //crate::info!("Exception current_elx_fiq for proc {:?}", e.tpidr);
     use exception::asynchronous::interface::IRQManager;
     bsp::exception::asynchronous::irq_manager().handle_fiq(e);
@@ -125,7 +209,8 @@ unsafe extern "C" fn current_elx_fiq(e: &mut ExceptionContext) {
 
 #[no_mangle]
 unsafe extern "C" fn current_elx_serror(e: &mut ExceptionContext) {
-    crate::info!(
+    let _context = ContextGuard::enter(e);
+    crate::error!(
         "Exception current_elx_serror for proc {:?}, core {}",
         e.tpidr,
         crate::cpu::core_id::<usize>()
@@ -139,21 +224,25 @@ unsafe extern "C" fn current_elx_serror(e: &mut ExceptionContext) {
 
 #[no_mangle]
 unsafe extern "C" fn lower_aarch64_synchronous(e: &mut ExceptionContext) {
+    let _context = ContextGuard::enter(e);
     default_exception_handler(e);
 }
 
 #[no_mangle]
 unsafe extern "C" fn lower_aarch64_irq(e: &mut ExceptionContext) {
+    let _context = ContextGuard::enter(e);
     default_exception_handler(e);
 }
 
 #[no_mangle]
 unsafe extern "C" fn lower_aarch64_fiq(e: &mut ExceptionContext) {
+    let _context = ContextGuard::enter(e);
     default_exception_handler(e);
 }
 
 #[no_mangle]
 unsafe extern "C" fn lower_aarch64_serror(e: &mut ExceptionContext) {
+    let _context = ContextGuard::enter(e);
     default_exception_handler(e);
 }
 
@@ -163,21 +252,25 @@ unsafe extern "C" fn lower_aarch64_serror(e: &mut ExceptionContext) {
 
 #[no_mangle]
 unsafe extern "C" fn lower_aarch32_synchronous(e: &mut ExceptionContext) {
+    let _context = ContextGuard::enter(e);
     default_exception_handler(e);
 }
 
 #[no_mangle]
 unsafe extern "C" fn lower_aarch32_irq(e: &mut ExceptionContext) {
+    let _context = ContextGuard::enter(e);
     default_exception_handler(e);
 }
 
 #[no_mangle]
 unsafe extern "C" fn lower_aarch32_fiq(e: &mut ExceptionContext) {
+    let _context = ContextGuard::enter(e);
     default_exception_handler(e);
 }
 
 #[no_mangle]
 unsafe extern "C" fn lower_aarch32_serror(e: &mut ExceptionContext) {
+    let _context = ContextGuard::enter(e);
     default_exception_handler(e);
 }
 
@@ -214,7 +307,7 @@ impl fmt::Display for EsrEL1 {
 /// Human readable print of the exception context.
 impl fmt::Display for ExceptionContext {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        writeln!(f, "ELR: {:#018x}", self.elr)?;
+        writeln!(f, "ELR: {}", symbols::Address(self.elr))?;
         writeln!(f, "{}", self.spsr)?;
         writeln!(f)?;
         writeln!(f, "General purpose register:")?;
@@ -228,12 +321,23 @@ impl fmt::Display for ExceptionContext {
         for (i, reg) in self.gpr.iter().enumerate() {
             write!(f, "      x{: <2}: {: >#018x}{}", i, reg, alternating(i))?;
         }
-        write!(f, "      lr : {:#018x}", self.lr)?;
+        write!(f, "      lr : {}", symbols::Address(self.lr))?;
 
         Ok(())
     }
 }
 
+/// The context of the exception being handled on the executing core, if any.
+///
+/// # Safety
+///
+/// - Only for the panic handler. The context is changed and goes away when the handler returns.
+pub unsafe fn current_context() -> Option<&'static ExceptionContext> {
+    CONTEXTS[cpu::core_id::<usize>()]
+        .load(Ordering::Relaxed)
+        .as_ref()
+}
+
 /// The processing element's current privilege level.
 pub fn current_privilege_level() -> (PrivilegeLevel, &'static str) {
     let el = CurrentEL.read_as_enum(CurrentEL::EL);
@@ -4,6 +4,7 @@ use cortex_a::regs::*;
 
 /// Asynchronous exception handling interfaces.
 pub mod interface {
+    use alloc::vec::Vec;
 
     /// Implemented by types that handle IRQs.
     pub trait IRQHandler {
@@ -36,6 +37,23 @@ pub mod interface {
         fn register_fiq(&self, descriptor: super::IRQDescriptor);
 
         fn handle_fiq(&self, _e: &mut super::ExceptionContext);
+
+        /// Route an interrupt to the given core.
+        ///
+        /// Controllers that can not steer the interrupt return an error.
+        fn set_affinity(
+            &self,
+            _irq_number: Self::IRQNumberType,
+            _core: usize,
+        ) -> Result<(), &'static str> {
+            Err("IRQ affinity not supported")
+        }
+
+        /// Return the core an interrupt is currently routed to, if the controller knows.
+        fn affinity(&self, _irq_number: Self::IRQNumberType) -> Option<usize> {
+            None
+        }
+
         /// Handle pending interrupts.
         ///
         /// This function is called directly from the CPU's IRQ exception vector. On AArch64,
@@ -52,6 +70,11 @@ pub mod interface {
 
         /// Print list of registered handlers.
         fn print_handler(&self);
+
+        /// How often each registered handler was called.
+        fn statistics(&self) -> Vec<super::IRQStatistic> {
+            Vec::new()
+        }
     }
 }
 
@@ -65,6 +88,24 @@ pub struct IRQDescriptor {
     pub handler: &'static (dyn interface::IRQHandler + Sync),
 }
 
+/// Call count of a registered interrupt handler.
+#[derive(Copy, Clone)]
+pub struct IRQStatistic {
+    /// Descriptive name of the handler.
+    pub name: &'static str,
+
+    /// The interrupt number within its controller.
+    pub number: usize,
+
+    /// The core of a core-local interrupt.
+    pub core: Option<usize>,
+
+    /// Whether the interrupt is raised as FIQ.
+    pub fiq: bool,
+
+    pub count: u64,
+}
+
 /// IRQContext token.
 ///
 /// An instance of this type indicates that the local core is currently executing in IRQ
@@ -135,7 +176,25 @@ pub fn exec_with_irq_masked<T>(f: impl FnOnce() -> T) -> T {
     ret
 }
 
+/// Executes the provided closure while IRQs and FIQs are masked on the executing core.
+///
+/// For code that must not be reentered from any interrupt handler, the FIQ included.
+#[inline(always)]
+pub fn exec_with_interrupts_masked<T>(f: impl FnOnce() -> T) -> T {
+    let ret: T;
+
+    unsafe {
+        let saved = local_irq_mask_save();
+        local_fiq_mask();
+        ret = f();
+        local_irq_restore(saved);
+    }
+
+    ret
+}
+
 mod daif_bits {
+    pub const DEBUG: u8 = 0b1000;
     pub const IRQ: u8 = 0b0010;
     pub const FIQ: u8 = 0b0001;
 }
@@ -238,6 +297,21 @@ pub unsafe fn local_fiq_mask() {
     );
 }
 
+/// Unmask debug exceptions on the executing core, for hardware breakpoints and watchpoints.
+///
+/// # Safety
+///
+/// - Changes the HW state of the executing core.
+#[inline(always)]
+pub unsafe fn local_debug_unmask() {
+    #[rustfmt::skip]
+    asm!(
+        "msr DAIFClr, {arg}",
+        arg = const daif_bits::DEBUG,
+        options(nomem, nostack, preserves_flags)
+    );
+}
+
 /// Mask IRQs on the executing core and return the previously saved interrupt mask bits (DAIF).
 ///
 /// # Safety
@@ -0,0 +1,618 @@
+//! GDB remote serial protocol stub.
+//!
+//! The stub talks to GDB over the mini UART, enabled with the `kgdb=ttyS0` command line option.
+//! With `kgdbwait` the kernel stops in the debugger during boot, so that breakpoints can be set
+//! before anything runs. Under QEMU, the mini UART is the second serial port:
+//!
+//! ```text
+//! qemu-system-aarch64 -M raspi3 -serial stdio -serial tcp::3333,server,nowait ...
+//! gdb-multiarch -ex 'target remote :3333' target/aarch64-unknown-none-softfloat/release/kernel
+//! ```
+//!
+//! Breakpoints are `BRK` instructions patched into the code and single steps use the software step
+//! of `MDSCR_EL1`. When one core stops, the others are stopped with an IPI and show up as threads,
+//! thread `n + 1` being core `n`. Cores with interrupts masked keep running. GDB can't interrupt
+//! the kernel, it only gets control on breakpoints.
+
+mod packet;
+
+use crate::cpu::debug::spsr;
+use crate::exception::ExceptionContext;
+use crate::{bsp, cpu, info, memory, runtime_init, warn};
+use core::fmt::Write;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use packet::{Connection, Reply, MAX_PACKET};
+use spin::Mutex;
+
+/// Immediate of the `BRK` in `breakpoint()`, resumed after rather than re-executed.
+const COMPILED_BREAK: u16 = 0x4b;
+
+/// `BRK #0`, for breakpoints set by GDB.
+const BRK_INSTRUCTION: u32 = 0xd420_0000;
+
+const MAX_BREAKPOINTS: usize = 32;
+
+/// `SIGTRAP`, the signal reported for every stop.
+const SIGTRAP: u8 = 5;
+
+/// Bytes pushed by the exception entry code, see `exception.S`.
+const CONTEXT_FRAME: u64 = 16 * 18;
+
+/// Registers in the order of GDB's AArch64 description: x0 to x30, sp, pc and the 32 bit cpsr.
+const NUM_REGS: usize = 34;
+const SP: usize = 31;
+const PC: usize = 32;
+const CPSR: usize = 33;
+
+/// Why a core entered the stub.
+#[derive(Copy, Clone, PartialEq)]
+pub enum Stop {
+    /// A `BRK` instruction, with its immediate.
+    Breakpoint(u16),
+    /// A single step completed.
+    Step,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Resume {
+    Continue,
+    Step,
+}
+
+enum Outcome {
+    Reply,
+    Resume(Resume),
+    /// Reply, then continue.
+    Detach,
+}
+
+#[derive(Copy, Clone)]
+struct Breakpoint {
+    addr: u64,
+    instruction: u32,
+}
+
+struct State {
+    connection: Option<Connection>,
+    /// Whether GDB sent anything since the last detach, and so expects stop replies.
+    attached: bool,
+    breakpoints: [Option<Breakpoint>; MAX_BREAKPOINTS],
+    /// The core that entered the stub.
+    current: usize,
+    /// The core register accesses go to.
+    selected: usize,
+    /// The D, I and F bits of cores being single-stepped, restored when the step completes.
+    stepping: [Option<u64>; cpu::NUM_CORES],
+}
+
+struct Stub {
+    input: [u8; MAX_PACKET],
+    reply: Reply,
+    state: State,
+}
+
+/// Held by the core in the stub.
+static STUB: Mutex<Stub> = Mutex::new(Stub {
+    input: [0; MAX_PACKET],
+    reply: Reply::new(),
+    state: State {
+        connection: None,
+        attached: false,
+        breakpoints: [None; MAX_BREAKPOINTS],
+        current: 0,
+        selected: 0,
+        stepping: [None; cpu::NUM_CORES],
+    },
+});
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// The core in the stub, or `NO_CORE` while the kernel runs.
+static STOPPED_BY: AtomicUsize = AtomicUsize::new(NO_CORE);
+const NO_CORE: usize = usize::MAX;
+
+/// Contexts of the cores parked while another one is in the stub.
+static PARKED: [AtomicPtr<ExceptionContext>; cpu::NUM_CORES] = [
+    AtomicPtr::new(ptr::null_mut()),
+    AtomicPtr::new(ptr::null_mut()),
+    AtomicPtr::new(ptr::null_mut()),
+    AtomicPtr::new(ptr::null_mut()),
+];
+
+/// Whether `[addr, addr + len)` is DRAM, which is safe to access. Device memory is not.
+fn accessible(addr: u64, len: u64) -> bool {
+    match addr.checked_add(len) {
+        Some(end) => end <= memory::map::mmio::BASE as u64,
+        None => false,
+    }
+}
+
+/// Park the executing core while another one is in the stub.
+fn park(e: &mut ExceptionContext) {
+    let core = cpu::core_id::<usize>();
+
+    PARKED[core].store(e, Ordering::Release);
+    while STOPPED_BY.load(Ordering::Acquire) != NO_CORE {
+        cpu::nop();
+    }
+    PARKED[core].store(ptr::null_mut(), Ordering::Release);
+}
+
+/// The stack pointer at the time of the exception.
+fn stack_pointer(e: &ExceptionContext) -> u64 {
+    if e.spsr & spsr::M == spsr::EL1T {
+        e.sp
+    } else {
+        // The entry code pushed the context onto SP_EL1.
+        e as *const _ as u64 + CONTEXT_FRAME
+    }
+}
+
+fn read_register(e: &ExceptionContext, reg: usize) -> Option<u64> {
+    match reg {
+        0..=29 => Some(e.gpr[reg]),
+        30 => Some(e.lr),
+        SP => Some(stack_pointer(e)),
+        PC => Some(e.elr),
+        CPSR => Some(e.spsr),
+        _ => None,
+    }
+}
+
+fn write_register(e: &mut ExceptionContext, reg: usize, value: u64) -> Result<(), ()> {
+    match reg {
+        0..=29 => e.gpr[reg] = value,
+        30 => e.lr = value,
+        SP if e.spsr & spsr::M == spsr::EL1T => e.sp = value,
+        PC => e.elr = value,
+        CPSR => e.spsr = value,
+        _ => return Err(()),
+    }
+    Ok(())
+}
+
+/// The size of `reg` in the register packets.
+fn register_size(reg: usize) -> usize {
+    if reg == CPSR {
+        4
+    } else {
+        8
+    }
+}
+
+impl State {
+    /// The context of `core`, if it is stopped.
+    fn context<'a>(
+        &self,
+        e: &'a mut ExceptionContext,
+        core: usize,
+    ) -> Option<&'a mut ExceptionContext> {
+        if core == self.current {
+            return Some(e);
+        }
+        unsafe { PARKED.get(core)?.load(Ordering::Acquire).as_mut() }
+    }
+
+    fn stopped_cores(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..cpu::NUM_CORES).filter(move |&core| {
+            core == self.current || !PARKED[core].load(Ordering::Acquire).is_null()
+        })
+    }
+
+    /// Parse a thread id into a core. `0` and `-1` mean any thread.
+    fn thread(&self, id: &[u8]) -> Option<usize> {
+        match id {
+            b"0" | b"-1" => Some(self.current),
+            _ => (packet::parse_hex(id)? as usize).checked_sub(1),
+        }
+    }
+
+    fn stop_reply(&self, reply: &mut Reply) {
+        let _ = write!(reply, "T{:02x}thread:{:x};", SIGTRAP, self.current + 1);
+    }
+
+    fn read_registers(&self, e: &mut ExceptionContext, reply: &mut Reply) {
+        let e = match self.context(e, self.selected) {
+            Some(e) => e,
+            None => return reply.push(b"E01"),
+        };
+
+        for reg in 0..NUM_REGS {
+            let value = read_register(e, reg).unwrap_or(0);
+            reply.push_hex(&value.to_le_bytes()[..register_size(reg)]);
+        }
+    }
+
+    fn write_registers(&self, e: &mut ExceptionContext, args: &[u8], reply: &mut Reply) {
+        let e = match self.context(e, self.selected) {
+            Some(e) => e,
+            None => return reply.push(b"E01"),
+        };
+
+        let mut digits = args;
+        for reg in 0..NUM_REGS {
+            let len = 2 * register_size(reg);
+            if digits.len() < len {
+                break;
+            }
+            let mut value = [0; 8];
+            if packet::decode_hex(&digits[..len], &mut value[..len / 2]).is_none() {
+                return reply.push(b"E02");
+            }
+            // Registers that can't be written, like SP_EL1, keep their value.
+            let _ = write_register(e, reg, u64::from_le_bytes(value));
+            digits = &digits[len..];
+        }
+        reply.push(b"OK");
+    }
+
+    /// `p<reg>` and `P<reg>=<value>`.
+    fn register(&self, e: &mut ExceptionContext, args: &[u8], write: bool, reply: &mut Reply) {
+        let mut parts = args.splitn(2, |&b| b == b'=');
+        let reg = match parts.next().and_then(packet::parse_hex) {
+            Some(reg) => reg as usize,
+            None => return reply.push(b"E01"),
+        };
+        let e = match self.context(e, self.selected) {
+            Some(e) => e,
+            None => return reply.push(b"E01"),
+        };
+
+        if !write {
+            return match read_register(e, reg) {
+                Some(value) => reply.push_hex(&value.to_le_bytes()[..register_size(reg)]),
+                None => reply.push(b"E01"),
+            };
+        }
+
+        let digits = parts.next().unwrap_or(b"");
+        let mut value = [0; 8];
+        let len = register_size(reg);
+        match packet::decode_hex(digits, &mut value[..len]) {
+            Some(()) if write_register(e, reg, u64::from_le_bytes(value)).is_ok() => {
+                reply.push(b"OK")
+            }
+            _ => reply.push(b"E01"),
+        }
+    }
+
+    /// `m<addr>,<len>`.
+    fn read_memory(&self, args: &[u8], reply: &mut Reply) {
+        let (addr, len) = match parse_range(args) {
+            Some(range) if 2 * range.1 as usize <= MAX_PACKET && accessible(range.0, range.1) => {
+                range
+            }
+            _ => return reply.push(b"E14"),
+        };
+
+        for addr in addr..addr + len {
+            reply.push_hex(&[unsafe { ptr::read_volatile(addr as *const u8) }]);
+        }
+    }
+
+    /// `M<addr>,<len>:<data>`.
+    fn write_memory(&self, args: &[u8], reply: &mut Reply) {
+        let mut parts = args.splitn(2, |&b| b == b':');
+        let (range, digits) = (parts.next().and_then(parse_range), parts.next());
+        let mut data = [0; MAX_PACKET / 2];
+
+        match (range, digits) {
+            (Some((addr, len)), Some(digits))
+                if len as usize <= data.len()
+                    && accessible(addr, len)
+                    && packet::decode_hex(digits, &mut data[..len as usize]).is_some() =>
+            {
+                match unsafe { memory::mmu::write_code(addr as usize, &data[..len as usize]) } {
+                    Ok(()) => reply.push(b"OK"),
+                    Err(_) => reply.push(b"E14"),
+                }
+            }
+            _ => reply.push(b"E01"),
+        }
+    }
+
+    /// `Z0,<addr>,<kind>` and `z0,<addr>,<kind>`. Other breakpoint types are not supported.
+    fn breakpoint(&mut self, args: &[u8], insert: bool, reply: &mut Reply) {
+        if !args.starts_with(b"0,") {
+            return;
+        }
+        let addr = args[2..]
+            .split(|&b| b == b',')
+            .next()
+            .and_then(packet::parse_hex);
+        let result = match addr {
+            Some(addr) if addr % 4 == 0 && accessible(addr, 4) => {
+                if insert {
+                    self.insert_breakpoint(addr)
+                } else {
+                    self.remove_breakpoint(addr)
+                }
+            }
+            _ => Err(()),
+        };
+
+        match result {
+            Ok(()) => reply.push(b"OK"),
+            Err(()) => reply.push(b"E01"),
+        }
+    }
+
+    fn insert_breakpoint(&mut self, addr: u64) -> Result<(), ()> {
+        if self.breakpoints.iter().flatten().any(|bp| bp.addr == addr) {
+            return Ok(());
+        }
+        let slot = self
+            .breakpoints
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(())?;
+
+        let instruction = unsafe { ptr::read_volatile(addr as *const u32) };
+        unsafe { memory::mmu::write_code(addr as usize, &BRK_INSTRUCTION.to_le_bytes()) }
+            .map_err(|_| ())?;
+        *slot = Some(Breakpoint { addr, instruction });
+        Ok(())
+    }
+
+    fn remove_breakpoint(&mut self, addr: u64) -> Result<(), ()> {
+        let slot = self
+            .breakpoints
+            .iter_mut()
+            .find(|slot| matches!(slot, Some(bp) if bp.addr == addr))
+            .ok_or(())?;
+
+        if let Some(bp) = slot.take() {
+            unsafe { memory::mmu::write_code(addr as usize, &bp.instruction.to_le_bytes()) }
+                .map_err(|_| ())?;
+        }
+        Ok(())
+    }
+
+    fn remove_all_breakpoints(&mut self) {
+        for bp in self.breakpoints.iter_mut() {
+            if let Some(bp) = bp.take() {
+                let _ = unsafe {
+                    memory::mmu::write_code(bp.addr as usize, &bp.instruction.to_le_bytes())
+                };
+            }
+        }
+    }
+
+    fn query(&self, query: &[u8], reply: &mut Reply) {
+        if query.starts_with(b"Supported") {
+            let _ = write!(reply, "PacketSize={:x}", MAX_PACKET);
+        } else if query == b"C" {
+            let _ = write!(reply, "QC{:x}", self.current + 1);
+        } else if query == b"fThreadInfo" {
+            reply.push(b"m");
+            for (i, core) in self.stopped_cores().enumerate() {
+                let _ = write!(reply, "{}{:x}", if i == 0 { "" } else { "," }, core + 1);
+            }
+        } else if query == b"sThreadInfo" {
+            reply.push(b"l");
+        } else if query == b"Attached" {
+            reply.push(b"1");
+        }
+    }
+
+    /// Handle a packet, leaving the answer in `reply`.
+    fn handle(&mut self, e: &mut ExceptionContext, packet: &[u8], reply: &mut Reply) -> Outcome {
+        let (command, args) = match packet.split_first() {
+            Some((command, args)) => (*command, args),
+            None => return Outcome::Reply,
+        };
+
+        match command {
+            b'?' => self.stop_reply(reply),
+            b'g' => self.read_registers(e, reply),
+            b'G' => self.write_registers(e, args, reply),
+            b'p' => self.register(e, args, false, reply),
+            b'P' => self.register(e, args, true, reply),
+            b'm' => self.read_memory(args, reply),
+            b'M' => self.write_memory(args, reply),
+            b'Z' => self.breakpoint(args, true, reply),
+            b'z' => self.breakpoint(args, false, reply),
+            b'q' => self.query(args, reply),
+            b'H' => match args.split_first() {
+                Some((&b'g', thread)) => match self.thread(thread) {
+                    Some(core) if self.context(e, core).is_some() => {
+                        self.selected = core;
+                        reply.push(b"OK");
+                    }
+                    _ => reply.push(b"E01"),
+                },
+                // All cores resume together.
+                _ => reply.push(b"OK"),
+            },
+            b'T' => match self.thread(args) {
+                Some(core) if self.context(e, core).is_some() => reply.push(b"OK"),
+                _ => reply.push(b"E01"),
+            },
+            b'c' | b's' => {
+                if let Some(addr) = packet::parse_hex(args) {
+                    e.elr = addr;
+                }
+                return Outcome::Resume(if command == b's' {
+                    Resume::Step
+                } else {
+                    Resume::Continue
+                });
+            }
+            b'D' => {
+                self.remove_all_breakpoints();
+                self.attached = false;
+                reply.push(b"OK");
+                return Outcome::Detach;
+            }
+            b'k' => {
+                self.remove_all_breakpoints();
+                self.attached = false;
+                return Outcome::Resume(Resume::Continue);
+            }
+            _ => (),
+        }
+        Outcome::Reply
+    }
+}
+
+/// `<addr>,<len>`.
+fn parse_range(args: &[u8]) -> Option<(u64, u64)> {
+    let mut parts = args.splitn(2, |&b| b == b',');
+    let addr = packet::parse_hex(parts.next()?)?;
+    let len = packet::parse_hex(parts.next()?)?;
+
+    Some((addr, len))
+}
+
+impl Stub {
+    /// Serve GDB until it resumes the kernel.
+    fn serve(&mut self, e: &mut ExceptionContext, core: usize, stop: Stop) -> Resume {
+        let Stub {
+            input,
+            reply,
+            state,
+        } = self;
+        let connection = match state.connection {
+            Some(connection) => connection,
+            None => return Resume::Continue,
+        };
+
+        match stop {
+            Stop::Breakpoint(COMPILED_BREAK) => e.elr += 4,
+            Stop::Step => {
+                if let Some(bits) = state.stepping[core].take() {
+                    spsr::end_step(&mut e.spsr, bits);
+                }
+                unsafe {
+                    let mdscr = runtime_init::MDSCR_EL1.get();
+                    runtime_init::MDSCR_EL1.set(mdscr & !runtime_init::MDSCR_EL1::SS);
+                }
+            }
+            Stop::Breakpoint(_) => (),
+        }
+        state.current = core;
+        state.selected = core;
+
+        if state.attached {
+            reply.clear();
+            state.stop_reply(reply);
+            connection.send(reply.as_bytes());
+        }
+
+        loop {
+            let len = connection.receive(input);
+            state.attached = true;
+
+            reply.clear();
+            match state.handle(e, &input[..len], reply) {
+                Outcome::Reply => connection.send(reply.as_bytes()),
+                Outcome::Resume(resume) => return resume,
+                Outcome::Detach => {
+                    connection.send(reply.as_bytes());
+                    return Resume::Continue;
+                }
+            }
+        }
+    }
+
+    /// Arm the software step for the executing core, which returns to `e`.
+    fn arm_step(&mut self, e: &mut ExceptionContext, core: usize) {
+        self.state.stepping[core] = Some(spsr::arm_step(&mut e.spsr));
+
+        unsafe {
+            runtime_init::OSLAR_EL1.set(0);
+            let mdscr = runtime_init::MDSCR_EL1.get();
+            runtime_init::MDSCR_EL1
+                .set(mdscr | runtime_init::MDSCR_EL1::KDE | runtime_init::MDSCR_EL1::SS);
+            llvm_asm!("isb" :::: "volatile");
+        }
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// Enable the stub if the command line asks for it, and stop if it says `kgdbwait`.
+pub fn init(cmdline: Option<&str>) {
+    let mut enable = false;
+    let mut wait = false;
+
+    for arg in cmdline.unwrap_or("").split_whitespace() {
+        match arg {
+            "kgdb=ttyS0" => enable = true,
+            "kgdbwait" => wait = true,
+            _ if arg.starts_with("kgdb=") => warn!("gdb: only kgdb=ttyS0 is supported"),
+            _ => (),
+        }
+    }
+    if !enable {
+        return;
+    }
+
+    match bsp::init_gdb_uart() {
+        Ok(uart) => STUB.lock().state.connection = Some(Connection::new(uart)),
+        Err(e) => {
+            warn!("gdb: {}", e);
+            return;
+        }
+    }
+    ENABLED.store(true, Ordering::Release);
+    info!("gdb: stub listening on ttyS0");
+
+    if wait {
+        info!("gdb: waiting for the debugger");
+        breakpoint();
+    }
+}
+
+/// Stop in the debugger, if it is enabled.
+#[inline(always)]
+pub fn breakpoint() {
+    if ENABLED.load(Ordering::Acquire) {
+        // The immediate is `COMPILED_BREAK`.
+        unsafe { llvm_asm!("brk #0x4b" :::: "volatile") };
+    }
+}
+
+/// Called on exception entry: park the executing core while another one is in the stub.
+pub fn park_if_stopped(e: &mut ExceptionContext) {
+    let stopped_by = STOPPED_BY.load(Ordering::Acquire);
+
+    if stopped_by != NO_CORE && stopped_by != cpu::core_id::<usize>() {
+        park(e);
+    }
+}
+
+/// Whether a core is in the stub, holding the others parked.
+pub fn is_stopped() -> bool {
+    STOPPED_BY.load(Ordering::Acquire) != NO_CORE
+}
+
+/// Enter the stub for a debug exception. Returns `false` if the stub is disabled.
+pub fn handle_exception(e: &mut ExceptionContext, stop: Stop) -> bool {
+    if !ENABLED.load(Ordering::Acquire) {
+        return false;
+    }
+    let core = cpu::core_id::<usize>();
+
+    // Cores stopping at the same time take turns.
+    let mut stub = loop {
+        if let Some(stub) = STUB.try_lock() {
+            break stub;
+        }
+        park(e);
+    };
+    STOPPED_BY.store(core, Ordering::Release);
+    for other in (0..cpu::NUM_CORES).filter(|&other| other != core) {
+        bsp::exception::asynchronous::send_ipi(other);
+    }
+
+    if stub.serve(e, core, stop) == Resume::Step {
+        stub.arm_step(e, core);
+    }
+
+    STOPPED_BY.store(NO_CORE, Ordering::Release);
+    true
+}
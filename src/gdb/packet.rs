@@ -0,0 +1,208 @@
+//! Remote serial protocol framing.
+//!
+//! Packets are sent as `$<data>#<checksum>`, with the checksum the modulo 256 sum of the data in
+//! two hex digits, and acknowledged with `+`, or `-` to ask for a retransmission.
+
+use crate::bsp::device_driver::MiniUart;
+use crate::cpu;
+use core::fmt;
+
+/// Largest packet accepted or sent, advertised to GDB as `PacketSize`.
+pub const MAX_PACKET: usize = 1024;
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+fn hex_value(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// Parse a big endian hex number, like addresses and lengths.
+pub fn parse_hex(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 {
+        return None;
+    }
+
+    digits.iter().try_fold(0, |value, &digit| {
+        Some(value << 4 | u64::from(hex_value(digit)?))
+    })
+}
+
+/// Decode hex encoded bytes into `out`, which must be exactly half as long as `digits`.
+pub fn decode_hex(digits: &[u8], out: &mut [u8]) -> Option<()> {
+    if digits.len() != 2 * out.len() {
+        return None;
+    }
+
+    for (byte, pair) in out.iter_mut().zip(digits.chunks(2)) {
+        *byte = hex_value(pair[0])? << 4 | hex_value(pair[1])?;
+    }
+    Some(())
+}
+
+/// A packet being assembled. Output beyond `MAX_PACKET` is dropped.
+pub struct Reply {
+    buf: [u8; MAX_PACKET],
+    len: usize,
+}
+
+impl Reply {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; MAX_PACKET],
+            len: 0,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        let len = bytes.len().min(MAX_PACKET - self.len);
+
+        self.buf[self.len..self.len + len].copy_from_slice(&bytes[..len]);
+        self.len += len;
+    }
+
+    /// Append `bytes` hex encoded, in memory order.
+    pub fn push_hex(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.push(&[
+                HEX_DIGITS[usize::from(byte >> 4)],
+                HEX_DIGITS[usize::from(byte & 0xf)],
+            ]);
+        }
+    }
+}
+
+impl fmt::Write for Reply {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// A connection to GDB over a UART, polled.
+#[derive(Copy, Clone)]
+pub struct Connection {
+    uart: &'static MiniUart,
+}
+
+impl Connection {
+    pub const fn new(uart: &'static MiniUart) -> Self {
+        Self { uart }
+    }
+
+    fn read_byte(&self) -> u8 {
+        loop {
+            if let Some(byte) = self.uart.try_read_byte() {
+                return byte;
+            }
+            cpu::nop();
+        }
+    }
+
+    fn write(&self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.uart.write_byte(byte);
+        }
+    }
+
+    /// Wait for the next intact packet, acknowledge it and return its length in `buf`.
+    ///
+    /// Anything between packets, like acknowledgements and interrupt requests, is skipped.
+    pub fn receive(&self, buf: &mut [u8; MAX_PACKET]) -> usize {
+        loop {
+            while self.read_byte() != b'$' {}
+
+            let mut len = 0;
+            let mut sum = 0u8;
+            let mut overflow = false;
+            loop {
+                let byte = self.read_byte();
+                if byte == b'#' {
+                    break;
+                }
+                match buf.get_mut(len) {
+                    Some(slot) => *slot = byte,
+                    None => overflow = true,
+                }
+                len += 1;
+                sum = sum.wrapping_add(byte);
+            }
+
+            let checksum = [self.read_byte(), self.read_byte()];
+            let mut expected = [0];
+            if !overflow && decode_hex(&checksum, &mut expected).is_some() && expected[0] == sum {
+                self.write(b"+");
+                return len;
+            }
+            self.write(b"-");
+        }
+    }
+
+    /// Send `data` as a packet, until GDB acknowledges it.
+    pub fn send(&self, data: &[u8]) {
+        let sum = data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        let checksum = [
+            b'#',
+            HEX_DIGITS[usize::from(sum >> 4)],
+            HEX_DIGITS[usize::from(sum & 0xf)],
+        ];
+
+        loop {
+            self.write(b"$");
+            self.write(data);
+            self.write(&checksum);
+
+            loop {
+                match self.read_byte() {
+                    b'+' => return,
+                    b'-' => break,
+                    _ => (),
+                }
+            }
+        }
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Testing
+//--------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_macros::kernel_test;
+
+    /// Numbers are big endian, register and memory contents in memory order.
+    #[kernel_test]
+    fn hex_round_trip() {
+        assert_eq!(parse_hex(b"80000"), Some(0x80000));
+        assert_eq!(parse_hex(b"FfFf"), Some(0xffff));
+        assert_eq!(parse_hex(b""), None);
+        assert_eq!(parse_hex(b"12g"), None);
+
+        let mut reply = Reply::new();
+        reply.push_hex(&0xd420_0000u32.to_le_bytes());
+        assert_eq!(reply.as_bytes(), b"000020d4");
+
+        let mut bytes = [0; 4];
+        decode_hex(reply.as_bytes(), &mut bytes).unwrap();
+        assert_eq!(u32::from_le_bytes(bytes), 0xd420_0000);
+        assert!(decode_hex(b"123", &mut bytes).is_none());
+    }
+}
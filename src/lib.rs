@@ -33,12 +33,19 @@ pub mod console;
 pub mod cpu;
 pub mod driver;
 pub mod exception;
+pub mod gdb;
+pub mod logging;
 pub mod memory;
 pub mod net;
 pub mod print;
 pub mod process;
+pub mod ring_buffer;
 pub mod sched;
+pub mod shell;
+pub mod symbols;
 pub mod syscall;
+pub mod time;
+pub mod watchdog;
 
 extern crate alloc;
 
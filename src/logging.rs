@@ -0,0 +1,282 @@
+//! Leveled kernel logging.
+//!
+//! Records pass a default level and per-module filters, and are printed with their level, the
+//! timestamp, the core, the current pid and the module. The `log` crate's facade is routed through
+//! the same filters, which covers smoltcp's output.
+//!
+//! A module's filter also applies to its submodules, and the longest matching module wins. Modules
+//! are named without the `libkernel::` prefix, e.g. `net::sntp` or `smoltcp::iface`. Filters are
+//! set with the `loglevel=<level>` and `log=<module>:<level>[,<module>:<level>...]` command line
+//! options, or at runtime with `set_level()` and `set_filter()`. The default level can also be set
+//! at build time with the `LOG_LEVEL` environment variable.
+//!
+//! Records are printed directly during boot. Once the drain task runs, they go into per-core
+//! buffers instead, so that logging neither waits for the console nor interleaves mid-line.
+
+mod buffer;
+
+use crate::exception::asynchronous::exec_with_interrupts_masked;
+use crate::{cpu, print, sched, syscall, warn};
+use alloc::{string::String, vec::Vec};
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::RwLock;
+
+pub use log::{Level, LevelFilter};
+
+/// The library's module path prefix, left out of filters and log lines.
+const CRATE_PREFIX: &str = "libkernel::";
+
+struct Filters {
+    default: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl Filters {
+    /// The level applying to `module`.
+    fn level(&self, module: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .filter(|(filter, _)| matches(filter, module))
+            .max_by_key(|(filter, _)| filter.len())
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    /// The most verbose level of all filters.
+    fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, core::cmp::max)
+    }
+
+    fn set(&mut self, module: &str, level: LevelFilter) {
+        match self.modules.iter_mut().find(|(filter, _)| filter == module) {
+            Some(filter) => filter.1 = level,
+            None => self.modules.push((String::from(module), level)),
+        }
+    }
+}
+
+static FILTERS: RwLock<Filters> = RwLock::new(Filters {
+    default: LevelFilter::Info,
+    modules: Vec::new(),
+});
+
+/// Set once the drain task runs.
+static BUFFERED: AtomicBool = AtomicBool::new(false);
+
+/// Time between drains, in milliseconds.
+const DRAIN_INTERVAL_MS: u64 = 20;
+
+struct KernelLogger;
+
+static LOGGER: KernelLogger = KernelLogger;
+
+/// Whether `filter` names `module` or one of its parents.
+fn matches(filter: &str, module: &str) -> bool {
+    match module.strip_prefix(filter) {
+        Some(rest) => rest.is_empty() || rest.starts_with("::"),
+        None => false,
+    }
+}
+
+fn short_name(module: &str) -> &str {
+    module.strip_prefix(CRATE_PREFIX).unwrap_or(module)
+}
+
+/// Change the filters and update the `log` crate's maximum level.
+///
+/// Interrupts stay masked, so that a handler logging on the same core doesn't spin on the lock
+/// under its writer.
+fn with_filters(f: impl FnOnce(&mut Filters)) {
+    exec_with_interrupts_masked(|| {
+        let mut filters = FILTERS.write();
+        f(&mut filters);
+        log::set_max_level(filters.max_level());
+    })
+}
+
+fn level_char(level: Level) -> char {
+    match level {
+        Level::Error => 'E',
+        Level::Warn => 'W',
+        Level::Info => 'I',
+        Level::Debug => 'D',
+        Level::Trace => 'T',
+    }
+}
+
+/// The current pid, or `-` before the first task runs.
+pub struct Pid;
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match sched::current_pid() {
+            Some(pid) => write!(f, "{}", pid),
+            None => f.write_str("-"),
+        }
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// Install the `log` crate logger and apply the build time and command line settings.
+pub fn init(cmdline: Option<&str>) {
+    with_filters(|filters| {
+        if let Some(level) = option_env!("LOG_LEVEL").and_then(|level| level.parse().ok()) {
+            filters.default = level;
+        }
+    });
+    if log::set_logger(&LOGGER).is_err() {
+        warn!("logging: a logger is already installed");
+    }
+
+    for arg in cmdline.unwrap_or("").split_whitespace() {
+        if let Some(level) = arg.strip_prefix("loglevel=") {
+            match level.parse() {
+                Ok(level) => set_level(level),
+                Err(_) => warn!("logging: ignoring invalid level {}", level),
+            }
+        } else if let Some(specs) = arg.strip_prefix("log=") {
+            for spec in specs.split(',') {
+                let mut parts = spec.rsplitn(2, ':');
+                match (parts.next().map(str::parse), parts.next()) {
+                    (Some(Ok(level)), Some(module)) => set_filter(module, level),
+                    _ => warn!("logging: ignoring invalid filter {}", spec),
+                }
+            }
+        }
+    }
+}
+
+/// Set the level of modules without a filter of their own.
+pub fn set_level(level: LevelFilter) {
+    with_filters(|filters| filters.default = level);
+}
+
+/// Set the level of `module` and its submodules.
+pub fn set_filter(module: &str, level: LevelFilter) {
+    with_filters(|filters| filters.set(short_name(module), level));
+}
+
+/// Drop the filter of `module`, it falls back to its parents' level.
+pub fn clear_filter(module: &str) {
+    let module = short_name(module);
+
+    with_filters(|filters| filters.modules.retain(|(filter, _)| filter != module));
+}
+
+/// Call `f` with the default level and then with every module filter.
+pub fn for_each_filter(mut f: impl FnMut(Option<&str>, LevelFilter)) {
+    let filters = FILTERS.read();
+
+    f(None, filters.default);
+    for (module, level) in filters.modules.iter() {
+        f(Some(module), *level);
+    }
+}
+
+/// Whether a record of `level` from `module` gets printed.
+///
+/// Only the filters decide: the `log` crate's maximum level is `Off` until `init()`, and records
+/// from the boot before it are printed at the default level.
+pub fn enabled(level: Level, module: &str) -> bool {
+    level <= FILTERS.read().level(short_name(module))
+}
+
+/// Print a record, if enabled. Use the `error!` to `trace!` macros rather than calling this.
+pub fn log(level: Level, module: &str, args: fmt::Arguments) {
+    if !enabled(level, module) {
+        return;
+    }
+
+    let mut record = buffer::Record::new();
+    let _ = writeln!(
+        record,
+        "[{} {} c{} p{} {}] {}",
+        level_char(level),
+        print::Timestamp,
+        cpu::core_id::<usize>(),
+        Pid,
+        short_name(module),
+        args
+    );
+
+    if BUFFERED.load(Ordering::Acquire) {
+        buffer::push(&record);
+    } else {
+        print::_print(format_args!("{}", record.as_str()));
+    }
+}
+
+/// Print all buffered records to the console.
+pub fn flush() {
+    buffer::drain(&mut |record| print::_print(format_args!("{}", record)));
+}
+
+/// The log drain task. Records are buffered from its start on.
+///
+/// Spawned with `process::add_user_process`, so that it can sleep through the syscall interface.
+pub fn drain_task() {
+    BUFFERED.store(true, Ordering::Release);
+
+    loop {
+        flush();
+        syscall::sleep(DRAIN_INTERVAL_MS);
+    }
+}
+
+/// Print all buffered records through `out`, even if a drain is in progress.
+///
+/// # Safety
+///
+/// - Only for the panic handler.
+pub unsafe fn panic_flush(out: &mut dyn FnMut(&str)) {
+    buffer::force_drain(out);
+}
+
+//------------------------------------------------------------------------------
+// OS Interface Code
+//------------------------------------------------------------------------------
+
+impl log::Log for KernelLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        enabled(metadata.level(), metadata.target())
+    }
+
+    fn log(&self, record: &log::Record) {
+        log(record.level(), record.target(), *record.args());
+    }
+
+    fn flush(&self) {}
+}
+
+//--------------------------------------------------------------------------------------------------
+// Testing
+//--------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_macros::kernel_test;
+
+    /// Filters cover submodules, and the most specific one wins.
+    #[kernel_test]
+    fn longest_module_filter_wins() {
+        let mut filters = Filters {
+            default: LevelFilter::Info,
+            modules: Vec::new(),
+        };
+        filters.set("net", LevelFilter::Warn);
+        filters.set("net::sntp", LevelFilter::Trace);
+
+        assert_eq!(filters.level("net"), LevelFilter::Warn);
+        assert_eq!(filters.level("net::uspi"), LevelFilter::Warn);
+        assert_eq!(filters.level("net::sntp"), LevelFilter::Trace);
+        assert_eq!(filters.level("network"), LevelFilter::Info);
+        assert_eq!(filters.max_level(), LevelFilter::Trace);
+    }
+}
@@ -0,0 +1,263 @@
+//! Per-core lock-free record buffers.
+//!
+//! Every core owns a single producer, single consumer ring. The owning core appends whole records
+//! with interrupts masked, so that handlers interrupting a record cannot interleave with it. The
+//! drain is the only consumer, and prints records of all cores in the order of their global
+//! sequence numbers. Neither side takes a lock, so logging never waits on the console.
+
+use crate::exception::asynchronous::exec_with_interrupts_masked;
+use crate::{cpu, print};
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+/// Size of each core's ring. A power of two.
+const RING_SIZE: usize = 16 * 1024;
+
+/// Longest record, longer ones are truncated.
+pub const MAX_RECORD: usize = 512;
+
+/// Sequence number and length.
+const HEADER: usize = 8 + 2;
+
+/// Formats a record on the stack, truncating at a character boundary.
+pub struct Record {
+    buf: [u8; MAX_RECORD],
+    len: usize,
+}
+
+impl Record {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; MAX_RECORD],
+            len: 0,
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    /// The record's text. Only whole characters are written, so it is valid UTF-8.
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(self.as_bytes()).unwrap_or("<invalid record>\n")
+    }
+}
+
+impl fmt::Write for Record {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut end = s.len().min(MAX_RECORD - self.len);
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.buf[self.len..self.len + end].copy_from_slice(&s.as_bytes()[..end]);
+        self.len += end;
+
+        Ok(())
+    }
+}
+
+struct Ring {
+    buf: UnsafeCell<[u8; RING_SIZE]>,
+    /// Total bytes appended, only advanced by the owning core.
+    head: AtomicUsize,
+    /// Total bytes consumed, only advanced by the drain.
+    tail: AtomicUsize,
+    /// Records lost because the ring was full.
+    dropped: AtomicUsize,
+}
+
+/// The producer side is confined to the owning core and the consumer side to the drain.
+unsafe impl Sync for Ring {}
+
+impl Ring {
+    const fn new() -> Self {
+        Self {
+            buf: UnsafeCell::new([0; RING_SIZE]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    /// Copy `bytes` in at position `at`, wrapping around.
+    ///
+    /// Only the producer may write, and only to the free part of the ring.
+    unsafe fn write_at(&self, at: usize, bytes: &[u8]) {
+        let buf = &mut *self.buf.get();
+        for (i, &byte) in bytes.iter().enumerate() {
+            buf[(at + i) % RING_SIZE] = byte;
+        }
+    }
+
+    /// Copy out from position `at`, wrapping around.
+    ///
+    /// Only the consumer may read, and only from the filled part of the ring.
+    unsafe fn read_at(&self, at: usize, bytes: &mut [u8]) {
+        let buf = &*self.buf.get();
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = buf[(at + i) % RING_SIZE];
+        }
+    }
+
+    /// Append a record. Must only be called by the owning core, with interrupts masked.
+    fn push(&self, seq: u64, record: &[u8]) -> bool {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        let len = HEADER + record.len();
+
+        if RING_SIZE - (head - tail) < len {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+
+        unsafe {
+            self.write_at(head, &seq.to_le_bytes());
+            self.write_at(head + 8, &(record.len() as u16).to_le_bytes());
+            self.write_at(head + HEADER, record);
+        }
+        self.head.store(head + len, Ordering::Release);
+
+        true
+    }
+
+    /// The sequence number of the oldest record. Consumer only.
+    fn peek(&self) -> Option<u64> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail == self.head.load(Ordering::Acquire) {
+            return None;
+        }
+
+        let mut seq = [0; 8];
+        unsafe { self.read_at(tail, &mut seq) };
+        Some(u64::from_le_bytes(seq))
+    }
+
+    /// Remove the oldest record into `record`. Consumer only, after `peek()` found one.
+    fn pop(&self, record: &mut Record) {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let mut len = [0; 2];
+
+        unsafe { self.read_at(tail + 8, &mut len) };
+        record.len = u16::from_le_bytes(len) as usize;
+        unsafe { self.read_at(tail + HEADER, &mut record.buf[..record.len]) };
+        self.tail
+            .store(tail + HEADER + record.len, Ordering::Release);
+    }
+}
+
+static RINGS: [Ring; cpu::NUM_CORES] = [Ring::new(), Ring::new(), Ring::new(), Ring::new()];
+
+/// Orders records across cores.
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// Held by the consumer.
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
+/// Print the records of all cores in order, through `out`.
+fn drain_unguarded(out: &mut dyn FnMut(&str)) {
+    let mut record = Record::new();
+
+    loop {
+        let oldest = RINGS
+            .iter()
+            .filter_map(|ring| ring.peek().map(|seq| (seq, ring)))
+            .min_by_key(|(seq, _)| *seq);
+        let ring = match oldest {
+            Some((_, ring)) => ring,
+            None => break,
+        };
+
+        ring.pop(&mut record);
+        out(record.as_str());
+    }
+
+    for (core, ring) in RINGS.iter().enumerate() {
+        let dropped = ring.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            let mut note = Record::new();
+            let _ = fmt::write(
+                &mut note,
+                format_args!(
+                    "[W {} c{} logging] dropped {} records\n",
+                    print::Timestamp,
+                    core,
+                    dropped
+                ),
+            );
+            out(note.as_str());
+        }
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// Append `record` to the executing core's ring. Returns `false` if it was dropped.
+pub fn push(record: &Record) -> bool {
+    exec_with_interrupts_masked(|| {
+        let seq = SEQUENCE.fetch_add(1, Ordering::Relaxed);
+
+        RINGS[cpu::core_id::<usize>()].push(seq, record.as_bytes())
+    })
+}
+
+/// Print buffered records through `out`, unless another core is already draining.
+pub fn drain(out: &mut dyn FnMut(&str)) {
+    if DRAINING
+        .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        return;
+    }
+
+    drain_unguarded(out);
+    DRAINING.store(false, Ordering::Release);
+}
+
+/// Print buffered records through `out`, even if a drain is in progress.
+///
+/// # Safety
+///
+/// - Only for the panic handler. Racing with another consumer may print records twice or garbled.
+pub unsafe fn force_drain(out: &mut dyn FnMut(&str)) {
+    drain_unguarded(out);
+}
+
+//--------------------------------------------------------------------------------------------------
+// Testing
+//--------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::fmt::Write;
+    use test_macros::kernel_test;
+
+    /// Records come out whole and in order, and a full ring drops instead of overwriting.
+    #[kernel_test]
+    fn ring_keeps_records_whole() {
+        let ring = Ring::new();
+        let mut record = Record::new();
+        for _ in 0..=MAX_RECORD {
+            record.write_char('x').unwrap();
+        }
+        assert_eq!(record.len, MAX_RECORD);
+
+        let mut pushed = 0;
+        while ring.push(pushed, record.as_bytes()) {
+            pushed += 1;
+        }
+        assert_eq!(pushed as usize, RING_SIZE / (HEADER + MAX_RECORD));
+        assert_eq!(ring.dropped.load(Ordering::Relaxed), 1);
+
+        let mut out = Record::new();
+        for seq in 0..pushed {
+            assert_eq!(ring.peek(), Some(seq));
+            ring.pop(&mut out);
+            assert_eq!(out.as_bytes(), record.as_bytes());
+        }
+        assert_eq!(ring.peek(), None);
+    }
+}
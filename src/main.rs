@@ -3,14 +3,20 @@
 #![no_std]
 
 use alloc::{boxed::Box, rc::Rc, vec, vec::Vec};
-use libkernel::{bsp, cpu, driver, exception, info, memory, net, process, sched, syscall, warn};
+use libkernel::{
+    bsp, console, cpu, driver, exception, gdb, info, logging, memory, net, process, sched, shell,
+    syscall, time, warn, watchdog,
+};
 extern crate alloc;
 use core::time::Duration;
 use cpu::CORE_COORD;
 use memory::ALLOCATOR;
-use net::{ETH, USB};
+use net::{Link, ETH, USB};
 use sched::SCHEDULER;
 
+/// How long boot waits for the Ethernet link before going on without it.
+const LINK_TIMEOUT: Duration = Duration::from_secs(3);
+
 // Early init code.
 #[no_mangle]
 unsafe fn kernel_init() -> ! {
@@ -26,6 +32,15 @@ unsafe fn kernel_init() -> ! {
     // enable the core's mmu
     memory::mmu::core_setup();
 
+    // Needs the MMU, the log is mapped non-cacheable.
+    console::ram_log::RAM_LOG.init();
+
+    // Drivers talk to the firmware through heap allocated mailbox buffers.
+    ALLOCATOR.lock().init(
+        memory::heap_start(),
+        memory::heap_end() - memory::heap_start(),
+    );
+
     // init all the drivers
     for i in bsp::driver::driver_manager().all_device_drivers().iter() {
         if i.init().is_err() {
@@ -33,11 +48,6 @@ unsafe fn kernel_init() -> ! {
         }
     }
 
-    ALLOCATOR.lock().init(
-        memory::heap_start(),
-        memory::heap_end() - memory::heap_start(),
-    );
-
     //Let device drivers register and enable their handlers with the interrupt controller.
     for i in bsp::driver::driver_manager().all_device_drivers() {
         if let Err(msg) = i.register_and_enable_irq_handler() {
@@ -45,32 +55,61 @@ unsafe fn kernel_init() -> ! {
         }
     }
 
+    if let Err(msg) = bsp::init_consoles() {
+        warn!("Ignoring console= option: {}", msg);
+    }
+    logging::init(bsp::cmdline());
+    // Stops here with `kgdbwait`, while the other cores wait for this one.
+    gdb::init(bsp::cmdline());
+    if let Some([older, newer]) = console::ram_log::RAM_LOG.previous_boot() {
+        info!(
+            "RAM log holds {} bytes from the previous boot",
+            older.len() + newer.len()
+        );
+    }
+
     let (_, privilege_level) = exception::current_privilege_level();
     info!("Current privilege level: {}", privilege_level);
 
     // need to catch interrupts for USB initialization
     exception::asynchronous::local_fiq_unmask();
 
-    if USB.initialize() {
-        info!("Powered on USB hub");
-        ETH.initialize();
+    if !USB.initialize() {
+        info!("Unable to initialize USB; continuing without networking");
+    } else if !USB.is_eth_available() {
+        info!("Powered on USB hub, no Ethernet controller; continuing without networking");
     } else {
-        info!("Unable to initialize Ethernet controller; skipping");
-    }
-
-    assert!(USB.is_eth_available());
-    info!("USB get mac {:?}", USB.get_eth_addr());
-    while !USB.is_eth_link_up() {
-        info!("USB ethernet link is not up yet");
+        info!("Powered on USB hub");
+        info!("USB get mac {:?}", USB.get_eth_addr());
+        ETH.initialize(Link::Usb);
+        wait_for_link();
     }
     exception::asynchronous::local_fiq_mask();
 
+    // USB is up; hand its FIQ over to the network core.
+    bsp::exception::asynchronous::route_preferred_cores();
+
     SCHEDULER.init();
     CORE_COORD.set_ready_and_wait();
 
     kernel_main()
 }
 
+/// Wait up to `LINK_TIMEOUT` for the Ethernet link. Without one, the network task's link watcher
+/// configures the addresses once it comes up.
+fn wait_for_link() {
+    use time::interface::TimeManager;
+
+    let deadline = time::time_manager().uptime() + LINK_TIMEOUT;
+    while !USB.is_eth_link_up() {
+        if time::time_manager().uptime() >= deadline {
+            warn!("No Ethernet link yet; continuing");
+            return;
+        }
+        time::time_manager().spin_for(Duration::from_millis(100));
+    }
+}
+
 // The main function running after the early init.
 fn kernel_main() -> ! {
     use driver::interface::DriverManager;
@@ -96,6 +135,8 @@ fn kernel_main() -> ! {
         info!("      {}. {}", i + 1, driver.compatible());
     }
 
+    info!("Last reset: {}", bsp::reset_reason());
+
     info!("Registered IRQ handlers:");
     bsp::exception::asynchronous::irq_manager().print_handler();
 
@@ -128,9 +169,21 @@ fn kernel_main() -> ! {
     }
     process::add_user_process(process2);
     process::add_kernel_process(process3);
+    process::add_user_process(watchdog::watchdog_task);
+    process::add_user_process(logging::drain_task);
 
-    USB.start_kernel_timer(Duration::from_millis(1000), Some(net::poll_ethernet));
+    shell::add_program("process", process);
+    shell::add_program("process2", process2);
+    shell::add_program("echo", echo_server);
+    process::add_user_process(shell::shell_task);
 
+    if unsafe { ETH.is_initialized() } {
+        process::add_user_process_on(net::network_task, bsp::exception::asynchronous::fiq_core());
+        process::add_user_process(net::http::server_task);
+    }
+
+    cpu::init_core_timer();
+    bsp::exception::asynchronous::enable_ipi();
     unsafe {
         exception::asynchronous::local_irq_unmask();
     }
@@ -169,3 +222,38 @@ fn process3() {
         cpu::spin_for_cycles(2000000000)
     }
 }
+
+/// TCP echo service on port 7, one client at a time.
+fn echo_server() {
+    let serve = || -> Result<(), net::socket::Error> {
+        let listener = syscall::socket(net::socket::SOCK_STREAM)?;
+        syscall::bind(listener, 7)?;
+        syscall::listen(listener, 1)?;
+        info!("echo: listening on port 7");
+
+        loop {
+            let client = syscall::accept(listener)?;
+            let mut buf = [0; 512];
+            loop {
+                match syscall::recv(client, &mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(len) => {
+                        let mut sent = 0;
+                        while sent < len {
+                            match syscall::send(client, &buf[sent..len]) {
+                                Ok(n) => sent += n,
+                                Err(_) => break,
+                            }
+                        }
+                    }
+                }
+            }
+            let _ = syscall::close(client);
+        }
+    };
+
+    if let Err(e) = serve() {
+        warn!("echo: {:?}", e);
+    }
+    syscall::exit();
+}
@@ -36,6 +36,7 @@ pub mod map {
     pub const UART_OFFSET:                              usize =        0x0020_1000;
     pub const SYS_TIMER_OFFSET:                         usize =        0x0000_3000;
     pub const MINI_UART_OFFSET:                         usize =        0x0021_5000;
+    pub const PM_OFFSET:                                usize =        0x0010_0000;
 
     /// Physical devices.
     pub mod mmio {
@@ -47,6 +48,7 @@ pub mod map {
         pub const PL011_UART_BASE:                      usize = BASE + UART_OFFSET;
         pub const MINI_UART_BASE:                       usize = BASE + MINI_UART_OFFSET;
         pub const SYS_TIMER_BASE:                       usize = BASE + SYS_TIMER_OFFSET;
+        pub const PM_BASE:                              usize = BASE + PM_OFFSET;
         pub const LOCAL_INTERRUPT_CONTROLLER_BASE:      usize =        0x4000_0000;
         pub const END_INCLUSIVE:                        usize =        0x4000_FFFF;
     }
@@ -111,7 +113,7 @@ pub mod kernel_mem_range {
 // Public Definitions
 //--------------------------------------------------------------------------------------------------
 
-const NUM_MEM_RANGES: usize = 3;
+const NUM_MEM_RANGES: usize = 4;
 
 /// The virtual memory layout.
 ///
@@ -153,6 +155,32 @@ pub static LAYOUT: KernelVirtualLayout<{ NUM_MEM_RANGES }> = KernelVirtualLayout
                 execute_never: false,
             },
         },
+        RangeDescriptor {
+            name: "Persistent RAM log",
+            virtual_range: || {
+                // The linker script aligns the .noinit section to 64 KiB on both ends:
+                //
+                // [__noinit_start, __noinit_end)
+                extern "C" {
+                    static __noinit_start: usize;
+                    static __noinit_end: usize;
+                }
+
+                unsafe {
+                    #[allow(clippy::range_minus_one)]
+                    RangeInclusive::new(
+                        &__noinit_start as *const _ as usize,
+                        &__noinit_end as *const _ as usize - 1,
+                    )
+                }
+            },
+            translation: Translation::Identity,
+            attribute_fields: AttributeFields {
+                mem_attributes: MemAttributes::NonCacheableDRAM,
+                acc_perms: AccessPermissions::ReadWrite,
+                execute_never: true,
+            },
+        },
         RangeDescriptor {
             name: "DMA heap pool",
             virtual_range: || RangeInclusive::new(heap_start(), heap_end()),
@@ -495,3 +495,67 @@ pub unsafe fn core_setup() {
     // Force MMU init to complete before next instruction
     barrier::isb(barrier::SY);
 }
+
+/// The translation table entry mapping the 64 KiB `page`.
+unsafe fn page_entry(page: usize) -> &'static mut PageDescriptor {
+    const ENTRIES_PER_TABLE_SHIFT: usize = FIVETWELVE_MIB_SHIFT - SIXTYFOUR_KIB_SHIFT;
+
+    &mut TABLES.lvl3[page >> ENTRIES_PER_TABLE_SHIFT][page & ((1 << ENTRIES_PER_TABLE_SHIFT) - 1)]
+}
+
+/// Make changed translation table entries visible on all cores.
+unsafe fn flush_tlb() {
+    llvm_asm!("dsb ishst; tlbi vmalle1is; dsb ish; isb" :::: "volatile");
+}
+
+/// Write `bytes` to `addr`, even if it is mapped read-only, and make the new contents visible to
+/// instruction fetches. Used by the debugger to patch breakpoints into the kernel's code.
+///
+/// # Safety
+///
+/// - The other cores must not execute the patched code meanwhile.
+pub unsafe fn write_code(addr: usize, bytes: &[u8]) -> Result<(), &'static str> {
+    const CACHE_LINE: usize = 64;
+
+    let end = addr
+        .checked_add(bytes.len())
+        .ok_or("address out of range")?;
+    if bytes.is_empty() {
+        return Ok(());
+    }
+    if end > memory::map::mmio::BASE {
+        return Err("address out of range");
+    }
+    let pages = (addr >> SIXTYFOUR_KIB_SHIFT)..=((end - 1) >> SIXTYFOUR_KIB_SHIFT);
+
+    let rw = STAGE1_PAGE_DESCRIPTOR::AP::RW_EL1;
+    for page in pages.clone() {
+        let entry = page_entry(page);
+        entry.0 = (entry.0 & !rw.mask) | rw.value;
+    }
+    flush_tlb();
+
+    for (i, &byte) in bytes.iter().enumerate() {
+        core::ptr::write_volatile((addr + i) as *mut u8, byte);
+    }
+    // Clean the data cache and invalidate the instruction cache to the point of unification.
+    for line in (addr & !(CACHE_LINE - 1)..end).step_by(CACHE_LINE) {
+        llvm_asm!("dc cvau, $0" :: "r"(line) :: "volatile");
+    }
+    llvm_asm!("dsb ish" :::: "volatile");
+    for line in (addr & !(CACHE_LINE - 1)..end).step_by(CACHE_LINE) {
+        llvm_asm!("ic ivau, $0" :: "r"(line) :: "volatile");
+    }
+    llvm_asm!("dsb ish; isb" :::: "volatile");
+
+    // Restore the permissions the layout asks for.
+    for page in pages {
+        let virt_addr = page << SIXTYFOUR_KIB_SHIFT;
+        let (output_addr, attribute_fields) =
+            memory::virt_mem_layout().virt_addr_properties(virt_addr)?;
+        *page_entry(page) = PageDescriptor::new(output_addr, attribute_fields);
+    }
+    flush_tlb();
+
+    Ok(())
+}
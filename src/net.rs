@@ -1,25 +1,51 @@
 // Borrowed from https://github.com/sslab-gatech/cs3210-rustos-public/blob/lab5/kern/src/net.rs
+pub mod capture;
+pub mod dhcp;
+pub mod http;
+pub mod link;
+pub mod netconsole;
+pub mod ping;
+pub mod rx;
+pub mod slaac;
+pub mod sntp;
+pub mod socket;
+pub mod telnet;
+pub mod tftp;
 pub mod uspi;
+pub mod virt;
 
 use alloc::boxed::Box;
 
 pub const USPI_FRAME_BUFFER_SIZE: u32 = 1600;
 
+/// The link-local address used when DHCP gets no answer.
 pub const IP_ADDR: [u8; 4] = [169, 254, 32, 10];
-pub const USPI_TIMER_HZ: usize = 10;
 
+/// Frame buffers allocated up front, for a full receive queue and the frames being handled.
+const FRAME_POOL_LEN: usize = rx::QUEUE_LEN + 4;
+
+/// The longest the network task sleeps, so that the sockets' own work like the netconsole's gets
+/// sent without a poll from smoltcp's timers.
+const MAX_POLL_DELAY: Duration = Duration::from_millis(100);
+
+use alloc::collections::BTreeMap;
 use alloc::vec;
 use alloc::vec::Vec;
 use core::convert::TryInto;
+use core::mem::ManuallyDrop;
 use core::time::Duration;
 
-use smoltcp::iface::{EthernetInterfaceBuilder, Neighbor, NeighborCache};
+use smoltcp::iface::{EthernetInterfaceBuilder, Neighbor, NeighborCache, Routes};
 use smoltcp::phy::{self, Device, DeviceCapabilities};
 use smoltcp::time::Instant;
-use smoltcp::wire::{IpAddress, IpCidr};
+use smoltcp::wire::{
+    ArpPacket, EthernetAddress, EthernetFrame, EthernetProtocol, IpAddress, IpCidr, Ipv4Address,
+    Ipv4Packet, Ipv6Address,
+};
 
-use crate::{bsp, cpu, info, warn};
+use crate::{cpu, debug, exception, sched, syscall, time, trace, warn};
 use spin::Mutex;
+use virt::VirtualDevice;
 
 pub type SocketSet = smoltcp::socket::SocketSet<'static>;
 pub type TcpSocket = smoltcp::socket::TcpSocket<'static>;
@@ -32,18 +58,38 @@ pub static USB: uspi::Usb = uspi::Usb::uninitialized();
 #[repr(align(8))]
 struct FrameBuf([u8; USPI_FRAME_BUFFER_SIZE as usize]);
 
+/// Spare frame buffers, taken by the receive interrupt without allocating.
+static FRAME_POOL: Mutex<Vec<Box<FrameBuf>>> = Mutex::new(Vec::new());
+
+fn new_frame_buf() -> Box<FrameBuf> {
+    Box::new(FrameBuf([0; USPI_FRAME_BUFFER_SIZE as usize]))
+}
+
 /// A fixed size buffer with length tracking functionality.
+///
+/// The buffer goes back to the pool when the frame is dropped, unless the pool is full.
 pub struct Frame {
-    buf: Box<FrameBuf>,
+    buf: ManuallyDrop<Box<FrameBuf>>,
     len: u32,
 }
 
 impl Frame {
+    /// A frame from the pool, or a newly allocated one if the pool is empty.
     pub fn new() -> Self {
-        Frame {
-            buf: Box::new(FrameBuf([0; USPI_FRAME_BUFFER_SIZE as usize])),
+        Self::from_pool().unwrap_or_else(|| Frame {
+            buf: ManuallyDrop::new(new_frame_buf()),
             len: USPI_FRAME_BUFFER_SIZE,
-        }
+        })
+    }
+
+    /// A frame from the pool, `None` if it is empty. Safe to call from interrupt handlers.
+    pub fn from_pool() -> Option<Self> {
+        let buf = exception::asynchronous::exec_with_interrupts_masked(|| FRAME_POOL.lock().pop())?;
+
+        Some(Frame {
+            buf: ManuallyDrop::new(buf),
+            len: USPI_FRAME_BUFFER_SIZE,
+        })
     }
 
     pub fn as_ptr(&self) -> *const u8 {
@@ -72,12 +118,103 @@ impl Frame {
     }
 }
 
+impl Drop for Frame {
+    fn drop(&mut self) {
+        let buf = unsafe { ManuallyDrop::take(&mut self.buf) };
+
+        // The pool's capacity is fixed, so that pushing never allocates.
+        let spare = exception::asynchronous::exec_with_interrupts_masked(|| {
+            let mut pool = FRAME_POOL.lock();
+            if pool.len() < pool.capacity() {
+                pool.push(buf);
+                None
+            } else {
+                Some(buf)
+            }
+        });
+        drop(spare);
+    }
+}
+
+/// Where the interface sends and receives frames.
 #[derive(Debug)]
-pub struct UsbEthernet;
+pub enum Link {
+    /// The USB Ethernet controller, receiving through `rx`.
+    Usb,
+    /// An in-memory device with the given MAC address, e.g. one end of `VirtualDevice::cable()`.
+    Virtual(VirtualDevice, EthernetAddress),
+}
+
+impl Link {
+    fn hw_addr(&self) -> EthernetAddress {
+        match self {
+            Link::Usb => USB.get_eth_addr(),
+            Link::Virtual(_, hw_addr) => *hw_addr,
+        }
+    }
+
+    /// Whether the link is up. Reads the PHY for USB.
+    fn is_up(&self) -> bool {
+        match self {
+            Link::Usb => USB.is_eth_link_up(),
+            Link::Virtual(..) => true,
+        }
+    }
+
+    fn receive(&self) -> Option<Frame> {
+        match self {
+            Link::Usb => rx::pop(),
+            Link::Virtual(device, _) => {
+                let data = device.recv()?;
+                link::count_rx(data.len());
+                Some(frame_from(&data))
+            }
+        }
+    }
+
+    fn send(&self, frame: &Frame) -> bool {
+        match self {
+            Link::Usb => USB.send_frame(frame).is_some(),
+            Link::Virtual(device, _) => device.send(frame.as_slice()),
+        }
+    }
+}
+
+fn frame_from(data: &[u8]) -> Frame {
+    let mut frame = Frame::new();
+    frame.set_len(data.len().try_into().unwrap());
+    frame.as_mut_slice().copy_from_slice(data);
+    frame
+}
 
-impl<'a> Device<'a> for UsbEthernet {
+/// Whether `frame` is an IPv4 packet or an ARP packet for 127.0.0.0/8.
+fn is_loopback(frame: &[u8]) -> bool {
+    let frame = match EthernetFrame::new_checked(frame) {
+        Ok(frame) => frame,
+        Err(_) => return false,
+    };
+
+    match frame.ethertype() {
+        EthernetProtocol::Ipv4 => Ipv4Packet::new_checked(frame.payload())
+            .map_or(false, |packet| packet.dst_addr().is_loopback()),
+        EthernetProtocol::Arp => ArpPacket::new_checked(frame.payload()).map_or(false, |packet| {
+            let target = packet.target_protocol_addr();
+            target.len() == 4 && Ipv4Address::from_bytes(target).is_loopback()
+        }),
+        _ => false,
+    }
+}
+
+/// The interface's device: the link, and a loopback device taking the frames for 127.0.0.0/8.
+#[derive(Debug)]
+pub struct NetDevice {
+    link: Link,
+    loopback: VirtualDevice,
+}
+
+impl<'a> Device<'a> for NetDevice {
     type RxToken = RxToken;
-    type TxToken = TxToken;
+    type TxToken = TxToken<'a>;
 
     fn capabilities(&self) -> DeviceCapabilities {
         let mut capability = DeviceCapabilities::default();
@@ -87,21 +224,18 @@ impl<'a> Device<'a> for UsbEthernet {
     }
 
     fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
-        info!("UsbEthernet receive");
-        let mut frame = Frame::new();
-        match USB.recv_frame(&mut frame) {
-            Some(_) => {
-                let rx = RxToken { frame };
-                let tx = TxToken {};
-                Some((rx, tx))
-            }
-            _ => None,
-        }
+        trace!("NetDevice receive");
+        let frame = match self.loopback.recv() {
+            Some(data) => frame_from(&data),
+            None => self.link.receive()?,
+        };
+
+        Some((RxToken { frame }, TxToken { device: self }))
     }
 
     fn transmit(&'a mut self) -> Option<Self::TxToken> {
-        info!("UsbEthernet TRANSMIT");
-        Some(TxToken)
+        trace!("NetDevice transmit");
+        Some(TxToken { device: self })
     }
 }
 
@@ -114,45 +248,61 @@ impl phy::RxToken for RxToken {
     where
         F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
     {
+        capture::tee(self.frame.as_slice());
         f(self.frame.as_mut_slice())
     }
 }
 
-pub struct TxToken;
+pub struct TxToken<'a> {
+    device: &'a NetDevice,
+}
 
-impl phy::TxToken for TxToken {
+impl<'a> phy::TxToken for TxToken<'a> {
     fn consume<R, F>(self, _timestamp: Instant, len: usize, f: F) -> smoltcp::Result<R>
     where
         F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
     {
-        info!("phy::TxToken for TxToken consume");
+        trace!("phy::TxToken for TxToken consume");
         let mut frame = Frame::new();
         frame.set_len(len.try_into().unwrap());
         let result = f(frame.as_mut_slice());
-        USB.send_frame(&frame);
+        // Looped back frames are captured once, when received.
+        if is_loopback(frame.as_slice()) {
+            self.device.loopback.send(frame.as_slice());
+        } else {
+            capture::tee(frame.as_slice());
+            link::count_tx(len, self.device.link.send(&frame));
+        }
         result
     }
 }
 
-/// Creates and returns a new ethernet interface using `UsbEthernet` struct.
-fn create_interface() -> EthernetInterface<UsbEthernet> {
-    info!("CREATE interface for smoltcp");
-    let device = UsbEthernet;
-    let hw_addr = USB.get_eth_addr();
+/// Creates and returns a new ethernet interface on `link`.
+fn create_interface(link: Link) -> EthernetInterface<NetDevice> {
+    debug!("Creating interface for smoltcp");
+    let hw_addr = link.hw_addr();
+    let device = NetDevice {
+        link,
+        loopback: VirtualDevice::loopback(),
+    };
+
+    // The first address is left to DHCP, the third and fourth to SLAAC.
+    let ip_addrs = [
+        IpCidr::new(Ipv4Address::UNSPECIFIED.into(), 0),
+        IpCidr::new(IpAddress::v4(127, 0, 0, 1), 8),
+        IpCidr::new(slaac::link_local(hw_addr).into(), 64),
+        IpCidr::new(Ipv6Address::UNSPECIFIED.into(), 128),
+        IpCidr::new(Ipv6Address::LOOPBACK.into(), 128),
+    ];
 
     unsafe {
-        ETH.private_cidr = Some(IpCidr::new(
-            IpAddress::v4(IP_ADDR[0], IP_ADDR[1], IP_ADDR[2], IP_ADDR[3]),
-            16,
-        ));
-        ETH.local_cidr = Some(IpCidr::new(IpAddress::v4(127, 0, 0, 1), 8));
-
         let neighbor_cache = NeighborCache::new(&mut ETH.neighbor_cache_storage.as_mut()[..]);
 
         EthernetInterfaceBuilder::new(device)
             .ethernet_addr(hw_addr)
             .neighbor_cache(neighbor_cache)
-            .ip_addrs([ETH.private_cidr.unwrap(), ETH.local_cidr.unwrap()])
+            .ip_addrs(ip_addrs)
+            .routes(Routes::new(BTreeMap::new()))
             .finalize()
     }
 }
@@ -162,43 +312,74 @@ const PORT_MAP_SIZE: usize = 65536 / 64;
 pub static mut ETH: EthernetDriver = EthernetDriver {
     socket_set: None,
     ethernet: None,
-    neighbor_cache_storage: [None; 16],
-    private_cidr: None,
-    local_cidr: None,
+    neighbor_cache_storage: [None; 32],
 };
 
 pub struct EthernetDriver {
-    /// A set of sockets
-    socket_set: Option<SocketSet>,
+    /// A set of sockets, shared by the poll and the socket syscalls
+    socket_set: Option<Mutex<SocketSet>>,
     /// Internal ethernet interface
-    ethernet: Option<Mutex<EthernetInterface<UsbEthernet>>>,
+    ethernet: Option<Mutex<EthernetInterface<NetDevice>>>,
 
-    neighbor_cache_storage: [Option<(IpAddress, Neighbor)>; 16],
-
-    private_cidr: Option<IpCidr>,
-
-    local_cidr: Option<IpCidr>,
+    /// Room for both IPv4 and IPv6 neighbors
+    neighbor_cache_storage: [Option<(IpAddress, Neighbor)>; 32],
 }
 
 impl EthernetDriver {
-    /// Creates a fresh ethernet driver.
-    pub fn initialize(&mut self) {
-        self.ethernet = Some(Mutex::new(create_interface()));
-        self.socket_set = Some(SocketSet::new(Vec::new()));
+    /// Creates a fresh ethernet driver on `link`.
+    pub fn initialize(&mut self, link: Link) {
+        let mut pool = Vec::with_capacity(FRAME_POOL_LEN);
+        pool.resize_with(FRAME_POOL_LEN, new_frame_buf);
+        *FRAME_POOL.lock() = pool;
+        if let Link::Usb = link {
+            if !rx::init() {
+                warn!("Unable to receive Ethernet frames");
+            }
+        }
+
+        self.ethernet = Some(Mutex::new(create_interface(link)));
+        let mut sockets = SocketSet::new(Vec::new());
+        let now = Instant::from_millis(time::time_manager().uptime().as_millis() as i64);
+        dhcp::init(&mut sockets, now);
+        slaac::init(&mut sockets, now);
+        sntp::init(&mut sockets);
+        netconsole::init(&mut sockets);
+        ping::init(&mut sockets);
+        tftp::init(&mut sockets);
+        telnet::init(&mut sockets);
+        self.socket_set = Some(Mutex::new(sockets));
+    }
+
+    /// Whether the interface was set up, which needs a USB Ethernet controller or a virtual link.
+    pub fn is_initialized(&self) -> bool {
+        self.ethernet.is_some()
+    }
+
+    /// Run `f` on the socket set, with interrupts masked so that the poll can't interrupt it.
+    ///
+    /// Returns `None` before initialization.
+    pub fn with_sockets<T>(&self, f: impl FnOnce(&mut SocketSet) -> T) -> Option<T> {
+        let sockets = self.socket_set.as_ref()?;
+
+        Some(exception::asynchronous::exec_with_interrupts_masked(|| {
+            f(&mut sockets.lock())
+        }))
     }
 
     /// Polls the ethernet interface.
     /// See also `smoltcp::iface::EthernetInterface::poll()`.
     pub fn poll(&mut self, timestamp: Instant) {
-        info!("EthernetDriver::poll() timestamp: {:?}", timestamp);
+        netconsole::set_muted(true);
+        trace!("EthernetDriver::poll() timestamp: {:?}", timestamp);
         let mut eth = self.ethernet.as_mut().unwrap().lock();
-        info!("EthernetDriver::poll() timestamp: {:?}", timestamp);
-        match eth.poll(&mut self.socket_set.as_mut().unwrap(), timestamp) {
+        let mut sockets = self.socket_set.as_ref().unwrap().lock();
+        link::poll(timestamp, || eth.device().link.is_up());
+        match eth.poll(&mut sockets, timestamp) {
             Ok(packets_processed) => {
                 if packets_processed {
-                    info!("EthernetDriver::poll() packets processed");
+                    trace!("EthernetDriver::poll() packets processed");
                 } else {
-                    info!("EthernetDriver::poll() no packets processed");
+                    trace!("EthernetDriver::poll() no packets processed");
                 }
             }
             Err(e) => match e {
@@ -206,27 +387,132 @@ impl EthernetDriver {
                 e => warn!("EthernetDriver::poll() error: {:?}", e),
             },
         }
+        dhcp::poll(&mut eth, &mut sockets, timestamp);
+        slaac::poll(&mut eth, &mut sockets, timestamp);
+        sntp::poll(&mut sockets);
+        ping::poll(&mut sockets);
+        tftp::poll(&mut sockets);
+        netconsole::poll(&mut sockets);
+        telnet::poll(&mut sockets, timestamp);
+        capture::poll(&mut sockets);
+        socket::poll(&mut sockets);
+        netconsole::set_muted(false);
+    }
+
+    /// The interface's addresses, none before initialization.
+    pub fn ip_addrs(&self) -> Vec<IpCidr> {
+        match self.ethernet.as_ref() {
+            Some(eth) => exception::asynchronous::exec_with_irq_masked(|| {
+                eth.lock().ip_addrs().to_vec()
+            }),
+            None => Vec::new(),
+        }
     }
 
     /// Returns an advisory wait time to call `poll()` the next time.
     /// See also `smoltcp::iface::EthernetInterface::poll_delay()`.
     pub fn poll_delay(&mut self, timestamp: Instant) -> Duration {
         let eth = self.ethernet.as_ref().unwrap().lock();
-        match eth.poll_delay(&self.socket_set.as_ref().unwrap(), timestamp) {
-            Some(delay) => delay.into(),
-            None => Duration::from_millis(0),
-        }
+        let sockets = self.socket_set.as_ref().unwrap().lock();
+        let delay = match eth.poll_delay(&sockets, timestamp) {
+            Some(delay) => Duration::from(delay).min(MAX_POLL_DELAY),
+            None => MAX_POLL_DELAY,
+        };
+
+        [
+            dhcp::next_poll(timestamp),
+            slaac::next_poll(timestamp),
+            Some(link::next_poll(timestamp)),
+        ]
+        .iter()
+        .flatten()
+        .fold(delay, |delay, &next| delay.min(next))
     }
 }
 
-pub extern "C" fn poll_ethernet(_: uspi::TKernelTimerHandle, _: *mut u8, _: *mut u8) {
-    unsafe {
-        ETH.poll(Instant::from_millis(
-            bsp::generic_timer().current_time().as_millis() as i64,
-        ));
-        let delay = ETH.poll_delay(Instant::from_millis(
-            bsp::generic_timer().current_time().as_millis() as i64,
-        ));
-        USB.start_kernel_timer(delay, Some(poll_ethernet));
+/// Have the network task poll the interface as soon as possible, e.g. after queueing data on a
+/// socket.
+pub fn wake() {
+    rx::wake();
+}
+
+/// Poll the interface whenever frames arrive, something wakes the task or smoltcp's timers are
+/// due.
+///
+/// Spawned on the core the USB FIQ is routed to: USPi's critical sections only mask interrupts on
+/// the executing core, so sends must not race the FIQ's transfers from another core.
+pub fn network_task() {
+    if let Some(pid) = sched::current_pid() {
+        rx::set_task(pid);
+    }
+    loop {
+        let now = Instant::from_millis(time::time_manager().uptime().as_millis() as i64);
+        // Sends block on the USB FIQ, so only IRQs are masked.
+        let delay = exception::asynchronous::exec_with_irq_masked(|| unsafe {
+            ETH.poll(now);
+            ETH.poll_delay(now)
+        });
+
+        syscall::wait_network(delay);
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Testing
+//--------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smoltcp::socket::{UdpPacketMetadata, UdpSocket, UdpSocketBuffer};
+    use smoltcp::wire::IpEndpoint;
+    use test_macros::kernel_test;
+
+    const LOCALHOST: IpAddress = IpAddress::Ipv4(Ipv4Address([127, 0, 0, 1]));
+
+    fn udp_socket(port: u16) -> UdpSocket<'static> {
+        let mut socket = UdpSocket::new(
+            UdpSocketBuffer::new(vec![UdpPacketMetadata::EMPTY; 4], vec![0; 1024]),
+            UdpSocketBuffer::new(vec![UdpPacketMetadata::EMPTY; 4], vec![0; 1024]),
+        );
+        socket.bind(IpEndpoint::new(LOCALHOST, port)).unwrap();
+        socket
+    }
+
+    /// Datagrams to 127.0.0.1 come back through the loopback device, without going out on the link.
+    #[kernel_test]
+    fn eth_loops_back_localhost() {
+        let (device, wire) = VirtualDevice::cable();
+        let hw_addr = EthernetAddress([0x02, 0, 0, 0, 0, 1]);
+        unsafe { ETH.initialize(Link::Virtual(device, hw_addr)) };
+
+        let (sender, receiver) = unsafe {
+            ETH.with_sockets(|sockets| {
+                (sockets.add(udp_socket(1000)), sockets.add(udp_socket(2000)))
+            })
+        }
+        .unwrap();
+        unsafe {
+            ETH.with_sockets(|sockets| {
+                sockets
+                    .get::<UdpSocket>(sender)
+                    .send_slice(b"ping", IpEndpoint::new(LOCALHOST, 2000))
+            })
+        }
+        .unwrap()
+        .unwrap();
+
+        let received = virt::poll_eth_until(&mut [], |_| unsafe {
+            ETH.with_sockets(|sockets| match sockets.get::<UdpSocket>(receiver).recv() {
+                Ok((data, from)) => data == b"ping" && from.port == 1000,
+                Err(_) => false,
+            })
+            .unwrap()
+        });
+
+        assert!(received);
+        while let Some(frame) = wire.recv() {
+            assert!(!is_loopback(&frame));
+        }
     }
 }
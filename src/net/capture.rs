@@ -0,0 +1,533 @@
+//! Packet capture in libpcap format.
+//!
+//! While enabled, every frame the USB Ethernet device receives or sends is copied into a ring
+//! buffer with a generic timer timestamp, if it passes the filter. The buffer can be written out
+//! as a pcap file, hex encoded over the console, or streamed live to a TCP client, e.g. after
+//! `pcap stream 2002` in the shell:
+//!
+//! ```text
+//! nc <pi> 2002 | wireshark -k -i -
+//! ```
+//!
+//! The stream's own frames are never captured.
+
+use super::SocketSet;
+use crate::exception::asynchronous::exec_with_irq_masked;
+use crate::time::{self, interface::TimeManager};
+use crate::{bsp, info, warn};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::time::Duration;
+use smoltcp::socket::{SocketHandle, TcpSocket, TcpSocketBuffer, TcpState};
+use smoltcp::wire::{
+    EthernetFrame, EthernetProtocol, IpAddress, IpProtocol, Ipv4Packet, Ipv6Packet,
+};
+use spin::Mutex;
+
+/// Bytes of frame data kept at most, older frames are overwritten.
+const BUFFER_LEN: usize = 256 * 1024;
+
+/// Frames longer than this are truncated.
+const SNAPLEN: usize = 1600;
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const PCAP_VERSION: (u16, u16) = (2, 4);
+const LINKTYPE_ETHERNET: u32 = 1;
+const RECORD_HEADER_LEN: usize = 16;
+
+const STREAM_BUFFER_LEN: usize = 16 * 1024;
+
+struct Record {
+    /// Generic timer uptime.
+    timestamp: Duration,
+    /// The frame's length before truncation.
+    len: usize,
+    data: Vec<u8>,
+}
+
+impl Record {
+    /// The pcap record header, with the timestamp moved by `epoch`.
+    fn header(&self, epoch: Duration) -> [u8; RECORD_HEADER_LEN] {
+        let timestamp = epoch + self.timestamp;
+        let mut header = [0; RECORD_HEADER_LEN];
+
+        header[0..4].copy_from_slice(&(timestamp.as_secs() as u32).to_le_bytes());
+        header[4..8].copy_from_slice(&timestamp.subsec_micros().to_le_bytes());
+        header[8..12].copy_from_slice(&(self.data.len() as u32).to_le_bytes());
+        header[12..16].copy_from_slice(&(self.len as u32).to_le_bytes());
+        header
+    }
+}
+
+enum StreamRequest {
+    Listen(u16),
+    Close,
+}
+
+struct Stream {
+    port: u16,
+    handle: SocketHandle,
+    /// The next record to send, `None` until the client got the file header.
+    next: Option<u64>,
+}
+
+struct Capture {
+    enabled: bool,
+    filter: Filter,
+    /// Oldest first.
+    records: Vec<Record>,
+    /// Frame bytes in `records`.
+    len: usize,
+    /// Records overwritten so far, the sequence number of the oldest record.
+    overwritten: u64,
+    stream: Option<Stream>,
+    /// A change from the shell, applied by the next poll.
+    pending: Option<StreamRequest>,
+}
+
+static CAPTURE: Mutex<Capture> = Mutex::new(Capture {
+    enabled: false,
+    filter: Filter::ANY,
+    records: Vec::new(),
+    len: 0,
+    overwritten: 0,
+    stream: None,
+    pending: None,
+});
+
+/// The pcap file header.
+fn file_header() -> [u8; 24] {
+    let mut header = [0; 24];
+
+    header[0..4].copy_from_slice(&PCAP_MAGIC.to_le_bytes());
+    header[4..6].copy_from_slice(&PCAP_VERSION.0.to_le_bytes());
+    header[6..8].copy_from_slice(&PCAP_VERSION.1.to_le_bytes());
+    // Zero time zone offset and timestamp accuracy.
+    header[16..20].copy_from_slice(&(SNAPLEN as u32).to_le_bytes());
+    header[20..24].copy_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+    header
+}
+
+/// What to add to generic timer uptimes for Unix time, zero while the realtime clock is unset.
+fn epoch() -> Duration {
+    match time::realtime() {
+        Some(now) => now
+            .checked_sub(time::time_manager().uptime())
+            .unwrap_or_default(),
+        None => Duration::default(),
+    }
+}
+
+/// Free space in the send buffer.
+fn room(socket: &TcpSocket) -> usize {
+    socket.send_capacity() - socket.send_queue()
+}
+
+/// The source and destination addresses, the protocol and the payload of an IP packet.
+fn ip_packet(frame: &EthernetFrame<&[u8]>) -> Option<(IpAddress, IpAddress, IpProtocol, &[u8])> {
+    match frame.ethertype() {
+        EthernetProtocol::Ipv4 => {
+            let packet = Ipv4Packet::new_checked(frame.payload()).ok()?;
+            Some((
+                packet.src_addr().into(),
+                packet.dst_addr().into(),
+                packet.protocol(),
+                packet.payload(),
+            ))
+        }
+        EthernetProtocol::Ipv6 => {
+            let packet = Ipv6Packet::new_checked(frame.payload()).ok()?;
+            Some((
+                packet.src_addr().into(),
+                packet.dst_addr().into(),
+                packet.next_header(),
+                packet.payload(),
+            ))
+        }
+        _ => None,
+    }
+}
+
+impl Capture {
+    fn push(&mut self, data: &[u8]) {
+        let record = Record {
+            timestamp: bsp::GENERIC_TIMER.uptime(),
+            len: data.len(),
+            data: data[..data.len().min(SNAPLEN)].to_vec(),
+        };
+
+        self.len += record.data.len();
+        self.records.push(record);
+        if self.len <= BUFFER_LEN {
+            return;
+        }
+
+        // Free a quarter of the buffer at once, so that moving the remaining records is rare.
+        let mut freed = 0;
+        let count = self
+            .records
+            .iter()
+            .take_while(|record| {
+                if self.len - freed <= BUFFER_LEN * 3 / 4 {
+                    return false;
+                }
+                freed += record.data.len();
+                true
+            })
+            .count();
+        self.records.drain(..count);
+        self.len -= freed;
+        self.overwritten += count as u64;
+    }
+
+    /// Sequence number of the next record to be captured.
+    fn end(&self) -> u64 {
+        self.overwritten + self.records.len() as u64
+    }
+
+    fn apply(&mut self, request: StreamRequest, sockets: &mut SocketSet) {
+        if let Some(stream) = self.stream.take() {
+            sockets.remove(stream.handle);
+            info!("pcap: stream on port {} closed", stream.port);
+        }
+
+        if let StreamRequest::Listen(port) = request {
+            let mut socket = TcpSocket::new(
+                TcpSocketBuffer::new(vec![0; 64]),
+                TcpSocketBuffer::new(vec![0; STREAM_BUFFER_LEN]),
+            );
+            if let Err(e) = socket.listen(port) {
+                warn!("pcap: failed to listen on port {}: {:?}", port, e);
+                return;
+            }
+            self.stream = Some(Stream {
+                port,
+                handle: sockets.add(socket),
+                next: None,
+            });
+            info!("pcap: streaming on port {}", port);
+        }
+    }
+
+    /// Send the file header and the records the client hasn't got yet, as far as they fit.
+    fn send(&mut self, sockets: &mut SocketSet) {
+        let stream = match self.stream.as_mut() {
+            Some(stream) => stream,
+            None => return,
+        };
+        let mut socket = sockets.get::<TcpSocket>(stream.handle);
+
+        if !socket.is_open() {
+            // The connection is gone, wait for the next client.
+            stream.next = None;
+            let _ = socket.listen(stream.port);
+            return;
+        }
+        if socket.state() == TcpState::CloseWait {
+            socket.close();
+        }
+        if !socket.may_send() {
+            return;
+        }
+
+        let next = match stream.next {
+            Some(next) => next,
+            None if room(&socket) >= 24 => {
+                let _ = socket.send_slice(&file_header());
+                self.overwritten
+            }
+            None => return,
+        };
+
+        // Records overwritten before they were sent are lost.
+        let mut next = next.max(self.overwritten);
+        let epoch = epoch();
+        while let Some(record) = self.records.get((next - self.overwritten) as usize) {
+            if room(&socket) < RECORD_HEADER_LEN + record.data.len() {
+                break;
+            }
+            let _ = socket.send_slice(&record.header(epoch));
+            let _ = socket.send_slice(&record.data);
+            next += 1;
+        }
+        stream.next = Some(next);
+    }
+
+    /// Whether `frame` belongs to the stream's connection.
+    fn is_stream(&self, frame: &[u8]) -> bool {
+        match self.stream.as_ref() {
+            Some(stream) => Filter {
+                protocol: Some(IpProtocol::Tcp),
+                port: Some(stream.port),
+                ..Filter::ANY
+            }
+            .matches(frame),
+            None => false,
+        }
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// Which frames to capture, all given conditions must hold.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Filter {
+    pub ethertype: Option<EthernetProtocol>,
+    /// Source or destination address.
+    pub host: Option<IpAddress>,
+    pub protocol: Option<IpProtocol>,
+    /// Source or destination TCP or UDP port.
+    pub port: Option<u16>,
+}
+
+impl Filter {
+    /// Capture everything.
+    pub const ANY: Filter = Filter {
+        ethertype: None,
+        host: None,
+        protocol: None,
+        port: None,
+    };
+
+    pub fn matches(&self, frame: &[u8]) -> bool {
+        let frame = match EthernetFrame::new_checked(frame) {
+            Ok(frame) => frame,
+            Err(_) => return *self == Filter::ANY,
+        };
+        if self
+            .ethertype
+            .map_or(false, |ethertype| ethertype != frame.ethertype())
+        {
+            return false;
+        }
+        if self.host.is_none() && self.protocol.is_none() && self.port.is_none() {
+            return true;
+        }
+
+        let (src, dst, protocol, payload) = match ip_packet(&frame) {
+            Some(packet) => packet,
+            None => return false,
+        };
+        if self.host.map_or(false, |host| host != src && host != dst) {
+            return false;
+        }
+        if self.protocol.map_or(false, |p| p != protocol) {
+            return false;
+        }
+        match self.port {
+            Some(port) => match protocol {
+                IpProtocol::Tcp | IpProtocol::Udp if payload.len() >= 4 => {
+                    let src = u16::from_be_bytes([payload[0], payload[1]]);
+                    let dst = u16::from_be_bytes([payload[2], payload[3]]);
+                    port == src || port == dst
+                }
+                _ => false,
+            },
+            None => true,
+        }
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if *self == Filter::ANY {
+            return f.write_str("any");
+        }
+        let mut sep = "";
+        if let Some(ethertype) = self.ethertype {
+            write!(f, "{}{}", sep, ethertype)?;
+            sep = " ";
+        }
+        if let Some(protocol) = self.protocol {
+            write!(f, "{}{}", sep, protocol)?;
+            sep = " ";
+        }
+        if let Some(host) = self.host {
+            write!(f, "{}host {}", sep, host)?;
+            sep = " ";
+        }
+        if let Some(port) = self.port {
+            write!(f, "{}port {}", sep, port)?;
+        }
+        Ok(())
+    }
+}
+
+/// Capture statistics, for the shell.
+#[derive(Copy, Clone, Debug)]
+pub struct Status {
+    pub enabled: bool,
+    pub filter: Filter,
+    pub frames: usize,
+    pub bytes: usize,
+    pub overwritten: u64,
+    /// The stream's port and whether a client is connected.
+    pub stream: Option<(u16, bool)>,
+}
+
+/// Copy `frame` into the buffer if capturing and it passes the filter.
+///
+/// Called by the USB Ethernet device for every frame it receives or sends.
+pub fn tee(frame: &[u8]) {
+    exec_with_irq_masked(|| {
+        let mut capture = CAPTURE.lock();
+        if capture.enabled && capture.filter.matches(frame) && !capture.is_stream(frame) {
+            capture.push(frame);
+        }
+    })
+}
+
+/// Start capturing frames that pass `filter`, dropping what was captured before.
+pub fn start(filter: Filter) {
+    exec_with_irq_masked(|| {
+        let mut capture = CAPTURE.lock();
+        capture.overwritten = capture.end();
+        capture.records.clear();
+        capture.len = 0;
+        capture.filter = filter;
+        capture.enabled = true;
+    })
+}
+
+/// Stop capturing, keeping the buffer.
+pub fn stop() {
+    exec_with_irq_masked(|| CAPTURE.lock().enabled = false)
+}
+
+/// Stream captured frames to clients connecting to `port` from the next poll on, or stop
+/// streaming for `None`.
+pub fn stream(port: Option<u16>) {
+    let request = match port {
+        Some(port) => StreamRequest::Listen(port),
+        None => StreamRequest::Close,
+    };
+
+    exec_with_irq_masked(|| CAPTURE.lock().pending = Some(request));
+    super::wake();
+}
+
+pub fn status() -> Status {
+    exec_with_irq_masked(|| {
+        let capture = CAPTURE.lock();
+        Status {
+            enabled: capture.enabled,
+            filter: capture.filter,
+            frames: capture.records.len(),
+            bytes: capture.len,
+            overwritten: capture.overwritten,
+            stream: capture
+                .stream
+                .as_ref()
+                .map(|stream| (stream.port, stream.next.is_some())),
+        }
+    })
+}
+
+/// The buffer as a pcap file.
+pub fn pcap() -> Vec<u8> {
+    exec_with_irq_masked(|| {
+        let capture = CAPTURE.lock();
+        let epoch = epoch();
+        let mut file = Vec::with_capacity(24 + capture.len + capture.records.len() * 16);
+
+        file.extend_from_slice(&file_header());
+        for record in capture.records.iter() {
+            file.extend_from_slice(&record.header(epoch));
+            file.extend_from_slice(&record.data);
+        }
+        file
+    })
+}
+
+/// Apply changes from the shell and stream new records.
+///
+/// Called from the ethernet driver's poll.
+pub fn poll(sockets: &mut SocketSet) {
+    exec_with_irq_masked(|| {
+        let mut capture = CAPTURE.lock();
+        if let Some(request) = capture.pending.take() {
+            capture.apply(request, sockets);
+        }
+        capture.send(sockets);
+    })
+}
+
+//--------------------------------------------------------------------------------------------------
+// Testing
+//--------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smoltcp::wire::{EthernetAddress, Ipv4Address};
+    use test_macros::kernel_test;
+
+    /// An Ethernet frame with an IPv4 UDP header from 10.0.0.1:1000 to 10.0.0.2:53.
+    fn udp_frame() -> Vec<u8> {
+        let mut buffer = vec![0; 14 + 20 + 8];
+        let mut frame = EthernetFrame::new_unchecked(&mut buffer[..]);
+        frame.set_dst_addr(EthernetAddress::BROADCAST);
+        frame.set_ethertype(EthernetProtocol::Ipv4);
+
+        let mut packet = Ipv4Packet::new_unchecked(frame.payload_mut());
+        packet.set_version(4);
+        packet.set_header_len(20);
+        packet.set_total_len(28);
+        packet.set_protocol(IpProtocol::Udp);
+        packet.set_src_addr(Ipv4Address::new(10, 0, 0, 1));
+        packet.set_dst_addr(Ipv4Address::new(10, 0, 0, 2));
+        packet.payload_mut()[..4].copy_from_slice(&[0x03, 0xe8, 0, 53]);
+        buffer
+    }
+
+    /// Filters match on each layer of a frame.
+    #[kernel_test]
+    fn filters_match_frames() {
+        let frame = udp_frame();
+        let filter = |filter: Filter| filter.matches(&frame);
+
+        assert!(filter(Filter::ANY));
+        assert!(filter(Filter {
+            ethertype: Some(EthernetProtocol::Ipv4),
+            host: Some(IpAddress::v4(10, 0, 0, 2)),
+            protocol: Some(IpProtocol::Udp),
+            port: Some(1000),
+        }));
+        assert!(!filter(Filter {
+            ethertype: Some(EthernetProtocol::Arp),
+            ..Filter::ANY
+        }));
+        assert!(!filter(Filter {
+            protocol: Some(IpProtocol::Tcp),
+            ..Filter::ANY
+        }));
+        assert!(!filter(Filter {
+            port: Some(80),
+            ..Filter::ANY
+        }));
+        assert!(!filter(Filter {
+            host: Some(IpAddress::v4(10, 0, 0, 3)),
+            ..Filter::ANY
+        }));
+    }
+
+    /// A record header holds the timestamp and both lengths in little endian.
+    #[kernel_test]
+    fn record_header_layout() {
+        let record = Record {
+            timestamp: Duration::new(5, 250_000_000),
+            len: 2000,
+            data: vec![0; SNAPLEN],
+        };
+        let header = record.header(Duration::from_secs(10));
+
+        assert_eq!(header[0..4], 15u32.to_le_bytes());
+        assert_eq!(header[4..8], 250_000u32.to_le_bytes());
+        assert_eq!(header[8..12], (SNAPLEN as u32).to_le_bytes());
+        assert_eq!(header[12..16], 2000u32.to_le_bytes());
+        assert_eq!(file_header()[0..4], [0xd4, 0xc3, 0xb2, 0xa1]);
+    }
+}
@@ -0,0 +1,308 @@
+//! DHCPv4 client, configuring the interface's address, default route and DNS server.
+//!
+//! The interface starts without an address. If no lease arrives within `TIMEOUT`, it falls back
+//! to the link-local `IP_ADDR` and keeps asking, switching over once a server answers. The shell
+//! can replace the lease with a static configuration and go back to DHCP at runtime.
+
+use super::{EthernetInterface, SocketSet, IP_ADDR};
+use crate::exception::asynchronous::exec_with_interrupts_masked;
+use crate::{info, warn};
+use alloc::vec;
+use core::fmt;
+use core::time::Duration;
+use smoltcp::dhcp::Dhcpv4Client;
+use smoltcp::phy::Device;
+use smoltcp::socket::{RawPacketMetadata, RawSocketBuffer};
+use smoltcp::time::Instant;
+use smoltcp::wire::{IpAddress, IpCidr, Ipv4Address, Ipv4Cidr};
+use spin::Mutex;
+
+/// How long to wait for a lease before falling back to the link-local address.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Buffer sizes for DHCP packets, as in the smoltcp example.
+const RX_BUFFER_LEN: usize = 900;
+const TX_BUFFER_LEN: usize = 600;
+
+enum Request {
+    Dhcp,
+    Static(Config),
+}
+
+struct Client {
+    dhcp: Option<Dhcpv4Client>,
+    /// Whether addresses come from DHCP, rather than the shell.
+    enabled: bool,
+    /// When the client started asking for a lease.
+    started: Instant,
+    config: Option<Config>,
+    /// A change from the shell, applied by the next poll.
+    pending: Option<Request>,
+}
+
+static CLIENT: Mutex<Client> = Mutex::new(Client {
+    dhcp: None,
+    enabled: true,
+    started: Instant { millis: 0 },
+    config: None,
+    pending: None,
+});
+
+fn link_local() -> Config {
+    Config {
+        address: Ipv4Cidr::new(Ipv4Address(IP_ADDR), 16),
+        router: None,
+        dns: None,
+        source: Source::LinkLocal,
+    }
+}
+
+/// Set the interface's first address and the default route, or remove both for `None`.
+fn configure<D>(iface: &mut EthernetInterface<D>, config: Option<&Config>)
+where
+    D: for<'d> Device<'d>,
+{
+    let address = match config {
+        Some(config) => IpCidr::Ipv4(config.address),
+        None => IpCidr::new(Ipv4Address::UNSPECIFIED.into(), 0),
+    };
+    iface.update_ip_addrs(|addrs| addrs[0] = address);
+
+    iface.routes_mut().update(|routes| {
+        routes.remove(&IpCidr::new(IpAddress::v4(0, 0, 0, 0), 0));
+    });
+    if let Some(router) = config.and_then(|config| config.router) {
+        if let Err(e) = iface.routes_mut().add_default_ipv4_route(router) {
+            warn!(
+                "dhcp: failed to add the default route via {}: {:?}",
+                router, e
+            );
+        }
+    }
+}
+
+impl Client {
+    fn apply<D>(&mut self, iface: &mut EthernetInterface<D>, config: Option<Config>)
+    where
+        D: for<'d> Device<'d>,
+    {
+        configure(iface, config.as_ref());
+        match &config {
+            Some(config) => info!("dhcp: {} {}", config.source, config.address),
+            None => info!("dhcp: address released"),
+        }
+        self.config = config;
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// Where the interface's configuration came from.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Source {
+    Dhcp,
+    /// No lease arrived in time.
+    LinkLocal,
+    Static,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Source::Dhcp => "dhcp",
+            Source::LinkLocal => "link-local",
+            Source::Static => "static",
+        };
+
+        f.write_str(name)
+    }
+}
+
+/// The interface's IPv4 configuration.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Config {
+    pub address: Ipv4Cidr,
+    /// The default gateway.
+    pub router: Option<Ipv4Address>,
+    pub dns: Option<Ipv4Address>,
+    pub source: Source,
+}
+
+/// Create the client's socket in `sockets` and start asking for a lease, dropping any earlier
+/// configuration.
+pub fn init(sockets: &mut SocketSet, now: Instant) {
+    let rx_buffer = RawSocketBuffer::new(vec![RawPacketMetadata::EMPTY; 1], vec![0; RX_BUFFER_LEN]);
+    let tx_buffer = RawSocketBuffer::new(vec![RawPacketMetadata::EMPTY; 1], vec![0; TX_BUFFER_LEN]);
+
+    let mut client = CLIENT.lock();
+    client.dhcp = Some(Dhcpv4Client::new(sockets, rx_buffer, tx_buffer, now));
+    client.enabled = true;
+    client.started = now;
+    client.config = None;
+    client.pending = None;
+}
+
+/// Apply changes from the shell, handle DHCP packets and fall back to the link-local address once
+/// the lease is overdue.
+///
+/// Called from the ethernet driver's poll.
+pub fn poll<D>(iface: &mut EthernetInterface<D>, sockets: &mut SocketSet, now: Instant)
+where
+    D: for<'d> Device<'d>,
+{
+    let mut client = CLIENT.lock();
+
+    match client.pending.take() {
+        Some(Request::Dhcp) => {
+            client.enabled = true;
+            client.started = now;
+            if let Some(dhcp) = client.dhcp.as_mut() {
+                dhcp.reset(now);
+            }
+            client.apply(iface, None);
+        }
+        Some(Request::Static(config)) => {
+            client.enabled = false;
+            client.apply(iface, Some(config));
+        }
+        None => (),
+    }
+    if !client.enabled {
+        return;
+    }
+
+    let lease = match client.dhcp.as_mut() {
+        Some(dhcp) => dhcp.poll(iface, sockets, now),
+        None => return,
+    };
+    match lease {
+        Ok(Some(lease)) => {
+            if let Some(address) = lease.address {
+                let config = Config {
+                    address,
+                    router: lease.router,
+                    dns: lease.dns_servers[0],
+                    source: Source::Dhcp,
+                };
+                if client.config != Some(config) {
+                    client.apply(iface, Some(config));
+                }
+            }
+        }
+        Ok(None) => (),
+        Err(e) => warn!("dhcp: {:?}", e),
+    }
+
+    if client.config.is_none() && Duration::from(now - client.started) >= TIMEOUT {
+        warn!("dhcp: no lease after {} s", TIMEOUT.as_secs());
+        client.apply(iface, Some(link_local()));
+    }
+}
+
+/// How long the client can wait before the next poll, `None` if it has nothing to do.
+pub fn next_poll(now: Instant) -> Option<Duration> {
+    let client = CLIENT.lock();
+    if !client.enabled {
+        return None;
+    }
+    let delay = Duration::from(client.dhcp.as_ref()?.next_poll(now));
+
+    match client.config {
+        Some(_) => Some(delay),
+        None => {
+            let waited = Duration::from(now - client.started);
+            Some(delay.min(TIMEOUT.checked_sub(waited).unwrap_or_default()))
+        }
+    }
+}
+
+/// Get the configuration from DHCP again, from the next poll on.
+pub fn use_dhcp() {
+    exec_with_interrupts_masked(|| CLIENT.lock().pending = Some(Request::Dhcp));
+    super::wake();
+}
+
+/// Ask for a new lease from the next poll on, unless the configuration is static.
+///
+/// For when the link comes back, possibly on another network.
+pub fn restart() {
+    exec_with_interrupts_masked(|| {
+        let mut client = CLIENT.lock();
+        if client.enabled {
+            client.pending = Some(Request::Dhcp);
+        }
+    });
+}
+
+/// Configure `address` and the default gateway `router` statically, from the next poll on.
+pub fn use_static(address: Ipv4Cidr, router: Option<Ipv4Address>) {
+    let config = Config {
+        address,
+        router,
+        dns: None,
+        source: Source::Static,
+    };
+
+    exec_with_interrupts_masked(|| CLIENT.lock().pending = Some(Request::Static(config)));
+    super::wake();
+}
+
+/// The current configuration, `None` while waiting for a lease.
+pub fn config() -> Option<Config> {
+    exec_with_interrupts_masked(|| CLIENT.lock().config)
+}
+
+//--------------------------------------------------------------------------------------------------
+// Testing
+//--------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::virt::{self, VirtualDevice};
+    use crate::net::{Link, ETH};
+    use smoltcp::wire::EthernetAddress;
+    use test_macros::kernel_test;
+
+    fn has_address(address: Ipv4Cidr) -> bool {
+        unsafe { ETH.ip_addrs() }.contains(&IpCidr::Ipv4(address))
+    }
+
+    fn source() -> Option<Source> {
+        config().map(|config| config.source)
+    }
+
+    /// Without a server the interface falls back to the link-local address after `TIMEOUT`. A
+    /// static address replaces it, and going back to DHCP starts over.
+    #[kernel_test]
+    fn link_local_fallback_and_static_switch() {
+        // Nobody answers on the other end.
+        let (device, _peer) = VirtualDevice::cable();
+        let hw_addr = EthernetAddress([0x02, 0, 0, 0, 0, 1]);
+        unsafe { ETH.initialize(Link::Virtual(device, hw_addr)) };
+
+        let mut polls = 0;
+        let fell_back = virt::poll_eth_until(&mut [], |_| {
+            polls += 1;
+            source() == Some(Source::LinkLocal)
+        });
+        // 10 ms of simulated time pass between polls.
+        let waited = Duration::from_millis((polls - 1) * 10);
+        assert!(fell_back);
+        assert!(waited > TIMEOUT - Duration::from_millis(500) && waited <= TIMEOUT);
+        assert!(has_address(link_local().address));
+
+        let address = Ipv4Cidr::new(Ipv4Address([192, 168, 1, 10]), 24);
+        use_static(address, Some(Ipv4Address([192, 168, 1, 1])));
+        assert!(virt::poll_eth_until(&mut [], |_| source() == Some(Source::Static)));
+        assert!(has_address(address));
+        assert!(!has_address(link_local().address));
+
+        use_dhcp();
+        assert!(virt::poll_eth_until(&mut [], |_| source().is_none()));
+        assert!(!has_address(address));
+        assert!(virt::poll_eth_until(&mut [], |_| source() == Some(Source::LinkLocal)));
+    }
+}
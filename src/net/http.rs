@@ -0,0 +1,420 @@
+//! HTTP/1.1 server for kernel status pages.
+//!
+//! Serves the state of the kernel as JSON on port 80, so that dashboards can poll a board instead
+//! of someone reading its serial console. It runs as a user task on the socket syscalls and serves
+//! one client at a time. Every response closes the connection, and so does a client that doesn't
+//! send its request head within `REQUEST_TIMEOUT`.
+
+use super::{link, rx, socket, ETH, USB};
+use crate::bsp::{self, device_driver::MBox};
+use crate::exception::asynchronous::{
+    exec_with_interrupts_masked, exec_with_irq_masked, interface::IRQManager,
+};
+use crate::memory::ALLOCATOR;
+use crate::{info, sched::SCHEDULER, syscall, time, warn};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use core::fmt::{self, Write};
+use core::str;
+use core::time::Duration;
+
+const PORT: u16 = 80;
+
+/// Longest request head accepted, the request line and headers.
+const MAX_HEAD_LEN: usize = 1024;
+
+/// How long a client has to send its request head, so that an idle one doesn't block the others.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Render = fn(&mut String) -> fmt::Result;
+
+/// The endpoints and how they render their document.
+static ENDPOINTS: [(&str, Render); 7] = [
+    ("/", index),
+    ("/tasks", tasks),
+    ("/memory", memory),
+    ("/irq", irq),
+    ("/uptime", uptime),
+    ("/board", board),
+    ("/net", net),
+];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Status {
+    Ok,
+    BadRequest,
+    NotFound,
+    MethodNotAllowed,
+    HeaderTooLarge,
+}
+
+impl Status {
+    fn code(self) -> u16 {
+        match self {
+            Status::Ok => 200,
+            Status::BadRequest => 400,
+            Status::NotFound => 404,
+            Status::MethodNotAllowed => 405,
+            Status::HeaderTooLarge => 431,
+        }
+    }
+
+    fn reason(self) -> &'static str {
+        match self {
+            Status::Ok => "OK",
+            Status::BadRequest => "Bad Request",
+            Status::NotFound => "Not Found",
+            Status::MethodNotAllowed => "Method Not Allowed",
+            Status::HeaderTooLarge => "Request Header Fields Too Large",
+        }
+    }
+}
+
+/// A request the server answers.
+#[derive(Debug, PartialEq)]
+struct Request<'a> {
+    /// `HEAD` leaves the body out.
+    head_only: bool,
+    path: &'a str,
+}
+
+/// A string as a JSON string literal.
+struct JsonStr<'a>(&'a str);
+
+impl fmt::Display for JsonStr<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_char('"')?;
+        for c in self.0.chars() {
+            match c {
+                '"' => f.write_str("\\\"")?,
+                '\\' => f.write_str("\\\\")?,
+                '\n' => f.write_str("\\n")?,
+                '\r' => f.write_str("\\r")?,
+                '\t' => f.write_str("\\t")?,
+                c if c.is_control() => write!(f, "\\u{:04x}", c as u32)?,
+                c => f.write_char(c)?,
+            }
+        }
+        f.write_char('"')
+    }
+}
+
+/// A value, or `null`.
+struct Nullable<T>(Option<T>);
+
+impl<T: fmt::Display> fmt::Display for Nullable<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.0 {
+            Some(value) => value.fmt(f),
+            None => f.write_str("null"),
+        }
+    }
+}
+
+/// Thousandths as a decimal number.
+struct Millis(u64);
+
+impl fmt::Display for Millis {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{:03}", self.0 / 1000, self.0 % 1000)
+    }
+}
+
+/// Write `items` as a JSON array, each rendered by `item`.
+fn array<T>(
+    out: &mut String,
+    items: impl IntoIterator<Item = T>,
+    mut item: impl FnMut(&mut String, T) -> fmt::Result,
+) -> fmt::Result {
+    out.push('[');
+    for (i, value) in items.into_iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        item(out, value)?;
+    }
+    out.push(']');
+    Ok(())
+}
+
+fn index(out: &mut String) -> fmt::Result {
+    array(out, ENDPOINTS.iter().skip(1), |out, (path, _)| {
+        write!(out, "{}", JsonStr(path))
+    })
+}
+
+fn tasks(out: &mut String) -> fmt::Result {
+    array(out, SCHEDULER.tasks(), |out, task| {
+        write!(
+            out,
+            r#"{{"pid":{},"state":{},"priority":{},"counter":{}}}"#,
+            task.pid,
+            JsonStr(task.state),
+            task.priority,
+            task.counter
+        )
+    })
+}
+
+fn memory(out: &mut String) -> fmt::Result {
+    let (size, used, free) = exec_with_irq_masked(|| {
+        let heap = ALLOCATOR.lock();
+        (heap.size(), heap.used(), heap.free())
+    });
+
+    write!(
+        out,
+        r#"{{"heap_size":{},"heap_used":{},"heap_free":{}}}"#,
+        size, used, free
+    )
+}
+
+fn irq(out: &mut String) -> fmt::Result {
+    let statistics = bsp::exception::asynchronous::irq_manager().statistics();
+
+    array(out, statistics, |out, irq| {
+        write!(
+            out,
+            r#"{{"name":{},"number":{},"core":{},"fiq":{},"count":{}}}"#,
+            JsonStr(irq.name),
+            irq.number,
+            Nullable(irq.core),
+            irq.fiq,
+            irq.count
+        )
+    })
+}
+
+fn uptime(out: &mut String) -> fmt::Result {
+    let up = time::time_manager().uptime();
+
+    write!(
+        out,
+        r#"{{"uptime":{},"realtime":{}}}"#,
+        Millis(up.as_millis() as u64),
+        Nullable(time::realtime().map(|now| now.as_secs()))
+    )
+}
+
+fn board(out: &mut String) -> fmt::Result {
+    let mut mbox = MBox::new();
+    // Revision codes and serial numbers read as hex, and a serial doesn't fit a JSON number.
+    let revision = mbox.board_revision().ok().map(|r| format!("\"{:x}\"", r));
+    let serial = mbox.serial_number().ok().map(|s| format!("\"{:016x}\"", s));
+    let temperature = mbox.core_temperature().ok().map(|t| Millis(t.into()));
+
+    write!(
+        out,
+        r#"{{"revision":{},"serial":{},"temperature":{}}}"#,
+        Nullable(revision),
+        Nullable(serial),
+        Nullable(temperature)
+    )
+}
+
+fn net(out: &mut String) -> fmt::Result {
+    // The USB driver is also used from its FIQ.
+    let mac = exec_with_interrupts_masked(|| USB.get_eth_addr());
+    let stats = link::stats();
+    let latency = rx::latency();
+    let mut addresses = unsafe { ETH.ip_addrs() };
+    // Slots still waiting for DHCP or SLAAC.
+    addresses.retain(|addr| !addr.address().is_unspecified());
+
+    write!(
+        out,
+        concat!(
+            r#"{{"mac":"{}","link":{},"link_changes":{},"#,
+            r#""rx_packets":{},"rx_bytes":{},"rx_dropped":{},"#,
+            r#""tx_packets":{},"tx_bytes":{},"tx_errors":{},"#,
+            r#""rx_latency_us":{},"rx_latency_max_us":{},"addresses":"#
+        ),
+        mac,
+        link::is_up(),
+        stats.link_changes,
+        stats.rx_packets,
+        stats.rx_bytes,
+        stats.rx_dropped,
+        stats.tx_packets,
+        stats.tx_bytes,
+        stats.tx_errors,
+        latency.average.as_micros(),
+        latency.max.as_micros()
+    )?;
+    array(out, addresses, |out, addr| write!(out, "\"{}\"", addr))?;
+    out.push('}');
+    Ok(())
+}
+
+/// The method and path of a request head.
+fn parse_request(head: &str) -> Result<Request, Status> {
+    let line = head.lines().next().ok_or(Status::BadRequest)?;
+    let mut parts = line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) => (method, target, version),
+        _ => return Err(Status::BadRequest),
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(Status::BadRequest);
+    }
+
+    let head_only = match method {
+        "GET" => false,
+        "HEAD" => true,
+        _ => return Err(Status::MethodNotAllowed),
+    };
+    // The query is ignored.
+    let path = target.split('?').next().unwrap_or(target);
+    Ok(Request { head_only, path })
+}
+
+/// The status and JSON body answering a request, and whether to leave the body out.
+fn respond(request: Result<Request, Status>) -> (Status, bool, String) {
+    let mut body = String::new();
+    let (status, head_only) = match request {
+        Ok(request) => match ENDPOINTS.iter().find(|(path, _)| *path == request.path) {
+            // Writing to a string can't fail.
+            Some((_, render)) => {
+                let _ = render(&mut body);
+                (Status::Ok, request.head_only)
+            }
+            None => (Status::NotFound, request.head_only),
+        },
+        Err(status) => (status, false),
+    };
+    if status != Status::Ok {
+        let _ = write!(body, r#"{{"error":{}}}"#, JsonStr(status.reason()));
+    }
+    (status, head_only, body)
+}
+
+fn send_all(fd: u64, mut data: &[u8]) -> Result<(), socket::Error> {
+    while !data.is_empty() {
+        let sent = syscall::send(fd, data)?;
+        data = &data[sent..];
+    }
+    Ok(())
+}
+
+fn now() -> Duration {
+    syscall::clock_gettime(syscall::CLOCK_MONOTONIC).unwrap_or_default()
+}
+
+/// Read a request head from `client` into `buf` and answer it.
+fn serve_client(client: u64, buf: &mut [u8]) -> Result<(), socket::Error> {
+    let deadline = now() + REQUEST_TIMEOUT;
+    let mut len = 0;
+
+    let head = loop {
+        if len == buf.len() {
+            break Err(Status::HeaderTooLarge);
+        }
+        let timeout = deadline.checked_sub(now()).ok_or(socket::Error::TimedOut)?;
+        match syscall::recv_timeout(client, &mut buf[len..], timeout)? {
+            // Closed before the head was complete.
+            0 => return Ok(()),
+            received => len += received,
+        }
+        if let Some(end) = buf[..len].windows(4).position(|w| w == b"\r\n\r\n") {
+            break str::from_utf8(&buf[..end]).map_err(|_| Status::BadRequest);
+        }
+    };
+
+    let (status, head_only, body) = respond(head.and_then(parse_request));
+    let mut header = String::new();
+    let _ = write!(
+        header,
+        concat!(
+            "HTTP/1.1 {} {}\r\n",
+            "Content-Type: application/json\r\n",
+            "Content-Length: {}\r\n",
+            "Connection: close\r\n\r\n"
+        ),
+        status.code(),
+        status.reason(),
+        body.len()
+    );
+
+    send_all(client, header.as_bytes())?;
+    if !head_only {
+        send_all(client, body.as_bytes())?;
+    }
+    Ok(())
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// The server task.
+///
+/// Spawned with `process::add_user_process`, as it runs on the socket syscalls.
+pub fn server_task() {
+    let serve = || -> Result<(), socket::Error> {
+        let listener = syscall::socket(socket::SOCK_STREAM)?;
+        syscall::bind(listener, PORT)?;
+        syscall::listen(listener, 4)?;
+        info!("http: listening on port {}", PORT);
+
+        // On the heap, the task stack is small.
+        let mut buf = vec![0; MAX_HEAD_LEN];
+        loop {
+            let client = syscall::accept(listener)?;
+            if let Err(e) = serve_client(client, &mut buf) {
+                warn!("http: {:?}", e);
+            }
+            let _ = syscall::close(client);
+        }
+    };
+
+    if let Err(e) = serve() {
+        warn!("http: {:?}", e);
+    }
+    syscall::exit();
+}
+
+//--------------------------------------------------------------------------------------------------
+// Testing
+//--------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_macros::kernel_test;
+
+    /// Request lines are split into method and path, malformed ones are rejected.
+    #[kernel_test]
+    fn parse_request_line() {
+        let get = parse_request("GET /tasks?all HTTP/1.1\r\nHost: pi");
+        assert_eq!(
+            get,
+            Ok(Request {
+                head_only: false,
+                path: "/tasks"
+            })
+        );
+        assert_eq!(
+            parse_request("HEAD / HTTP/1.0").map(|r| r.head_only),
+            Ok(true)
+        );
+        assert_eq!(
+            parse_request("POST / HTTP/1.1"),
+            Err(Status::MethodNotAllowed)
+        );
+        assert_eq!(parse_request("GET /"), Err(Status::BadRequest));
+        assert_eq!(parse_request("GET / SPDY/3"), Err(Status::BadRequest));
+        assert_eq!(
+            respond(parse_request("GET /nope HTTP/1.1")).0,
+            Status::NotFound
+        );
+    }
+
+    /// Strings are quoted and escaped.
+    #[kernel_test]
+    fn json_strings() {
+        let mut out = String::new();
+        write!(out, "{}", JsonStr("a \"b\"\\\n\u{1}")).unwrap();
+        assert_eq!(out, r#""a \"b\"\\\n\u0001""#);
+    }
+}
@@ -0,0 +1,144 @@
+//! Interface statistics and link state.
+//!
+//! The link's device and its receive path count frames and bytes. The link watcher reads the link
+//! state from the network task once a second and counts changes. When the link comes back,
+//! the cable may lead to another network, so DHCP starts over and routers are solicited again.
+
+use super::{dhcp, slaac};
+use crate::{info, warn};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use smoltcp::time::Instant;
+use spin::Mutex;
+
+/// How often the link state is read.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Counters {
+    rx_packets: AtomicU64,
+    rx_bytes: AtomicU64,
+    rx_dropped: AtomicU64,
+    tx_packets: AtomicU64,
+    tx_bytes: AtomicU64,
+    tx_errors: AtomicU64,
+    link_changes: AtomicU64,
+}
+
+static COUNTERS: Counters = Counters {
+    rx_packets: AtomicU64::new(0),
+    rx_bytes: AtomicU64::new(0),
+    rx_dropped: AtomicU64::new(0),
+    tx_packets: AtomicU64::new(0),
+    tx_bytes: AtomicU64::new(0),
+    tx_errors: AtomicU64::new(0),
+    link_changes: AtomicU64::new(0),
+};
+
+static LINK_UP: AtomicBool = AtomicBool::new(false);
+
+struct Watcher {
+    /// The link state seen last, `None` before the first check.
+    up: Option<bool>,
+    next_check: Instant,
+}
+
+static WATCHER: Mutex<Watcher> = Mutex::new(Watcher {
+    up: None,
+    next_check: Instant { millis: 0 },
+});
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// A snapshot of the counters.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Stats {
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    /// Frames lost on arrival because the receive queue or the frame pool was full.
+    pub rx_dropped: u64,
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+    /// Frames the device failed to send.
+    pub tx_errors: u64,
+    /// How often the link went down or up.
+    pub link_changes: u64,
+}
+
+/// Count a received frame of `len` bytes, queued for the stack.
+pub fn count_rx(len: usize) {
+    COUNTERS.rx_packets.fetch_add(1, Ordering::Relaxed);
+    COUNTERS.rx_bytes.fetch_add(len as u64, Ordering::Relaxed);
+}
+
+/// Count a received frame that was dropped.
+pub fn count_rx_dropped() {
+    COUNTERS.rx_dropped.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count a frame of `len` bytes sent, or failed to send.
+pub fn count_tx(len: usize, sent: bool) {
+    if sent {
+        COUNTERS.tx_packets.fetch_add(1, Ordering::Relaxed);
+        COUNTERS.tx_bytes.fetch_add(len as u64, Ordering::Relaxed);
+    } else {
+        COUNTERS.tx_errors.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub fn stats() -> Stats {
+    Stats {
+        rx_packets: COUNTERS.rx_packets.load(Ordering::Relaxed),
+        rx_bytes: COUNTERS.rx_bytes.load(Ordering::Relaxed),
+        rx_dropped: COUNTERS.rx_dropped.load(Ordering::Relaxed),
+        tx_packets: COUNTERS.tx_packets.load(Ordering::Relaxed),
+        tx_bytes: COUNTERS.tx_bytes.load(Ordering::Relaxed),
+        tx_errors: COUNTERS.tx_errors.load(Ordering::Relaxed),
+        link_changes: COUNTERS.link_changes.load(Ordering::Relaxed),
+    }
+}
+
+/// Whether the link was up at the last check.
+pub fn is_up() -> bool {
+    LINK_UP.load(Ordering::Relaxed)
+}
+
+/// Read the link state with `is_up` when it's due, and configure addresses again once the link is
+/// back.
+///
+/// Called from the ethernet driver's poll.
+pub fn poll(now: Instant, is_up: impl FnOnce() -> bool) {
+    let mut watcher = WATCHER.lock();
+    if now < watcher.next_check {
+        return;
+    }
+    watcher.next_check = now + CHECK_INTERVAL.into();
+
+    let up = is_up();
+    LINK_UP.store(up, Ordering::Relaxed);
+    let previous = watcher.up.replace(up);
+    if previous.map_or(true, |previous| previous == up) {
+        return;
+    }
+
+    COUNTERS.link_changes.fetch_add(1, Ordering::Relaxed);
+    if up {
+        info!("net: link up");
+        dhcp::restart();
+        slaac::restart(now);
+    } else {
+        warn!("net: link down");
+    }
+}
+
+/// How long until the next link check.
+pub fn next_poll(now: Instant) -> Duration {
+    let next_check = WATCHER.lock().next_check;
+
+    if next_check > now {
+        (next_check - now).into()
+    } else {
+        Duration::from_millis(0)
+    }
+}
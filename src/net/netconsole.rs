@@ -0,0 +1,173 @@
+//! Network console: sends console output as UDP datagrams, like Linux' netconsole.
+//!
+//! Output is buffered and sent from the ethernet driver's poll. While the poll runs, output of the
+//! polling core is dropped, so that the network stack logging about a datagram does not produce
+//! the next one.
+
+use super::SocketSet;
+use crate::exception::asynchronous::exec_with_irq_masked;
+use crate::{console, cpu, ring_buffer::RingBuffer, warn};
+use alloc::vec;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use smoltcp::socket::{SocketHandle, UdpPacketMetadata, UdpSocket, UdpSocketBuffer};
+use smoltcp::wire::{IpAddress, IpEndpoint, Ipv4Address};
+use spin::Mutex;
+
+/// The default receiver, port 6666 of the host end of the link-local network.
+pub const DEFAULT_TARGET: IpEndpoint = IpEndpoint {
+    addr: IpAddress::Ipv4(Ipv4Address([169, 254, 32, 1])),
+    port: 6666,
+};
+
+const LOCAL_PORT: u16 = 6665;
+const BUFFER_SIZE: usize = 8192;
+const MAX_PAYLOAD: usize = 1024;
+
+struct NetConsoleInner {
+    buffer: RingBuffer<BUFFER_SIZE>,
+    /// Bytes lost because the buffer was full.
+    dropped: usize,
+    handle: Option<SocketHandle>,
+    target: IpEndpoint,
+}
+
+impl fmt::Write for NetConsoleInner {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            if !self.buffer.push(byte) {
+                self.dropped += 1;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+static MUTED: [AtomicBool; cpu::NUM_CORES] = [
+    AtomicBool::new(false),
+    AtomicBool::new(false),
+    AtomicBool::new(false),
+    AtomicBool::new(false),
+];
+
+//--------------------------------------------------------------------------------------------------
+// Public Definitions
+//--------------------------------------------------------------------------------------------------
+
+pub struct NetConsole {
+    inner: Mutex<NetConsoleInner>,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Global instances
+//--------------------------------------------------------------------------------------------------
+
+pub static NETCONSOLE: NetConsole = NetConsole {
+    inner: Mutex::new(NetConsoleInner {
+        buffer: RingBuffer::new(),
+        dropped: 0,
+        handle: None,
+        target: DEFAULT_TARGET,
+    }),
+};
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// Create the console's socket in `sockets`.
+pub fn init(sockets: &mut SocketSet) {
+    let rx_buffer = UdpSocketBuffer::new(vec![UdpPacketMetadata::EMPTY; 1], vec![0; 0]);
+    let tx_buffer =
+        UdpSocketBuffer::new(vec![UdpPacketMetadata::EMPTY; 4], vec![0; 4 * MAX_PAYLOAD]);
+    let mut socket = UdpSocket::new(rx_buffer, tx_buffer);
+
+    if let Err(e) = socket.bind(LOCAL_PORT) {
+        warn!("netconsole: failed to bind port {}: {:?}", LOCAL_PORT, e);
+        return;
+    }
+    let handle = sockets.add(socket);
+    exec_with_irq_masked(|| NETCONSOLE.inner.lock().handle = Some(handle));
+}
+
+/// Send output to `target` from now on.
+pub fn set_target(target: IpEndpoint) {
+    exec_with_irq_masked(|| NETCONSOLE.inner.lock().target = target);
+}
+
+/// Drop output of the executing core while `muted` is set.
+pub fn set_muted(muted: bool) {
+    MUTED[cpu::core_id::<usize>()].store(muted, Ordering::Relaxed);
+}
+
+/// Whether output of the executing core is dropped. The Telnet console drops it as well.
+pub fn is_muted() -> bool {
+    MUTED[cpu::core_id::<usize>()].load(Ordering::Relaxed)
+}
+
+/// Send buffered output, as far as the socket takes it.
+///
+/// Called from the ethernet driver's poll, with the executing core muted.
+pub fn poll(sockets: &mut SocketSet) {
+    let (handle, target) = match exec_with_irq_masked(|| {
+        let inner = NETCONSOLE.inner.lock();
+        inner.handle.map(|handle| (handle, inner.target))
+    }) {
+        Some(socket) => socket,
+        None => return,
+    };
+    let mut socket = sockets.get::<UdpSocket>(handle);
+    let mut payload = [0; MAX_PAYLOAD];
+
+    while socket.can_send() {
+        let len = exec_with_irq_masked(|| {
+            let mut inner = NETCONSOLE.inner.lock();
+            let mut len = 0;
+            while len < MAX_PAYLOAD {
+                match inner.buffer.pop() {
+                    Some(byte) => payload[len] = byte,
+                    None => break,
+                }
+                len += 1;
+            }
+            len
+        });
+        if len == 0 {
+            return;
+        }
+
+        if let Err(e) = socket.send_slice(&payload[..len], target) {
+            warn!("netconsole: dropping {} bytes: {:?}", len, e);
+            return;
+        }
+    }
+}
+
+impl NetConsole {
+    /// Number of bytes lost because the network did not keep up.
+    pub fn dropped(&self) -> usize {
+        exec_with_irq_masked(|| self.inner.lock().dropped)
+    }
+}
+
+//------------------------------------------------------------------------------
+// OS Interface Code
+//------------------------------------------------------------------------------
+
+impl console::interface::Write for NetConsole {
+    fn write_char(&self, c: char) {
+        let mut buf = [0; 4];
+        let _ = self.write_fmt(format_args!("{}", c.encode_utf8(&mut buf)));
+    }
+
+    fn write_fmt(&self, args: fmt::Arguments) -> fmt::Result {
+        if is_muted() {
+            return Ok(());
+        }
+
+        exec_with_irq_masked(|| fmt::Write::write_fmt(&mut *self.inner.lock(), args))
+    }
+
+    fn flush(&self) {}
+}
@@ -0,0 +1,171 @@
+//! ICMP and ICMPv6 echo client.
+//!
+//! One request is outstanding at a time. `start()` queues it, the ethernet driver's poll sends it
+//! and collects the reply, and `result()` reports the round trip time.
+
+use super::SocketSet;
+use crate::exception::asynchronous::exec_with_irq_masked;
+use crate::{time, warn};
+use alloc::vec;
+use core::time::Duration;
+use smoltcp::phy::ChecksumCapabilities;
+use smoltcp::socket::{
+    IcmpEndpoint, IcmpPacketMetadata, IcmpSocket, IcmpSocketBuffer, SocketHandle,
+};
+use smoltcp::wire::{Icmpv4Packet, Icmpv4Repr, Icmpv6Packet, Icmpv6Repr, IpAddress, Ipv6Address};
+use spin::Mutex;
+
+/// Identifies our echo requests.
+const IDENT: u16 = 0x7270;
+const BUFFER_LEN: usize = 256;
+
+#[derive(Copy, Clone)]
+enum Request {
+    /// Waiting for the poll to send it.
+    Queued(IpAddress),
+    /// Sent at the given uptime.
+    Sent(Duration),
+    /// Answered after the given round trip time.
+    Answered(Duration),
+}
+
+struct Pinger {
+    handle: Option<SocketHandle>,
+    seq_no: u16,
+    request: Option<Request>,
+}
+
+static PINGER: Mutex<Pinger> = Mutex::new(Pinger {
+    handle: None,
+    seq_no: 0,
+    request: None,
+});
+
+/// The sequence number of an echo reply.
+fn reply_seq_no(payload: &[u8], from: IpAddress) -> Option<u16> {
+    match from {
+        IpAddress::Ipv4(_) => {
+            let packet = Icmpv4Packet::new_checked(payload).ok()?;
+            match Icmpv4Repr::parse(&packet, &ChecksumCapabilities::default()).ok()? {
+                Icmpv4Repr::EchoReply { seq_no, .. } => Some(seq_no),
+                _ => None,
+            }
+        }
+        IpAddress::Ipv6(_) => {
+            // The checksum covers our address, which we don't know here, but the interface
+            // checked it already.
+            let packet = Icmpv6Packet::new_checked(payload).ok()?;
+            let to = IpAddress::Ipv6(Ipv6Address::UNSPECIFIED);
+            match Icmpv6Repr::parse(&from, &to, &packet, &ChecksumCapabilities::ignored()).ok()? {
+                Icmpv6Repr::EchoReply { seq_no, .. } => Some(seq_no),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// Create the client's socket in `sockets`.
+pub fn init(sockets: &mut SocketSet) {
+    let rx_buffer = IcmpSocketBuffer::new(vec![IcmpPacketMetadata::EMPTY; 4], vec![0; BUFFER_LEN]);
+    let tx_buffer = IcmpSocketBuffer::new(vec![IcmpPacketMetadata::EMPTY; 1], vec![0; BUFFER_LEN]);
+    let mut socket = IcmpSocket::new(rx_buffer, tx_buffer);
+
+    if let Err(e) = socket.bind(IcmpEndpoint::Ident(IDENT)) {
+        warn!("ping: failed to bind: {:?}", e);
+        return;
+    }
+    PINGER.lock().handle = Some(sockets.add(socket));
+}
+
+/// Send an echo request to `target` right away, replacing any outstanding one.
+///
+/// Returns the request's sequence number.
+pub fn start(target: IpAddress) -> Result<u16, &'static str> {
+    exec_with_irq_masked(|| {
+        let mut pinger = PINGER.lock();
+        if pinger.handle.is_none() {
+            return Err("network not initialized");
+        }
+
+        pinger.seq_no = pinger.seq_no.wrapping_add(1);
+        pinger.request = Some(Request::Queued(target));
+        super::wake();
+        Ok(pinger.seq_no)
+    })
+}
+
+/// The round trip time of the last request, once answered.
+pub fn result() -> Option<Duration> {
+    exec_with_irq_masked(|| match PINGER.lock().request {
+        Some(Request::Answered(rtt)) => Some(rtt),
+        _ => None,
+    })
+}
+
+/// Send a queued request and collect replies.
+///
+/// Called from the ethernet driver's poll.
+pub fn poll(sockets: &mut SocketSet) {
+    let mut pinger = PINGER.lock();
+    let handle = match pinger.handle {
+        Some(handle) => handle,
+        None => return,
+    };
+    let mut socket = sockets.get::<IcmpSocket>(handle);
+    let checksum = ChecksumCapabilities::default();
+    let now = time::time_manager().uptime();
+
+    while let Ok((payload, from)) = socket.recv() {
+        let sent_at = match (reply_seq_no(payload, from), pinger.request) {
+            (Some(seq_no), Some(Request::Sent(sent_at))) if seq_no == pinger.seq_no => sent_at,
+            _ => continue,
+        };
+        pinger.request = Some(Request::Answered(now - sent_at));
+    }
+
+    let target = match pinger.request {
+        Some(Request::Queued(target)) if socket.can_send() => target,
+        _ => return,
+    };
+    // Echoed back, but unused: the send time is kept locally.
+    let payload = (now.as_nanos() as u64).to_be_bytes();
+    let sent = match target {
+        IpAddress::Ipv6(_) => {
+            let repr = Icmpv6Repr::EchoRequest {
+                ident: IDENT,
+                seq_no: pinger.seq_no,
+                data: &payload,
+            };
+            // The socket fills in the source address and the checksum covering it.
+            let from = IpAddress::Ipv6(Ipv6Address::UNSPECIFIED);
+            socket.send(repr.buffer_len(), target).map(|buffer| {
+                repr.emit(
+                    &from,
+                    &target,
+                    &mut Icmpv6Packet::new_unchecked(buffer),
+                    &checksum,
+                )
+            })
+        }
+        _ => {
+            let repr = Icmpv4Repr::EchoRequest {
+                ident: IDENT,
+                seq_no: pinger.seq_no,
+                data: &payload,
+            };
+            socket
+                .send(repr.buffer_len(), target)
+                .map(|buffer| repr.emit(&mut Icmpv4Packet::new_unchecked(buffer), &checksum))
+        }
+    };
+
+    match sent {
+        Ok(()) => pinger.request = Some(Request::Sent(now)),
+        Err(e) => warn!("ping: failed to send request: {:?}", e),
+    }
+}
@@ -0,0 +1,132 @@
+//! Receive path from the USB interrupt to the network task.
+//!
+//! The Ethernet driver hands every frame to `frame_received` from the USB FIQ. It is copied into a
+//! frame from the pool and queued, and the network task is woken to run the interface's poll,
+//! which takes frames off the queue. Nothing is allocated in the interrupt: when the queue or the
+//! pool is full, the frame is dropped like on a congested link.
+//!
+//! Waking the task raises an IPI on its core, which switches to it right away instead of at the
+//! next scheduler tick. `latency` tells how long frames wait in the queue.
+
+use super::{link, Frame, USB, USPI_FRAME_BUFFER_SIZE};
+use crate::exception::asynchronous::exec_with_interrupts_masked;
+use crate::{cpu, sched, time};
+use alloc::collections::VecDeque;
+use core::slice;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+
+/// Frames queued at most for the network task.
+pub const QUEUE_LEN: usize = 32;
+
+/// Received frames and the uptime they were queued at.
+static QUEUE: Mutex<Option<VecDeque<(Frame, Duration)>>> = Mutex::new(None);
+
+/// Set when the network task has something to do.
+static WAKE: AtomicBool = AtomicBool::new(false);
+
+/// The network task and its core, 0 until it runs.
+static TASK_PID: AtomicU64 = AtomicU64::new(0);
+static TASK_CORE: AtomicUsize = AtomicUsize::new(0);
+
+/// Time frames spent in the queue, in microseconds.
+static WAITED_FRAMES: AtomicU64 = AtomicU64::new(0);
+static WAITED_TOTAL_US: AtomicU64 = AtomicU64::new(0);
+static WAITED_MAX_US: AtomicU64 = AtomicU64::new(0);
+
+unsafe extern "C" fn frame_received(buffer: *const u8, len: u32) {
+    if len > USPI_FRAME_BUFFER_SIZE {
+        return;
+    }
+    let mut frame = match Frame::from_pool() {
+        Some(frame) => frame,
+        None => {
+            link::count_rx_dropped();
+            return;
+        }
+    };
+    frame.set_len(len);
+    frame
+        .as_mut_slice()
+        .copy_from_slice(slice::from_raw_parts(buffer, len as usize));
+
+    // A frame that doesn't fit goes back to the pool once the queue is unlocked.
+    let rejected = match QUEUE.lock().as_mut() {
+        Some(queue) if queue.len() < QUEUE_LEN => {
+            queue.push_back((frame, time::time_manager().uptime()));
+            None
+        }
+        _ => Some(frame),
+    };
+    match rejected {
+        Some(_) => link::count_rx_dropped(),
+        None => link::count_rx(len as usize),
+    }
+    wake();
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// Allocate the queue and have the Ethernet driver deliver frames into it.
+pub fn init() -> bool {
+    *QUEUE.lock() = Some(VecDeque::with_capacity(QUEUE_LEN));
+    USB.register_receive_handler(Some(frame_received))
+}
+
+/// How long received frames waited for the stack.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Latency {
+    pub frames: u64,
+    pub average: Duration,
+    pub max: Duration,
+}
+
+/// The oldest received frame.
+pub fn pop() -> Option<Frame> {
+    let (frame, queued) = exec_with_interrupts_masked(|| QUEUE.lock().as_mut()?.pop_front())?;
+    let waited = time::time_manager()
+        .uptime()
+        .checked_sub(queued)
+        .unwrap_or_default();
+
+    let waited_us = waited.as_micros() as u64;
+    WAITED_FRAMES.fetch_add(1, Ordering::Relaxed);
+    WAITED_TOTAL_US.fetch_add(waited_us, Ordering::Relaxed);
+    WAITED_MAX_US.fetch_max(waited_us, Ordering::Relaxed);
+    Some(frame)
+}
+
+/// The time from the FIQ queueing a frame to the stack taking it, since boot.
+pub fn latency() -> Latency {
+    let frames = WAITED_FRAMES.load(Ordering::Relaxed);
+    let total = WAITED_TOTAL_US.load(Ordering::Relaxed);
+
+    Latency {
+        frames,
+        average: Duration::from_micros(total.checked_div(frames).unwrap_or(0)),
+        max: Duration::from_micros(WAITED_MAX_US.load(Ordering::Relaxed)),
+    }
+}
+
+/// Register the executing task, pinned to the executing core, as the one `wake` switches to.
+pub fn set_task(pid: u64) {
+    TASK_CORE.store(cpu::core_id::<usize>(), Ordering::Relaxed);
+    TASK_PID.store(pid, Ordering::Release);
+}
+
+/// Have the network task poll the interface as soon as possible.
+pub fn wake() {
+    WAKE.store(true, Ordering::Release);
+    match TASK_PID.load(Ordering::Acquire) {
+        0 => (),
+        pid => sched::wake(pid, TASK_CORE.load(Ordering::Relaxed)),
+    }
+}
+
+/// Whether the network task was woken since the last call.
+pub fn take_wakeup() -> bool {
+    WAKE.swap(false, Ordering::AcqRel)
+}
@@ -0,0 +1,361 @@
+//! IPv6 stateless address autoconfiguration (RFC 4862).
+//!
+//! The interface always has the link-local address derived from its MAC. Router advertisements
+//! with an autonomous /64 prefix add a global address derived the same way, and make the sender
+//! the default router, both for as long as the advertisement says. Router solicitations go out at
+//! boot so that no one has to wait for the next unsolicited advertisement. Duplicate address
+//! detection is left out: the addresses come from the MAC, which is unique on the link.
+//!
+//! smoltcp answers neighbor solicitations and echo requests itself, but ignores router
+//! advertisements, so they are picked up with a raw socket.
+
+use super::{EthernetInterface, SocketSet};
+use crate::exception::asynchronous::exec_with_interrupts_masked;
+use crate::{info, warn};
+use alloc::vec;
+use core::time::Duration;
+use smoltcp::phy::{ChecksumCapabilities, Device};
+use smoltcp::socket::{RawPacketMetadata, RawSocket, RawSocketBuffer, SocketHandle};
+use smoltcp::time::{self, Instant};
+use smoltcp::wire::{
+    EthernetAddress, Icmpv6Packet, Icmpv6Repr, IpAddress, IpCidr, IpProtocol, IpVersion,
+    Ipv6Address, Ipv6Cidr, Ipv6Packet, Ipv6Repr, NdiscPrefixInfoFlags, NdiscRepr,
+};
+use spin::Mutex;
+
+/// The interface's IPv6 addresses, see `net::create_interface`. The global address goes first,
+/// as smoltcp picks the first one as the source.
+const SLOTS: [usize; 2] = [2, 3];
+
+/// Solicitations sent at most, and how far apart (RFC 4861, section 10).
+const MAX_SOLICITATIONS: u8 = 3;
+const SOLICITATION_INTERVAL: Duration = Duration::from_secs(4);
+
+/// The only prefix length the interface identifiers fit.
+const PREFIX_LEN: u8 = 64;
+
+const ALL_ROUTERS: Ipv6Address =
+    Ipv6Address([0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+
+const BUFFER_LEN: usize = 1024;
+
+/// What a router advertisement tells us.
+struct Advert {
+    router: Ipv6Address,
+    router_lifetime: time::Duration,
+    /// An autonomous prefix, and how long addresses in it stay valid.
+    prefix: Option<(Ipv6Address, time::Duration)>,
+}
+
+struct Slaac {
+    handle: Option<SocketHandle>,
+    solicitations: u8,
+    next_solicitation: Instant,
+    /// The global address, and when it expires.
+    address: Option<(Ipv6Cidr, Instant)>,
+    /// The default router, and until when.
+    router: Option<(Ipv6Address, Instant)>,
+}
+
+static SLAAC: Mutex<Slaac> = Mutex::new(Slaac {
+    handle: None,
+    solicitations: 0,
+    next_solicitation: Instant { millis: 0 },
+    address: None,
+    router: None,
+});
+
+/// The modified EUI-64 interface identifier (RFC 4291, appendix A).
+fn interface_id(mac: EthernetAddress) -> [u8; 8] {
+    let mac = mac.0;
+
+    [
+        mac[0] ^ 0x02,
+        mac[1],
+        mac[2],
+        0xff,
+        0xfe,
+        mac[3],
+        mac[4],
+        mac[5],
+    ]
+}
+
+/// The address for `mac` in the /64 `prefix`.
+fn address_in(prefix: Ipv6Address, mac: EthernetAddress) -> Ipv6Address {
+    let mut address = prefix.0;
+
+    address[8..].copy_from_slice(&interface_id(mac));
+    Ipv6Address(address)
+}
+
+fn parse_advert(packet: &[u8]) -> Option<Advert> {
+    let packet = Ipv6Packet::new_checked(packet).ok()?;
+    let ip_repr = Ipv6Repr::parse(&packet).ok()?;
+    // Routers advertise from their link-local address, and nothing forwarded counts.
+    if ip_repr.next_header != IpProtocol::Icmpv6
+        || ip_repr.hop_limit != 255
+        || !ip_repr.src_addr.is_link_local()
+    {
+        return None;
+    }
+
+    let icmp = Icmpv6Packet::new_checked(packet.payload()).ok()?;
+    let icmp_repr = Icmpv6Repr::parse(
+        &IpAddress::Ipv6(ip_repr.src_addr),
+        &IpAddress::Ipv6(ip_repr.dst_addr),
+        &icmp,
+        &ChecksumCapabilities::default(),
+    )
+    .ok()?;
+    match icmp_repr {
+        Icmpv6Repr::Ndisc(NdiscRepr::RouterAdvert {
+            router_lifetime,
+            prefix_info,
+            ..
+        }) => Some(Advert {
+            router: ip_repr.src_addr,
+            router_lifetime,
+            prefix: prefix_info
+                .filter(|info| {
+                    info.flags.contains(NdiscPrefixInfoFlags::ADDRCONF)
+                        && info.prefix_len == PREFIX_LEN
+                        && !info.prefix.is_link_local()
+                })
+                .map(|info| (info.prefix, info.valid_lifetime)),
+        }),
+        _ => None,
+    }
+}
+
+fn send_solicitation(
+    socket: &mut RawSocket,
+    src_addr: Ipv6Address,
+    mac: EthernetAddress,
+) -> smoltcp::Result<()> {
+    let icmp_repr = Icmpv6Repr::Ndisc(NdiscRepr::RouterSolicit { lladdr: Some(mac) });
+    let ip_repr = Ipv6Repr {
+        src_addr,
+        dst_addr: ALL_ROUTERS,
+        next_header: IpProtocol::Icmpv6,
+        payload_len: icmp_repr.buffer_len(),
+        hop_limit: 255,
+    };
+
+    let mut packet =
+        Ipv6Packet::new_unchecked(socket.send(ip_repr.buffer_len() + icmp_repr.buffer_len())?);
+    ip_repr.emit(&mut packet);
+    icmp_repr.emit(
+        &IpAddress::Ipv6(src_addr),
+        &IpAddress::Ipv6(ALL_ROUTERS),
+        &mut Icmpv6Packet::new_unchecked(packet.payload_mut()),
+        &ChecksumCapabilities::default(),
+    );
+    Ok(())
+}
+
+impl Slaac {
+    /// Take in an advertisement, returning whether the configuration changed.
+    fn update(&mut self, advert: Advert, mac: EthernetAddress, now: Instant) -> bool {
+        let (address, router) = (self.address, self.router);
+
+        if let Some((prefix, valid_lifetime)) = advert.prefix {
+            let cidr = Ipv6Cidr::new(address_in(prefix, mac), PREFIX_LEN);
+            if valid_lifetime.total_millis() != 0 {
+                self.address = Some((cidr, now + valid_lifetime));
+            } else if matches!(self.address, Some((current, _)) if current == cidr) {
+                self.address = None;
+            }
+        }
+        if advert.router_lifetime.total_millis() != 0 {
+            self.router = Some((advert.router, now + advert.router_lifetime));
+        } else if matches!(self.router, Some((current, _)) if current == advert.router) {
+            self.router = None;
+        }
+
+        address.map(|(cidr, _)| cidr) != self.address.map(|(cidr, _)| cidr)
+            || router.map(|(router, _)| router) != self.router.map(|(router, _)| router)
+    }
+
+    /// Drop what expired, returning whether anything did.
+    fn expire(&mut self, now: Instant) -> bool {
+        let mut expired = false;
+
+        if matches!(self.address, Some((_, until)) if until <= now) {
+            self.address = None;
+            expired = true;
+        }
+        if matches!(self.router, Some((_, until)) if until <= now) {
+            self.router = None;
+            expired = true;
+        }
+        expired
+    }
+
+    fn configure<D>(&self, iface: &mut EthernetInterface<D>)
+    where
+        D: for<'d> Device<'d>,
+    {
+        let local = Ipv6Cidr::new(link_local(iface.ethernet_addr()), PREFIX_LEN);
+        let addresses = match self.address {
+            Some((global, _)) => [global, local],
+            None => [local, Ipv6Cidr::new(Ipv6Address::UNSPECIFIED, 128)],
+        };
+        iface.update_ip_addrs(|addrs| {
+            for (&slot, &address) in SLOTS.iter().zip(addresses.iter()) {
+                addrs[slot] = IpCidr::Ipv6(address);
+            }
+        });
+
+        iface.routes_mut().update(|routes| {
+            routes.remove(&IpCidr::new(IpAddress::Ipv6(Ipv6Address::UNSPECIFIED), 0));
+        });
+        if let Some((router, _)) = self.router {
+            if let Err(e) = iface.routes_mut().add_default_ipv6_route(router) {
+                warn!(
+                    "slaac: failed to add the default route via {}: {:?}",
+                    router, e
+                );
+            }
+        }
+
+        match self.address {
+            Some((global, _)) => info!("slaac: {} via {:?}", global, self.router.map(|r| r.0)),
+            None => info!("slaac: link-local only"),
+        }
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// The link-local address for `mac`.
+pub fn link_local(mac: EthernetAddress) -> Ipv6Address {
+    address_in(
+        Ipv6Address([0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+        mac,
+    )
+}
+
+/// Create the socket for router advertisements in `sockets`, and solicit some with the next poll.
+pub fn init(sockets: &mut SocketSet, now: Instant) {
+    let rx_buffer = RawSocketBuffer::new(vec![RawPacketMetadata::EMPTY; 4], vec![0; BUFFER_LEN]);
+    let tx_buffer = RawSocketBuffer::new(vec![RawPacketMetadata::EMPTY; 1], vec![0; BUFFER_LEN]);
+    let socket = RawSocket::new(IpVersion::Ipv6, IpProtocol::Icmpv6, rx_buffer, tx_buffer);
+
+    let mut slaac = SLAAC.lock();
+    slaac.handle = Some(sockets.add(socket));
+    slaac.next_solicitation = now;
+}
+
+/// Handle router advertisements, solicit them while there is no router and drop what expired.
+///
+/// Called from the ethernet driver's poll.
+pub fn poll<D>(iface: &mut EthernetInterface<D>, sockets: &mut SocketSet, now: Instant)
+where
+    D: for<'d> Device<'d>,
+{
+    let mut slaac = SLAAC.lock();
+    let handle = match slaac.handle {
+        Some(handle) => handle,
+        None => return,
+    };
+    let mac = iface.ethernet_addr();
+    let mut socket = sockets.get::<RawSocket>(handle);
+    let mut changed = false;
+
+    while let Ok(packet) = socket.recv() {
+        if let Some(advert) = parse_advert(packet) {
+            changed |= slaac.update(advert, mac, now);
+        }
+    }
+
+    if slaac.router.is_none()
+        && slaac.solicitations < MAX_SOLICITATIONS
+        && now >= slaac.next_solicitation
+        && socket.can_send()
+    {
+        match send_solicitation(&mut socket, link_local(mac), mac) {
+            Ok(()) => {
+                slaac.solicitations += 1;
+                slaac.next_solicitation = now + time::Duration::from(SOLICITATION_INTERVAL);
+            }
+            Err(e) => warn!("slaac: failed to send a router solicitation: {:?}", e),
+        }
+    }
+
+    if slaac.expire(now) || changed {
+        slaac.configure(iface);
+    }
+}
+
+/// How long the client can wait before the next poll, `None` if it has nothing to do.
+pub fn next_poll(now: Instant) -> Option<Duration> {
+    let slaac = SLAAC.lock();
+    slaac.handle?;
+
+    let solicitation = match slaac.router {
+        None if slaac.solicitations < MAX_SOLICITATIONS => Some(slaac.next_solicitation),
+        _ => None,
+    };
+    let deadline = [
+        solicitation,
+        slaac.address.map(|(_, until)| until),
+        slaac.router.map(|(_, until)| until),
+    ]
+    .iter()
+    .flatten()
+    .min()
+    .copied()?;
+
+    if deadline > now {
+        Some((deadline - now).into())
+    } else {
+        Some(Duration::from_millis(0))
+    }
+}
+
+/// Solicit router advertisements again, from `now` on.
+///
+/// For when the link comes back, possibly on another network.
+pub fn restart(now: Instant) {
+    let mut slaac = SLAAC.lock();
+    slaac.solicitations = 0;
+    slaac.next_solicitation = now;
+}
+
+/// The default router, once one advertised itself.
+pub fn router() -> Option<Ipv6Address> {
+    exec_with_interrupts_masked(|| SLAAC.lock().router.map(|(router, _)| router))
+}
+
+//--------------------------------------------------------------------------------------------------
+// Testing
+//--------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_macros::kernel_test;
+
+    /// Interface identifiers are the MAC with the universal/local bit flipped and ff:fe inserted.
+    #[kernel_test]
+    fn addresses_derive_from_mac() {
+        let mac = EthernetAddress([0xb8, 0x27, 0xeb, 0x12, 0x34, 0x56]);
+        let prefix = Ipv6Address([0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+        assert_eq!(
+            link_local(mac),
+            Ipv6Address([
+                0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0xba, 0x27, 0xeb, 0xff, 0xfe, 0x12, 0x34, 0x56
+            ])
+        );
+        assert_eq!(
+            address_in(prefix, mac),
+            Ipv6Address([
+                0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 1, 0xba, 0x27, 0xeb, 0xff, 0xfe, 0x12, 0x34, 0x56
+            ])
+        );
+    }
+}
@@ -0,0 +1,194 @@
+//! Simple Network Time Protocol client (RFC 4330).
+//!
+//! Sets the realtime clock from a single server and resyncs periodically. The round trip delay is
+//! assumed symmetric.
+
+use super::SocketSet;
+use crate::{info, time, warn};
+use alloc::vec;
+use core::convert::TryInto;
+use core::time::Duration;
+use smoltcp::socket::{SocketHandle, UdpPacketMetadata, UdpSocket, UdpSocketBuffer};
+use smoltcp::wire::{IpAddress, IpEndpoint, Ipv4Address};
+use spin::Mutex;
+
+/// The default server, the host end of the link-local network.
+pub const DEFAULT_SERVER: Ipv4Address = Ipv4Address([169, 254, 32, 1]);
+
+const NTP_PORT: u16 = 123;
+const LOCAL_PORT: u16 = 49123;
+const PACKET_LEN: usize = 48;
+
+/// Seconds from the NTP era 0 (1900) to the Unix epoch.
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+const SYNC_INTERVAL: Duration = Duration::from_secs(64);
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Leap indicator 0, version 4, mode 3 (client).
+const CLIENT_HEADER: u8 = 0b00_100_011;
+const MODE_SERVER: u8 = 4;
+const LEAP_UNSYNCHRONIZED: u8 = 3;
+
+struct SntpClient {
+    handle: Option<SocketHandle>,
+    server: Ipv4Address,
+    /// Uptime at which the next request is due.
+    next_request: Duration,
+    /// Uptime at which the outstanding request was sent.
+    sent_at: Option<Duration>,
+}
+
+static CLIENT: Mutex<SntpClient> = Mutex::new(SntpClient {
+    handle: None,
+    server: DEFAULT_SERVER,
+    next_request: Duration::from_secs(0),
+    sent_at: None,
+});
+
+fn read_be_u32(bytes: &[u8]) -> u64 {
+    u32::from_be_bytes(bytes[..4].try_into().unwrap()) as u64
+}
+
+/// The request's transmit timestamp, echoed by the server as the originate timestamp.
+///
+/// Only used to match replies to requests, so the uptime is good enough.
+fn cookie(sent_at: Duration) -> [u8; 8] {
+    (sent_at.as_nanos() as u64).to_be_bytes()
+}
+
+/// Extract the server's transmit time, as time since the Unix epoch, from a reply.
+fn parse_reply(packet: &[u8], cookie: &[u8; 8]) -> Result<Duration, &'static str> {
+    if packet.len() < PACKET_LEN {
+        return Err("short packet");
+    }
+    if packet[0] & 0b111 != MODE_SERVER {
+        return Err("not a server reply");
+    }
+    if packet[0] >> 6 == LEAP_UNSYNCHRONIZED {
+        return Err("server clock unsynchronized");
+    }
+    if packet[1] == 0 {
+        return Err("kiss-o'-death");
+    }
+    if &packet[24..32] != cookie {
+        return Err("originate timestamp mismatch");
+    }
+
+    let secs = read_be_u32(&packet[40..44]);
+    let fraction = read_be_u32(&packet[44..48]);
+    if secs < NTP_UNIX_OFFSET {
+        return Err("transmit timestamp before the Unix epoch");
+    }
+
+    Ok(Duration::new(
+        secs - NTP_UNIX_OFFSET,
+        ((fraction * 1_000_000_000) >> 32) as u32,
+    ))
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// Create the client's socket in `sockets`.
+pub fn init(sockets: &mut SocketSet) {
+    let rx_buffer = UdpSocketBuffer::new(
+        vec![UdpPacketMetadata::EMPTY; 4],
+        vec![0; 4 * PACKET_LEN],
+    );
+    let tx_buffer = UdpSocketBuffer::new(vec![UdpPacketMetadata::EMPTY; 1], vec![0; PACKET_LEN]);
+    let mut socket = UdpSocket::new(rx_buffer, tx_buffer);
+
+    if let Err(e) = socket.bind(LOCAL_PORT) {
+        warn!("sntp: failed to bind port {}: {:?}", LOCAL_PORT, e);
+        return;
+    }
+    CLIENT.lock().handle = Some(sockets.add(socket));
+}
+
+/// Sync against `server` from now on, starting with the next poll.
+pub fn set_server(server: Ipv4Address) {
+    let mut client = CLIENT.lock();
+
+    client.server = server;
+    client.sent_at = None;
+    client.next_request = Duration::from_secs(0);
+}
+
+/// Handle replies and send a request if one is due.
+///
+/// Called from the ethernet driver's poll.
+pub fn poll(sockets: &mut SocketSet) {
+    let mut client = CLIENT.lock();
+    let handle = match client.handle {
+        Some(handle) => handle,
+        None => return,
+    };
+    let mut socket = sockets.get::<UdpSocket>(handle);
+    let now = time::time_manager().uptime();
+
+    while let Ok((packet, endpoint)) = socket.recv() {
+        let sent_at = match client.sent_at {
+            Some(sent_at) => sent_at,
+            None => continue,
+        };
+        if endpoint.addr != IpAddress::Ipv4(client.server) || endpoint.port != NTP_PORT {
+            continue;
+        }
+
+        match parse_reply(packet, &cookie(sent_at)) {
+            Ok(server_time) => {
+                time::set_realtime(server_time + (now - sent_at) / 2);
+                client.sent_at = None;
+                client.next_request = now + SYNC_INTERVAL;
+                info!("sntp: synced with {}", client.server);
+            }
+            Err(e) => warn!("sntp: dropping reply from {}: {}", endpoint, e),
+        }
+    }
+
+    if now < client.next_request || !socket.can_send() {
+        return;
+    }
+
+    let mut request = [0; PACKET_LEN];
+    request[0] = CLIENT_HEADER;
+    request[40..48].copy_from_slice(&cookie(now));
+
+    let server = IpEndpoint::new(IpAddress::Ipv4(client.server), NTP_PORT);
+    match socket.send_slice(&request, server) {
+        Ok(()) => {
+            client.sent_at = Some(now);
+            client.next_request = now + RETRY_INTERVAL;
+        }
+        Err(e) => warn!("sntp: failed to send request: {:?}", e),
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Testing
+//--------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_macros::kernel_test;
+
+    /// A valid reply yields the server's transmit time since the Unix epoch.
+    #[kernel_test]
+    fn parse_reply_converts_ntp_era() {
+        let sent_at = Duration::from_millis(1234);
+        let mut reply = [0; PACKET_LEN];
+        reply[0] = 0b00_100_100;
+        reply[1] = 2;
+        reply[24..32].copy_from_slice(&cookie(sent_at));
+        reply[40..44].copy_from_slice(&((NTP_UNIX_OFFSET + 10) as u32).to_be_bytes());
+        reply[44..48].copy_from_slice(&0x8000_0000u32.to_be_bytes());
+
+        let now = parse_reply(&reply, &cookie(sent_at)).unwrap();
+        assert_eq!(now, Duration::from_millis(10_500));
+
+        assert!(parse_reply(&reply, &cookie(Duration::from_millis(1))).is_err());
+    }
+}
@@ -0,0 +1,751 @@
+//! BSD-like TCP and UDP sockets for tasks, behind the socket syscalls.
+//!
+//! Handles are small numbers private to each task. A listening socket keeps a pool of smoltcp
+//! sockets listening on its port, and `accept()` hands out one that got a connection and replaces
+//! it. Closed connections linger until smoltcp is done with them. UDP sockets are bound to an
+//! ephemeral port when they first send, unless bound before. The sockets of exiting tasks are
+//! closed.
+//!
+//! Sockets are dual-stack: bound ports accept IPv4 and IPv6 alike, and tasks pass IPv4 addresses
+//! as IPv4-mapped IPv6 ones (`::ffff:a.b.c.d`).
+//!
+//! The operations that block return `Ok(None)` instead, and the syscall layer parks the task and
+//! retries them until they complete.
+
+use super::{SocketSet, TcpSocket, ETH};
+use crate::exception::asynchronous::exec_with_irq_masked;
+use crate::memory;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+use smoltcp::socket::{
+    SocketHandle, TcpSocketBuffer, TcpState, UdpPacketMetadata, UdpSocket, UdpSocketBuffer,
+};
+use smoltcp::wire::{IpAddress, IpEndpoint, Ipv6Address};
+use spin::Mutex;
+
+const BUFFER_LEN: usize = 4096;
+
+/// Datagrams a UDP socket buffers at most, in each direction.
+const MAX_DATAGRAMS: usize = 8;
+
+/// Connections a listening socket accepts at most before `accept()` takes them.
+const MAX_BACKLOG: usize = 8;
+
+/// Local ports of outgoing connections, the IANA dynamic range.
+const EPHEMERAL_PORTS: Range<u16> = 49152..65535;
+
+enum State {
+    /// Created, and bound to `port` unless it is 0.
+    Idle {
+        port: u16,
+    },
+    Listening {
+        port: u16,
+        pool: Vec<SocketHandle>,
+    },
+    Connected(SocketHandle),
+    /// A UDP socket, sending to `peer` unless told otherwise.
+    Datagram {
+        handle: SocketHandle,
+        port: u16,
+        peer: Option<IpEndpoint>,
+    },
+}
+
+struct Entry {
+    pid: u64,
+    fd: u64,
+    kind: u64,
+    state: State,
+}
+
+struct Table {
+    entries: Vec<Entry>,
+    next_port: u16,
+}
+
+static TABLE: Mutex<Table> = Mutex::new(Table {
+    entries: Vec::new(),
+    next_port: EPHEMERAL_PORTS.start,
+});
+
+/// Connections closed by their owners, removed from the socket set once smoltcp is done with them.
+static ORPHANS: Mutex<Vec<SocketHandle>> = Mutex::new(Vec::new());
+
+fn new_tcp_socket() -> TcpSocket {
+    TcpSocket::new(
+        TcpSocketBuffer::new(vec![0; BUFFER_LEN]),
+        TcpSocketBuffer::new(vec![0; BUFFER_LEN]),
+    )
+}
+
+/// A listening socket for the pool of a listener on `port`.
+fn listen_on(sockets: &mut SocketSet, port: u16) -> Result<SocketHandle, Error> {
+    let mut socket = new_tcp_socket();
+    socket.listen(port).map_err(|_| Error::InvalidArgument)?;
+
+    Ok(sockets.add(socket))
+}
+
+fn new_udp_socket() -> UdpSocket {
+    UdpSocket::new(
+        UdpSocketBuffer::new(
+            vec![UdpPacketMetadata::EMPTY; MAX_DATAGRAMS],
+            vec![0; BUFFER_LEN],
+        ),
+        UdpSocketBuffer::new(
+            vec![UdpPacketMetadata::EMPTY; MAX_DATAGRAMS],
+            vec![0; BUFFER_LEN],
+        ),
+    )
+}
+
+/// Run `f` on the socket set.
+fn with_sockets<T>(f: impl FnOnce(&mut SocketSet) -> Result<T, Error>) -> Result<T, Error> {
+    unsafe { ETH.with_sockets(f) }.unwrap_or(Err(Error::NetworkDown))
+}
+
+/// Check that `[addr, addr + len)` is memory a task may pass, and turn it into a slice.
+///
+/// # Safety
+///
+/// - The memory must not be accessed otherwise while the slice lives.
+unsafe fn user_buffer(addr: u64, len: u64) -> Result<&'static mut [u8], Error> {
+    match addr.checked_add(len) {
+        Some(end) if addr != 0 && end <= memory::map::mmio::BASE as u64 => Ok(
+            core::slice::from_raw_parts_mut(addr as *mut u8, len as usize),
+        ),
+        _ => Err(Error::InvalidArgument),
+    }
+}
+
+/// Check that `addr` points to an `Endpoint` a task may pass.
+///
+/// # Safety
+///
+/// - The memory must not be accessed otherwise while the reference lives.
+unsafe fn user_endpoint(addr: u64) -> Result<&'static mut Endpoint, Error> {
+    if addr % core::mem::align_of::<Endpoint>() as u64 != 0 {
+        return Err(Error::InvalidArgument);
+    }
+    let buffer = user_buffer(addr, core::mem::size_of::<Endpoint>() as u64)?;
+
+    Ok(&mut *(buffer.as_mut_ptr() as *mut Endpoint))
+}
+
+/// Read the remote `Endpoint` at `addr`.
+///
+/// # Safety
+///
+/// - The memory must not change meanwhile.
+unsafe fn user_peer(addr: u64) -> Result<IpEndpoint, Error> {
+    let peer = IpEndpoint::from(*user_endpoint(addr)?);
+    if peer.port == 0 || peer.addr.is_unspecified() {
+        return Err(Error::InvalidArgument);
+    }
+
+    Ok(peer)
+}
+
+/// Queue a datagram to `peer`. `Ok(None)` while the send buffer is full.
+fn send_datagram(
+    sockets: &mut SocketSet,
+    handle: SocketHandle,
+    data: &[u8],
+    peer: IpEndpoint,
+) -> Result<Option<u64>, Error> {
+    if data.len() > BUFFER_LEN {
+        return Err(Error::InvalidArgument);
+    }
+
+    match sockets.get::<UdpSocket>(handle).send_slice(data, peer) {
+        Ok(()) => Ok(Some(data.len() as u64)),
+        Err(smoltcp::Error::Exhausted) => Ok(None),
+        Err(_) => Err(Error::InvalidArgument),
+    }
+}
+
+/// Take a datagram, truncated to `buffer`, and return its length and sender. `Ok(None)` until one
+/// arrives.
+fn recv_datagram(
+    sockets: &mut SocketSet,
+    handle: SocketHandle,
+    buffer: &mut [u8],
+) -> Result<Option<(u64, IpEndpoint)>, Error> {
+    let mut socket = sockets.get::<UdpSocket>(handle);
+    if !socket.can_recv() {
+        return Ok(None);
+    }
+
+    let (data, peer) = socket.recv().map_err(|_| Error::NotConnected)?;
+    let len = data.len().min(buffer.len());
+    buffer[..len].copy_from_slice(&data[..len]);
+
+    Ok(Some((len as u64, peer)))
+}
+
+impl Table {
+    fn entry(&mut self, pid: u64, fd: u64) -> Result<&mut Entry, Error> {
+        self.entries
+            .iter_mut()
+            .find(|entry| entry.pid == pid && entry.fd == fd)
+            .ok_or(Error::BadHandle)
+    }
+
+    /// Whether a socket of `kind` is bound to `port`. TCP and UDP ports are separate.
+    fn port_in_use(&self, kind: u64, port: u16) -> bool {
+        self.entries
+            .iter()
+            .filter(|entry| entry.kind == kind)
+            .any(|entry| match entry.state {
+                State::Idle { port: bound }
+                | State::Listening { port: bound, .. }
+                | State::Datagram { port: bound, .. } => bound == port,
+                State::Connected(_) => false,
+            })
+    }
+
+    fn ephemeral_port(&mut self) -> u16 {
+        let port = self.next_port;
+
+        self.next_port = if port + 1 == EPHEMERAL_PORTS.end {
+            EPHEMERAL_PORTS.start
+        } else {
+            port + 1
+        };
+        port
+    }
+
+    /// The UDP socket of the entry, bound to `port` first if it is not bound yet. Port 0 picks an
+    /// ephemeral one.
+    fn datagram(&mut self, pid: u64, fd: u64, port: u16) -> Result<SocketHandle, Error> {
+        let entry = self.entry(pid, fd)?;
+        match entry.state {
+            _ if entry.kind != SOCK_DGRAM => return Err(Error::InvalidArgument),
+            State::Datagram { handle, .. } if port == 0 => return Ok(handle),
+            State::Idle { .. } => (),
+            _ => return Err(Error::InvalidArgument),
+        }
+
+        let port = match port {
+            0 => loop {
+                let port = self.ephemeral_port();
+                if !self.port_in_use(SOCK_DGRAM, port) {
+                    break port;
+                }
+            },
+            port if self.port_in_use(SOCK_DGRAM, port) => return Err(Error::AddressInUse),
+            port => port,
+        };
+        let handle = with_sockets(|sockets| {
+            let mut socket = new_udp_socket();
+            socket.bind(port).map_err(|_| Error::InvalidArgument)?;
+            Ok(sockets.add(socket))
+        })?;
+        self.entry(pid, fd)?.state = State::Datagram {
+            handle,
+            port,
+            peer: None,
+        };
+
+        Ok(handle)
+    }
+
+    /// Remove the entry and release its sockets.
+    fn close(&mut self, pid: u64, fd: u64) -> Result<(), Error> {
+        let index = self
+            .entries
+            .iter()
+            .position(|entry| entry.pid == pid && entry.fd == fd)
+            .ok_or(Error::BadHandle)?;
+
+        match self.entries.remove(index).state {
+            State::Idle { .. } => Ok(()),
+            State::Listening { pool, .. } => with_sockets(|sockets| {
+                for handle in pool {
+                    sockets.remove(handle);
+                }
+                Ok(())
+            }),
+            State::Connected(handle) => with_sockets(|sockets| {
+                sockets.get::<TcpSocket>(handle).close();
+                ORPHANS.lock().push(handle);
+                Ok(())
+            }),
+            State::Datagram { handle, .. } => with_sockets(|sockets| {
+                sockets.remove(handle);
+                Ok(())
+            }),
+        }
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// Socket types for `socket()`: TCP and UDP.
+pub const SOCK_STREAM: u64 = 1;
+pub const SOCK_DGRAM: u64 = 2;
+
+/// An IPv6 address, or an IPv4-mapped one, and a port, as passed to `connect()` and `sendto()`
+/// and filled in by `recvfrom()`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Endpoint {
+    pub addr: [u8; 16],
+    pub port: u16,
+}
+
+impl From<IpEndpoint> for Endpoint {
+    fn from(endpoint: IpEndpoint) -> Self {
+        let addr = match endpoint.addr {
+            IpAddress::Ipv4(addr) => {
+                let mut mapped = [0; 16];
+                mapped[10..12].copy_from_slice(&[0xff, 0xff]);
+                mapped[12..].copy_from_slice(&addr.0);
+                mapped
+            }
+            IpAddress::Ipv6(addr) => addr.0,
+            _ => [0; 16],
+        };
+
+        Self {
+            addr,
+            port: endpoint.port,
+        }
+    }
+}
+
+impl From<Endpoint> for IpEndpoint {
+    fn from(endpoint: Endpoint) -> Self {
+        let addr = Ipv6Address(endpoint.addr);
+        let addr = match addr.as_ipv4() {
+            Some(addr) => IpAddress::Ipv4(addr),
+            None => IpAddress::Ipv6(addr),
+        };
+
+        IpEndpoint::new(addr, endpoint.port)
+    }
+}
+
+/// Socket errors, passed to tasks in x7.
+#[repr(u64)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Error {
+    /// The task has no such socket.
+    BadHandle = 1,
+    InvalidArgument = 2,
+    /// Another socket is bound to the port.
+    AddressInUse = 3,
+    NotConnected = 4,
+    ConnectionRefused = 5,
+    /// The socket type is not supported.
+    Unsupported = 6,
+    /// The network is not initialized.
+    NetworkDown = 7,
+    /// Nothing arrived before the call's timeout.
+    TimedOut = 8,
+}
+
+impl Error {
+    /// The error for a code in x7, `None` for 0.
+    pub fn from_code(code: u64) -> Option<Self> {
+        let error = match code {
+            0 => return None,
+            1 => Error::BadHandle,
+            2 => Error::InvalidArgument,
+            3 => Error::AddressInUse,
+            4 => Error::NotConnected,
+            5 => Error::ConnectionRefused,
+            6 => Error::Unsupported,
+            7 => Error::NetworkDown,
+            8 => Error::TimedOut,
+            _ => Error::InvalidArgument,
+        };
+
+        Some(error)
+    }
+}
+
+/// Create a socket of `kind` for `pid` and return its handle, the lowest one free.
+pub fn socket(pid: u64, kind: u64) -> Result<u64, Error> {
+    if kind != SOCK_STREAM && kind != SOCK_DGRAM {
+        return Err(Error::Unsupported);
+    }
+
+    exec_with_irq_masked(|| {
+        let mut table = TABLE.lock();
+        let fd = (0..).find(|&fd| table.entry(pid, fd).is_err()).unwrap();
+        table.entries.push(Entry {
+            pid,
+            fd,
+            kind,
+            state: State::Idle { port: 0 },
+        });
+
+        Ok(fd)
+    })
+}
+
+/// Bind the socket to the local `port`, on all addresses.
+pub fn bind(pid: u64, fd: u64, port: u16) -> Result<(), Error> {
+    exec_with_irq_masked(|| {
+        let mut table = TABLE.lock();
+        if port == 0 {
+            return Err(Error::InvalidArgument);
+        }
+        if table.entry(pid, fd)?.kind == SOCK_DGRAM {
+            return table.datagram(pid, fd, port).map(|_| ());
+        }
+        if table.port_in_use(SOCK_STREAM, port) {
+            return Err(Error::AddressInUse);
+        }
+
+        match &mut table.entry(pid, fd)?.state {
+            State::Idle { port: bound } if *bound == 0 => *bound = port,
+            _ => return Err(Error::InvalidArgument),
+        }
+        Ok(())
+    })
+}
+
+/// Accept up to `backlog` connections on the bound port.
+pub fn listen(pid: u64, fd: u64, backlog: u64) -> Result<(), Error> {
+    exec_with_irq_masked(|| {
+        let mut table = TABLE.lock();
+        let entry = table.entry(pid, fd)?;
+        let port = match entry.state {
+            State::Idle { port } if port != 0 => port,
+            _ => return Err(Error::InvalidArgument),
+        };
+
+        let backlog = (backlog as usize).max(1).min(MAX_BACKLOG);
+        let pool = with_sockets(|sockets| {
+            let mut pool = Vec::with_capacity(backlog);
+            for _ in 0..backlog {
+                pool.push(listen_on(sockets, port)?);
+            }
+            Ok(pool)
+        })?;
+        entry.state = State::Listening { port, pool };
+
+        Ok(())
+    })
+}
+
+/// Take a connection from the listening socket, as a new socket. `Ok(None)` until one arrives.
+pub fn accept(pid: u64, fd: u64) -> Result<Option<u64>, Error> {
+    exec_with_irq_masked(|| {
+        let mut table = TABLE.lock();
+        let (port, pool) = match &mut table.entry(pid, fd)?.state {
+            State::Listening { port, pool } => (*port, pool),
+            _ => return Err(Error::InvalidArgument),
+        };
+
+        let connection = with_sockets(|sockets| {
+            for slot in pool.iter_mut() {
+                match sockets.get::<TcpSocket>(*slot).state() {
+                    TcpState::Listen | TcpState::SynReceived => continue,
+                    // Reset before the handshake completed.
+                    TcpState::Closed => {
+                        sockets.get::<TcpSocket>(*slot).listen(port).ok();
+                    }
+                    _ => {
+                        let replacement = listen_on(sockets, port)?;
+                        return Ok(Some(core::mem::replace(slot, replacement)));
+                    }
+                }
+            }
+            Ok(None)
+        })?;
+
+        let handle = match connection {
+            Some(handle) => handle,
+            None => return Ok(None),
+        };
+        let new_fd = (0..).find(|&fd| table.entry(pid, fd).is_err()).unwrap();
+        table.entries.push(Entry {
+            pid,
+            fd: new_fd,
+            kind: SOCK_STREAM,
+            state: State::Connected(handle),
+        });
+
+        Ok(Some(new_fd))
+    })
+}
+
+/// Start connecting to the `Endpoint` at `to`. Wait for the connection with `poll_connect()`.
+///
+/// UDP sockets only remember the peer, for `send()`.
+///
+/// # Safety
+///
+/// - The endpoint must not change meanwhile.
+pub unsafe fn connect(pid: u64, fd: u64, to: u64) -> Result<(), Error> {
+    let remote = user_peer(to)?;
+
+    exec_with_irq_masked(|| {
+        let mut table = TABLE.lock();
+        if table.entry(pid, fd)?.kind == SOCK_DGRAM {
+            table.datagram(pid, fd, 0)?;
+            if let State::Datagram { peer, .. } = &mut table.entry(pid, fd)?.state {
+                *peer = Some(remote);
+            }
+            return Ok(());
+        }
+
+        let local_port = match table.entry(pid, fd)?.state {
+            State::Idle { port } => port,
+            _ => return Err(Error::InvalidArgument),
+        };
+        let local_port = if local_port == 0 {
+            table.ephemeral_port()
+        } else {
+            local_port
+        };
+
+        let handle = with_sockets(|sockets| {
+            let mut socket = new_tcp_socket();
+            socket
+                .connect(remote, local_port)
+                .map_err(|_| Error::InvalidArgument)?;
+            Ok(sockets.add(socket))
+        })?;
+        table.entry(pid, fd)?.state = State::Connected(handle);
+
+        Ok(())
+    })
+}
+
+/// `Ok(Some(0))` once the connection started by `connect()` is established, `Ok(None)` before.
+pub fn poll_connect(pid: u64, fd: u64) -> Result<Option<u64>, Error> {
+    exec_with_irq_masked(|| {
+        let mut table = TABLE.lock();
+        let handle = match table.entry(pid, fd)?.state {
+            State::Connected(handle) => handle,
+            State::Datagram { .. } => return Ok(Some(0)),
+            _ => return Err(Error::NotConnected),
+        };
+
+        with_sockets(|sockets| match sockets.get::<TcpSocket>(handle).state() {
+            TcpState::SynSent | TcpState::SynReceived => Ok(None),
+            TcpState::Closed => Err(Error::ConnectionRefused),
+            _ => Ok(Some(0)),
+        })
+    })
+}
+
+/// Queue bytes from `[addr, addr + len)` for sending and return how many. `Ok(None)` while the
+/// send buffer is full.
+///
+/// # Safety
+///
+/// - The buffer must not change meanwhile.
+pub unsafe fn send(pid: u64, fd: u64, addr: u64, len: u64) -> Result<Option<u64>, Error> {
+    let buffer = user_buffer(addr, len)?;
+
+    exec_with_irq_masked(|| {
+        let handle = match TABLE.lock().entry(pid, fd)?.state {
+            State::Connected(handle) => handle,
+            State::Datagram {
+                handle,
+                peer: Some(peer),
+                ..
+            } => return with_sockets(|sockets| send_datagram(sockets, handle, buffer, peer)),
+            _ => return Err(Error::NotConnected),
+        };
+
+        with_sockets(|sockets| {
+            let mut socket = sockets.get::<TcpSocket>(handle);
+            if !socket.may_send() {
+                return Err(Error::NotConnected);
+            }
+            if !socket.can_send() {
+                return Ok(None);
+            }
+
+            let sent = socket.send_slice(buffer).map_err(|_| Error::NotConnected)?;
+            Ok(Some(sent as u64))
+        })
+    })
+}
+
+/// Receive bytes into `[addr, addr + len)` and return how many, 0 once the peer closed the
+/// connection. `Ok(None)` until data arrives. Datagrams longer than the buffer are truncated.
+///
+/// # Safety
+///
+/// - The buffer must not be accessed otherwise meanwhile.
+pub unsafe fn recv(pid: u64, fd: u64, addr: u64, len: u64) -> Result<Option<u64>, Error> {
+    let buffer = user_buffer(addr, len)?;
+
+    exec_with_irq_masked(|| {
+        let handle = match TABLE.lock().entry(pid, fd)?.state {
+            State::Connected(handle) => handle,
+            State::Datagram { handle, .. } => {
+                return with_sockets(|sockets| {
+                    Ok(recv_datagram(sockets, handle, buffer)?.map(|(len, _)| len))
+                })
+            }
+            _ => return Err(Error::NotConnected),
+        };
+
+        with_sockets(|sockets| {
+            let mut socket = sockets.get::<TcpSocket>(handle);
+            if socket.can_recv() {
+                let received = socket.recv_slice(buffer).map_err(|_| Error::NotConnected)?;
+                Ok(Some(received as u64))
+            } else if !socket.may_recv() {
+                Ok(Some(0))
+            } else {
+                Ok(None)
+            }
+        })
+    })
+}
+
+/// Send the datagram in `[addr, addr + len)` to the `Endpoint` at `to` and return its length.
+/// `Ok(None)` while the send buffer is full.
+///
+/// # Safety
+///
+/// - The buffer and the endpoint must not change meanwhile.
+pub unsafe fn sendto(
+    pid: u64,
+    fd: u64,
+    addr: u64,
+    len: u64,
+    to: u64,
+) -> Result<Option<u64>, Error> {
+    let buffer = user_buffer(addr, len)?;
+    let peer = user_peer(to)?;
+
+    exec_with_irq_masked(|| {
+        let handle = TABLE.lock().datagram(pid, fd, 0)?;
+
+        with_sockets(|sockets| send_datagram(sockets, handle, buffer, peer))
+    })
+}
+
+/// Receive a datagram into `[addr, addr + len)` and return its length, storing the sender in the
+/// `Endpoint` at `from` unless it is 0. `Ok(None)` until one arrives.
+///
+/// # Safety
+///
+/// - The buffer and the endpoint must not be accessed otherwise meanwhile.
+pub unsafe fn recvfrom(
+    pid: u64,
+    fd: u64,
+    addr: u64,
+    len: u64,
+    from: u64,
+) -> Result<Option<u64>, Error> {
+    let buffer = user_buffer(addr, len)?;
+    let from = match from {
+        0 => None,
+        from => Some(user_endpoint(from)?),
+    };
+
+    exec_with_irq_masked(|| {
+        let handle = match TABLE.lock().entry(pid, fd)?.state {
+            State::Datagram { handle, .. } => handle,
+            _ => return Err(Error::InvalidArgument),
+        };
+
+        let (len, peer) = match with_sockets(|sockets| recv_datagram(sockets, handle, buffer))? {
+            Some(received) => received,
+            None => return Ok(None),
+        };
+        if let Some(from) = from {
+            *from = Endpoint::from(peer);
+        }
+        Ok(Some(len))
+    })
+}
+
+/// Close the socket. Connections are shut down gracefully.
+pub fn close(pid: u64, fd: u64) -> Result<(), Error> {
+    exec_with_irq_masked(|| TABLE.lock().close(pid, fd))
+}
+
+/// Close all sockets of `pid`, for tasks exiting.
+pub fn close_all(pid: u64) {
+    exec_with_irq_masked(|| {
+        let mut table = TABLE.lock();
+        let fds: Vec<u64> = table
+            .entries
+            .iter()
+            .filter(|entry| entry.pid == pid)
+            .map(|entry| entry.fd)
+            .collect();
+
+        for fd in fds {
+            let _ = table.close(pid, fd);
+        }
+    })
+}
+
+/// Remove the closed connections smoltcp is done with.
+///
+/// Called from the ethernet driver's poll.
+pub fn poll(sockets: &mut SocketSet) {
+    ORPHANS.lock().retain(|&handle| {
+        match sockets.get::<TcpSocket>(handle).state() {
+            TcpState::Closed | TcpState::TimeWait => (),
+            _ => return true,
+        }
+        sockets.remove(handle);
+        false
+    });
+}
+
+//--------------------------------------------------------------------------------------------------
+// Testing
+//--------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_macros::kernel_test;
+
+    /// Handles are per task and reused once closed, ports are shared by all tasks. UDP sockets
+    /// can't listen.
+    #[kernel_test]
+    fn handles_are_per_task() {
+        let (a, b) = (u64::MAX - 1, u64::MAX - 2);
+
+        assert_eq!(socket(a, SOCK_STREAM), Ok(0));
+        assert_eq!(socket(a, SOCK_STREAM), Ok(1));
+        assert_eq!(socket(b, SOCK_STREAM), Ok(0));
+        assert_eq!(socket(b, 3), Err(Error::Unsupported));
+
+        assert_eq!(bind(a, 1, 8080), Ok(()));
+        assert_eq!(bind(b, 0, 8080), Err(Error::AddressInUse));
+        assert_eq!(bind(b, 1, 8081), Err(Error::BadHandle));
+        assert_eq!(socket(b, SOCK_DGRAM), Ok(1));
+        assert_eq!(listen(b, 1, 1), Err(Error::InvalidArgument));
+
+        assert_eq!(close(a, 0), Ok(()));
+        assert_eq!(close(a, 0), Err(Error::BadHandle));
+        assert_eq!(socket(a, SOCK_STREAM), Ok(0));
+
+        close_all(a);
+        close_all(b);
+        assert_eq!(bind(b, 0, 8080), Err(Error::BadHandle));
+    }
+
+    /// IPv4 addresses pass through tasks IPv4-mapped.
+    #[kernel_test]
+    fn endpoints_map_ipv4() {
+        let v4 = IpEndpoint::new(IpAddress::v4(10, 0, 2, 2), 7);
+        let v6 = IpEndpoint::new(IpAddress::v6(0xfe80, 0, 0, 0, 0, 0, 0, 1), 7);
+
+        let mapped = Endpoint::from(v4);
+        assert_eq!(&mapped.addr[10..], &[0xff, 0xff, 10, 0, 2, 2]);
+        assert_eq!(IpEndpoint::from(mapped), v4);
+        assert_eq!(IpEndpoint::from(Endpoint::from(v6)), v6);
+    }
+}
@@ -0,0 +1,534 @@
+//! Remote console over Telnet.
+//!
+//! A client connecting to port 23 logs in with the password from `telnet.password=` on the kernel
+//! command line. It then gets the console's output and its input reaches the shell as if it were
+//! typed on the serial console. One session at a time: while a client is connected, or after too
+//! many failed logins, nobody else can connect. Without a password the port stays closed.
+//! `telnet.port=` listens on another port.
+//!
+//! Like the netconsole, output is buffered and sent from the ethernet driver's poll, and output of
+//! the polling core is dropped. The server offers to echo, so the client sends characters as they
+//! are typed and the shell's echo is all that shows.
+
+use super::{netconsole, SocketSet};
+use crate::exception::asynchronous::exec_with_irq_masked;
+use crate::{bsp, console, info, ring_buffer::RingBuffer, syscall, warn};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::time::Duration;
+use smoltcp::socket::{SocketHandle, TcpSocket, TcpSocketBuffer, TcpState};
+use smoltcp::time::Instant;
+use smoltcp::wire::IpEndpoint;
+use spin::Mutex;
+
+pub const DEFAULT_PORT: u16 = 23;
+
+/// The name the session is registered under as a console sink and input.
+const NAME: &str = "telnet";
+
+const OUTPUT_LEN: usize = 8192;
+const INPUT_LEN: usize = 256;
+const MAX_PASSWORD_LEN: usize = 128;
+
+/// Failed logins, counted across connections, before the port closes, and for how long.
+const MAX_ATTEMPTS: u8 = 3;
+const LOCKOUT: Duration = Duration::from_secs(10);
+
+/// How long a client has to log in, so that an idle connection doesn't block the console.
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Probe an idle session, and drop it when the client is gone.
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+const TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Telnet commands (RFC 854) and options.
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+const OPT_ECHO: u8 = 1;
+const OPT_SUPPRESS_GO_AHEAD: u8 = 3;
+
+/// Character at a time mode: the server echoes and nobody sends go aheads.
+const NEGOTIATION: [u8; 6] = [IAC, WILL, OPT_ECHO, IAC, WILL, OPT_SUPPRESS_GO_AHEAD];
+
+/// Strips commands and option negotiation from the client's data.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Parser {
+    Data,
+    /// After a carriage return, which a line feed or a NUL follows.
+    Cr,
+    Iac,
+    /// Waiting for the option of a negotiation.
+    Option,
+    Subnegotiation,
+    SubnegotiationIac,
+}
+
+impl Parser {
+    /// Feed a byte received, returning the input character it completes. A carriage return is
+    /// returned as a line feed, like the UART does.
+    fn feed(&mut self, byte: u8) -> Option<u8> {
+        match (*self, byte) {
+            (Parser::Cr, b'\n') | (Parser::Cr, 0) => {
+                *self = Parser::Data;
+                None
+            }
+            (Parser::Cr, _) => {
+                *self = Parser::Data;
+                self.feed(byte)
+            }
+            (Parser::Data, IAC) => {
+                *self = Parser::Iac;
+                None
+            }
+            (Parser::Data, b'\r') => {
+                *self = Parser::Cr;
+                Some(b'\n')
+            }
+            (Parser::Data, _) => Some(byte),
+            (Parser::Iac, IAC) => {
+                *self = Parser::Data;
+                Some(IAC)
+            }
+            (Parser::Iac, WILL..=DONT) => {
+                *self = Parser::Option;
+                None
+            }
+            (Parser::Iac, SB) => {
+                *self = Parser::Subnegotiation;
+                None
+            }
+            (Parser::Iac, _) | (Parser::Option, _) => {
+                *self = Parser::Data;
+                None
+            }
+            (Parser::Subnegotiation, IAC) => {
+                *self = Parser::SubnegotiationIac;
+                None
+            }
+            (Parser::Subnegotiation, _) => None,
+            (Parser::SubnegotiationIac, SE) => {
+                *self = Parser::Data;
+                None
+            }
+            (Parser::SubnegotiationIac, _) => {
+                *self = Parser::Subnegotiation;
+                None
+            }
+        }
+    }
+}
+
+/// Compare without returning early, so that the time taken doesn't tell how much matched.
+fn password_matches(password: &str, given: &[u8]) -> bool {
+    password.len() == given.len()
+        && password
+            .bytes()
+            .zip(given)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// The port and the password from `telnet.port=` and `telnet.password=` in a kernel command line.
+fn parse_cmdline(cmdline: &str) -> (u16, Option<&str>) {
+    let mut port = DEFAULT_PORT;
+    let mut password = None;
+
+    for arg in cmdline.split_whitespace() {
+        if let Some(value) = arg.strip_prefix("telnet.port=") {
+            match value.parse() {
+                Ok(value) if value != 0 => port = value,
+                _ => warn!("telnet: ignoring invalid port {}", value),
+            }
+        } else if let Some(value) = arg.strip_prefix("telnet.password=") {
+            password = Some(value).filter(|value| !value.is_empty());
+        }
+    }
+    (port, password)
+}
+
+struct TelnetInner {
+    output: RingBuffer<OUTPUT_LEN>,
+    input: RingBuffer<INPUT_LEN>,
+}
+
+impl fmt::Write for TelnetInner {
+    /// Buffer `s` in the network virtual terminal's encoding: lines end in CR LF and `IAC` is sent
+    /// twice.
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            let escape = match byte {
+                b'\n' => Some(b'\r'),
+                IAC => Some(IAC),
+                _ => None,
+            };
+            // Output the client doesn't keep up with is lost, as on the netconsole.
+            let room = OUTPUT_LEN - self.output.len();
+            if room < 1 + escape.is_some() as usize {
+                continue;
+            }
+            if let Some(escape) = escape {
+                self.output.push(escape);
+            }
+            self.output.push(byte);
+        }
+
+        Ok(())
+    }
+}
+
+enum Session {
+    /// Listening, or the port is closed until `reopen`.
+    Closed {
+        reopen: Instant,
+    },
+    Login {
+        peer: IpEndpoint,
+        since: Instant,
+        password: Vec<u8>,
+    },
+    Attached {
+        peer: IpEndpoint,
+    },
+}
+
+struct Server {
+    handle: Option<SocketHandle>,
+    port: u16,
+    password: &'static str,
+    parser: Parser,
+    session: Session,
+    /// Failed logins since the last successful one or the last lockout.
+    failures: u8,
+}
+
+static SERVER: Mutex<Server> = Mutex::new(Server {
+    handle: None,
+    port: DEFAULT_PORT,
+    password: "",
+    parser: Parser::Data,
+    session: Session::Closed {
+        reopen: Instant { millis: 0 },
+    },
+    failures: 0,
+});
+
+impl Server {
+    /// Take the console's output and feed its input.
+    fn attach(&mut self, peer: IpEndpoint, socket: &mut TcpSocket) {
+        console::interface::Read::clear(&TELNET);
+        let console = bsp::console();
+        if let Err(e) = console.attach_input(NAME, &TELNET) {
+            warn!("telnet: {}", e);
+            socket.close();
+            return;
+        }
+        if let Err(e) = console.add(NAME, &TELNET) {
+            warn!("telnet: {}", e);
+            console.detach_input(NAME);
+            socket.close();
+            return;
+        }
+
+        let _ = socket.send_slice(b"\r\nConsole attached, the serial port sees the same.\r\n");
+        self.session = Session::Attached { peer };
+        self.failures = 0;
+        info!("telnet: session from {}", peer);
+    }
+
+    /// End the session, leaving the port closed until `reopen`.
+    fn detach(&mut self, reopen: Instant) {
+        if let Session::Attached { peer } = self.session {
+            let console = bsp::console();
+            console.remove(NAME);
+            console.detach_input(NAME);
+            info!("telnet: session from {} closed", peer);
+        }
+        self.session = Session::Closed { reopen };
+    }
+
+    /// Handle an input character of the login.
+    fn login(&mut self, c: u8, socket: &mut TcpSocket, now: Instant) {
+        let (peer, password) = match &mut self.session {
+            Session::Login { peer, password, .. } => (*peer, password),
+            _ => return,
+        };
+
+        match c {
+            b'\n' => (),
+            0x08 | 0x7f => {
+                password.pop();
+                return;
+            }
+            _ if password.len() < MAX_PASSWORD_LEN => {
+                password.push(c);
+                return;
+            }
+            _ => return,
+        }
+
+        if password_matches(self.password, password) {
+            self.attach(peer, socket);
+            return;
+        }
+        password.clear();
+        self.failures += 1;
+        warn!("telnet: failed login from {}", peer);
+        if self.failures < MAX_ATTEMPTS {
+            let _ = socket.send_slice(b"\r\nLogin incorrect\r\nPassword: ");
+        } else {
+            let _ = socket.send_slice(b"\r\nLogin incorrect\r\n");
+            socket.close();
+            self.session = Session::Closed {
+                reopen: now + LOCKOUT.into(),
+            };
+        }
+    }
+
+    fn poll(&mut self, sockets: &mut SocketSet, now: Instant) {
+        let handle = match self.handle {
+            Some(handle) => handle,
+            None => return,
+        };
+        let mut socket = sockets.get::<TcpSocket>(handle);
+
+        if !socket.is_open() {
+            if !matches!(self.session, Session::Closed { .. }) {
+                self.detach(now);
+            }
+            match self.session {
+                Session::Closed { reopen } if now >= reopen => {
+                    // Reconnecting doesn't reset the count, only the end of a lockout does.
+                    if self.failures >= MAX_ATTEMPTS {
+                        self.failures = 0;
+                    }
+                    if let Err(e) = socket.listen(self.port) {
+                        warn!("telnet: failed to listen on port {}: {:?}", self.port, e);
+                        self.handle = None;
+                    }
+                }
+                _ => (),
+            }
+            return;
+        }
+
+        if let Session::Closed { .. } = self.session {
+            if !socket.may_send() {
+                return;
+            }
+            // A client connected.
+            socket.set_keep_alive(Some(KEEP_ALIVE.into()));
+            socket.set_timeout(Some(TIMEOUT.into()));
+            self.parser = Parser::Data;
+            self.session = Session::Login {
+                peer: socket.remote_endpoint(),
+                since: now,
+                password: Vec::new(),
+            };
+            let _ = socket.send_slice(&NEGOTIATION);
+            let _ = socket.send_slice(b"Password: ");
+        }
+
+        let mut buffer = [0; 64];
+        let mut input = false;
+        while socket.can_recv() {
+            let len = match socket.recv_slice(&mut buffer) {
+                Ok(len) if len > 0 => len,
+                _ => break,
+            };
+            for &byte in &buffer[..len] {
+                let c = match self.parser.feed(byte) {
+                    Some(c) => c,
+                    None => continue,
+                };
+                match self.session {
+                    Session::Login { .. } => self.login(c, &mut socket, now),
+                    Session::Attached { .. } => {
+                        TELNET.push_input(c);
+                        input = true;
+                    }
+                    Session::Closed { .. } => (),
+                }
+            }
+        }
+        if input {
+            syscall::wake_reader();
+        }
+
+        match self.session {
+            Session::Login { since, peer, .. }
+                if socket.may_send() && now >= since + LOGIN_TIMEOUT.into() =>
+            {
+                info!("telnet: login from {} timed out", peer);
+                socket.close();
+            }
+            Session::Attached { .. } => {
+                while socket.can_send() {
+                    let sent = socket.send(|buffer| {
+                        let len = TELNET.pop_output(buffer);
+                        (len, len)
+                    });
+                    if sent.map_or(true, |len| len == 0) {
+                        break;
+                    }
+                }
+            }
+            _ => (),
+        }
+        if socket.state() == TcpState::CloseWait {
+            socket.close();
+        }
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public Definitions
+//--------------------------------------------------------------------------------------------------
+
+/// The console device of the session.
+pub struct Telnet {
+    inner: Mutex<TelnetInner>,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Global instances
+//--------------------------------------------------------------------------------------------------
+
+pub static TELNET: Telnet = Telnet {
+    inner: Mutex::new(TelnetInner {
+        output: RingBuffer::new(),
+        input: RingBuffer::new(),
+    }),
+};
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// Create the server's socket in `sockets`, if the command line sets a password.
+pub fn init(sockets: &mut SocketSet) {
+    let (port, password) = parse_cmdline(bsp::cmdline().unwrap_or(""));
+    let password = match password {
+        Some(password) => password,
+        None => {
+            info!("telnet: no telnet.password= set, remote console disabled");
+            return;
+        }
+    };
+
+    let socket = TcpSocket::new(
+        TcpSocketBuffer::new(vec![0; INPUT_LEN]),
+        TcpSocketBuffer::new(vec![0; OUTPUT_LEN]),
+    );
+    let handle = sockets.add(socket);
+    exec_with_irq_masked(|| {
+        let mut server = SERVER.lock();
+        server.handle = Some(handle);
+        server.port = port;
+        server.password = password;
+    });
+    info!("telnet: remote console on port {}", port);
+}
+
+/// Accept a client, handle its login and input, and send it the console's output.
+///
+/// Called from the ethernet driver's poll, with the executing core muted.
+pub fn poll(sockets: &mut SocketSet, now: Instant) {
+    exec_with_irq_masked(|| SERVER.lock().poll(sockets, now))
+}
+
+impl Telnet {
+    fn push_input(&self, c: u8) {
+        // Input typed faster than the shell reads it is lost, like on the UART.
+        exec_with_irq_masked(|| self.inner.lock().input.push(c));
+    }
+
+    /// Move buffered output to `buffer`, returning the number of bytes moved.
+    fn pop_output(&self, buffer: &mut [u8]) -> usize {
+        exec_with_irq_masked(|| {
+            let mut inner = self.inner.lock();
+            let mut len = 0;
+            while len < buffer.len() {
+                match inner.output.pop() {
+                    Some(byte) => buffer[len] = byte,
+                    None => break,
+                }
+                len += 1;
+            }
+            len
+        })
+    }
+}
+
+//------------------------------------------------------------------------------
+// OS Interface Code
+//------------------------------------------------------------------------------
+
+impl console::interface::Write for Telnet {
+    fn write_char(&self, c: char) {
+        let mut buf = [0; 4];
+        let _ = self.write_fmt(format_args!("{}", c.encode_utf8(&mut buf)));
+    }
+
+    fn write_fmt(&self, args: fmt::Arguments) -> fmt::Result {
+        if netconsole::is_muted() {
+            return Ok(());
+        }
+
+        exec_with_irq_masked(|| fmt::Write::write_fmt(&mut *self.inner.lock(), args))
+    }
+
+    fn flush(&self) {}
+}
+
+impl console::interface::Read for Telnet {
+    fn try_read_char(&self) -> Option<char> {
+        exec_with_irq_masked(|| self.inner.lock().input.pop()).map(char::from)
+    }
+
+    fn clear(&self) {
+        exec_with_irq_masked(|| {
+            let mut inner = self.inner.lock();
+            inner.output.clear();
+            inner.input.clear();
+        })
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Testing
+//--------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_macros::kernel_test;
+
+    /// Negotiation and subnegotiation are stripped, CR LF and CR NUL become a line feed.
+    #[kernel_test]
+    fn parse_client_data() {
+        let data = [
+            b'l', IAC, WILL, 24, b's', IAC, SB, 24, 0, IAC, IAC, IAC, SE, b'\r', 0, IAC, IAC,
+            b'\r', b'\n', b'\r', b'x',
+        ];
+        let mut parser = Parser::Data;
+        let input: Vec<u8> = data.iter().filter_map(|&b| parser.feed(b)).collect();
+
+        assert_eq!(input, [b'l', b's', b'\n', IAC, b'\n', b'\n', b'x']);
+    }
+
+    /// Both options are read, an empty password leaves the console disabled.
+    #[kernel_test]
+    fn cmdline_options() {
+        assert_eq!(
+            parse_cmdline("telnet.port=2323 telnet.password=s3cret"),
+            (2323, Some("s3cret"))
+        );
+        assert_eq!(parse_cmdline("telnet.password="), (DEFAULT_PORT, None));
+        assert!(password_matches("s3cret", b"s3cret"));
+        assert!(!password_matches("s3cret", b"s3cre"));
+    }
+}
@@ -0,0 +1,505 @@
+//! Trivial File Transfer Protocol client (RFC 1350), to fetch kernel images for `netboot`.
+//!
+//! Reads one file at a time into memory, in octet mode. The block size option (RFC 2348) asks for
+//! blocks that fill an Ethernet frame instead of 512 bytes, and the transfer size option
+//! (RFC 2349) lets the buffer be allocated up front. The ethernet driver's poll sends the request
+//! and the acknowledgments, and sends the last one again when the server goes quiet.
+//!
+//! The buffer only grows while the heap has room for it: an allocation failure would panic the
+//! kernel, so a file the heap can't hold fails the transfer instead.
+
+use super::SocketSet;
+use crate::exception::asynchronous::exec_with_irq_masked;
+use crate::memory::ALLOCATOR;
+use crate::{time, warn};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::time::Duration;
+use core::{mem, str};
+use smoltcp::socket::{SocketHandle, UdpPacketMetadata, UdpSocket, UdpSocketBuffer};
+use smoltcp::wire::{IpAddress, IpEndpoint};
+use spin::Mutex;
+
+const TFTP_PORT: u16 = 69;
+const LOCAL_PORT: u16 = 49069;
+
+/// The block size asked for, an Ethernet payload minus the IPv6, UDP and TFTP headers.
+const BLOCK_SIZE: usize = 1448;
+/// The block size of servers without the option.
+const DEFAULT_BLOCK_SIZE: usize = 512;
+
+/// Largest file accepted.
+pub const MAX_FILE_LEN: usize = 64 * 1024 * 1024;
+
+/// Heap left free for everything else while the buffer grows.
+const HEAP_RESERVE: usize = 256 * 1024;
+
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_RETRIES: u32 = 5;
+
+const OP_READ_REQUEST: u16 = 1;
+const OP_DATA: u16 = 3;
+const OP_ACK: u16 = 4;
+const OP_ERROR: u16 = 5;
+const OP_OPTION_ACK: u16 = 6;
+
+/// Progress of the transfer.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Status {
+    /// Nothing started since the last file was taken.
+    Idle,
+    /// `received` bytes so far, of `size` if the server told.
+    Receiving {
+        received: usize,
+        size: Option<usize>,
+    },
+    /// A file of that many bytes waits to be taken.
+    Done(usize),
+    Failed(String),
+}
+
+#[derive(Debug, PartialEq)]
+enum Packet<'a> {
+    Data { block: u16, data: &'a [u8] },
+    OptionAck(&'a [u8]),
+    Error { code: u16, message: &'a str },
+}
+
+struct Transfer {
+    server: IpAddress,
+    /// The server's port for this transfer, from its first answer.
+    peer_port: Option<u16>,
+    /// The request or the last acknowledgment, kept to send it again.
+    last_sent: Vec<u8>,
+    /// Whether `last_sent` is still to be sent.
+    pending: bool,
+    sent_at: Duration,
+    retries: u32,
+    block_size: usize,
+    /// The last block received.
+    block: u16,
+    size: Option<usize>,
+    data: Vec<u8>,
+    /// Set with the last block, done once it is acknowledged.
+    complete: bool,
+}
+
+enum State {
+    Idle,
+    Running(Transfer),
+    Done(Vec<u8>),
+    Failed(String),
+}
+
+struct TftpClient {
+    handle: Option<SocketHandle>,
+    state: State,
+}
+
+static CLIENT: Mutex<TftpClient> = Mutex::new(TftpClient {
+    handle: None,
+    state: State::Idle,
+});
+
+fn read_request(file: &str) -> Vec<u8> {
+    let block_size = BLOCK_SIZE.to_string();
+    let mut packet = OP_READ_REQUEST.to_be_bytes().to_vec();
+
+    for field in &[file, "octet", "blksize", block_size.as_str(), "tsize", "0"] {
+        packet.extend_from_slice(field.as_bytes());
+        packet.push(0);
+    }
+    packet
+}
+
+fn ack(block: u16) -> Vec<u8> {
+    [OP_ACK.to_be_bytes(), block.to_be_bytes()].concat()
+}
+
+fn parse(packet: &[u8]) -> Option<Packet> {
+    if packet.len() < 4 {
+        return None;
+    }
+    let opcode = u16::from_be_bytes(packet[..2].try_into().unwrap());
+    let argument = u16::from_be_bytes(packet[2..4].try_into().unwrap());
+
+    match opcode {
+        OP_DATA => Some(Packet::Data {
+            block: argument,
+            data: &packet[4..],
+        }),
+        OP_OPTION_ACK => Some(Packet::OptionAck(&packet[2..])),
+        OP_ERROR => {
+            let message = packet[4..].split(|&b| b == 0).next().unwrap_or(&[]);
+            Some(Packet::Error {
+                code: argument,
+                message: str::from_utf8(message).unwrap_or("?"),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// The block size and the file size the server agreed to.
+fn parse_options(options: &[u8]) -> Result<(usize, Option<usize>), &'static str> {
+    let mut fields = options.split(|&b| b == 0);
+    let mut block_size = DEFAULT_BLOCK_SIZE;
+    let mut size = None;
+
+    while let (Some(name), Some(value)) = (fields.next(), fields.next()) {
+        let value = str::from_utf8(value)
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .ok_or("malformed option")?;
+        if name.eq_ignore_ascii_case(b"blksize") {
+            if value < 8 || value > BLOCK_SIZE {
+                return Err("block size out of range");
+            }
+            block_size = value;
+        } else if name.eq_ignore_ascii_case(b"tsize") {
+            size = Some(value);
+        }
+    }
+    Ok((block_size, size))
+}
+
+/// Make room in `data` for `additional` more bytes, doubling its capacity if the heap has room for
+/// that, or just what is needed. Rounded up to 8 bytes, so that `chainload::boot` can pad an image
+/// without reallocating it.
+fn grow(data: &mut Vec<u8>, additional: usize) -> Result<(), String> {
+    let needed = (data.len() + additional + 7) & !7;
+    if needed <= data.capacity() {
+        return Ok(());
+    }
+
+    // The old buffer stays allocated until the new one is filled.
+    let free = exec_with_irq_masked(|| ALLOCATOR.lock().free()).saturating_sub(HEAP_RESERVE);
+    let capacity = (data.capacity() * 2).min(MAX_FILE_LEN).max(needed);
+    let capacity = if capacity <= free { capacity } else { needed };
+    if capacity > free {
+        return Err(format!("file too large for the heap, {} bytes free", free));
+    }
+    data.reserve_exact(capacity - data.len());
+    Ok(())
+}
+
+impl Transfer {
+    fn new(server: IpAddress, file: &str) -> Self {
+        Self {
+            server,
+            peer_port: None,
+            last_sent: read_request(file),
+            pending: true,
+            sent_at: Duration::from_secs(0),
+            retries: 0,
+            block_size: DEFAULT_BLOCK_SIZE,
+            block: 0,
+            size: None,
+            data: Vec::new(),
+            complete: false,
+        }
+    }
+
+    /// Send `packet` with the next poll, and again until the server answers.
+    fn queue(&mut self, packet: Vec<u8>) {
+        self.last_sent = packet;
+        self.pending = true;
+        self.retries = 0;
+    }
+
+    /// Handle a packet from port `port` of the server.
+    fn receive(&mut self, packet: &[u8], port: u16) -> Result<(), String> {
+        match parse(packet).ok_or("malformed packet")? {
+            Packet::Error { code, message } => {
+                return Err(format!("server error {}: {}", code, message))
+            }
+            Packet::OptionAck(options) if self.peer_port.is_none() => {
+                let (block_size, size) = parse_options(options)?;
+                if size.map_or(false, |size| size > MAX_FILE_LEN) {
+                    return Err("file too large".into());
+                }
+                self.block_size = block_size;
+                self.size = size;
+                grow(&mut self.data, size.unwrap_or(0))?;
+                self.peer_port = Some(port);
+                self.queue(ack(0));
+            }
+            Packet::Data { block, data } if block == self.block.wrapping_add(1) => {
+                if self.data.len() + data.len() > MAX_FILE_LEN {
+                    return Err("file too large".into());
+                }
+                grow(&mut self.data, data.len())?;
+                self.data.extend_from_slice(data);
+                self.peer_port = Some(port);
+                self.block = block;
+                self.complete = data.len() < self.block_size;
+                self.queue(ack(block));
+            }
+            // Our acknowledgment got lost.
+            Packet::Data { block, .. } if block == self.block && self.block != 0 => {
+                self.queue(ack(block))
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// Create the client's socket in `sockets`.
+pub fn init(sockets: &mut SocketSet) {
+    let rx_buffer = UdpSocketBuffer::new(
+        vec![UdpPacketMetadata::EMPTY; 4],
+        vec![0; 4 * (BLOCK_SIZE + 4)],
+    );
+    let tx_buffer = UdpSocketBuffer::new(vec![UdpPacketMetadata::EMPTY; 1], vec![0; 512]);
+    let mut socket = UdpSocket::new(rx_buffer, tx_buffer);
+
+    if let Err(e) = socket.bind(LOCAL_PORT) {
+        warn!("tftp: failed to bind port {}: {:?}", LOCAL_PORT, e);
+        return;
+    }
+    CLIENT.lock().handle = Some(sockets.add(socket));
+}
+
+/// Start reading `file` from `server`, dropping a file not taken yet.
+pub fn start(server: IpAddress, file: &str) -> Result<(), &'static str> {
+    // The request, with its options, has to fit the socket's buffer.
+    if file.is_empty() || file.len() > 256 {
+        return Err("invalid file name");
+    }
+
+    exec_with_irq_masked(|| {
+        let mut client = CLIENT.lock();
+        if client.handle.is_none() {
+            return Err("network not initialized");
+        }
+        if let State::Running(_) = client.state {
+            return Err("transfer in progress");
+        }
+
+        client.state = State::Running(Transfer::new(server, file));
+        super::wake();
+        Ok(())
+    })
+}
+
+/// Give up on the transfer, or drop the file read.
+pub fn cancel() {
+    exec_with_irq_masked(|| CLIENT.lock().state = State::Idle);
+}
+
+pub fn status() -> Status {
+    exec_with_irq_masked(|| match &CLIENT.lock().state {
+        State::Idle => Status::Idle,
+        State::Running(transfer) => Status::Receiving {
+            received: transfer.data.len(),
+            size: transfer.size,
+        },
+        State::Done(data) => Status::Done(data.len()),
+        State::Failed(e) => Status::Failed(e.clone()),
+    })
+}
+
+/// The file read, once complete.
+pub fn take() -> Option<Vec<u8>> {
+    exec_with_irq_masked(|| {
+        let mut client = CLIENT.lock();
+        match mem::replace(&mut client.state, State::Idle) {
+            State::Done(data) => Some(data),
+            state => {
+                client.state = state;
+                None
+            }
+        }
+    })
+}
+
+/// Handle the server's packets, and send the request or an acknowledgment when due.
+///
+/// Called from the ethernet driver's poll.
+pub fn poll(sockets: &mut SocketSet) {
+    let mut client = CLIENT.lock();
+    let handle = match client.handle {
+        Some(handle) => handle,
+        None => return,
+    };
+    let mut socket = sockets.get::<UdpSocket>(handle);
+    let now = time::time_manager().uptime();
+
+    let transfer = match &mut client.state {
+        State::Running(transfer) => transfer,
+        _ => {
+            // Answers to a transfer given up on.
+            while socket.recv().is_ok() {}
+            return;
+        }
+    };
+
+    let mut result = Ok(());
+    while let Ok((packet, from)) = socket.recv() {
+        // Other transfer ids belong to a stale transfer.
+        if from.addr != transfer.server || transfer.peer_port.map_or(false, |p| p != from.port) {
+            continue;
+        }
+        result = transfer.receive(packet, from.port);
+        if result.is_err() {
+            break;
+        }
+    }
+
+    if result.is_ok() && !transfer.pending && now >= transfer.sent_at + RETRY_INTERVAL {
+        if transfer.retries == MAX_RETRIES {
+            result = Err("timed out".into());
+        } else {
+            transfer.retries += 1;
+            transfer.pending = true;
+        }
+    }
+    if result.is_ok() && transfer.pending && socket.can_send() {
+        let port = transfer.peer_port.unwrap_or(TFTP_PORT);
+        match socket.send_slice(&transfer.last_sent, IpEndpoint::new(transfer.server, port)) {
+            Ok(()) => {
+                transfer.pending = false;
+                transfer.sent_at = now;
+            }
+            Err(e) => warn!("tftp: failed to send: {:?}", e),
+        }
+    }
+
+    match result {
+        Err(e) => client.state = State::Failed(e),
+        // The server may send the last block again if the last acknowledgment gets lost, but it
+        // has all the data then.
+        Ok(()) if transfer.complete && !transfer.pending => {
+            let data = mem::take(&mut transfer.data);
+            client.state = State::Done(data);
+        }
+        Ok(()) => (),
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Testing
+//--------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::virt::{self, Host, VirtualDevice};
+    use crate::net::{dhcp, Link, ETH};
+    use smoltcp::wire::{EthernetAddress, IpCidr, Ipv4Address, Ipv4Cidr};
+    use test_macros::kernel_test;
+
+    /// A server for one file: the read request gets the options acknowledged, and each
+    /// acknowledgment the next block.
+    struct Responder {
+        handle: SocketHandle,
+        file: Vec<u8>,
+    }
+
+    impl Responder {
+        fn respond(&self, host: &mut Host) {
+            let mut socket = host.sockets.get::<UdpSocket>(self.handle);
+
+            while let Ok((packet, from)) = socket.recv().map(|(p, from)| (p.to_vec(), from)) {
+                let opcode = u16::from_be_bytes(packet[..2].try_into().unwrap());
+                let reply = match opcode {
+                    OP_READ_REQUEST => {
+                        let options =
+                            format!("blksize\0{}\0tsize\0{}\0", BLOCK_SIZE, self.file.len());
+                        [&OP_OPTION_ACK.to_be_bytes()[..], options.as_bytes()].concat()
+                    }
+                    OP_ACK => {
+                        let block = u16::from_be_bytes(packet[2..4].try_into().unwrap());
+                        let start = block as usize * BLOCK_SIZE;
+                        if start > self.file.len() {
+                            continue;
+                        }
+                        let end = (start + BLOCK_SIZE).min(self.file.len());
+                        let header = [OP_DATA.to_be_bytes(), (block + 1).to_be_bytes()].concat();
+                        [&header[..], &self.file[start..end]].concat()
+                    }
+                    _ => continue,
+                };
+                socket.send_slice(&reply, from).unwrap();
+            }
+        }
+    }
+
+    /// Packets and option acknowledgments are parsed, unknown options are ignored.
+    #[kernel_test]
+    fn parse_packets_and_options() {
+        assert_eq!(
+            parse(&[0, 3, 0, 7, 0xaa]),
+            Some(Packet::Data {
+                block: 7,
+                data: &[0xaa]
+            })
+        );
+        assert_eq!(
+            parse(b"\x00\x05\x00\x01File not found\x00"),
+            Some(Packet::Error {
+                code: 1,
+                message: "File not found"
+            })
+        );
+        assert_eq!(parse(&[0, 4, 0, 1]), None);
+
+        assert_eq!(
+            parse_options(b"BLKSIZE\x001024\x00tsize\x00123456\x00timeout\x005\x00"),
+            Ok((1024, Some(123_456)))
+        );
+        assert_eq!(parse_options(b""), Ok((DEFAULT_BLOCK_SIZE, None)));
+        assert!(parse_options(b"blksize\x0065464\x00").is_err());
+
+        let request = read_request("kernel8.img");
+        assert!(request.starts_with(b"\x00\x01kernel8.img\x00octet\x00blksize\x001448\x00"));
+    }
+
+    /// A file of a few blocks, the last one partial, comes over a cable from the responder.
+    #[kernel_test]
+    fn transfer_over_cable() {
+        let (device, peer) = VirtualDevice::cable();
+        let hw_addr = EthernetAddress([0x02, 0, 0, 0, 0, 1]);
+        unsafe { ETH.initialize(Link::Virtual(device, hw_addr)) };
+        dhcp::use_static(Ipv4Cidr::new(Ipv4Address([10, 0, 0, 1]), 24), None);
+
+        let mut server = Host::new(
+            peer,
+            EthernetAddress([0x02, 0, 0, 0, 0, 2]),
+            &[IpCidr::new(IpAddress::v4(10, 0, 0, 2), 24)],
+        );
+        let mut socket = UdpSocket::new(
+            UdpSocketBuffer::new(vec![UdpPacketMetadata::EMPTY; 4], vec![0; 4096]),
+            UdpSocketBuffer::new(
+                vec![UdpPacketMetadata::EMPTY; 4],
+                vec![0; 4 * (BLOCK_SIZE + 4)],
+            ),
+        );
+        socket.bind(TFTP_PORT).unwrap();
+        let responder = Responder {
+            handle: server.sockets.add(socket),
+            file: (0..3 * BLOCK_SIZE + 100).map(|i| i as u8).collect(),
+        };
+
+        start(IpAddress::v4(10, 0, 0, 2), "kernel8.img").unwrap();
+        let finished = virt::poll_eth_until(core::slice::from_mut(&mut server), |hosts| {
+            responder.respond(&mut hosts[0]);
+            match status() {
+                Status::Receiving { .. } => false,
+                _ => true,
+            }
+        });
+
+        assert!(finished);
+        assert_eq!(status(), Status::Done(responder.file.len()));
+        assert_eq!(take().as_ref(), Some(&responder.file));
+    }
+}
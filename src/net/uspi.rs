@@ -10,17 +10,18 @@ use core::time::Duration;
 use smoltcp::wire::EthernetAddress;
 
 use crate::bsp::device_driver::MBox;
-use crate::bsp::generic_timer;
 use crate::exception::asynchronous::{interface::IRQHandler, interface::IRQManager, IRQDescriptor};
-use crate::info;
+use crate::{debug, error, info, warn};
 use crate::memory::ALLOCATOR;
 use crate::net::Frame;
+use crate::time;
 use spin;
 
 pub type TKernelTimerHandle = u64;
 pub type TKernelTimerHandler =
     Option<unsafe extern "C" fn(hTimer: TKernelTimerHandle, pParam: *mut u8, pContext: *mut u8)>;
 pub type TInterruptHandler = Option<unsafe extern "C" fn(pParam: *mut u8)>;
+pub type TFrameReceivedHandler = Option<unsafe extern "C" fn(pBuffer: *const u8, nLength: u32)>;
 
 static mut USB_DRIVER: USBHandler = USBHandler::uninitialized();
 static mut TIMER3_DRIVER: TimerHandler = TimerHandler::uninitialized();
@@ -31,7 +32,7 @@ unsafe impl Sync for Param {}
 mod inner {
     use crate::net::Frame;
     pub struct USPi(());
-    use super::{TKernelTimerHandle, TKernelTimerHandler};
+    use super::{TFrameReceivedHandler, TKernelTimerHandle, TKernelTimerHandler};
     use core::convert::TryInto;
     use core::ptr;
     use core::time::Duration;
@@ -47,9 +48,9 @@ mod inner {
         fn USPiEthernetIsLinkUp() -> i32;
         /// Returns 0 on failure
         fn USPiSendFrame(pBuffer: *const u8, nLength: u32) -> i32;
-        /// pBuffer must have size USPI_FRAME_BUFFER_SIZE
-        /// Returns 0 if no frame is available or on failure
-        fn USPiReceiveFrame(pBuffer: *mut u8, pResultLength: *mut u32) -> i32;
+        /// pHandler is called from the USB interrupt with each received frame
+        /// Returns 0 on failure
+        fn USPiEthernetRegisterReceiveHandler(pHandler: TFrameReceivedHandler) -> i32;
         fn TimerStartKernelTimer(
             pThis: TKernelTimerHandle,
             nDelay: usize, // in HZ units
@@ -96,15 +97,9 @@ mod inner {
             }
         }
 
-        /// Receives an ethernet frame using USPiRecvFrame
-        pub fn recv_frame(&mut self, frame: &mut Frame) -> Option<i32> {
-            let mut result_len = 0;
-            let result = unsafe { USPiReceiveFrame(frame.as_mut_ptr(), &mut result_len) };
-            frame.set_len(result_len);
-            match result {
-                0 => None,
-                n => Some(n),
-            }
+        /// Receives ethernet frames with `handler` from the USB interrupt on.
+        pub fn register_receive_handler(&mut self, handler: TFrameReceivedHandler) -> bool {
+            unsafe { USPiEthernetRegisterReceiveHandler(handler) != 0 }
         }
 
         /// A wrapper function to `TimerStartKernelHandler`.
@@ -153,12 +148,12 @@ fn free(ptr: *mut u8) {
 
 #[no_mangle]
 pub fn TimerSimpleMsDelay(nMilliSeconds: u32) {
-    generic_timer().spin_sleep(Duration::from_millis(nMilliSeconds as u64));
+    time::time_manager().spin_for(Duration::from_millis(nMilliSeconds as u64));
 }
 
 #[no_mangle]
 pub fn TimerSimpleusDelay(nMicroSeconds: u32) {
-    generic_timer().spin_sleep(Duration::from_micros(nMicroSeconds as u64));
+    time::time_manager().spin_for(Duration::from_micros(nMicroSeconds as u64));
 }
 
 #[no_mangle]
@@ -176,30 +171,28 @@ pub fn usDelay(nMicroSeconds: u32) {
 /// function should be invoked with `pParam`.
 #[no_mangle]
 pub unsafe fn ConnectInterrupt(nIRQ: u32, pHandler: TInterruptHandler, pParam: *mut u8) {
-    use crate::bsp::device_driver::{IRQNumber, PeripheralIRQ};
-    use crate::bsp::exception::asynchronous::irq_manager;
+    use crate::bsp::exception::asynchronous::{irq_manager, irq_map};
 
     match nIRQ as usize {
         9 => {
             USB_DRIVER.initialize(pHandler, pParam);
-            pub const usb: IRQNumber = IRQNumber::Peripheral(PeripheralIRQ::new(9));
             let descriptor = IRQDescriptor {
                 name: "USB",
                 handler: &USB_DRIVER,
             };
-            //irq_manager().register_handler(usb, descriptor).unwrap();
             irq_manager().register_fiq(descriptor);
-            irq_manager().enable_fiq(usb);
+            irq_manager().enable_fiq(irq_map::USB);
         }
         3 => {
             TIMER3_DRIVER.initialize(pHandler, pParam);
-            pub const timer: IRQNumber = IRQNumber::Peripheral(PeripheralIRQ::new(3));
             let descriptor = IRQDescriptor {
                 name: "Timer3",
                 handler: &TIMER3_DRIVER,
             };
-            irq_manager().register_handler(timer, descriptor).unwrap();
-            irq_manager().enable(timer);
+            irq_manager()
+                .register_handler(irq_map::SYSTEM_TIMER3, descriptor)
+                .unwrap();
+            irq_manager().enable(irq_map::SYSTEM_TIMER3);
         }
         int => panic!("IRQ is {:?}, only Usb and Timer3 supported", int),
     }
@@ -296,12 +289,18 @@ impl IRQHandler for TimerHandler {
 }
 
 #[no_mangle]
-pub unsafe fn DoLogWrite(_pSource: *const u8, _Severity: u32, pMessage: *const u8) {
+pub unsafe fn DoLogWrite(_pSource: *const u8, Severity: u32, pMessage: *const u8) {
     let message = match cstring(pMessage) {
         Ok(message_string) => message_string,
         Err(_) => String::from("pMessage sent to DoLogWrite() is not valid UTF-8"),
     };
-    info!("[USPi Log] {}", message);
+    // LOG_ERROR, LOG_WARNING, LOG_NOTICE and LOG_DEBUG in uspios.h
+    match Severity {
+        1 => error!("[USPi Log] {}", message),
+        2 => warn!("[USPi Log] {}", message),
+        3 => info!("[USPi Log] {}", message),
+        _ => debug!("[USPi Log] {}", message),
+    }
 }
 
 #[no_mangle]
@@ -319,7 +318,7 @@ pub unsafe fn uspi_assertion_failed(pExpr: *const u8, pFile: *const u8, nLine: u
         Ok(file_string) => file_string,
         Err(_) => String::from("pFile sent to uspi_assertion_failed() is not valid UTF-8"),
     };
-    info!(
+    error!(
         "USPi Assertion Failed: Expression: {}, File: {}, Line: {}",
         expr, file, nLine
     );
@@ -388,12 +387,12 @@ impl Usb {
             .send_frame(frame)
     }
 
-    pub fn recv_frame(&self, frame: &mut Frame) -> Option<i32> {
+    pub fn register_receive_handler(&self, handler: TFrameReceivedHandler) -> bool {
         self.0
             .lock()
             .as_mut()
             .expect("USB not initialized")
-            .recv_frame(frame)
+            .register_receive_handler(handler)
     }
 
     pub fn start_kernel_timer(&self, delay: Duration, handler: TKernelTimerHandler) {
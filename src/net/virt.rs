@@ -0,0 +1,323 @@
+//! In-memory network devices, for running the stack without USB.
+//!
+//! A loopback device receives what it sends, and the two ends of a virtual cable receive what the
+//! other end sends. Frames are queued until the receiving interface polls, and dropped when the
+//! queue is full, like on a congested link. `Host` puts an interface with its own sockets on a
+//! device, so that tests can run clients and servers entirely inside the kernel.
+//!
+//! The kernel's own interface `ETH` can be initialized on one end of a cable instead of USB, so
+//! that its DHCP, SLAAC and TFTP clients and socket syscalls talk to a `Host` on the other end.
+//! `poll_eth_until` drives both.
+
+use super::{EthernetInterface, SocketSet, ETH};
+use crate::{time, warn};
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use smoltcp::iface::{EthernetInterfaceBuilder, NeighborCache, Routes};
+use smoltcp::phy::{self, Device, DeviceCapabilities};
+use smoltcp::time::Instant;
+use smoltcp::wire::{EthernetAddress, IpCidr};
+use spin::Mutex;
+
+/// Largest frame, an Ethernet header and a 1500 byte payload.
+const MTU: usize = 1514;
+
+/// Frames queued at most in each direction.
+const QUEUE_LEN: usize = 64;
+
+/// Frames in flight in one direction.
+type Queue = Arc<Mutex<VecDeque<Vec<u8>>>>;
+
+fn new_queue() -> Queue {
+    Arc::new(Mutex::new(VecDeque::with_capacity(QUEUE_LEN)))
+}
+
+/// Queue `frame`, returning whether there was room.
+fn push(queue: &Queue, frame: Vec<u8>) -> bool {
+    let mut queue = queue.lock();
+    if queue.len() < QUEUE_LEN {
+        queue.push_back(frame);
+        true
+    } else {
+        false
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// A device sending into one queue and receiving from another.
+#[derive(Debug)]
+pub struct VirtualDevice {
+    rx: Queue,
+    tx: Queue,
+}
+
+impl VirtualDevice {
+    /// A device receiving everything it sends.
+    pub fn loopback() -> Self {
+        let queue = new_queue();
+
+        Self {
+            rx: queue.clone(),
+            tx: queue,
+        }
+    }
+
+    /// Two devices connected to each other.
+    pub fn cable() -> (Self, Self) {
+        let (a_to_b, b_to_a) = (new_queue(), new_queue());
+
+        (
+            Self {
+                rx: b_to_a.clone(),
+                tx: a_to_b.clone(),
+            },
+            Self {
+                rx: a_to_b,
+                tx: b_to_a,
+            },
+        )
+    }
+
+    /// Send a copy of `frame`, returning whether the queue had room for it.
+    pub fn send(&self, frame: &[u8]) -> bool {
+        push(&self.tx, frame.to_vec())
+    }
+
+    /// The oldest frame received.
+    pub fn recv(&self) -> Option<Vec<u8>> {
+        self.rx.lock().pop_front()
+    }
+}
+
+impl<'a> Device<'a> for VirtualDevice {
+    type RxToken = RxToken;
+    type TxToken = TxToken;
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut capability = DeviceCapabilities::default();
+        capability.max_transmission_unit = MTU;
+        capability
+    }
+
+    fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        let frame = self.recv()?;
+
+        Some((
+            RxToken { frame },
+            TxToken {
+                queue: self.tx.clone(),
+            },
+        ))
+    }
+
+    fn transmit(&'a mut self) -> Option<Self::TxToken> {
+        Some(TxToken {
+            queue: self.tx.clone(),
+        })
+    }
+}
+
+pub struct RxToken {
+    frame: Vec<u8>,
+}
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F>(mut self, _timestamp: Instant, f: F) -> smoltcp::Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
+    {
+        f(&mut self.frame)
+    }
+}
+
+pub struct TxToken {
+    queue: Queue,
+}
+
+impl phy::TxToken for TxToken {
+    fn consume<R, F>(self, _timestamp: Instant, len: usize, f: F) -> smoltcp::Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
+    {
+        let mut frame = vec![0; len];
+        let result = f(&mut frame)?;
+
+        push(&self.queue, frame);
+        Ok(result)
+    }
+}
+
+/// An interface on a virtual device with its own sockets, like a separate machine.
+pub struct Host {
+    pub iface: EthernetInterface<VirtualDevice>,
+    pub sockets: SocketSet,
+}
+
+impl Host {
+    pub fn new(device: VirtualDevice, mac: EthernetAddress, ip_addrs: &[IpCidr]) -> Self {
+        let iface = EthernetInterfaceBuilder::new(device)
+            .ethernet_addr(mac)
+            .neighbor_cache(NeighborCache::new(BTreeMap::new()))
+            .ip_addrs(ip_addrs.to_vec())
+            .routes(Routes::new(BTreeMap::new()))
+            .finalize();
+
+        Self {
+            iface,
+            sockets: SocketSet::new(Vec::new()),
+        }
+    }
+
+    /// Process the frames that arrived and send what the sockets have queued.
+    pub fn poll(&mut self, timestamp: Instant) {
+        match self.iface.poll(&mut self.sockets, timestamp) {
+            Ok(_) | Err(smoltcp::Error::Unrecognized) => (),
+            Err(e) => warn!("virt: poll error: {:?}", e),
+        }
+    }
+}
+
+fn poll_steps(
+    hosts: &mut [Host],
+    mut poll: impl FnMut(Instant),
+    mut done: impl FnMut(&mut [Host]) -> bool,
+) -> bool {
+    // From the current uptime, which the kernel's clients took their start times from.
+    let start = time::time_manager().uptime().as_millis() as i64;
+    for step in 0..6000 {
+        let timestamp = Instant::from_millis(start + step * 10);
+        poll(timestamp);
+        for host in hosts.iter_mut() {
+            host.poll(timestamp);
+        }
+        if done(hosts) {
+            return true;
+        }
+    }
+
+    false
+}
+
+/// Poll `hosts` in 10 ms steps of simulated time until `done` or up to a minute, returning whether
+/// `done` happened.
+pub fn poll_until(hosts: &mut [Host], done: impl FnMut(&mut [Host]) -> bool) -> bool {
+    poll_steps(hosts, |_| (), done)
+}
+
+/// Like `poll_until`, polling the kernel's interface as well, which has to be on a virtual link.
+///
+/// The network task must not be running.
+pub fn poll_eth_until(hosts: &mut [Host], done: impl FnMut(&mut [Host]) -> bool) -> bool {
+    poll_steps(hosts, |timestamp| unsafe { ETH.poll(timestamp) }, done)
+}
+
+//--------------------------------------------------------------------------------------------------
+// Testing
+//--------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::TcpSocket;
+    use smoltcp::socket::{TcpSocketBuffer, UdpPacketMetadata, UdpSocket, UdpSocketBuffer};
+    use smoltcp::wire::{IpAddress, IpEndpoint};
+    use test_macros::kernel_test;
+
+    fn tcp_socket() -> TcpSocket {
+        TcpSocket::new(
+            TcpSocketBuffer::new(vec![0; 1024]),
+            TcpSocketBuffer::new(vec![0; 1024]),
+        )
+    }
+
+    fn udp_socket(port: u16) -> UdpSocket<'static> {
+        let mut socket = UdpSocket::new(
+            UdpSocketBuffer::new(vec![UdpPacketMetadata::EMPTY; 4], vec![0; 1024]),
+            UdpSocketBuffer::new(vec![UdpPacketMetadata::EMPTY; 4], vec![0; 1024]),
+        );
+        socket.bind(port).unwrap();
+        socket
+    }
+
+    /// A TCP client connects over a cable, and the server echoes what it sends.
+    #[kernel_test]
+    fn tcp_echo_over_cable() {
+        let (a, b) = VirtualDevice::cable();
+        let mut hosts = [
+            Host::new(
+                a,
+                EthernetAddress([0x02, 0, 0, 0, 0, 1]),
+                &[IpCidr::new(IpAddress::v4(10, 0, 0, 1), 24)],
+            ),
+            Host::new(
+                b,
+                EthernetAddress([0x02, 0, 0, 0, 0, 2]),
+                &[IpCidr::new(IpAddress::v4(10, 0, 0, 2), 24)],
+            ),
+        ];
+
+        let mut server = tcp_socket();
+        server.listen(7).unwrap();
+        let server = hosts[1].sockets.add(server);
+        let mut client = tcp_socket();
+        client
+            .connect(IpEndpoint::new(IpAddress::v4(10, 0, 0, 2), 7), 49152)
+            .unwrap();
+        let client = hosts[0].sockets.add(client);
+
+        let (mut sent, mut reply) = (false, Vec::new());
+        let echoed = poll_until(&mut hosts, |hosts| {
+            let mut socket = hosts[0].sockets.get::<TcpSocket>(client);
+            if socket.may_send() && !sent {
+                sent = socket.send_slice(b"hello").unwrap() == 5;
+            }
+            if socket.can_recv() {
+                socket
+                    .recv(|data| (data.len(), reply.extend_from_slice(data)))
+                    .unwrap();
+            }
+            drop(socket);
+
+            let mut socket = hosts[1].sockets.get::<TcpSocket>(server);
+            let mut buffer = [0; 16];
+            if let Ok(len @ 1..=16) = socket.recv_slice(&mut buffer) {
+                socket.send_slice(&buffer[..len]).unwrap();
+            }
+            reply == b"hello"
+        });
+
+        assert!(echoed);
+    }
+
+    /// Datagrams reach a socket on the same interface through the loopback device.
+    #[kernel_test]
+    fn udp_over_loopback() {
+        let mut hosts = [Host::new(
+            VirtualDevice::loopback(),
+            EthernetAddress([0x02, 0, 0, 0, 0, 1]),
+            &[IpCidr::new(IpAddress::v4(127, 0, 0, 1), 8)],
+        )];
+        let sender = hosts[0].sockets.add(udp_socket(1000));
+        let receiver = hosts[0].sockets.add(udp_socket(2000));
+
+        hosts[0]
+            .sockets
+            .get::<UdpSocket>(sender)
+            .send_slice(b"ping", IpEndpoint::new(IpAddress::v4(127, 0, 0, 1), 2000))
+            .unwrap();
+        let received = poll_until(&mut hosts, |hosts| {
+            let mut socket = hosts[0].sockets.get::<UdpSocket>(receiver);
+            match socket.recv() {
+                Ok((data, from)) => data == b"ping" && from.port == 1000,
+                Err(_) => false,
+            }
+        });
+
+        assert!(received);
+    }
+}
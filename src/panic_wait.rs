@@ -1,6 +1,11 @@
-use crate::{bsp, cpu};
+use crate::{bsp, console, cpu, exception, logging, symbols};
+use core::sync::atomic::{AtomicUsize, Ordering};
 use core::{fmt, panic::PanicInfo};
 
+/// The core that panicked first, or `NO_CORE`.
+static PANICKED_CORE: AtomicUsize = AtomicUsize::new(NO_CORE);
+const NO_CORE: usize = usize::MAX;
+
 //--------------------------------------------------------------------------------------------------
 // Private Code
 //--------------------------------------------------------------------------------------------------
@@ -9,9 +14,27 @@ fn _panic_print(args: fmt::Arguments) {
     use fmt::Write;
     bsp::board_name();
 
+    // Keep the message for the next boot, too.
+    let _ = console::ram_log::RAM_LOG.try_write_fmt(args);
     unsafe { bsp::panic_console_out().write_fmt(args).unwrap() };
 }
 
+/// Stop the other cores, they park on their next exception entry.
+fn halt_other_cores() {
+    for core in (0..cpu::NUM_CORES).filter(|&core| core != cpu::core_id::<usize>()) {
+        bsp::exception::asynchronous::send_ipi(core);
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// Whether a core panicked. Exception handlers park their core if so.
+pub fn is_panicking() -> bool {
+    PANICKED_CORE.load(Ordering::Relaxed) != NO_CORE
+}
+
 /// The point of exit for the "standard" (non-testing) `libkernel`.
 ///
 /// This code will be used by the release kernel binary and the `integration tests`. It is linked
@@ -41,10 +64,45 @@ macro_rules! panic_println {
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    let core = cpu::core_id::<usize>();
+
+    match PANICKED_CORE.compare_exchange(NO_CORE, core, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => halt_other_cores(),
+        // Panicked while printing the first panic, don't recurse.
+        Err(first) if first == core => _panic_exit(),
+        Err(_) => cpu::wait_forever(),
+    }
+
+    // Records still buffered predate the panic, print them first.
+    unsafe {
+        let mut out = bsp::panic_console_out();
+        logging::panic_flush(&mut |record| {
+            let _ = console::ram_log::RAM_LOG.try_write_fmt(format_args!("{}", record));
+            let _ = fmt::Write::write_str(&mut out, record);
+        });
+    }
+
     if let Some(args) = info.message() {
-        panic_println!("\nKernel panic: {}", args);
+        panic_println!(
+            "\nKernel panic on core {}, pid {}: {}",
+            core,
+            logging::Pid,
+            args
+        );
     } else {
-        panic_println!("\nKernel panic!");
+        panic_println!("\nKernel panic on core {}, pid {}!", core, logging::Pid);
+    }
+    if let Some(location) = info.location() {
+        panic_println!("at {}", location);
+    }
+
+    if let Some(e) = unsafe { exception::current_context() } {
+        panic_println!("\nException context:\n{}", e);
+    }
+
+    panic_println!("\nBacktrace:");
+    for (i, addr) in cpu::backtrace::Frames::here().enumerate() {
+        panic_println!("  {:2}: {}", i, symbols::Address(addr));
     }
 
     _panic_exit()
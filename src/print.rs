@@ -1,4 +1,4 @@
-use crate::{bsp, console};
+use crate::{bsp, console, time};
 use core::fmt;
 
 //--------------------------------------------------------------------------------------------------
@@ -15,6 +15,40 @@ pub fn _print(args: fmt::Arguments) {
 // Public Code
 //--------------------------------------------------------------------------------------------------
 
+/// The log record timestamp.
+///
+/// Prints the UTC time of day once the realtime clock is set, and the uptime before that.
+pub struct Timestamp;
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match time::realtime() {
+            Some(now) => {
+                let secs = now.as_secs() % 86_400;
+                write!(
+                    f,
+                    "{:02}:{:02}:{:02}.{:06}",
+                    secs / 3600,
+                    (secs / 60) % 60,
+                    secs % 60,
+                    now.subsec_micros()
+                )
+            }
+            None => {
+                let uptime = time::time_manager().uptime();
+                let subsec_us = uptime.subsec_micros();
+                write!(
+                    f,
+                    "{:>3}.{:03}{:03}",
+                    uptime.as_secs(),
+                    subsec_us / 1_000,
+                    subsec_us % 1_000
+                )
+            }
+        }
+    }
+}
+
 /// Prints without a newline.
 ///
 /// Carbon copy from https://doc.rust-lang.org/src/std/macros.rs.html
@@ -34,69 +68,42 @@ macro_rules! println {
     })
 }
 
-/// Prints an info, with a newline.
+/// Logs an error, with a newline. See `logging` for the filters.
 #[macro_export]
-macro_rules! info {
-    ($string:expr) => ({
-        #[allow(unused_imports)]
-        //use crate::time::TimeManager;
-
-        let timestamp = $crate::bsp::generic_timer().current_time();
-        let timestamp_subsec_us = timestamp.subsec_micros();
-
-        $crate::print::_print(format_args_nl!(
-            concat!("[  {:>3}.{:03}{:03}] ", $string),
-            timestamp.as_secs(),
-            timestamp_subsec_us / 1_000,
-            timestamp_subsec_us % 1_000
-        ));
-    });
-    ($format_string:expr, $($arg:tt)*) => ({
-        #[allow(unused_imports)]
-        //use crate::time::TimeManager;
-
-        let timestamp = $crate::bsp::generic_timer().current_time();
-        let timestamp_subsec_us = timestamp.subsec_micros();
-
-        $crate::print::_print(format_args_nl!(
-            concat!("[  {:>3}.{:03}{:03}] ", $format_string),
-            timestamp.as_secs(),
-            timestamp_subsec_us / 1_000,
-            timestamp_subsec_us % 1_000,
-            $($arg)*
-        ));
+macro_rules! error {
+    ($($arg:tt)*) => ({
+        $crate::logging::log($crate::logging::Level::Error, module_path!(), format_args!($($arg)*));
     })
 }
 
-/// Prints a warning, with a newline.
+/// Logs a warning, with a newline.
 #[macro_export]
 macro_rules! warn {
-    ($string:expr) => ({
-        #[allow(unused_imports)]
-
-        let timestamp = $crate::bsp::generic_timer().current_time();
-        let timestamp_subsec_us = timestamp.subsec_micros();
+    ($($arg:tt)*) => ({
+        $crate::logging::log($crate::logging::Level::Warn, module_path!(), format_args!($($arg)*));
+    })
+}
 
-        $crate::print::_print(format_args_nl!(
-            concat!("[W {:>3}.{:03}{:03}] ", $string),
-            timestamp.as_secs(),
-            timestamp_subsec_us / 1_000,
-            timestamp_subsec_us % 1_000
-        ));
-    });
-    ($format_string:expr, $($arg:tt)*) => ({
-        #[allow(unused_imports)]
-        //use crate::time::TimeManager;
+/// Logs an info, with a newline.
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => ({
+        $crate::logging::log($crate::logging::Level::Info, module_path!(), format_args!($($arg)*));
+    })
+}
 
-        let timestamp = $crate::bsp::generic_timer().current_time();
-        let timestamp_subsec_us = timestamp.subsec_micros();
+/// Logs a debug message, with a newline.
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => ({
+        $crate::logging::log($crate::logging::Level::Debug, module_path!(), format_args!($($arg)*));
+    })
+}
 
-        $crate::print::_print(format_args_nl!(
-            concat!("[W {:>3}.{:03}{:03}] ", $format_string),
-            timestamp.as_secs(),
-            timestamp_subsec_us / 1_000,
-            timestamp_subsec_us % 1_000,
-            $($arg)*
-        ));
+/// Logs a trace message, with a newline.
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => ({
+        $crate::logging::log($crate::logging::Level::Trace, module_path!(), format_args!($($arg)*));
     })
 }
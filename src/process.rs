@@ -15,6 +15,10 @@ pub struct Task {
     pub priority: i8,
     pub pid: u64,
     pub stack: Stack,
+    /// Set by `kill`, the task exits the next time it is descheduled.
+    pub killed: bool,
+    /// The only core the task runs on, `None` for any.
+    pub core: Option<usize>,
 }
 
 /// Type of a function used to determine if a task is ready to be scheduled
@@ -32,6 +36,18 @@ pub enum TaskState {
     ZOMBIE,
 }
 
+impl TaskState {
+    /// The state's name, for listings.
+    pub fn name(&self) -> &'static str {
+        match self {
+            TaskState::RUNNING => "running",
+            TaskState::WAITING(_) => "waiting",
+            TaskState::READY => "ready",
+            TaskState::ZOMBIE => "zombie",
+        }
+    }
+}
+
 impl Task {
     pub fn new() -> Option<Task> {
         match Stack::new() {
@@ -42,6 +58,8 @@ impl Task {
                 priority: 1,
                 pid: 0,
                 stack: stack,
+                killed: false,
+                core: None,
             }),
             None => None,
         }
@@ -68,6 +86,11 @@ impl Task {
         }
     }
 
+    /// Whether the task may be scheduled on `core`.
+    pub fn runs_on(&self, core: usize) -> bool {
+        self.core.map_or(true, |only| only == core)
+    }
+
     pub fn is_waiting(&mut self) -> bool {
         match self.state {
             TaskState::WAITING(_) => true,
@@ -83,6 +106,7 @@ impl Task {
     }
 
     pub fn exit(&mut self) {
+        crate::net::socket::close_all(self.pid);
         self.state = TaskState::ZOMBIE;
         self.counter = 0;
         self.priority = 0;
@@ -201,18 +225,26 @@ macro_rules! impl_for {
 
 impl_for!(PhysicalAddr);
 
-pub fn add_user_process(entry: fn()) {
-    add_process(entry, 0b0100); // EL0
+/// Spawn `entry` as a user task and return its pid.
+pub fn add_user_process(entry: fn()) -> u64 {
+    add_process(entry, 0b0100, None) // EL0
+}
+
+/// Spawn `entry` as a user task that only runs on `core` and return its pid.
+pub fn add_user_process_on(entry: fn(), core: usize) -> u64 {
+    add_process(entry, 0b0100, Some(core)) // EL0
 }
 
-pub fn add_kernel_process(entry: fn()) {
-    add_process(entry, 0b0101); // EL1
+/// Spawn `entry` as a kernel task and return its pid.
+pub fn add_kernel_process(entry: fn()) -> u64 {
+    add_process(entry, 0b0101, None) // EL1
 }
 
-fn add_process(entry: fn(), spsr: u64) {
+fn add_process(entry: fn(), spsr: u64, core: Option<usize>) -> u64 {
     let mut task = Task::new().unwrap();
     task.context.sp = task.stack.bottom().as_u64();
     task.context.elr = entry as *mut u8 as u64;
     task.context.spsr = spsr;
-    SCHEDULER.add_task(task).unwrap();
+    task.core = core;
+    SCHEDULER.add_task(task).unwrap()
 }
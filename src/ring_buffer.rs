@@ -0,0 +1,95 @@
+//! Fixed size byte ring buffer.
+//!
+//! Not synchronized, so it is meant to live behind the lock of its owner.
+
+/// A FIFO of at most `N` bytes.
+pub struct RingBuffer<const N: usize> {
+    buf: [u8; N],
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> RingBuffer<N> {
+    /// Create an empty instance.
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// Append `byte`. Returns `false` if the buffer is full.
+    pub fn push(&mut self, byte: u8) -> bool {
+        if self.is_full() {
+            return false;
+        }
+        self.buf[(self.head + self.len) % N] = byte;
+        self.len += 1;
+
+        true
+    }
+
+    /// Remove the oldest byte.
+    pub fn pop(&mut self) -> Option<u8> {
+        if self.is_empty() {
+            return None;
+        }
+        let byte = self.buf[self.head];
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+
+        Some(byte)
+    }
+
+    /// Drop all bytes.
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Testing
+//--------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_macros::kernel_test;
+
+    /// Bytes come out in order across the wrap-around, and a full buffer rejects pushes.
+    #[kernel_test]
+    fn ring_buffer_wraps_in_order() {
+        let mut ring: RingBuffer<4> = RingBuffer::new();
+
+        for i in 0..3 {
+            assert!(ring.push(i));
+        }
+        assert_eq!(ring.pop(), Some(0));
+        assert_eq!(ring.pop(), Some(1));
+
+        for i in 3..6 {
+            assert!(ring.push(i));
+        }
+        assert!(ring.is_full());
+        assert!(!ring.push(6));
+
+        for i in 2..6 {
+            assert_eq!(ring.pop(), Some(i));
+        }
+        assert_eq!(ring.pop(), None);
+    }
+}
@@ -154,11 +154,34 @@ defreg!(
     ]
 );
 
+// (ref: D13.2.137 EL0 Read/Write Software Thread ID Register), holds the running task's pid
+defreg!(TPIDR_EL0);
+
 // (ref: D7.2.19 Architectural Feature Trap Register EL2)
 defreg!(CPTR_EL2);
 // (ref: D7.2.18 Architectural Feature Access Control Register)
 defreg!(CPACR_EL1);
 
+// (ref: D13.3.15 Monitor Debug System Control Register)
+defreg!(
+    MDSCR_EL1,
+    [
+        MDE[15 - 15], // Monitor debug events, enables breakpoints and watchpoints
+        KDE[13 - 13], // Local (kernel) debug enable, for debug exceptions at EL1
+        SS[00 - 00],  // Software step control
+    ]
+);
+// (ref: D13.3.23 OS Lock Access Register), writing 0 unlocks debug exceptions after a cold reset
+defreg!(OSLAR_EL1);
+// (ref: D13.2.61 AArch64 Debug Feature Register 0), the number of breakpoints and watchpoints
+defreg!(
+    ID_AA64DFR0_EL1,
+    [
+        WRPS[23 - 20], // Watchpoints, minus one
+        BRPS[15 - 12], // Breakpoints, minus one
+    ]
+);
+
 //--------------------------------------------------------------------------------------------------
 // Testing
 //--------------------------------------------------------------------------------------------------
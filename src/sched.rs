@@ -1,10 +1,19 @@
-use crate::{exception, process};
+use crate::{bsp, cpu, exception, process};
 extern crate alloc;
 use alloc::collections::vec_deque::VecDeque;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
 use cortex_a::regs::*;
 use process::{Task, TaskState};
 use spin::Mutex;
 
+/// SPSR.M[0], set for tasks running on SP_EL1.
+const SPSR_SP_ELX: u64 = 0b1;
+
+/// The scheduler's time slice.
+pub const TICK: Duration = Duration::from_millis(200);
+
 pub struct GlobalScheduler(Mutex<Option<Scheduler>>);
 
 pub static SCHEDULER: GlobalScheduler = GlobalScheduler::uninitialized();
@@ -84,9 +93,129 @@ impl GlobalScheduler {
         }
     }
 
+    /// Switch the executing core to task `pid` if it is ready, putting the running task back in
+    /// the queue as if its time slice had run out.
+    pub fn switch_to(&self, pid: u64, ec: &mut exception::ExceptionContext) {
+        exception::asynchronous::exec_with_irq_masked(|| {
+            self.0
+                .lock()
+                .as_mut()
+                .expect("scheduler uninitialized")
+                .switch_to(pid, ec)
+        })
+    }
+
     pub fn timer_tick(&self, e: &mut exception::ExceptionContext) {
         exception::asynchronous::exec_with_irq_masked(|| self.switch(TaskState::READY, e))
     }
+
+    /// A snapshot of all tasks.
+    pub fn tasks(&self) -> Vec<TaskInfo> {
+        exception::asynchronous::exec_with_irq_masked(|| {
+            self.0
+                .lock()
+                .as_ref()
+                .expect("scheduler uninitialized")
+                .processes
+                .iter()
+                .map(|task| TaskInfo {
+                    pid: task.pid,
+                    state: task.state.name(),
+                    priority: task.priority,
+                    counter: task.counter,
+                })
+                .collect()
+        })
+    }
+
+    /// Terminate the task `pid`. A running task exits the next time it is descheduled.
+    pub fn kill(&self, pid: u64) -> Result<(), &'static str> {
+        exception::asynchronous::exec_with_irq_masked(|| {
+            let mut sched = self.0.lock();
+            let task = sched
+                .as_mut()
+                .expect("scheduler uninitialized")
+                .processes
+                .iter_mut()
+                .find(|task| task.pid == pid)
+                .ok_or("no such task")?;
+
+            match task.state {
+                TaskState::ZOMBIE => return Err("task already exited"),
+                // Kernel tasks take exceptions on their own stack, which must not be freed under
+                // them.
+                TaskState::RUNNING if task.context.spsr & SPSR_SP_ELX != 0 => {
+                    return Err("can't kill a running kernel task")
+                }
+                TaskState::RUNNING => task.killed = true,
+                _ => task.exit(),
+            }
+            Ok(())
+        })
+    }
+}
+
+/// A task's scheduling data, see `GlobalScheduler::tasks()`.
+pub struct TaskInfo {
+    pub pid: u64,
+    pub state: &'static str,
+    pub priority: i8,
+    pub counter: i8,
+}
+
+/// Completed scheduler ticks per core.
+static HEARTBEATS: [AtomicU64; cpu::NUM_CORES] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+
+/// Tasks `wake` asked each core to switch to, 0 for none.
+static WOKEN: [AtomicU64; cpu::NUM_CORES] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+
+/// Periodic timer event driving preemption on every core.
+pub fn tick(e: &mut exception::ExceptionContext) {
+    SCHEDULER.timer_tick(e);
+    HEARTBEATS[cpu::core_id::<usize>()].fetch_add(1, Ordering::Relaxed);
+}
+
+/// The number of scheduler ticks `core` has completed.
+///
+/// Stops advancing if the core hangs with IRQs masked or deadlocks inside the scheduler.
+pub fn heartbeat(core: usize) -> u64 {
+    HEARTBEATS[core].load(Ordering::Relaxed)
+}
+
+/// Have `core` switch to the task `pid` right away if it is ready, instead of at its next tick.
+///
+/// Only stores the request and raises an IPI, so it may be called from any handler, the FIQ
+/// included. The switch happens when `core` takes the IPI, see `switch_to_woken`.
+pub fn wake(pid: u64, core: usize) {
+    WOKEN[core].store(pid, Ordering::Release);
+    bsp::exception::asynchronous::send_ipi(core);
+}
+
+/// Switch to the task `wake` asked the executing core for, if any. Called after IRQ handling.
+pub fn switch_to_woken(e: &mut exception::ExceptionContext) {
+    match WOKEN[cpu::core_id::<usize>()].swap(0, Ordering::AcqRel) {
+        0 => (),
+        pid => SCHEDULER.switch_to(pid, e),
+    }
+}
+
+/// The pid of the task running on the executing core, `None` before the first task runs.
+pub fn current_pid() -> Option<u64> {
+    // The context switch restores the task's pid into TPIDR_EL0.
+    match unsafe { crate::runtime_init::TPIDR_EL0.get() } {
+        0 => None,
+        pid => Some(pid),
+    }
 }
 
 struct Scheduler {
@@ -143,31 +272,71 @@ impl Scheduler {
                 tsk.counter -= 1;
                 match update_state {
                     TaskState::READY => {
-                        if tsk.counter > 0 {
+                        if tsk.counter > 0 && !tsk.killed {
                             return false;
                         }
                     }
                     _ => {}
                 }
                 // times up, deschedule running task
-                if let Some(mut running) = self.processes.remove(ind) {
-                    running.counter = 1;
-                    running.state = update_state;
-                    *running.context = *ec;
-                    flush_tlb(&running.stack);
-                    self.processes.push_back(running);
-                }
+                self.put_back(ind, update_state, ec);
                 break;
             }
         }
         return true;
     }
 
+    /// Take the running task at `ind` off the core, saving `ec` into it, and queue it last in
+    /// `update_state`.
+    fn put_back(
+        &mut self,
+        ind: usize,
+        update_state: TaskState,
+        ec: &mut exception::ExceptionContext,
+    ) {
+        if let Some(mut running) = self.processes.remove(ind) {
+            running.counter = 1;
+            if running.killed {
+                running.exit();
+            } else {
+                running.state = update_state;
+            }
+            *running.context = *ec;
+            flush_tlb(&running.stack);
+            self.processes.push_back(running);
+        }
+    }
+
+    /// Switch to task `pid` if it may run on the executing core and is ready, and queue the
+    /// running task last. Returns whether it switched.
+    fn switch_to(&mut self, pid: u64, ec: &mut exception::ExceptionContext) -> bool {
+        let core = cpu::core_id::<usize>();
+        let ind = match self.processes.iter().position(|task| task.pid == pid) {
+            Some(ind) => ind,
+            None => return false,
+        };
+        // A running task, here or on another core, is not ready.
+        if !self.processes[ind].runs_on(core) || !self.processes[ind].is_ready() {
+            return false;
+        }
+
+        let mut task = self.processes.remove(ind).unwrap();
+        if let Some(running) = self.processes.iter().position(|task| task.pid == ec.tpidr) {
+            self.put_back(running, TaskState::READY, ec);
+        }
+        *ec = *task.context;
+        task.state = TaskState::RUNNING;
+        self.processes.push_front(task);
+        true
+    }
+
     fn schedule(&mut self, ec: &mut exception::ExceptionContext) -> u64 {
+        let core = cpu::core_id::<usize>();
         let num_tasks = self.processes.len();
         for _ in 0..num_tasks {
             let mut new_task = self.processes.pop_front().unwrap();
-            if new_task.is_ready() {
+            // Tasks pinned to another core are left alone, their event poll included.
+            if new_task.runs_on(core) && new_task.is_ready() {
                 let pid = ec.tpidr;
                 *ec = *new_task.context;
                 new_task.state = TaskState::RUNNING;
@@ -0,0 +1,297 @@
+//! Interactive kernel shell on the console.
+//!
+//! The shell task reads lines with basic editing: backspace, Ctrl-C and Ctrl-U, a history on the
+//! up and down arrow keys, and tab completion of command names. Commands implement `Command` and
+//! are added with `register()`, so drivers can provide their own next to the builtins.
+
+mod builtins;
+
+use crate::{print, println, syscall};
+use alloc::{collections::VecDeque, string::String, vec::Vec};
+use core::fmt;
+use spin::RwLock;
+
+/// Shell interfaces.
+pub mod interface {
+    use core::fmt;
+
+    /// A shell command.
+    pub trait Command: Sync {
+        /// The name the command is invoked by.
+        fn name(&self) -> &'static str;
+
+        /// One line of usage help.
+        fn help(&self) -> &'static str;
+
+        /// Run with `args`, the words following the name, writing output to `out`.
+        fn run(&self, args: &[&str], out: &mut dyn fmt::Write) -> Result<(), &'static str>;
+    }
+}
+
+use interface::Command;
+
+/// A command implemented by a plain function.
+pub struct FnCommand {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub run: fn(&[&str], &mut dyn fmt::Write) -> Result<(), &'static str>,
+}
+
+impl Command for FnCommand {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn help(&self) -> &'static str {
+        self.help
+    }
+
+    fn run(&self, args: &[&str], out: &mut dyn fmt::Write) -> Result<(), &'static str> {
+        (self.run)(args, out)
+    }
+}
+
+const PROMPT: &str = "kernel> ";
+const HISTORY_LEN: usize = 16;
+
+const CTRL_C: char = '\x03';
+const BACKSPACE: char = '\x08';
+const TAB: char = '\t';
+const CTRL_U: char = '\x15';
+const ESCAPE: char = '\x1b';
+const DELETE: char = '\x7f';
+
+static COMMANDS: RwLock<Vec<&'static dyn Command>> = RwLock::new(Vec::new());
+
+/// Programs `spawn` can start.
+static PROGRAMS: RwLock<Vec<(&'static str, fn())>> = RwLock::new(Vec::new());
+
+/// Look up a builtin or registered command.
+fn find(name: &str) -> Option<&'static dyn Command> {
+    builtins::BUILTINS
+        .iter()
+        .map(|command| command as &'static dyn Command)
+        .chain(COMMANDS.read().iter().copied())
+        .find(|command| command.name() == name)
+}
+
+/// The names of all commands starting with `prefix`.
+fn completions(prefix: &str) -> Vec<&'static str> {
+    builtins::BUILTINS
+        .iter()
+        .map(|command| command.name)
+        .chain(COMMANDS.read().iter().map(|command| command.name()))
+        .filter(|name| name.starts_with(prefix))
+        .collect()
+}
+
+/// The longest prefix shared by all `names`.
+fn common_prefix<'a>(names: &[&'a str]) -> &'a str {
+    let first = match names.first() {
+        Some(first) => *first,
+        None => return "",
+    };
+
+    let len = names.iter().skip(1).fold(first.len(), |len, name| {
+        first
+            .bytes()
+            .zip(name.bytes())
+            .take(len)
+            .take_while(|(a, b)| a == b)
+            .count()
+    });
+    &first[..len]
+}
+
+/// Command output to the console.
+struct ConsoleWriter;
+
+impl fmt::Write for ConsoleWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        print!("{}", s);
+        Ok(())
+    }
+}
+
+struct LineEditor {
+    line: String,
+    history: VecDeque<String>,
+    /// The history entry shown while browsing.
+    history_pos: Option<usize>,
+}
+
+impl LineEditor {
+    fn new() -> Self {
+        Self {
+            line: String::new(),
+            history: VecDeque::with_capacity(HISTORY_LEN),
+            history_pos: None,
+        }
+    }
+
+    fn redraw(&self) {
+        print!("\r\x1b[K{}{}", PROMPT, self.line);
+    }
+
+    fn show_history(&mut self, pos: Option<usize>) {
+        self.history_pos = pos;
+        self.line = match pos {
+            Some(pos) => self.history[pos].clone(),
+            None => String::new(),
+        };
+        self.redraw();
+    }
+
+    /// Handle an escape sequence. Only the up and down arrow keys are supported.
+    fn escape(&mut self) {
+        if syscall::getc() != '[' {
+            return;
+        }
+
+        match (syscall::getc(), self.history_pos) {
+            ('A', None) if !self.history.is_empty() => {
+                self.show_history(Some(self.history.len() - 1))
+            }
+            ('A', Some(pos)) if pos > 0 => self.show_history(Some(pos - 1)),
+            ('B', Some(pos)) if pos + 1 < self.history.len() => self.show_history(Some(pos + 1)),
+            ('B', Some(_)) => self.show_history(None),
+            _ => (),
+        }
+    }
+
+    /// Complete the command name, or list the candidates if it is ambiguous.
+    fn complete(&mut self) {
+        if self.line.contains(' ') {
+            return;
+        }
+
+        let candidates = completions(&self.line);
+        let prefix = common_prefix(&candidates);
+        if candidates.len() == 1 {
+            self.line = String::from(prefix);
+            self.line.push(' ');
+        } else if prefix.len() > self.line.len() {
+            self.line = String::from(prefix);
+        } else if !candidates.is_empty() {
+            println!();
+            for name in candidates {
+                print!("{}  ", name);
+            }
+            println!();
+        }
+        self.redraw();
+    }
+
+    /// Read a line, echoing and editing it.
+    fn read_line(&mut self) -> String {
+        self.line.clear();
+        self.history_pos = None;
+        print!("{}", PROMPT);
+
+        loop {
+            match syscall::getc() {
+                '\n' | '\r' => break,
+                BACKSPACE | DELETE => {
+                    if self.line.pop().is_some() {
+                        print!("\x08 \x08");
+                    }
+                }
+                TAB => self.complete(),
+                CTRL_C => {
+                    println!("^C");
+                    self.line.clear();
+                    print!("{}", PROMPT);
+                }
+                CTRL_U => {
+                    self.line.clear();
+                    self.redraw();
+                }
+                ESCAPE => self.escape(),
+                c if c == ' ' || c.is_ascii_graphic() => {
+                    self.line.push(c);
+                    print!("{}", c);
+                }
+                _ => (),
+            }
+        }
+        println!();
+
+        let line = String::from(self.line.trim());
+        if !line.is_empty() && self.history.back() != Some(&line) {
+            if self.history.len() == HISTORY_LEN {
+                self.history.pop_front();
+            }
+            self.history.push_back(line.clone());
+        }
+        line
+    }
+}
+
+fn execute(line: &str) {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let (name, args) = match words.split_first() {
+        Some(command) => command,
+        None => return,
+    };
+
+    match find(name) {
+        Some(command) => {
+            if let Err(e) = command.run(args, &mut ConsoleWriter) {
+                println!("{}: {}", name, e);
+            }
+        }
+        None => println!("{}: command not found, try `help`", name),
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// Add `command`. Names of builtins and earlier commands can't be reused.
+pub fn register(command: &'static dyn Command) -> Result<(), &'static str> {
+    if find(command.name()).is_some() {
+        return Err("command already registered");
+    }
+
+    COMMANDS.write().push(command);
+    Ok(())
+}
+
+/// Let `spawn` start `entry` as a user task under `name`.
+pub fn add_program(name: &'static str, entry: fn()) {
+    PROGRAMS.write().push((name, entry));
+}
+
+/// The shell task.
+///
+/// Spawned with `process::add_user_process`, so that it can read through the syscall interface.
+pub fn shell_task() {
+    let mut editor = LineEditor::new();
+
+    println!("Kernel shell, type `help` for a list of commands.");
+    loop {
+        let line = editor.read_line();
+        execute(&line);
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Testing
+//--------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_macros::kernel_test;
+
+    /// Completion extends to the longest prefix all candidates share.
+    #[kernel_test]
+    fn completion_common_prefix() {
+        assert_eq!(common_prefix(&["ifconfig", "irq"]), "i");
+        assert_eq!(common_prefix(&["reboot", "register"]), "re");
+        assert_eq!(common_prefix(&["uptime"]), "uptime");
+        assert_eq!(common_prefix(&[]), "");
+        assert_eq!(completions("up"), ["uptime"]);
+    }
+}
@@ -0,0 +1,574 @@
+//! Builtin shell commands.
+
+use super::{FnCommand, PROGRAMS};
+use crate::bsp::{self, device_driver::MBox};
+use crate::exception::asynchronous::{
+    exec_with_interrupts_masked, exec_with_irq_masked, interface::IRQManager,
+};
+use crate::memory::{self, ALLOCATOR};
+use crate::net::{self, capture::Filter, tftp, ETH, USB};
+use crate::{cpu, logging, process, sched::SCHEDULER, syscall, time};
+use alloc::{format, vec::Vec};
+use core::{fmt, str};
+use smoltcp::wire::{
+    EthernetProtocol, IpAddress, IpCidr, IpProtocol, Ipv4Address, Ipv4Cidr, Ipv6Address,
+};
+
+type Out<'a> = &'a mut dyn fmt::Write;
+
+/// Map output errors, the console never fails.
+const WRITE_FAILED: &str = "output failed";
+
+/// How long `ping` waits for a reply, in milliseconds.
+const PING_TIMEOUT_MS: u64 = 1000;
+const PING_POLL_MS: u64 = 10;
+
+/// Bytes per line of `pcap dump`.
+const DUMP_LINE_LEN: usize = 32;
+
+/// The image `netboot` reads by default, and how often it checks on the transfer.
+const NETBOOT_IMAGE: &str = "kernel8.img";
+const NETBOOT_POLL_MS: u64 = 100;
+
+pub static BUILTINS: [FnCommand; 14] = [
+    FnCommand {
+        name: "help",
+        help: "help                 list commands",
+        run: help,
+    },
+    FnCommand {
+        name: "ps",
+        help: "ps                   list tasks",
+        run: ps,
+    },
+    FnCommand {
+        name: "mem",
+        help: "mem                  heap usage and memory layout",
+        run: mem,
+    },
+    FnCommand {
+        name: "irq",
+        help: "irq                  registered IRQ handlers",
+        run: irq,
+    },
+    FnCommand {
+        name: "kill",
+        help: "kill <pid>           terminate a task",
+        run: kill,
+    },
+    FnCommand {
+        name: "spawn",
+        help: "spawn [program]      start a program, or list them",
+        run: spawn,
+    },
+    FnCommand {
+        name: "reboot",
+        help: "reboot               reset the board",
+        run: reboot,
+    },
+    FnCommand {
+        name: "temp",
+        help: "temp                 SoC temperature",
+        run: temp,
+    },
+    FnCommand {
+        name: "ifconfig",
+        help: "ifconfig [args]      addresses; `dhcp` or `<a.b.c.d/n> [gw]` to configure",
+        run: ifconfig,
+    },
+    FnCommand {
+        name: "ping",
+        help: "ping <address> [n]   send n ICMP or ICMPv6 echo requests, 4 by default",
+        run: ping,
+    },
+    FnCommand {
+        name: "pcap",
+        help: "pcap [args]          capture frames; start [filter], stop, dump, stream <port|off>",
+        run: pcap,
+    },
+    FnCommand {
+        name: "uptime",
+        help: "uptime               time since boot and wall clock time",
+        run: uptime,
+    },
+    FnCommand {
+        name: "log",
+        help: "log [[module] level] show or set log levels",
+        run: log,
+    },
+    FnCommand {
+        name: "netboot",
+        help: "netboot [host] [img] boot an image read over TFTP, checked against <img>.crc32",
+        run: netboot,
+    },
+];
+
+fn parse_ipv4(s: &str) -> Option<Ipv4Address> {
+    let mut octets = [0; 4];
+    let mut parts = s.split('.');
+
+    for octet in octets.iter_mut() {
+        *octet = parts.next()?.parse().ok()?;
+    }
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(Ipv4Address(octets))
+}
+
+/// Parse colon separated hex groups into `groups`, returning how many there were.
+fn parse_ipv6_groups(s: &str, groups: &mut [u16]) -> Option<usize> {
+    if s.is_empty() {
+        return Some(0);
+    }
+
+    let mut len = 0;
+    for group in s.split(':') {
+        if group.is_empty() || group.len() > 4 {
+            return None;
+        }
+        *groups.get_mut(len)? = u16::from_str_radix(group, 16).ok()?;
+        len += 1;
+    }
+    Some(len)
+}
+
+/// Parse an IPv6 address, with at most one `::` standing for a run of zero groups.
+fn parse_ipv6(s: &str) -> Option<Ipv6Address> {
+    let mut halves = s.splitn(2, "::");
+    let mut groups = [0; 8];
+    let head_len = parse_ipv6_groups(halves.next()?, &mut groups)?;
+
+    match halves.next() {
+        Some(tail) => {
+            let mut tail_groups = [0; 8];
+            let tail_len = parse_ipv6_groups(tail, &mut tail_groups)?;
+            if head_len + tail_len > 7 {
+                return None;
+            }
+            groups[8 - tail_len..].copy_from_slice(&tail_groups[..tail_len]);
+        }
+        None if head_len == 8 => (),
+        None => return None,
+    }
+
+    let mut octets = [0; 16];
+    for (pair, group) in octets.chunks_mut(2).zip(groups.iter()) {
+        pair.copy_from_slice(&group.to_be_bytes());
+    }
+    Some(Ipv6Address(octets))
+}
+
+fn parse_ip(s: &str) -> Option<IpAddress> {
+    match parse_ipv4(s) {
+        Some(address) => Some(IpAddress::Ipv4(address)),
+        None => parse_ipv6(s).map(IpAddress::Ipv6),
+    }
+}
+
+fn parse_ipv4_cidr(s: &str) -> Option<Ipv4Cidr> {
+    let mut parts = s.splitn(2, '/');
+    let address = parse_ipv4(parts.next()?)?;
+    let prefix_len = parts.next()?.parse().ok().filter(|&len| len <= 32)?;
+
+    Some(Ipv4Cidr::new(address, prefix_len))
+}
+
+/// Parse a capture filter like `ip6 tcp host fe80::1 port 22`, an empty one captures everything.
+fn parse_filter(args: &[&str]) -> Result<Filter, &'static str> {
+    let mut filter = Filter::ANY;
+    let mut args = args.iter();
+
+    while let Some(&arg) = args.next() {
+        match arg {
+            "arp" => filter.ethertype = Some(EthernetProtocol::Arp),
+            "ip" => filter.ethertype = Some(EthernetProtocol::Ipv4),
+            "ip6" => filter.ethertype = Some(EthernetProtocol::Ipv6),
+            "icmp" => filter.protocol = Some(IpProtocol::Icmp),
+            "icmp6" => filter.protocol = Some(IpProtocol::Icmpv6),
+            "tcp" => filter.protocol = Some(IpProtocol::Tcp),
+            "udp" => filter.protocol = Some(IpProtocol::Udp),
+            "host" => {
+                let host = args.next().and_then(|host| parse_ip(host));
+                filter.host = Some(host.ok_or("invalid host")?);
+            }
+            "port" => {
+                let port = args.next().and_then(|port| port.parse().ok());
+                filter.port = Some(port.ok_or("invalid port")?);
+            }
+            "and" => (),
+            _ => return Err("filter: [arp|ip|ip6] [icmp|icmp6|tcp|udp] [host <a>] [port <n>]"),
+        }
+    }
+    Ok(filter)
+}
+
+fn help(_: &[&str], out: Out) -> Result<(), &'static str> {
+    let mut result = Ok(());
+
+    for command in BUILTINS.iter() {
+        result = result.and_then(|_| writeln!(out, "{}", command.help));
+    }
+    for command in super::COMMANDS.read().iter() {
+        result = result.and_then(|_| writeln!(out, "{}", command.help()));
+    }
+    result.map_err(|_| WRITE_FAILED)
+}
+
+fn ps(_: &[&str], out: Out) -> Result<(), &'static str> {
+    writeln!(out, "  PID STATE    PRIO COUNTER").map_err(|_| WRITE_FAILED)?;
+    for task in SCHEDULER.tasks() {
+        writeln!(
+            out,
+            "{:5} {:8} {:4} {:7}",
+            task.pid, task.state, task.priority, task.counter
+        )
+        .map_err(|_| WRITE_FAILED)?;
+    }
+    Ok(())
+}
+
+fn mem(_: &[&str], out: Out) -> Result<(), &'static str> {
+    let (size, used, free) = exec_with_irq_masked(|| {
+        let heap = ALLOCATOR.lock();
+        (heap.size(), heap.used(), heap.free())
+    });
+
+    writeln!(
+        out,
+        "heap: {} KiB, {} KiB used, {} KiB free",
+        size / 1024,
+        used / 1024,
+        free / 1024
+    )
+    .map_err(|_| WRITE_FAILED)?;
+    // Printed through the log.
+    memory::virt_mem_layout().print_layout();
+    Ok(())
+}
+
+fn irq(_: &[&str], _: Out) -> Result<(), &'static str> {
+    // Printed through the log.
+    bsp::exception::asynchronous::irq_manager().print_handler();
+    Ok(())
+}
+
+fn kill(args: &[&str], _: Out) -> Result<(), &'static str> {
+    let pid = match args {
+        [pid] => pid.parse().map_err(|_| "invalid pid")?,
+        _ => return Err("usage: kill <pid>"),
+    };
+
+    SCHEDULER.kill(pid)
+}
+
+fn spawn(args: &[&str], out: Out) -> Result<(), &'static str> {
+    let programs = PROGRAMS.read();
+    let name = match args {
+        [] => {
+            for (name, _) in programs.iter() {
+                writeln!(out, "{}", name).map_err(|_| WRITE_FAILED)?;
+            }
+            return Ok(());
+        }
+        [name] => name,
+        _ => return Err("usage: spawn [program]"),
+    };
+
+    let (_, entry) = programs
+        .iter()
+        .find(|(program, _)| program == name)
+        .ok_or("no such program")?;
+    let pid = process::add_user_process(*entry);
+    writeln!(out, "started {} as pid {}", name, pid).map_err(|_| WRITE_FAILED)
+}
+
+fn reboot(_: &[&str], _: Out) -> Result<(), &'static str> {
+    logging::flush();
+    bsp::reboot()
+}
+
+fn temp(_: &[&str], out: Out) -> Result<(), &'static str> {
+    let temperature = MBox::new()
+        .core_temperature()
+        .map_err(|_| "mailbox query failed")?;
+
+    writeln!(out, "{}.{:03} C", temperature / 1000, temperature % 1000).map_err(|_| WRITE_FAILED)
+}
+
+fn ifconfig(args: &[&str], out: Out) -> Result<(), &'static str> {
+    match args {
+        [] => (),
+        ["dhcp"] => {
+            net::dhcp::use_dhcp();
+            return Ok(());
+        }
+        [address] | [address, _] => {
+            let address = parse_ipv4_cidr(address).ok_or("invalid address")?;
+            let router = match args.get(1) {
+                Some(router) => Some(parse_ipv4(router).ok_or("invalid gateway")?),
+                None => None,
+            };
+            net::dhcp::use_static(address, router);
+            return Ok(());
+        }
+        _ => return Err("usage: ifconfig [dhcp|<a.b.c.d/n> [gw]]"),
+    }
+
+    if !unsafe { ETH.is_initialized() } {
+        return Err("no ethernet device");
+    }
+    // The USB driver is also used from its FIQ.
+    let mac = exec_with_interrupts_masked(|| USB.get_eth_addr());
+    let stats = net::link::stats();
+
+    writeln!(
+        out,
+        "eth0: {} link {}",
+        mac,
+        if net::link::is_up() { "up" } else { "down" }
+    )
+    .map_err(|_| WRITE_FAILED)?;
+    writeln!(
+        out,
+        "    rx packets {} bytes {} dropped {}",
+        stats.rx_packets, stats.rx_bytes, stats.rx_dropped
+    )
+    .map_err(|_| WRITE_FAILED)?;
+    writeln!(
+        out,
+        "    tx packets {} bytes {} errors {}",
+        stats.tx_packets, stats.tx_bytes, stats.tx_errors
+    )
+    .map_err(|_| WRITE_FAILED)?;
+    writeln!(out, "    link changes {}", stats.link_changes).map_err(|_| WRITE_FAILED)?;
+    let latency = net::rx::latency();
+    writeln!(
+        out,
+        "    rx latency avg {} us max {} us",
+        latency.average.as_micros(),
+        latency.max.as_micros()
+    )
+    .map_err(|_| WRITE_FAILED)?;
+    for addr in unsafe { ETH.ip_addrs() } {
+        match addr {
+            // Slots still waiting for DHCP or SLAAC.
+            _ if addr.address().is_unspecified() => Ok(()),
+            IpCidr::Ipv6(addr) => writeln!(out, "    inet6 {}", addr),
+            addr => writeln!(out, "    inet {}", addr),
+        }
+        .map_err(|_| WRITE_FAILED)?;
+    }
+    if let Some(router) = net::slaac::router() {
+        writeln!(out, "    gateway6 {}", router).map_err(|_| WRITE_FAILED)?;
+    }
+    match net::dhcp::config() {
+        Some(config) => {
+            if let Some(router) = config.router {
+                writeln!(out, "    gateway {}", router).map_err(|_| WRITE_FAILED)?;
+            }
+            if let Some(dns) = config.dns {
+                writeln!(out, "    dns {}", dns).map_err(|_| WRITE_FAILED)?;
+            }
+            writeln!(out, "    config {}", config.source)
+        }
+        None => writeln!(out, "    config dhcp, waiting for a lease"),
+    }
+    .map_err(|_| WRITE_FAILED)
+}
+
+fn ping(args: &[&str], out: Out) -> Result<(), &'static str> {
+    let (target, count) = match args {
+        [target] => (target, 4),
+        [target, count] => (target, count.parse().map_err(|_| "invalid count")?),
+        _ => return Err("usage: ping <address> [count]"),
+    };
+    let target = parse_ip(target).ok_or("invalid address")?;
+
+    for _ in 0..count {
+        let seq_no = net::ping::start(target)?;
+        let mut waited = 0;
+        let rtt = loop {
+            if let Some(rtt) = net::ping::result() {
+                break Some(rtt);
+            }
+            if waited >= PING_TIMEOUT_MS {
+                break None;
+            }
+            syscall::sleep(PING_POLL_MS);
+            waited += PING_POLL_MS;
+        };
+
+        match rtt {
+            Some(rtt) => writeln!(
+                out,
+                "reply from {}: seq={} time={}.{:03} ms",
+                target,
+                seq_no,
+                rtt.as_millis(),
+                rtt.as_micros() % 1000
+            ),
+            None => writeln!(out, "no reply from {}: seq={}", target, seq_no),
+        }
+        .map_err(|_| WRITE_FAILED)?;
+
+        // One request per second.
+        if waited < PING_TIMEOUT_MS {
+            syscall::sleep(PING_TIMEOUT_MS - waited);
+        }
+    }
+    Ok(())
+}
+
+/// Control packet capture. `dump` writes the buffer as a hex encoded pcap file, for
+/// `xxd -r -p capture.hex capture.pcap` on the host.
+fn pcap(args: &[&str], out: Out) -> Result<(), &'static str> {
+    match args {
+        [] => (),
+        ["start", filter @ ..] => {
+            net::capture::start(parse_filter(filter)?);
+            return Ok(());
+        }
+        ["stop"] => {
+            net::capture::stop();
+            return Ok(());
+        }
+        ["stream", "off"] => {
+            net::capture::stream(None);
+            return Ok(());
+        }
+        ["stream", port] => {
+            let port = port.parse().map_err(|_| "invalid port")?;
+            net::capture::stream(Some(port));
+            return Ok(());
+        }
+        ["dump"] => {
+            for line in net::capture::pcap().chunks(DUMP_LINE_LEN) {
+                for byte in line {
+                    write!(out, "{:02x}", byte).map_err(|_| WRITE_FAILED)?;
+                }
+                writeln!(out).map_err(|_| WRITE_FAILED)?;
+            }
+            return Ok(());
+        }
+        _ => return Err("usage: pcap [start [filter]|stop|dump|stream <port|off>]"),
+    }
+
+    let status = net::capture::status();
+    let state = if status.enabled {
+        "capturing"
+    } else {
+        "stopped"
+    };
+    writeln!(out, "{}, filter {}", state, status.filter).map_err(|_| WRITE_FAILED)?;
+    writeln!(
+        out,
+        "    {} frames, {} bytes buffered, {} overwritten",
+        status.frames, status.bytes, status.overwritten
+    )
+    .map_err(|_| WRITE_FAILED)?;
+    match status.stream {
+        Some((port, true)) => writeln!(out, "    streaming on port {}, client connected", port),
+        Some((port, false)) => writeln!(out, "    streaming on port {}", port),
+        None => Ok(()),
+    }
+    .map_err(|_| WRITE_FAILED)
+}
+
+fn uptime(_: &[&str], out: Out) -> Result<(), &'static str> {
+    let up = time::time_manager().uptime().as_secs();
+
+    writeln!(
+        out,
+        "up {}d {:02}:{:02}:{:02}",
+        up / 86400,
+        up / 3600 % 24,
+        up / 60 % 60,
+        up % 60
+    )
+    .map_err(|_| WRITE_FAILED)?;
+    match time::realtime() {
+        Some(now) => writeln!(out, "realtime: {} s since the epoch", now.as_secs()),
+        None => writeln!(out, "realtime: not set"),
+    }
+    .map_err(|_| WRITE_FAILED)
+}
+
+fn log(args: &[&str], out: Out) -> Result<(), &'static str> {
+    let parse = |level: &str| {
+        level
+            .parse::<logging::LevelFilter>()
+            .map_err(|_| "invalid level")
+    };
+
+    match args {
+        [] => {
+            let mut result = Ok(());
+            logging::for_each_filter(|module, level| {
+                result = result
+                    .and_then(|_| writeln!(out, "{:20} {}", module.unwrap_or("(default)"), level));
+            });
+            result.map_err(|_| WRITE_FAILED)
+        }
+        [level] => {
+            logging::set_level(parse(level)?);
+            Ok(())
+        }
+        [module, "clear"] => {
+            logging::clear_filter(module);
+            Ok(())
+        }
+        [module, level] => {
+            logging::set_filter(module, parse(level)?);
+            Ok(())
+        }
+        _ => Err("usage: log [[module] level|clear]"),
+    }
+}
+
+/// Read `file` from `server` over TFTP.
+fn fetch(server: IpAddress, file: &str, out: Out) -> Result<Vec<u8>, &'static str> {
+    tftp::start(server, file)?;
+    loop {
+        match tftp::status() {
+            tftp::Status::Receiving { .. } => syscall::sleep(NETBOOT_POLL_MS),
+            tftp::Status::Done(_) => return tftp::take().ok_or("transfer cancelled"),
+            tftp::Status::Idle => return Err("transfer cancelled"),
+            tftp::Status::Failed(e) => {
+                tftp::cancel();
+                writeln!(out, "{}: {}", file, e).map_err(|_| WRITE_FAILED)?;
+                return Err("transfer failed");
+            }
+        }
+    }
+}
+
+/// Read a kernel image and its CRC-32, written in hex by `make netboot`, and chain-load it. The
+/// server defaults to the router.
+fn netboot(args: &[&str], out: Out) -> Result<(), &'static str> {
+    let (server, image) = match args {
+        [] => (None, NETBOOT_IMAGE),
+        [server] => (Some(*server), NETBOOT_IMAGE),
+        [server, image] => (Some(*server), *image),
+        _ => return Err("usage: netboot [host] [image]"),
+    };
+    let server = match server {
+        Some(server) => parse_ip(server).ok_or("invalid address")?,
+        None => net::dhcp::config()
+            .and_then(|config| config.router)
+            .map(IpAddress::Ipv4)
+            .ok_or("no router to boot from, give a host")?,
+    };
+
+    let crc = fetch(server, &format!("{}.crc32", image), out)?;
+    let crc = str::from_utf8(&crc)
+        .ok()
+        .and_then(|crc| u32::from_str_radix(crc.trim(), 16).ok())
+        .ok_or("invalid checksum file")?;
+    writeln!(out, "reading {} from {}", image, server).map_err(|_| WRITE_FAILED)?;
+    let image = fetch(server, image, out)?;
+
+    writeln!(out, "booting {} bytes", image.len()).map_err(|_| WRITE_FAILED)?;
+    logging::flush();
+    cpu::chainload::boot(image, crc).map(|_| ())
+}
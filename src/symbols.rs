@@ -0,0 +1,155 @@
+//! Kernel symbol table, for resolving code addresses to function names.
+//!
+//! The build reserves the `.ksyms` section and `utils/ksyms.rb` fills it in from the linked ELF,
+//! see there for the format. Kernels built without that step, like the test binaries, have an empty
+//! table and resolve nothing.
+
+use core::convert::TryInto;
+use core::fmt;
+
+/// Space reserved for the table.
+const TABLE_SIZE: usize = 256 * 1024;
+
+const MAGIC: &[u8; 4] = b"KSYM";
+const HEADER_LEN: usize = 8;
+const ENTRY_LEN: usize = 16;
+
+#[used]
+#[link_section = ".ksyms"]
+static TABLE_SPACE: [u8; TABLE_SIZE] = [0; TABLE_SIZE];
+
+struct Table<'a> {
+    entries: &'a [u8],
+    names: &'a [u8],
+}
+
+impl<'a> Table<'a> {
+    fn parse(bytes: &'a [u8]) -> Option<Self> {
+        if bytes.len() < HEADER_LEN || &bytes[..4] != MAGIC {
+            return None;
+        }
+        let count = u32::from_le_bytes(bytes[4..8].try_into().ok()?) as usize;
+        let names_start = HEADER_LEN.checked_add(count.checked_mul(ENTRY_LEN)?)?;
+
+        Some(Self {
+            entries: bytes.get(HEADER_LEN..names_start)?,
+            names: &bytes[names_start..],
+        })
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len() / ENTRY_LEN
+    }
+
+    /// Address, size and name offset of entry `i`.
+    fn entry(&self, i: usize) -> (u64, u64, usize) {
+        let entry = &self.entries[i * ENTRY_LEN..(i + 1) * ENTRY_LEN];
+
+        (
+            u64::from_le_bytes(entry[0..8].try_into().unwrap()),
+            u32::from_le_bytes(entry[8..12].try_into().unwrap()) as u64,
+            u32::from_le_bytes(entry[12..16].try_into().unwrap()) as usize,
+        )
+    }
+
+    fn name(&self, offset: usize) -> Option<&'a str> {
+        let len = *self.names.get(offset)? as usize;
+        let name = self.names.get(offset + 1..offset + 1 + len)?;
+
+        core::str::from_utf8(name).ok()
+    }
+
+    /// The symbol containing `addr`, and the offset into it.
+    fn lookup(&self, addr: u64) -> Option<(&'a str, u64)> {
+        // The number of entries starting at or below `addr`.
+        let mut low = 0;
+        let mut high = self.len();
+        while low < high {
+            let mid = (low + high) / 2;
+            if self.entry(mid).0 <= addr {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+
+        let (start, size, name) = self.entry(low.checked_sub(1)?);
+        let offset = addr - start;
+        // Symbols without a size extend to the next one.
+        if size != 0 && offset >= size {
+            return None;
+        }
+        Some((self.name(name)?, offset))
+    }
+}
+
+fn table() -> Option<Table<'static>> {
+    extern "C" {
+        static __ksyms_start: u8;
+        static __ksyms_end: u8;
+    }
+
+    // Read through the linker symbols, the compiler knows `TABLE_SPACE` as all zeroes.
+    let bytes = unsafe {
+        let start = &__ksyms_start as *const u8;
+        let len = &__ksyms_end as *const u8 as usize - start as usize;
+        core::slice::from_raw_parts(start, len)
+    };
+    Table::parse(bytes)
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// The function containing `addr`, and the offset into it.
+pub fn resolve(addr: u64) -> Option<(&'static str, u64)> {
+    table()?.lookup(addr)
+}
+
+/// Prints a code address, followed by `function+0x1c` if it resolves.
+#[derive(Copy, Clone)]
+pub struct Address(pub u64);
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#018x}", self.0)?;
+        if let Some((name, offset)) = resolve(self.0) {
+            write!(f, " {}+{:#x}", name, offset)?;
+        }
+
+        Ok(())
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Testing
+//--------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_macros::kernel_test;
+
+    /// Addresses resolve to the closest symbol below, within its size.
+    #[kernel_test]
+    fn lookup_finds_enclosing_symbol() {
+        #[rustfmt::skip]
+        let bytes = [
+            b'K', b'S', b'Y', b'M', 2, 0, 0, 0,
+            // 0x1000, 0x20 bytes, "a"
+            0x00, 0x10, 0, 0, 0, 0, 0, 0, 0x20, 0, 0, 0, 0, 0, 0, 0,
+            // 0x1040, no size, "bc"
+            0x40, 0x10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0,
+            1, b'a', 2, b'b', b'c',
+        ];
+        let table = Table::parse(&bytes).unwrap();
+
+        assert_eq!(table.lookup(0xfff), None);
+        assert_eq!(table.lookup(0x1000), Some(("a", 0)));
+        assert_eq!(table.lookup(0x101c), Some(("a", 0x1c)));
+        assert_eq!(table.lookup(0x1020), None);
+        assert_eq!(table.lookup(0x2000), Some(("bc", 0xfc0)));
+        assert!(Table::parse(&bytes[..HEADER_LEN + ENTRY_LEN]).is_none());
+    }
+}
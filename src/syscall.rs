@@ -1,16 +1,22 @@
-use crate::bsp::generic_timer;
+use crate::console::interface::Read;
 use crate::exception::{self, ExceptionContext};
+use crate::net::{self, socket};
 use crate::process::{Task, TaskState};
-use crate::sched::SCHEDULER;
+use crate::sched::{self, SCHEDULER};
+use crate::{bsp, cpu, time};
 use alloc::boxed::Box;
+use core::sync::atomic::{AtomicU64, Ordering};
 use core::time::Duration;
 
+/// Clock ids for `clock_gettime`.
+pub const CLOCK_REALTIME: u64 = 0;
+pub const CLOCK_MONOTONIC: u64 = 1;
+
 fn sleep_task(ms: u64, ec: &mut ExceptionContext) {
-    let timer = generic_timer();
-    let begin = timer.current_time();
+    let begin = time::time_manager().uptime();
     let target_time = begin + Duration::from_millis(ms as u64);
     let polling_fn = Box::new(move |task: &mut Task| {
-        let current = timer.current_time();
+        let current = time::time_manager().uptime();
         if current > target_time {
             task.context.gpr[7] = 0; // x7 = 0; succeed
             task.context.gpr[0] = (current - begin).as_millis() as u64; // x0 = elapsed time in ms
@@ -25,6 +31,151 @@ fn sleep_task(ms: u64, ec: &mut ExceptionContext) {
     })
 }
 
+fn read_clock(clock: u64, ec: &mut ExceptionContext) {
+    let now = match clock {
+        CLOCK_REALTIME => time::realtime(),
+        CLOCK_MONOTONIC => Some(time::time_manager().uptime()),
+        _ => None,
+    };
+
+    match now {
+        Some(now) => {
+            ec.gpr[0] = now.as_secs(); // x0 = seconds
+            ec.gpr[1] = now.subsec_nanos() as u64; // x1 = nanoseconds
+            ec.gpr[7] = 0;
+        }
+        None => ec.gpr[7] = 1,
+    }
+}
+
+fn set_clock(secs: u64, micros: u64, ec: &mut ExceptionContext) {
+    if micros >= 1_000_000 {
+        ec.gpr[7] = 1;
+        return;
+    }
+    time::set_realtime(Duration::new(secs, micros as u32 * 1_000));
+    ec.gpr[7] = 0;
+}
+
+/// The last task to wait in `getc`, 0 for none.
+static GETC_WAITER: AtomicU64 = AtomicU64::new(0);
+
+fn getc_task(ec: &mut ExceptionContext) {
+    if let Some(c) = bsp::console().try_read_char() {
+        ec.gpr[0] = c as u64; // x0 = character
+        ec.gpr[7] = 0;
+        return;
+    }
+
+    let polling_fn = Box::new(move |task: &mut Task| match bsp::console().try_read_char() {
+        Some(c) => {
+            let _ = GETC_WAITER.compare_exchange(task.pid, 0, Ordering::AcqRel, Ordering::Relaxed);
+            task.context.gpr[7] = 0;
+            task.context.gpr[0] = c as u64;
+            true
+        }
+        None => false,
+    });
+
+    GETC_WAITER.store(ec.tpidr, Ordering::Release);
+    exception::asynchronous::exec_with_irq_masked(|| {
+        SCHEDULER.switch(TaskState::WAITING(polling_fn), ec)
+    })
+}
+
+fn wait_network_task(ms: u64, ec: &mut ExceptionContext) {
+    let deadline = time::time_manager().uptime() + Duration::from_millis(ms);
+    if net::rx::take_wakeup() {
+        ec.gpr[7] = 0;
+        return;
+    }
+
+    let polling_fn = Box::new(move |task: &mut Task| {
+        if net::rx::take_wakeup() || time::time_manager().uptime() >= deadline {
+            task.context.gpr[7] = 0;
+            true
+        } else {
+            false
+        }
+    });
+
+    exception::asynchronous::exec_with_irq_masked(|| {
+        SCHEDULER.switch(TaskState::WAITING(polling_fn), ec)
+    })
+}
+
+/// Store the result of a socket call in x0, or the error in x7.
+fn socket_result(result: Result<u64, socket::Error>, ec: &mut ExceptionContext) {
+    match result {
+        Ok(value) => {
+            ec.gpr[0] = value;
+            ec.gpr[7] = 0;
+        }
+        Err(e) => ec.gpr[7] = e as u64,
+    }
+}
+
+/// Run a socket operation that may block, parking the task until it completes.
+fn socket_wait(
+    mut op: impl FnMut() -> Result<Option<u64>, socket::Error> + Send + 'static,
+    ec: &mut ExceptionContext,
+) {
+    match op() {
+        Ok(Some(value)) => return socket_result(Ok(value), ec),
+        Err(e) => return socket_result(Err(e), ec),
+        Ok(None) => (),
+    }
+
+    let polling_fn = Box::new(move |task: &mut Task| match op() {
+        Ok(None) => false,
+        result => {
+            socket_result(result.map(Option::unwrap), &mut task.context);
+            net::wake();
+            true
+        }
+    });
+
+    exception::asynchronous::exec_with_irq_masked(|| {
+        SCHEDULER.switch(TaskState::WAITING(polling_fn), ec)
+    })
+}
+
+fn socket_call(ec: &mut ExceptionContext) {
+    let pid = ec.tpidr;
+    let (a0, a1, a2, a3) = (ec.gpr[0], ec.gpr[1], ec.gpr[2], ec.gpr[3]);
+
+    match ec.gpr[8] {
+        6 => socket_result(socket::socket(pid, a0), ec),
+        7 => socket_result(socket::bind(pid, a0, a1 as u16).map(|_| 0), ec),
+        8 => socket_result(socket::listen(pid, a0, a1).map(|_| 0), ec),
+        9 => socket_wait(move || socket::accept(pid, a0), ec),
+        10 => match unsafe { socket::connect(pid, a0, a1) } {
+            Ok(()) => socket_wait(move || socket::poll_connect(pid, a0), ec),
+            Err(e) => socket_result(Err(e), ec),
+        },
+        // The buffer belongs to the task, which is parked until the call completes.
+        11 => socket_wait(move || unsafe { socket::send(pid, a0, a1, a2) }, ec),
+        12 => {
+            // A timeout in milliseconds, 0 to wait for ever.
+            let deadline = time::time_manager().uptime() + Duration::from_millis(a3);
+            socket_wait(
+                move || match unsafe { socket::recv(pid, a0, a1, a2) } {
+                    Ok(None) if a3 != 0 && time::time_manager().uptime() >= deadline => {
+                        Err(socket::Error::TimedOut)
+                    }
+                    result => result,
+                },
+                ec,
+            )
+        }
+        13 => socket_result(socket::close(pid, a0).map(|_| 0), ec),
+        14 => socket_wait(move || unsafe { socket::sendto(pid, a0, a1, a2, a3) }, ec),
+        _ => socket_wait(move || unsafe { socket::recvfrom(pid, a0, a1, a2, a3) }, ec),
+    }
+    // Send what the call queued without waiting for the next timed poll.
+    net::wake();
+}
+
 fn exit_task(ec: &mut ExceptionContext) {
     exception::asynchronous::exec_with_irq_masked(|| SCHEDULER.exit_task(ec))
 }
@@ -41,30 +192,264 @@ pub fn handle(ec: &mut ExceptionContext) -> Result<(), &str> {
             exit_task(ec);
             Ok(())
         }
+        3 => {
+            // clock_gettime syscall
+            read_clock(ec.gpr[0], ec);
+            Ok(())
+        }
+        4 => {
+            // settimeofday syscall
+            set_clock(ec.gpr[0], ec.gpr[1], ec);
+            Ok(())
+        }
+        5 => {
+            // getc syscall, blocks until a character arrives
+            getc_task(ec);
+            Ok(())
+        }
+        6..=15 => {
+            // socket, bind, listen, accept, connect, send, recv, close, sendto and recvfrom
+            // syscalls
+            socket_call(ec);
+            Ok(())
+        }
+        16 => {
+            // wait_network syscall, for the network task
+            wait_network_task(ec.gpr[0], ec);
+            Ok(())
+        }
         _ => Err("does not exist"),
     }
 }
 
+/// Have the task waiting in `getc`, if any, take its character right away rather than at the next
+/// scheduler tick. For console inputs that are fed outside of an interrupt, like the Telnet
+/// session.
+pub fn wake_reader() {
+    match GETC_WAITER.load(Ordering::Acquire) {
+        0 => (),
+        pid => sched::wake(pid, cpu::core_id::<usize>()),
+    }
+}
+
+/// Make a socket syscall, returning x0 or the error in x7.
+fn socket_syscall(number: u64, args: [u64; 4]) -> Result<u64, socket::Error> {
+    let (value, err): (u64, u64);
+    unsafe {
+        llvm_asm! {"
+                mov x8, $2
+                mov x0, $3
+                mov x1, $4
+                mov x2, $5
+                mov x3, $6
+                svc #0
+                mov $0, x0
+                mov $1, x7
+            "
+        : "=r"(value), "=r"(err)
+        : "r"(number), "r"(args[0]), "r"(args[1]), "r"(args[2]), "r"(args[3])
+        : "x0", "x1", "x2", "x3", "x7", "x8"
+        : "volatile"
+        }
+    }
+
+    match socket::Error::from_code(err) {
+        None => Ok(value),
+        Some(e) => Err(e),
+    }
+}
+
+/// Sleep for `time` milliseconds.
 pub fn sleep(time: u64) {
     unsafe {
         llvm_asm! {"
                 mov w8, 1
                 mov x0, $0
                 svc #0
-                ret
             "
-        ::   "r"(time)
+        :
+        : "r"(time)
+        : "x0", "x7", "x8"
+        : "volatile"
+        }
+    }
+}
+
+/// Sleep until the network task is woken by a received frame or a socket call, or for `timeout`.
+pub fn wait_network(timeout: Duration) {
+    unsafe {
+        llvm_asm! {"
+                mov w8, 16
+                mov x0, $0
+                svc #0
+            "
+        :
+        : "r"(timeout.as_millis() as u64)
+        : "x0", "x7", "x8"
+        : "volatile"
         }
     }
 }
 
+/// Terminate the calling task.
 pub fn exit() {
     unsafe {
         llvm_asm! {"
                 mov w8, 2
                 svc #0
-                ret
             "
+        :
+        :
+        : "x7", "x8"
+        : "volatile"
+        }
+    }
+}
+
+/// Read `clock`, one of `CLOCK_REALTIME` or `CLOCK_MONOTONIC`.
+///
+/// Returns `None` for unknown clocks and while the realtime clock is unset.
+pub fn clock_gettime(clock: u64) -> Option<Duration> {
+    let (secs, nanos, err): (u64, u64, u64);
+    unsafe {
+        llvm_asm! {"
+                mov w8, 3
+                mov x0, $3
+                svc #0
+                mov $0, x0
+                mov $1, x1
+                mov $2, x7
+            "
+        : "=r"(secs), "=r"(nanos), "=r"(err)
+        : "r"(clock)
+        : "x0", "x1", "x7", "x8"
+        : "volatile"
+        }
+    }
+
+    if err == 0 {
+        Some(Duration::new(secs, nanos as u32))
+    } else {
+        None
+    }
+}
+
+/// Set the realtime clock to `now`, the time since the Unix epoch.
+pub fn settimeofday(now: Duration) -> Result<(), ()> {
+    let err: u64;
+    unsafe {
+        llvm_asm! {"
+                mov w8, 4
+                mov x0, $1
+                mov x1, $2
+                svc #0
+                mov $0, x7
+            "
+        : "=r"(err)
+        : "r"(now.as_secs()), "r"(now.subsec_micros() as u64)
+        : "x0", "x1", "x7", "x8"
+        : "volatile"
         }
     }
+
+    if err == 0 {
+        Ok(())
+    } else {
+        Err(())
+    }
+}
+
+/// Read a character from the console, sleeping until one arrives.
+pub fn getc() -> char {
+    let c: u64;
+    unsafe {
+        llvm_asm! {"
+                mov w8, 5
+                svc #0
+                mov $0, x0
+            "
+        : "=r"(c)
+        :
+        : "x0", "x7", "x8"
+        : "volatile"
+        }
+    }
+
+    c as u8 as char
+}
+
+/// Create a socket of `kind`, `socket::SOCK_STREAM` or `socket::SOCK_DGRAM`, and return its
+/// handle.
+pub fn socket(kind: u64) -> Result<u64, socket::Error> {
+    socket_syscall(6, [kind, 0, 0, 0])
+}
+
+/// Bind the socket `fd` to the local `port`.
+pub fn bind(fd: u64, port: u16) -> Result<(), socket::Error> {
+    socket_syscall(7, [fd, port as u64, 0, 0]).map(|_| ())
+}
+
+/// Accept connections on the bound socket `fd`, up to `backlog` before they are taken.
+pub fn listen(fd: u64, backlog: u64) -> Result<(), socket::Error> {
+    socket_syscall(8, [fd, backlog, 0, 0]).map(|_| ())
+}
+
+/// Wait for a connection on the listening socket `fd` and return a new socket for it.
+pub fn accept(fd: u64) -> Result<u64, socket::Error> {
+    socket_syscall(9, [fd, 0, 0, 0])
+}
+
+/// Connect the socket `fd` to `to`, sleeping until the connection is established. UDP sockets
+/// just send there.
+pub fn connect(fd: u64, to: &socket::Endpoint) -> Result<(), socket::Error> {
+    let to = to as *const socket::Endpoint as u64;
+
+    socket_syscall(10, [fd, to, 0, 0]).map(|_| ())
+}
+
+/// Send bytes from `buf` on `fd`, sleeping while the send buffer is full. Returns how many were
+/// queued, which may be fewer than `buf.len()`.
+pub fn send(fd: u64, buf: &[u8]) -> Result<usize, socket::Error> {
+    socket_syscall(11, [fd, buf.as_ptr() as u64, buf.len() as u64, 0]).map(|sent| sent as usize)
+}
+
+/// Receive bytes into `buf` from `fd`, sleeping until some arrive. Returns how many, 0 once the
+/// peer closed the connection.
+pub fn recv(fd: u64, buf: &mut [u8]) -> Result<usize, socket::Error> {
+    socket_syscall(12, [fd, buf.as_mut_ptr() as u64, buf.len() as u64, 0])
+        .map(|received| received as usize)
+}
+
+/// Like `recv`, but fail with `socket::Error::TimedOut` if nothing arrives within `timeout`, at
+/// least a millisecond.
+pub fn recv_timeout(fd: u64, buf: &mut [u8], timeout: Duration) -> Result<usize, socket::Error> {
+    let timeout = (timeout.as_millis() as u64).max(1);
+
+    socket_syscall(12, [fd, buf.as_mut_ptr() as u64, buf.len() as u64, timeout])
+        .map(|received| received as usize)
+}
+
+/// Close the socket `fd`.
+pub fn close(fd: u64) -> Result<(), socket::Error> {
+    socket_syscall(13, [fd, 0, 0, 0]).map(|_| ())
+}
+
+/// Send `buf` as a datagram on the UDP socket `fd` to `to`, sleeping while the send buffer is full.
+pub fn sendto(fd: u64, buf: &[u8], to: &socket::Endpoint) -> Result<usize, socket::Error> {
+    let to = to as *const socket::Endpoint as u64;
+
+    socket_syscall(14, [fd, buf.as_ptr() as u64, buf.len() as u64, to]).map(|sent| sent as usize)
+}
+
+/// Receive a datagram into `buf` from the UDP socket `fd`, sleeping until one arrives. Returns its
+/// length, truncated to `buf`, and the sender.
+pub fn recvfrom(fd: u64, buf: &mut [u8]) -> Result<(usize, socket::Endpoint), socket::Error> {
+    let mut from = socket::Endpoint::default();
+    let from_addr = &mut from as *mut socket::Endpoint as u64;
+
+    let received = socket_syscall(
+        15,
+        [fd, buf.as_mut_ptr() as u64, buf.len() as u64, from_addr],
+    )?;
+    Ok((received as usize, from))
 }
@@ -0,0 +1,238 @@
+//! Timekeeping.
+//!
+//! The kernel's monotonic clock is the ARM generic timer, whose counter is shared by all cores.
+//! The BCM system timer can be selected as the clocksource instead; the clock stays continuous
+//! across the switch. Timer events are per core and
+//! backed by each core's CNTP timer. The realtime clock is the monotonic clock plus an epoch
+//! offset, set through `settimeofday` or by the SNTP client.
+
+use crate::{
+    bsp,
+    exception::{asynchronous::exec_with_interrupts_masked, ExceptionContext},
+};
+use core::sync::atomic::{
+    fence, spin_loop_hint, AtomicBool, AtomicI64, AtomicU64, AtomicU8, AtomicUsize, Ordering,
+};
+use core::time::Duration;
+use spin::Mutex;
+
+/// Timekeeping interfaces.
+pub mod interface {
+    use core::time::Duration;
+
+    /// Time management functions.
+    pub trait TimeManager {
+        /// The timer's resolution.
+        fn resolution(&self) -> Duration;
+
+        /// The uptime since power-on of the device.
+        ///
+        /// This includes time consumed by firmware and bootloaders.
+        fn uptime(&self) -> Duration;
+
+        /// Spin for a given duration.
+        fn spin_for(&self, duration: Duration);
+    }
+
+    /// Per-core timer events.
+    ///
+    /// All functions act on the executing core's timer. Handlers run in IRQ context.
+    pub trait EventTimer {
+        /// Call `handler` once, `after` from now.
+        fn set_oneshot(
+            &self,
+            after: Duration,
+            handler: super::EventHandler,
+        ) -> Result<super::EventId, &'static str>;
+
+        /// Call `handler` every `period`, starting one period from now.
+        fn set_periodic(
+            &self,
+            period: Duration,
+            handler: super::EventHandler,
+        ) -> Result<super::EventId, &'static str>;
+
+        /// Remove a pending event.
+        fn cancel(&self, id: super::EventId);
+    }
+}
+
+/// Handler called when a timer event fires.
+pub type EventHandler = fn(&mut ExceptionContext);
+
+/// Identifies a pending timer event on the core that created it.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct EventId(pub usize);
+
+/// Sources for the monotonic clock.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Clocksource {
+    /// The ARM generic timer (CNTPCT_EL0).
+    ArmGeneric,
+
+    /// The BCM 1 MHz system timer.
+    BcmSystemTimer,
+}
+
+static CLOCKSOURCE: AtomicU8 = AtomicU8::new(Clocksource::ArmGeneric as u8);
+
+/// Added to the active clocksource's uptime, in nanoseconds.
+static CLOCK_OFFSET_NS: AtomicI64 = AtomicI64::new(0);
+
+/// Odd while `set_clocksource` updates the clocksource and its offset.
+static CLOCK_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+/// Serializes `set_clocksource`.
+static CLOCK_SWITCH: Mutex<()> = Mutex::new(());
+
+/// The monotonic clock: the active clocksource plus the offset carried across switches.
+struct Clock;
+
+static CLOCK: Clock = Clock;
+
+fn source(id: u8) -> &'static dyn interface::TimeManager {
+    if id == Clocksource::BcmSystemTimer as u8 {
+        &bsp::SYSTEM_TIMER
+    } else {
+        &bsp::GENERIC_TIMER
+    }
+}
+
+impl interface::TimeManager for Clock {
+    fn resolution(&self) -> Duration {
+        source(CLOCKSOURCE.load(Ordering::Relaxed)).resolution()
+    }
+
+    fn uptime(&self) -> Duration {
+        loop {
+            let seq = CLOCK_SEQ.load(Ordering::Acquire);
+            if seq & 1 == 0 {
+                let id = CLOCKSOURCE.load(Ordering::Relaxed);
+                let offset = CLOCK_OFFSET_NS.load(Ordering::Relaxed);
+                let raw = source(id).uptime().as_nanos() as i64;
+
+                fence(Ordering::Acquire);
+                if CLOCK_SEQ.load(Ordering::Relaxed) == seq {
+                    return Duration::from_nanos((raw + offset).max(0) as u64);
+                }
+            }
+            spin_loop_hint();
+        }
+    }
+
+    fn spin_for(&self, duration: Duration) {
+        source(CLOCKSOURCE.load(Ordering::Relaxed)).spin_for(duration)
+    }
+}
+
+/// Select the clocksource backing `time_manager()`.
+///
+/// The clocksources count from different epochs. The new one is offset so that `uptime()`
+/// continues from its current value, which keeps the realtime offset and pending deadlines valid.
+pub fn set_clocksource(source_id: Clocksource) {
+    exec_with_interrupts_masked(|| {
+        let _guard = CLOCK_SWITCH.lock();
+        if clocksource() == source_id {
+            return;
+        }
+        let now = time_manager().uptime().as_nanos() as i64;
+
+        let seq = CLOCK_SEQ.load(Ordering::Relaxed);
+        CLOCK_SEQ.store(seq + 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+
+        let raw = source(source_id as u8).uptime().as_nanos() as i64;
+        CLOCKSOURCE.store(source_id as u8, Ordering::Relaxed);
+        CLOCK_OFFSET_NS.store(now - raw, Ordering::Relaxed);
+
+        CLOCK_SEQ.store(seq + 2, Ordering::Release);
+    })
+}
+
+/// Return the active clocksource.
+pub fn clocksource() -> Clocksource {
+    if CLOCKSOURCE.load(Ordering::Relaxed) == Clocksource::BcmSystemTimer as u8 {
+        Clocksource::BcmSystemTimer
+    } else {
+        Clocksource::ArmGeneric
+    }
+}
+
+/// Return a reference to the monotonic clock.
+pub fn time_manager() -> &'static dyn interface::TimeManager {
+    &CLOCK
+}
+
+/// Offset of the realtime clock from the monotonic clock, in nanoseconds since the Unix epoch.
+static REALTIME_OFFSET_NS: AtomicU64 = AtomicU64::new(0);
+static REALTIME_VALID: AtomicBool = AtomicBool::new(false);
+
+/// Set the realtime clock to `now`, the time since the Unix epoch.
+pub fn set_realtime(now: Duration) {
+    let offset = now.checked_sub(time_manager().uptime()).unwrap_or_default();
+
+    REALTIME_OFFSET_NS.store(offset.as_nanos() as u64, Ordering::Relaxed);
+    REALTIME_VALID.store(true, Ordering::Release);
+}
+
+/// The time since the Unix epoch, or `None` if the realtime clock was never set.
+///
+/// The Pi has no RTC, so this stays `None` until userspace or the SNTP client sets it.
+pub fn realtime() -> Option<Duration> {
+    if !REALTIME_VALID.load(Ordering::Acquire) {
+        return None;
+    }
+    let offset = Duration::from_nanos(REALTIME_OFFSET_NS.load(Ordering::Relaxed));
+
+    Some(offset + time_manager().uptime())
+}
+
+/// Return a reference to the per-core event timer.
+pub fn event_timer() -> &'static impl interface::EventTimer {
+    &bsp::LOCAL_TIMER
+}
+
+//--------------------------------------------------------------------------------------------------
+// Testing
+//--------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_macros::kernel_test;
+
+    /// Switching clocksources must neither move the clock backwards nor shift realtime.
+    #[kernel_test]
+    fn clocksource_switch_is_continuous() {
+        set_realtime(Duration::from_secs(1_600_000_000));
+
+        let sources = [
+            Clocksource::BcmSystemTimer,
+            Clocksource::ArmGeneric,
+            Clocksource::BcmSystemTimer,
+            Clocksource::ArmGeneric,
+        ];
+        for source in sources.iter() {
+            let before = time_manager().uptime();
+            let realtime_before = realtime().unwrap();
+            set_clocksource(*source);
+            let after = time_manager().uptime();
+
+            assert_eq!(clocksource(), *source);
+            assert!(after >= before);
+            assert!(after - before < Duration::from_millis(10));
+            assert!(realtime().unwrap() >= realtime_before);
+        }
+    }
+
+    /// The realtime clock keeps counting from the value it was set to.
+    #[kernel_test]
+    fn realtime_follows_monotonic_clock() {
+        let epoch = Duration::from_secs(1_600_000_000);
+        set_realtime(epoch);
+
+        let now = realtime().unwrap();
+        assert!(now >= epoch);
+        assert!(now < epoch + Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,70 @@
+//! Hardware watchdog.
+//!
+//! The watchdog task feeds the hardware watchdog as long as the scheduler ticks on every core. If
+//! a core stops ticking, the board resets once the timeout expires.
+
+use crate::{bsp, cpu, sched, syscall, warn};
+use core::time::Duration;
+
+/// Watchdog interfaces.
+pub mod interface {
+    use core::time::Duration;
+
+    /// Watchdog functions.
+    pub trait Watchdog {
+        /// Arm the watchdog. The board resets unless fed within `timeout`.
+        ///
+        /// Timeouts beyond the hardware's limit are clamped.
+        fn start(&self, timeout: Duration);
+
+        /// Restart the countdown.
+        fn feed(&self);
+
+        /// Disarm the watchdog.
+        fn stop(&self);
+
+        /// The armed timeout, or `None` while stopped.
+        fn timeout(&self) -> Option<Duration>;
+    }
+}
+
+/// Time without progress after which the board resets.
+pub const TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Time between progress checks, in milliseconds. Must span a few scheduler ticks.
+const CHECK_INTERVAL_MS: u64 = 2000;
+
+/// The watchdog task.
+///
+/// Spawned with `process::add_user_process`, so that it can sleep through the syscall interface.
+pub fn watchdog_task() {
+    use interface::Watchdog;
+
+    let mut last = [0; cpu::NUM_CORES];
+    let mut stalled = [false; cpu::NUM_CORES];
+    for (core, heartbeat) in last.iter_mut().enumerate() {
+        *heartbeat = sched::heartbeat(core);
+    }
+
+    bsp::watchdog().start(TIMEOUT);
+    loop {
+        syscall::sleep(CHECK_INTERVAL_MS);
+
+        let mut progress = true;
+        for core in 0..cpu::NUM_CORES {
+            let heartbeat = sched::heartbeat(core);
+            let core_stalled = heartbeat == last[core];
+
+            if core_stalled && !stalled[core] {
+                warn!("watchdog: core {} stopped scheduling, not feeding", core);
+            }
+            progress &= !core_stalled;
+            stalled[core] = core_stalled;
+            last[core] = heartbeat;
+        }
+
+        if progress {
+            bsp::watchdog().feed();
+        }
+    }
+}
@@ -0,0 +1,84 @@
+#!/usr/bin/env ruby
+# frozen_string_literal: true
+
+# SPDX-License-Identifier: MIT OR Apache-2.0
+
+# Embeds the kernel's function symbols into its `.ksyms` section.
+#
+# Usage: ksyms.rb <kernel ELF>
+#
+# The section is reserved at a fixed size by `src/symbols.rs`, so filling it in after linking moves
+# no other code or data. The format, all little endian:
+#
+#   "KSYM" | count: u32 | count * (addr: u64, size: u32, name offset: u32) | names
+#
+# Entries are sorted by address. Names are demangled, without the hash suffix, and stored as a
+# length byte followed by the bytes.
+
+require 'open3'
+require 'tempfile'
+
+NM = 'rust-nm'
+OBJCOPY = 'rust-objcopy'
+MAX_NAME_LEN = 255
+
+def run(*cmd)
+    out, status = Open3.capture2(*cmd)
+    abort("ksyms: `#{cmd.join(' ')}` failed") unless status.success?
+    out
+end
+
+def read_symbols(elf)
+    symbols = {}
+    section = {}
+
+    run(NM, '--demangle', '--print-size', '--defined-only', elf).each_line do |line|
+        match = /^(\h+) (?:(\h+) )?([a-zA-Z]) (.+)$/.match(line.chomp)
+        next unless match
+
+        addr = match[1].hex
+        name = match[4]
+        section[name] = addr if %w[__ksyms_start __ksyms_end].include?(name)
+        next unless 'tTwW'.include?(match[3])
+
+        # Keep the first name at an address, but prefer one with a size.
+        size = match[2] ? match[2].hex : 0
+        next if symbols.key?(addr) && (symbols[addr][0].positive? || size.zero?)
+
+        name = name.sub(/::h\h{16}$/, '').byteslice(0, MAX_NAME_LEN).scrub('?')
+        symbols[addr] = [size, name]
+    end
+
+    [symbols.sort, section['__ksyms_end'].to_i - section['__ksyms_start'].to_i]
+end
+
+def encode(symbols)
+    entries = +''.b
+    names = +''.b
+
+    symbols.each do |addr, (size, name)|
+        entries << [addr, size, names.bytesize].pack('Q<L<L<')
+        names << [name.bytesize].pack('C') << name.b
+    end
+
+    ['KSYM', symbols.length].pack('a4L<') + entries + names
+end
+
+elf = ARGV[0] || abort('usage: ksyms.rb <kernel ELF>')
+symbols, capacity = read_symbols(elf)
+abort('ksyms: no .ksyms section, is src/symbols.rs linked in?') unless capacity.positive?
+
+table = encode(symbols)
+if table.bytesize > capacity
+    abort("ksyms: the table needs #{table.bytesize} bytes, but only #{capacity} are reserved. " \
+          'Raise TABLE_SIZE in src/symbols.rs.')
+end
+
+Tempfile.create('ksyms') do |file|
+    file.binmode
+    file.write(table.ljust(capacity, "\0"))
+    file.close
+    run(OBJCOPY, "--update-section=.ksyms=#{file.path}", elf)
+end
+
+puts "ksyms: embedded #{symbols.length} symbols, #{table.bytesize} of #{capacity} bytes"